@@ -0,0 +1,137 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, LitInt, LitStr, Path};
+
+enum Column {
+    Index(usize),
+    Name(String),
+}
+
+struct FieldSpec {
+    column: Column,
+    with: Option<Path>,
+    map: Option<Path>,
+}
+
+fn parse_field(field: &syn::Field) -> syn::Result<FieldSpec> {
+    let mut column = None;
+    let mut with = None;
+    let mut map = None;
+
+    for attr in field.attrs.iter() {
+        if !attr.path().is_ident("column") {
+            continue;
+        }
+
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("index") {
+                let value: LitInt = meta.value()?.parse()?;
+                column = Some(Column::Index(value.base10_parse()?));
+            } else if meta.path.is_ident("name") {
+                let value: LitStr = meta.value()?.parse()?;
+                column = Some(Column::Name(value.value()));
+            } else if meta.path.is_ident("with") {
+                with = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("map") {
+                map = Some(meta.value()?.parse()?);
+            } else {
+                return Err(meta.error("expected `index`, `name`, `with` or `map`"));
+            }
+
+            Ok(())
+        })?;
+    }
+
+    match column {
+        Some(column) => Ok(FieldSpec { column, with, map }),
+        None => Err(syn::Error::new_spanned(
+            field,
+            "missing #[column(index = ..)] or #[column(name = \"..\")]",
+        )),
+    }
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let ident = &input.ident;
+    let entity = ident.to_string();
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    ident,
+                    "DumpRow only supports structs with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                ident,
+                "DumpRow only supports structs",
+            ))
+        }
+    };
+
+    let mut values = vec![];
+
+    for field in fields.iter() {
+        let spec = parse_field(field)?;
+        let field_ident = field.ident.as_ref().unwrap();
+        let field_name = field_ident.to_string();
+        let ty = &field.ty;
+
+        let column = match spec.column {
+            Column::Index(index) => quote! { ::library_updater::dump_row::Column::Index(#index) },
+            Column::Name(name) => quote! { ::library_updater::dump_row::Column::Name(#name) },
+        };
+
+        let parse = match spec.with {
+            Some(with) => quote! { #with },
+            None => {
+                quote! { <#ty as ::library_updater::dump_row::FromExpression>::from_expression }
+            }
+        };
+
+        let value = quote! {
+            ::library_updater::dump_row::field(value, columns, #entity, #field_name, #column, #parse)?
+        };
+
+        let value = match spec.map {
+            Some(map) => quote! { #map(#value) },
+            None => value,
+        };
+
+        values.push(quote! { #field_ident: #value });
+    }
+
+    Ok(quote! {
+        impl ::library_updater::types::FromVecExpression<#ident> for #ident {
+            fn from_vec_expression(
+                value: &[::library_updater::dump_row::sql_parse::Expression],
+                columns: &::library_updater::dump_row::Columns,
+            ) -> Result<#ident, ::library_updater::errors::UpdaterError> {
+                Ok(#ident {
+                    #(#values,)*
+                })
+            }
+        }
+    })
+}
+
+/// Derives `FromVecExpression` from `#[column(..)]` attributes.
+///
+/// Every field needs either `index = N` (position in the `VALUES` tuple) or
+/// `name = "Column"` (resolved through the dump's `CREATE TABLE`). Optional
+/// `with = path` replaces the default `FromExpression` parser and
+/// `map = path` post-processes the parsed value.
+#[proc_macro_derive(DumpRow, attributes(column))]
+pub fn derive_dump_row(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    match expand(input) {
+        Ok(v) => v.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
@@ -0,0 +1,23 @@
+#![no_main]
+
+use library_updater::utils::insert_tuples;
+use libfuzzer_sys::fuzz_target;
+
+// Dump lines come from upstream as is, splitting them must never panic and
+// every tuple has to stay inside the line
+fuzz_target!(|data: &[u8]| {
+    let Ok(line) = std::str::from_utf8(data) else {
+        return;
+    };
+
+    let Some((head, tuples)) = insert_tuples(line) else {
+        return;
+    };
+
+    assert!(head.ends_with(" VALUES "));
+
+    for tuple in tuples {
+        assert!(tuple.starts_with('(') && tuple.ends_with(')'));
+        assert!(tuple.len() <= line.len() - head.len());
+    }
+});
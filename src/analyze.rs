@@ -0,0 +1,280 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use async_compression::futures::bufread::GzipDecoder;
+use futures::{io::copy, AsyncWriteExt, TryStreamExt};
+use sql_parse::{parse_statement, CreateDefinition, Expression, InsertReplace, Issues, Statement};
+use tokio::fs::File;
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+use crate::config::DumpEncoding;
+use crate::http;
+use crate::updater::parse_options;
+use crate::utils::{insert_tuples, read_lines};
+
+const SAMPLES_PER_TYPE: usize = 3;
+const SAMPLE_LENGTH: usize = 40;
+
+/// What a dump looks like to the parser, see `library_updater analyze`
+#[derive(Default)]
+pub struct Analysis {
+    pub create_tables: u64,
+    pub inserts: u64,
+    pub rows: u64,
+    pub unparsed_rows: u64,
+    pub other_statements: u64,
+    /// Column names of the last `CREATE TABLE`
+    pub column_names: Vec<String>,
+    /// Number of rows by the number of values in them
+    pub widths: BTreeMap<usize, u64>,
+    pub columns: Vec<ColumnAnalysis>,
+}
+
+#[derive(Default)]
+pub struct ColumnAnalysis {
+    /// Number of values by expression type
+    pub types: BTreeMap<&'static str, u64>,
+    pub samples: BTreeMap<&'static str, Vec<String>>,
+}
+
+fn expression_type(value: &Expression) -> &'static str {
+    match value {
+        Expression::Null(_) => "null",
+        Expression::Bool(..) => "bool",
+        Expression::String(_) => "string",
+        Expression::Integer(_) => "integer",
+        Expression::Float(_) => "float",
+        Expression::Unary { .. } => "unary",
+        Expression::Binary { .. } => "binary",
+        Expression::Function(..) => "function",
+        Expression::Identifier(_) => "identifier",
+        _ => "other",
+    }
+}
+
+fn sample(value: &Expression) -> String {
+    let sample = match value {
+        Expression::Null(_) => "NULL".to_string(),
+        Expression::Bool(v, _) => v.to_string(),
+        Expression::String(v) => format!("'{}'", v.value),
+        Expression::Integer(v) => v.0.to_string(),
+        Expression::Float(v) => v.0.to_string(),
+        Expression::Unary { operand, .. } => format!("-{}", sample(operand)),
+        _ => format!("{value:?}"),
+    };
+
+    match sample.char_indices().nth(SAMPLE_LENGTH) {
+        Some((index, _)) => format!("{}...", &sample[..index]),
+        None => sample,
+    }
+}
+
+impl Analysis {
+    fn add_row(&mut self, values: &[Expression]) {
+        *self.widths.entry(values.len()).or_default() += 1;
+
+        if self.columns.len() < values.len() {
+            self.columns
+                .resize_with(values.len(), ColumnAnalysis::default);
+        }
+
+        for (column, value) in self.columns.iter_mut().zip(values.iter()) {
+            let type_ = expression_type(value);
+            *column.types.entry(type_).or_default() += 1;
+
+            let samples = column.samples.entry(type_).or_default();
+            if samples.len() < SAMPLES_PER_TYPE {
+                let value = sample(value);
+                if !samples.contains(&value) {
+                    samples.push(value);
+                }
+            }
+        }
+    }
+
+    pub fn print(&self) {
+        println!("Statements:");
+        println!("  CREATE TABLE: {}", self.create_tables);
+        println!("  INSERT: {} ({} rows)", self.inserts, self.rows);
+        println!("  other: {}", self.other_statements);
+        println!("  unparsed rows: {}", self.unparsed_rows);
+
+        println!("Values per row:");
+        for (width, count) in self.widths.iter() {
+            println!("  {width}: {count} rows");
+        }
+
+        println!("Columns:");
+        for (index, column) in self.columns.iter().enumerate() {
+            let name = self.column_names.get(index).map_or("?", String::as_str);
+            println!("  #{index} {name}");
+
+            for (type_, count) in column.types.iter() {
+                let samples = column.samples.get(type_).cloned().unwrap_or_default();
+                println!("    {type_}: {count}, e.g. {}", samples.join(", "));
+            }
+        }
+    }
+}
+
+/// Parses every row of a dump the way an import would, without writing anything.
+pub fn analyze_file(path: &Path) -> Result<Analysis, Box<dyn std::error::Error + Send>> {
+    let lines = match read_lines(path, DumpEncoding::Auto) {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let parse_options = parse_options();
+    let mut analysis = Analysis::default();
+    let mut create_table: Option<String> = None;
+
+    for line in lines {
+        let line = match line {
+            Ok(v) => v,
+            Err(err) => return Err(Box::new(err)),
+        };
+
+        if line.starts_with("CREATE TABLE") {
+            create_table = Some(String::new());
+        }
+
+        if let Some(mut statement) = create_table.take() {
+            statement.push_str(&line);
+            statement.push('\n');
+
+            if !line.trim_end().ends_with(';') {
+                create_table = Some(statement);
+                continue;
+            }
+
+            analysis.create_tables += 1;
+
+            let mut issues = Issues::new(&statement);
+            if let Some(Statement::CreateTable(v)) =
+                parse_statement(&statement, &mut issues, &parse_options)
+            {
+                analysis.column_names = v
+                    .create_definitions
+                    .iter()
+                    .filter_map(|definition| match definition {
+                        CreateDefinition::ColumnDefinition { identifier, .. } => {
+                            Some(identifier.value.to_string())
+                        }
+                        _ => None,
+                    })
+                    .collect();
+            }
+
+            continue;
+        }
+
+        let (head, tuples) = match insert_tuples(&line) {
+            Some(v) => v,
+            None => {
+                if line.trim_end().ends_with(';') && !line.starts_with("--") {
+                    analysis.other_statements += 1;
+                }
+                continue;
+            }
+        };
+
+        analysis.inserts += 1;
+
+        for tuple in tuples {
+            analysis.rows += 1;
+
+            let statement = format!("{head}{tuple};");
+            let mut issues = Issues::new(&statement);
+
+            match parse_statement(&statement, &mut issues, &parse_options) {
+                Some(Statement::InsertReplace(InsertReplace {
+                    values: Some((_, values)),
+                    ..
+                })) if values.len() == 1 => analysis.add_row(&values[0]),
+                _ => analysis.unparsed_rows += 1,
+            };
+        }
+    }
+
+    Ok(analysis)
+}
+
+/// Gzipped dumps (local or downloaded) are unpacked next to the other temporary files.
+async fn unpack(
+    target: &str,
+) -> Result<(PathBuf, Option<PathBuf>), Box<dyn std::error::Error + Send>> {
+    let is_url = target.starts_with("http://") || target.starts_with("https://");
+
+    if !is_url && !target.ends_with(".gz") {
+        return Ok((PathBuf::from(target), None));
+    }
+
+    let file_name = target.rsplit('/').next().unwrap_or(target);
+    let path = std::env::temp_dir().join(format!(
+        "analyze-{}",
+        file_name.strip_suffix(".gz").unwrap_or(file_name)
+    ));
+
+    let data: Box<dyn futures::AsyncBufRead + Unpin + Send> = if is_url {
+        let response = match http::get(target).await {
+            Ok(v) => v,
+            Err(err) => return Err(Box::new(err)),
+        };
+
+        let response = match response.error_for_status() {
+            Ok(v) => v,
+            Err(err) => return Err(Box::new(err)),
+        };
+
+        Box::new(
+            response
+                .bytes_stream()
+                .map_err(std::io::Error::other)
+                .into_async_read(),
+        )
+    } else {
+        match File::open(target).await {
+            Ok(v) => Box::new(futures::io::BufReader::new(v.compat())),
+            Err(err) => return Err(Box::new(err)),
+        }
+    };
+
+    let mut file = match File::create(&path).await {
+        Ok(v) => v.compat(),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let result = if file_name.ends_with(".gz") {
+        copy(GzipDecoder::new(data), &mut file).await
+    } else {
+        copy(data, &mut file).await
+    };
+
+    if let Err(err) = result {
+        return Err(Box::new(err));
+    }
+
+    match file.close().await {
+        Ok(_) => Ok((path.clone(), Some(path))),
+        Err(err) => Err(Box::new(err)),
+    }
+}
+
+/// `library_updater analyze <file-or-url>`
+pub async fn run(target: &str) -> Result<Analysis, Box<dyn std::error::Error + Send>> {
+    let (path, temporary) = match unpack(target).await {
+        Ok(v) => v,
+        Err(err) => return Err(err),
+    };
+
+    let result = match tokio::task::spawn_blocking(move || analyze_file(&path)).await {
+        Ok(v) => v,
+        Err(err) => Err(Box::new(err) as Box<dyn std::error::Error + Send>),
+    };
+
+    if let Some(path) = temporary {
+        let _ = tokio::fs::remove_file(path).await;
+    }
+
+    result
+}
@@ -0,0 +1,49 @@
+use tokio_postgres::Client;
+use tracing::log;
+use uuid::Uuid;
+
+use crate::ids::RemoteBookId;
+
+/// Stores books inserted by the run in `new_arrivals`.
+pub async fn record(
+    client: &Client,
+    run_id: Uuid,
+    source_id: i16,
+    books: &[RemoteBookId],
+) -> Result<(), Box<dyn std::error::Error + Send>> {
+    match client
+        .batch_execute(
+            "
+            CREATE TABLE IF NOT EXISTS new_arrivals (
+                book integer NOT NULL,
+                run_id uuid NOT NULL,
+                added_at date NOT NULL DEFAULT current_date,
+                PRIMARY KEY (book, run_id)
+            );
+            CREATE INDEX IF NOT EXISTS new_arrivals_added_at ON new_arrivals (added_at);
+            ",
+        )
+        .await
+    {
+        Ok(_) => (),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    match client
+        .execute(
+            "
+            INSERT INTO new_arrivals (book, run_id)
+                SELECT id, $2 FROM books WHERE source = $1 AND remote_id = ANY($3)
+            ON CONFLICT DO NOTHING;
+            ",
+            &[&source_id, &run_id, &books],
+        )
+        .await
+    {
+        Ok(count) => {
+            log::info!("Recorded {count} new arrivals");
+            Ok(())
+        }
+        Err(err) => Err(Box::new(err)),
+    }
+}
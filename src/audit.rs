@@ -0,0 +1,80 @@
+use deadpool_postgres::Pool;
+use tokio::sync::OnceCell;
+use tokio_postgres::Client;
+use tracing::log;
+
+use crate::db;
+
+static SCHEMA: OnceCell<()> = OnceCell::const_new();
+
+/// Creates `audit_log`. Entity changes have a source and a remote id, API
+/// actions an actor instead.
+pub async fn prepare(client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
+    match client
+        .batch_execute(
+            "
+            CREATE TABLE IF NOT EXISTS audit_log (
+                id bigserial PRIMARY KEY,
+                source smallint,
+                entity varchar(32) NOT NULL,
+                remote_id int,
+                action varchar(32) NOT NULL,
+                old_value text,
+                new_value text,
+                actor varchar(64),
+                created_at timestamptz NOT NULL DEFAULT now()
+            );
+            CREATE INDEX IF NOT EXISTS audit_log_entity ON audit_log (entity, source, remote_id, id);
+            ",
+        )
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(err) => Err(Box::new(err)),
+    }
+}
+
+/// `prepare` once per process, for the API actions
+async fn prepare_once(pool: &Pool) -> Result<(), Box<dyn std::error::Error + Send>> {
+    SCHEMA
+        .get_or_try_init(|| async {
+            let client = match db::checkout(pool).await {
+                Ok(v) => v,
+                Err(err) => return Err(Box::new(err) as Box<dyn std::error::Error + Send>),
+            };
+
+            match prepare(&client).await {
+                Ok(_) => Ok(()),
+                Err(err) => Err(err as Box<dyn std::error::Error + Send>),
+            }
+        })
+        .await
+        .map(|_| ())
+}
+
+/// Records an API action done with the key named `actor`. Failures are only
+/// logged, the action itself already happened.
+pub async fn record(pool: &Pool, actor: &str, action: &str, value: Option<String>) {
+    if let Err(err) = prepare_once(pool).await {
+        log::error!("Can't prepare the audit log: {:?}", err);
+        return;
+    }
+
+    let client = match db::checkout(pool).await {
+        Ok(v) => v,
+        Err(err) => {
+            log::error!("Can't record {action} by {actor}: {:?}", err);
+            return;
+        }
+    };
+
+    if let Err(err) = client
+        .execute(
+            "INSERT INTO audit_log (entity, action, new_value, actor) VALUES ('api', $1, $2, $3);",
+            &[&action, &value, &actor],
+        )
+        .await
+    {
+        log::error!("Can't record {action} by {actor}: {:?}", err);
+    }
+}
@@ -0,0 +1,219 @@
+use std::marker::PhantomData;
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{request::Parts, StatusCode},
+};
+
+use crate::{
+    config::{self, ApiKey, Scope},
+    tls::ClientCertificate,
+};
+
+pub trait RequiredScope {
+    const SCOPE: Scope;
+}
+
+pub struct Trigger;
+
+impl RequiredScope for Trigger {
+    const SCOPE: Scope = Scope::Trigger;
+}
+
+pub struct ReadStatus;
+
+impl RequiredScope for ReadStatus {
+    const SCOPE: Scope = Scope::ReadStatus;
+}
+
+pub struct Admin;
+
+impl RequiredScope for Admin {
+    const SCOPE: Scope = Scope::Admin;
+}
+
+/// The caller of an endpoint, extracting it checks the `Authorization` key
+/// has the scope `S`. A verified client certificate passes the admin check.
+pub struct Caller<S> {
+    /// Name of the key used
+    pub name: String,
+    scope: PhantomData<S>,
+}
+
+impl<S> Caller<S> {
+    fn new(name: String) -> Self {
+        Caller {
+            name,
+            scope: PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<S, State> FromRequestParts<State> for Caller<S>
+where
+    S: RequiredScope,
+    State: Send + Sync,
+{
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &State,
+    ) -> Result<Self, Self::Rejection> {
+        match authorize(
+            parts,
+            S::SCOPE,
+            &config::CONFIG.api_keys,
+            config::CONFIG.require_read_key,
+        ) {
+            Ok(name) => Ok(Caller::new(name)),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Name of the caller allowed to use `scope`
+fn authorize(
+    parts: &Parts,
+    scope: Scope,
+    api_keys: &[ApiKey],
+    require_read_key: bool,
+) -> Result<String, (StatusCode, &'static str)> {
+    if scope == Scope::ReadStatus && !require_read_key {
+        return Ok("anonymous".to_string());
+    }
+
+    // Client certificates stand in for a key on the admin endpoints only
+    if scope == Scope::Admin {
+        if let Some(certificate) = parts.extensions.get::<ClientCertificate>() {
+            return Ok(format!("cert:{}", certificate.fingerprint));
+        }
+    }
+
+    let key = match parts.headers.get("Authorization").map(|v| v.to_str()) {
+        Some(Ok(v)) => v,
+        _ => return Err((StatusCode::UNAUTHORIZED, "No api-key!")),
+    };
+
+    let api_key = match api_keys.iter().find(|v| v.key == key) {
+        Some(v) => v,
+        None => return Err((StatusCode::UNAUTHORIZED, "Wrong api-key!")),
+    };
+
+    if !api_key.allows(scope) {
+        return Err((StatusCode::FORBIDDEN, "Api-key lacks the scope!"));
+    }
+
+    Ok(api_key.name.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{request::Parts, Request, StatusCode};
+
+    use crate::auth::authorize;
+    use crate::config::{ApiKey, Scope};
+    use crate::tls::ClientCertificate;
+
+    fn api_keys() -> Vec<ApiKey> {
+        vec![ApiKey {
+            name: "bot".to_string(),
+            key: "secret".to_string(),
+            scopes: vec![Scope::Trigger],
+        }]
+    }
+
+    fn parts(key: Option<&str>) -> Parts {
+        let mut request = Request::builder();
+        if let Some(key) = key {
+            request = request.header("Authorization", key);
+        }
+        request.body(()).unwrap().into_parts().0
+    }
+
+    #[test]
+    fn test_authorize_no_key() {
+        let input = parts(None);
+        let expected_result = Err((StatusCode::UNAUTHORIZED, "No api-key!"));
+
+        let result = authorize(&input, Scope::Trigger, &api_keys(), true);
+
+        assert_eq!(result, expected_result);
+    }
+
+    #[test]
+    fn test_authorize_wrong_key() {
+        let input = parts(Some("guess"));
+        let expected_result = Err((StatusCode::UNAUTHORIZED, "Wrong api-key!"));
+
+        let result = authorize(&input, Scope::Trigger, &api_keys(), true);
+
+        assert_eq!(result, expected_result);
+    }
+
+    #[test]
+    fn test_authorize_key_without_scope() {
+        let input = parts(Some("secret"));
+        let expected_result = Err((StatusCode::FORBIDDEN, "Api-key lacks the scope!"));
+
+        let result = authorize(&input, Scope::Admin, &api_keys(), true);
+
+        assert_eq!(result, expected_result);
+    }
+
+    #[test]
+    fn test_authorize_key_with_scope() {
+        let input = parts(Some("secret"));
+        let expected_result = Ok("bot".to_string());
+
+        let result = authorize(&input, Scope::Trigger, &api_keys(), true);
+
+        assert_eq!(result, expected_result);
+    }
+
+    #[test]
+    fn test_authorize_client_certificate() {
+        let mut input = parts(None);
+        input.extensions.insert(ClientCertificate {
+            fingerprint: "ab12".to_string(),
+        });
+        let expected_result = Ok("cert:ab12".to_string());
+
+        let result = authorize(&input, Scope::Admin, &api_keys(), true);
+
+        assert_eq!(result, expected_result);
+    }
+
+    #[test]
+    fn test_authorize_client_certificate_not_admin() {
+        let mut input = parts(None);
+        input.extensions.insert(ClientCertificate {
+            fingerprint: "ab12".to_string(),
+        });
+        let expected_result = Err((StatusCode::UNAUTHORIZED, "No api-key!"));
+
+        let result = authorize(&input, Scope::Trigger, &api_keys(), true);
+
+        assert_eq!(result, expected_result);
+    }
+
+    #[test]
+    fn test_authorize_anonymous() {
+        let input = parts(None);
+
+        assert_eq!(
+            authorize(&input, Scope::ReadStatus, &api_keys(), false),
+            Ok("anonymous".to_string())
+        );
+        assert_eq!(
+            authorize(&input, Scope::ReadStatus, &api_keys(), true),
+            Err((StatusCode::UNAUTHORIZED, "No api-key!"))
+        );
+        assert_eq!(
+            authorize(&input, Scope::Trigger, &api_keys(), false),
+            Err((StatusCode::UNAUTHORIZED, "No api-key!"))
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use std::time::Duration;
+
+use tracing::log;
+
+use crate::config::{self, WriteSettings};
+
+/// Batch size of a table. With `ADAPTIVE_BATCH` it follows the latency of the
+/// written batches: grows while they are fast, shrinks when they get slow or
+/// fail, always within `BATCH_SIZE_MIN` and `BATCH_SIZE_MAX`.
+pub struct BatchSizer {
+    size: usize,
+    adaptive: bool,
+    min: usize,
+    max: usize,
+    target: Duration,
+}
+
+impl BatchSizer {
+    pub fn new(settings: &WriteSettings) -> BatchSizer {
+        let min = config::CONFIG.batch_size_min.max(1);
+        let max = config::CONFIG.batch_size_max.max(min);
+        let adaptive = config::CONFIG.adaptive_batch;
+
+        BatchSizer {
+            size: match adaptive {
+                true => settings.batch_size.clamp(min, max),
+                false => settings.batch_size,
+            },
+            adaptive,
+            min,
+            max,
+            target: Duration::from_millis(config::CONFIG.batch_target_latency_ms),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Takes the outcome of a batch of `rows`: how long its last attempt took
+    /// and how many attempts failed before.
+    pub fn observe(&mut self, file_name: &str, rows: usize, latency: Duration, errors: u32) {
+        // Only full batches say something about the size
+        if !self.adaptive || rows < self.size {
+            return;
+        }
+
+        let size = if errors > 0 {
+            self.size / 2
+        } else if latency > self.target + self.target / 2 {
+            self.size * 3 / 4
+        } else if latency < self.target / 2 {
+            self.size + (self.size / 4).max(1)
+        } else {
+            self.size
+        };
+        let size = size.clamp(self.min, self.max);
+
+        if size != self.size {
+            log::debug!(
+                "{file_name}: batch size {} -> {size} ({} ms, {errors} errors)",
+                self.size,
+                latency.as_millis()
+            );
+            self.size = size;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::batching::BatchSizer;
+
+    fn sizer(size: usize) -> BatchSizer {
+        BatchSizer {
+            size,
+            adaptive: true,
+            min: 10,
+            max: 1000,
+            target: Duration::from_millis(100),
+        }
+    }
+
+    #[test]
+    fn test_observe_grows() {
+        let mut input = sizer(100);
+        let expected_result = 125;
+
+        input.observe("lib.a.annotations", 100, Duration::from_millis(10), 0);
+        let result = input.size();
+
+        assert_eq!(result, expected_result);
+    }
+
+    #[test]
+    fn test_observe_shrinks() {
+        let mut slow = sizer(100);
+        slow.observe("lib.a.annotations", 100, Duration::from_millis(200), 0);
+
+        let mut failed = sizer(100);
+        failed.observe("lib.a.annotations", 100, Duration::from_millis(100), 1);
+
+        assert_eq!(slow.size(), 75);
+        assert_eq!(failed.size(), 50);
+    }
+
+    #[test]
+    fn test_observe_keeps() {
+        let mut partial = sizer(100);
+        partial.observe("lib.a.annotations", 40, Duration::from_millis(10), 0);
+
+        let mut on_target = sizer(100);
+        on_target.observe("lib.a.annotations", 100, Duration::from_millis(100), 0);
+
+        assert_eq!(partial.size(), 100);
+        assert_eq!(on_target.size(), 100);
+    }
+
+    #[test]
+    fn test_observe_clamps() {
+        let mut large = sizer(900);
+        large.observe("lib.a.annotations", 900, Duration::from_millis(10), 0);
+
+        let mut small = sizer(15);
+        small.observe("lib.a.annotations", 15, Duration::from_millis(100), 3);
+
+        assert_eq!(large.size(), 1000);
+        assert_eq!(small.size(), 10);
+    }
+}
@@ -0,0 +1,488 @@
+use chrono::{DateTime, Utc};
+use deadpool_postgres::{Object, Transaction};
+use tokio::sync::{Mutex, OnceCell};
+use tokio_postgres::Client;
+use tracing::log;
+use uuid::Uuid;
+
+use crate::config;
+use crate::errors::UpdaterError;
+use crate::shadow;
+
+/// Readers query the views of this schema, they select from the tables of the
+/// current catalog schema. `current_catalog` is a reserved word.
+pub const CURRENT: &str = "live_catalog";
+
+static REGISTRY: OnceCell<()> = OnceCell::const_new();
+
+/// The catalog schema of the running import
+struct Staging {
+    schema: String,
+    /// Where its tables are copied from: the current catalog or `public`
+    from: String,
+    tables: Vec<&'static str>,
+}
+
+lazy_static! {
+    static ref STAGING: Mutex<Option<Staging>> = Mutex::new(None);
+
+    /// A catalog schema holds the rows of all sources, one import at a time
+    pub static ref LOCK: Mutex<()> = Mutex::new(());
+}
+
+pub fn enabled() -> bool {
+    config::CONFIG.catalog_schemas
+}
+
+/// `catalog_20240101_030000` for a run started then
+pub fn schema_name(started_at: DateTime<Utc>) -> String {
+    format!("catalog_{}", started_at.format("%Y%m%d_%H%M%S"))
+}
+
+async fn prepare_registry(client: &Client) -> Result<(), Box<dyn std::error::Error + Send>> {
+    REGISTRY
+        .get_or_try_init(|| async {
+            match client
+                .batch_execute(
+                    "
+                    CREATE TABLE IF NOT EXISTS catalog_schemas (
+                        name text PRIMARY KEY,
+                        run_id uuid NOT NULL,
+                        created_at timestamptz NOT NULL DEFAULT now(),
+                        switched_at timestamptz,
+                        is_current boolean NOT NULL DEFAULT false
+                    );
+
+                    CREATE UNIQUE INDEX IF NOT EXISTS catalog_schemas_current
+                        ON catalog_schemas (is_current) WHERE is_current;
+                    ",
+                )
+                .await
+            {
+                Ok(_) => Ok(()),
+                Err(err) => Err(Box::new(err) as Box<dyn std::error::Error + Send>),
+            }
+        })
+        .await
+        .map(|_| ())
+}
+
+/// The schema the views of `CURRENT` select from
+async fn current(client: &Client) -> Result<Option<String>, Box<dyn std::error::Error + Send>> {
+    match client
+        .query_opt("SELECT name FROM catalog_schemas WHERE is_current;", &[])
+        .await
+    {
+        Ok(row) => Ok(row.map(|row| row.get(0))),
+        Err(err) => Err(Box::new(err)),
+    }
+}
+
+/// Creates the catalog schema of the run, the tables are copied to it as
+/// their imports start
+pub async fn start(
+    client: &Client,
+    run_id: Uuid,
+    schema: &str,
+) -> Result<(), Box<dyn std::error::Error + Send>> {
+    match prepare_registry(client).await {
+        Ok(_) => (),
+        Err(err) => return Err(err),
+    };
+
+    let from = match current(client).await {
+        Ok(v) => v.unwrap_or_else(|| "public".to_string()),
+        Err(err) => return Err(err),
+    };
+
+    match client
+        .batch_execute(&format!(
+            "DROP SCHEMA IF EXISTS {schema} CASCADE; CREATE SCHEMA {schema};"
+        ))
+        .await
+    {
+        Ok(_) => (),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    match client
+        .execute(
+            "
+            INSERT INTO catalog_schemas (name, run_id) VALUES ($1, $2)
+            ON CONFLICT (name) DO UPDATE
+                SET run_id = $2, created_at = now(), switched_at = NULL, is_current = false;
+            ",
+            &[&schema, &run_id],
+        )
+        .await
+    {
+        Ok(_) => (),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    log::info!("Import into {schema}, tables are copied from {from}");
+
+    *STAGING.lock().await = Some(Staging {
+        schema: schema.to_string(),
+        from,
+        tables: vec![],
+    });
+
+    Ok(())
+}
+
+/// Copies the tables to the catalog schema of the run with their rows, the
+/// ones copied before are left as they are
+pub async fn prepare(
+    client: &Client,
+    tables: &[&'static str],
+) -> Result<(), Box<dyn std::error::Error + Send>> {
+    let mut staging = STAGING.lock().await;
+    let staging = match staging.as_mut() {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+
+    for table in tables {
+        if staging.tables.contains(table) {
+            continue;
+        }
+
+        match shadow::copy_table(client, table, &staging.from, &staging.schema).await {
+            Ok(_) => (),
+            Err(err) => return Err(err),
+        };
+
+        staging.tables.push(table);
+    }
+
+    Ok(())
+}
+
+/// Tables that lost more rows than `CATALOG_MAX_SHRINK_PERCENT` allows
+async fn validate(
+    client: &Client,
+    staging: &Staging,
+) -> Result<Vec<String>, Box<dyn std::error::Error + Send>> {
+    let mut problems = vec![];
+
+    for table in staging.tables.iter() {
+        let row = match client
+            .query_one(
+                &format!(
+                    "SELECT (SELECT count(*) FROM {}.{table}), (SELECT count(*) FROM {}.{table});",
+                    staging.schema, staging.from
+                ),
+                &[],
+            )
+            .await
+        {
+            Ok(v) => v,
+            Err(err) => return Err(Box::new(err)),
+        };
+
+        let (rows, previous_rows): (i64, i64) = (row.get(0), row.get(1));
+        let min_rows =
+            previous_rows as f64 * (1.0 - config::CONFIG.catalog_max_shrink_percent / 100.0);
+
+        if (rows as f64) < min_rows {
+            problems.push(format!(
+                "{table} has {rows} rows, {previous_rows} in {}",
+                staging.from
+            ));
+        }
+    }
+
+    Ok(problems)
+}
+
+/// Points the views of `CURRENT` at the tables of `schema`
+async fn point(
+    client: &Transaction<'_>,
+    schema: &str,
+) -> Result<(), Box<dyn std::error::Error + Send>> {
+    let rows = match client
+        .query(
+            "
+            SELECT table_name::text FROM information_schema.tables
+            WHERE table_schema = $1 AND table_type = 'BASE TABLE'
+            ORDER BY table_name;
+            ",
+            &[&schema],
+        )
+        .await
+    {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let mut statements =
+        format!("DROP SCHEMA IF EXISTS {CURRENT} CASCADE; CREATE SCHEMA {CURRENT};");
+    for row in rows {
+        let table: String = row.get(0);
+        statements.push_str(&format!(
+            "CREATE VIEW {CURRENT}.{table} AS SELECT * FROM {schema}.{table};"
+        ));
+    }
+
+    match client.batch_execute(&statements).await {
+        Ok(_) => (),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    match client
+        .execute(
+            "UPDATE catalog_schemas SET is_current = false WHERE is_current;",
+            &[],
+        )
+        .await
+    {
+        Ok(_) => (),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    match client
+        .execute(
+            "UPDATE catalog_schemas SET is_current = true, switched_at = now() WHERE name = $1;",
+            &[&schema],
+        )
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(err) => Err(Box::new(err)),
+    }
+}
+
+/// Validates the catalog schema of the run and switches the views of `CURRENT`
+/// to it in one transaction. The tables of the current catalog the run didn't
+/// import are copied over first, foreign keys between the tables too.
+pub async fn switch(client: &mut Object) -> Result<(), Box<dyn std::error::Error + Send>> {
+    let mut staging = match STAGING.lock().await.take() {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+
+    if staging.from != "public" {
+        let rows = match client
+            .query(
+                "
+                SELECT table_name::text FROM information_schema.tables
+                WHERE table_schema = $1 AND table_type = 'BASE TABLE';
+                ",
+                &[&staging.from],
+            )
+            .await
+        {
+            Ok(v) => v,
+            Err(err) => return Err(Box::new(err)),
+        };
+
+        for row in rows {
+            let table: String = row.get(0);
+            if staging.tables.iter().any(|v| *v == table) {
+                continue;
+            }
+
+            match shadow::copy_table(client, &table, &staging.from, &staging.schema).await {
+                Ok(_) => (),
+                Err(err) => return Err(err),
+            };
+        }
+    }
+
+    let problems = match validate(client, &staging).await {
+        Ok(v) => v,
+        Err(err) => return Err(err),
+    };
+
+    if !problems.is_empty() {
+        return Err(Box::new(UpdaterError::CatalogRejected {
+            schema: staging.schema,
+            problems,
+        }));
+    }
+
+    log::info!("Switch {CURRENT} to {}...", staging.schema);
+
+    let transaction = match client.transaction().await {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    // Printed without the schema, they point at the tables of the new one
+    match transaction
+        .batch_execute(&format!("SET LOCAL search_path = {};", staging.from))
+        .await
+    {
+        Ok(_) => (),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let keys = match transaction
+        .query(
+            "
+            SELECT quote_ident(c.conname), r.relname::text, pg_get_constraintdef(c.oid)
+            FROM pg_constraint c
+            JOIN pg_class r ON r.oid = c.conrelid
+            JOIN pg_class f ON f.oid = c.confrelid
+            JOIN pg_namespace n ON n.oid = r.relnamespace AND n.oid = f.relnamespace
+            WHERE c.contype = 'f' AND n.nspname = $1;
+            ",
+            &[&staging.from],
+        )
+        .await
+    {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let mut statements = format!("SET LOCAL search_path = {}, public;", staging.schema);
+    let mut added = vec![];
+    for key in keys {
+        let (name, table, definition): (String, String, String) =
+            (key.get(0), key.get(1), key.get(2));
+
+        // Checked after the commit, the switch shouldn't wait for it
+        statements.push_str(&format!(
+            "ALTER TABLE {}.{table} ADD CONSTRAINT {name} {definition} NOT VALID;",
+            staging.schema
+        ));
+        added.push((name, table));
+    }
+
+    match transaction.batch_execute(&statements).await {
+        Ok(_) => (),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    match point(&transaction, &staging.schema).await {
+        Ok(_) => (),
+        Err(err) => return Err(err),
+    };
+
+    match transaction.commit().await {
+        Ok(_) => (),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    for (name, table) in added {
+        if let Err(err) = client
+            .batch_execute(&format!(
+                "ALTER TABLE {}.{table} VALIDATE CONSTRAINT {name};",
+                staging.schema
+            ))
+            .await
+        {
+            log::error!("Can't validate {name} of {table}: {:?}", err);
+        }
+    }
+
+    staging.tables.clear();
+    log::info!("Switched {CURRENT} to {}", staging.schema);
+
+    prune(client).await;
+
+    Ok(())
+}
+
+/// Forgets the catalog schema of a failed run, it's kept for a look until the
+/// next switches prune it
+pub async fn abandon() {
+    *STAGING.lock().await = None;
+}
+
+/// Drops the catalog schemas past `CATALOG_SCHEMAS_KEPT`, oldest first
+async fn prune(client: &Client) {
+    let rows = match client
+        .query(
+            "SELECT name FROM catalog_schemas WHERE NOT is_current ORDER BY name DESC OFFSET $1;",
+            &[&config::CONFIG.catalog_schemas_kept],
+        )
+        .await
+    {
+        Ok(v) => v,
+        Err(err) => {
+            log::error!("Can't list old catalog schemas: {:?}", err);
+            return;
+        }
+    };
+
+    for row in rows {
+        let name: String = row.get(0);
+
+        let result = client
+            .batch_execute(&format!("DROP SCHEMA IF EXISTS {name} CASCADE;"))
+            .await;
+        let result = match result {
+            Ok(_) => {
+                client
+                    .execute("DELETE FROM catalog_schemas WHERE name = $1;", &[&name])
+                    .await
+            }
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok(_) => log::info!("Dropped old catalog schema {name}"),
+            Err(err) => log::error!("Can't drop old catalog schema {name}: {:?}", err),
+        };
+    }
+}
+
+/// Points the views of `CURRENT` back at the catalog switched to before the
+/// current one. Returns that schema.
+pub async fn rollback(
+    client: &mut Object,
+) -> Result<Option<String>, Box<dyn std::error::Error + Send>> {
+    let _lock = LOCK.lock().await;
+
+    match prepare_registry(client).await {
+        Ok(_) => (),
+        Err(err) => return Err(err),
+    };
+
+    let current = match current(client).await {
+        Ok(Some(v)) => v,
+        Ok(None) => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    let previous: Option<String> = match client
+        .query_opt(
+            "
+            SELECT name FROM catalog_schemas
+            WHERE switched_at IS NOT NULL AND name < $1
+            ORDER BY name DESC LIMIT 1;
+            ",
+            &[&current],
+        )
+        .await
+    {
+        Ok(row) => row.map(|row| row.get(0)),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let previous = match previous {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+
+    let transaction = match client.transaction().await {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    match point(&transaction, &previous).await {
+        Ok(_) => (),
+        Err(err) => return Err(err),
+    };
+
+    match transaction.commit().await {
+        Ok(_) => (),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    log::info!("Rolled {CURRENT} back from {current} to {previous}");
+
+    Ok(Some(previous))
+}
@@ -0,0 +1,258 @@
+use deadpool_postgres::Object;
+use serde_json::{json, Value};
+use tokio_postgres::Client;
+use tracing::log;
+
+use crate::config;
+
+/// What a change of a row of a catalog table invalidates: the table, the
+/// entity and the column with the entity's id
+const CAPTURED: [(&str, &str, &str); 14] = [
+    ("authors", "author", "id"),
+    ("books", "book", "id"),
+    ("book_authors", "book", "book"),
+    ("book_authors", "author", "author"),
+    ("translations", "book", "book"),
+    ("translations", "author", "author"),
+    ("sequences", "sequence", "id"),
+    ("book_sequences", "book", "book"),
+    ("book_sequences", "sequence", "sequence"),
+    ("book_annotations", "book", "book"),
+    ("author_annotations", "author", "author"),
+    ("genres", "genre", "id"),
+    ("book_genres", "book", "book"),
+    ("book_genres", "genre", "genre"),
+];
+
+/// Columns written on every run whatever the row, a change of only these
+/// isn't one of the entity
+const BOOKKEEPING: &str = "'{seen_at}'::text[]";
+
+/// `NOTIFY` payloads are limited to 8000 bytes
+const MAX_NOTIFY_BATCH: usize = 200;
+
+/// Whether changes are captured: `CHANGE_CAPTURE`, `CHANGE_NOTIFY` and the
+/// search indexer need them
+pub fn enabled() -> bool {
+    config::CONFIG.change_capture
+        || config::CONFIG.change_notify
+        || config::CONFIG.meilisearch_url.is_some()
+}
+
+/// With `enabled` triggers on the catalog tables write every changed entity to
+/// `catalog_changes`, downstream caches read it to invalidate just those.
+/// Without it the triggers are dropped. Changes older than
+/// `CHANGES_RETENTION_DAYS` are removed.
+pub async fn prepare(client: &Client) -> Result<(), Box<dyn std::error::Error + Send>> {
+    let enabled = enabled();
+
+    if enabled {
+        match client
+            .batch_execute(
+                "
+                CREATE TABLE IF NOT EXISTS catalog_changes (
+                    id bigserial PRIMARY KEY,
+                    entity varchar(32) NOT NULL,
+                    entity_id integer NOT NULL,
+                    action varchar(16) NOT NULL,
+                    created_at timestamptz NOT NULL DEFAULT now()
+                );
+                CREATE INDEX IF NOT EXISTS catalog_changes_created_at ON catalog_changes (created_at);
+
+                ALTER TABLE catalog_changes ADD COLUMN IF NOT EXISTS pending boolean NOT NULL DEFAULT false;
+                CREATE INDEX IF NOT EXISTS catalog_changes_pending ON catalog_changes (id) WHERE pending;
+
+                CREATE OR REPLACE FUNCTION capture_catalog_change() RETURNS trigger AS $$
+                    DECLARE
+                        changed jsonb;
+                    BEGIN
+                        IF TG_OP = 'DELETE' THEN
+                            changed := to_jsonb(OLD);
+                        ELSE
+                            changed := to_jsonb(NEW);
+                        END IF;
+
+                        INSERT INTO catalog_changes (entity, entity_id, action)
+                            VALUES (TG_ARGV[0], (changed ->> TG_ARGV[1])::integer, lower(TG_OP));
+                        RETURN NULL;
+                    END;
+                $$ LANGUAGE plpgsql;
+                ",
+            )
+            .await
+        {
+            Ok(_) => (),
+            Err(err) => return Err(Box::new(err)),
+        };
+    }
+
+    for (table, entity, column) in CAPTURED {
+        let exists: bool = match client
+            .query_one(
+                "SELECT to_regclass($1) IS NOT NULL;",
+                &[&format!("public.{table}")],
+            )
+            .await
+        {
+            Ok(row) => row.get(0),
+            Err(err) => return Err(Box::new(err)),
+        };
+
+        if !exists {
+            continue;
+        }
+
+        // `WHEN` of an `INSERT` trigger can't look at `OLD`, updates get a trigger of their own
+        let mut statements = format!(
+            "
+            DROP TRIGGER IF EXISTS catalog_changes_{entity} ON {table};
+            DROP TRIGGER IF EXISTS catalog_changes_{entity}_update ON {table};
+            "
+        );
+
+        if enabled {
+            statements.push_str(&format!(
+                "
+                CREATE TRIGGER catalog_changes_{entity} AFTER INSERT OR DELETE ON {table}
+                    FOR EACH ROW EXECUTE FUNCTION capture_catalog_change('{entity}', '{column}');
+                CREATE TRIGGER catalog_changes_{entity}_update AFTER UPDATE ON {table}
+                    FOR EACH ROW WHEN (
+                        (to_jsonb(OLD) - {BOOKKEEPING}) IS DISTINCT FROM (to_jsonb(NEW) - {BOOKKEEPING})
+                    )
+                    EXECUTE FUNCTION capture_catalog_change('{entity}', '{column}');
+                "
+            ));
+        }
+
+        match client.batch_execute(&statements).await {
+            Ok(_) => (),
+            Err(err) => return Err(Box::new(err)),
+        };
+    }
+
+    if !enabled {
+        return Ok(());
+    }
+
+    // Only changes made while `CHANGE_NOTIFY` is on wait for `notify`
+    match client
+        .batch_execute(&format!(
+            "
+            ALTER TABLE catalog_changes ALTER COLUMN pending SET DEFAULT {};
+            UPDATE catalog_changes SET pending = false WHERE pending AND NOT {0};
+            ",
+            config::CONFIG.change_notify
+        ))
+        .await
+    {
+        Ok(_) => (),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    match client
+        .execute(
+            "DELETE FROM catalog_changes WHERE created_at < now() - make_interval(days => $1);",
+            &[&config::CONFIG.changes_retention_days],
+        )
+        .await
+    {
+        Ok(count) => {
+            log::info!("Removed {count} old catalog changes");
+            Ok(())
+        }
+        Err(err) => Err(Box::new(err)),
+    }
+}
+
+/// The id of the last captured change, the ones made later are above it.
+/// `None` if changes aren't captured.
+pub async fn last_id(client: &Client) -> Result<Option<i64>, Box<dyn std::error::Error + Send>> {
+    if !enabled() {
+        return Ok(None);
+    }
+
+    match client
+        .query_one("SELECT coalesce(max(id), 0) FROM catalog_changes;", &[])
+        .await
+    {
+        Ok(row) => Ok(Some(row.get(0))),
+        Err(err) => Err(Box::new(err)),
+    }
+}
+
+/// With `CHANGE_NOTIFY` sends the pending changes to `NOTIFY_CHANNEL`, as
+/// `{"entity":"book","id":123}` or, with `NOTIFY_BATCH_SIZE` above one, as
+/// arrays of those. Changes committed meanwhile wait for the next call.
+pub async fn notify(client: &mut Object) -> Result<(), Box<dyn std::error::Error + Send>> {
+    if !config::CONFIG.change_notify {
+        return Ok(());
+    }
+
+    let transaction = match client.transaction().await {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let rows = match transaction
+        .query(
+            "
+            UPDATE catalog_changes SET pending = false WHERE pending
+            RETURNING entity::text, entity_id;
+            ",
+            &[],
+        )
+        .await
+    {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let mut changed: Vec<(String, i32)> = rows.iter().map(|row| (row.get(0), row.get(1))).collect();
+    changed.sort();
+    changed.dedup();
+
+    if changed.is_empty() {
+        return Ok(());
+    }
+
+    let batch_size = config::CONFIG.notify_batch_size.clamp(1, MAX_NOTIFY_BATCH);
+    let payloads: Vec<String> = changed
+        .chunks(batch_size)
+        .map(|chunk| {
+            let mut values: Vec<Value> = chunk
+                .iter()
+                .map(|(entity, id)| json!({"entity": entity, "id": id}))
+                .collect();
+
+            match batch_size {
+                1 => values.remove(0).to_string(),
+                _ => Value::Array(values).to_string(),
+            }
+        })
+        .collect();
+
+    // Delivered on commit, together with the changes being marked as sent
+    match transaction
+        .execute(
+            "SELECT pg_notify($1, payload) FROM unnest($2::text[]) AS payload;",
+            &[&config::CONFIG.notify_channel, &payloads],
+        )
+        .await
+    {
+        Ok(_) => (),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    match transaction.commit().await {
+        Ok(_) => (),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    log::info!(
+        "Notified {} of {} changed entities",
+        config::CONFIG.notify_channel,
+        changed.len()
+    );
+
+    Ok(())
+}
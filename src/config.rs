@@ -1,3 +1,6 @@
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::NaiveDate;
 use serde::Deserialize;
 use serde_json::Map;
 
@@ -9,49 +12,758 @@ pub enum Method {
     Post,
 }
 
+/// Shape of the `post` payload: the report as is or a chat message
+#[derive(Deserialize, Clone, Copy, Default)]
+pub enum WebhookFormat {
+    #[default]
+    #[serde(rename = "raw")]
+    Raw,
+    #[serde(rename = "slack")]
+    Slack,
+    #[serde(rename = "discord")]
+    Discord,
+}
+
+/// What an API key is allowed to do, `admin` allows everything
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    Trigger,
+    ReadStatus,
+    Admin,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ApiKey {
+    /// Recorded in the audit log instead of the key itself
+    pub name: String,
+    pub key: String,
+    pub scopes: Vec<Scope>,
+}
+
+impl ApiKey {
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope) || self.scopes.contains(&Scope::Admin)
+    }
+}
+
+/// Scoped keys from `API_KEYS`, the plain `API_KEY` stays an admin one
+fn load_api_keys() -> Vec<ApiKey> {
+    let mut api_keys: Vec<ApiKey> = serde_json::from_str(&get_env_or("API_KEYS", "[]")).unwrap();
+
+    if let Some(key) = get_optional_env("API_KEY") {
+        api_keys.push(ApiKey {
+            name: "default".to_string(),
+            key,
+            scopes: vec![Scope::Admin],
+        });
+    }
+
+    api_keys
+}
+
+/// A library the dumps are downloaded from
+#[derive(Deserialize, Clone)]
+pub struct Source {
+    pub name: String,
+    pub base_url: String,
+    /// Set for the replay of archived dumps, see `updater::replay`
+    #[serde(skip)]
+    pub archive_date: Option<NaiveDate>,
+}
+
+/// `SOURCE_NAME` and `FL_BASE_URL` describe the default source, others come
+/// from `EXTRA_SOURCES`.
+fn load_sources() -> Vec<Source> {
+    let mut sources = vec![Source {
+        name: get_env_or("SOURCE_NAME", "flibusta"),
+        base_url: get_env("FL_BASE_URL"),
+        archive_date: None,
+    }];
+
+    let extra: Vec<Source> = serde_json::from_str(&get_env_or("EXTRA_SOURCES", "[]")).unwrap();
+
+    for source in extra {
+        if sources.iter().any(|v| v.name == source.name) {
+            panic!("Source {} is declared twice", source.name);
+        }
+
+        sources.push(source);
+    }
+
+    sources
+}
+
 #[derive(Deserialize, Clone)]
 pub struct Webhook {
     pub method: Method,
     pub url: String,
     pub headers: Map<String, serde_json::Value>,
+    #[serde(default)]
+    pub format: WebhookFormat,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    /// `DEPLOY_ENV`s the webhook is called from, all of them if empty
+    #[serde(default)]
+    pub environments: Vec<String>,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+impl Webhook {
+    /// Evaluated at send time, so one `WEBHOOKS` can serve staging and production
+    pub fn is_active(&self) -> bool {
+        if !self.enabled {
+            return false;
+        }
+
+        if self.environments.is_empty() {
+            return true;
+        }
+
+        match &CONFIG.deploy_env {
+            Some(env) => self.environments.contains(env),
+            None => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum DumpEncoding {
+    Utf8,
+    Windows1251,
+    Auto,
+}
+
+impl DumpEncoding {
+    fn parse(value: &str) -> DumpEncoding {
+        match value.to_lowercase().as_str() {
+            "utf-8" | "utf8" => DumpEncoding::Utf8,
+            "windows-1251" | "cp1251" => DumpEncoding::Windows1251,
+            "auto" => DumpEncoding::Auto,
+            _ => panic!("Unknown dump encoding: {}", value),
+        }
+    }
+}
+
+/// What to do with dumps the source lists but `TABLES` doesn't
+#[derive(Clone, Copy, PartialEq)]
+pub enum DumpDiscovery {
+    Off,
+    Report,
+    /// Also imports the ones of `DEFAULT_TABLES`
+    Import,
+}
+
+impl DumpDiscovery {
+    fn parse(value: &str) -> DumpDiscovery {
+        match value.to_lowercase().as_str() {
+            "off" | "" => DumpDiscovery::Off,
+            "report" => DumpDiscovery::Report,
+            "import" => DumpDiscovery::Import,
+            _ => panic!("Unknown dump discovery mode: {}", value),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum TranslitScheme {
+    Gost,
+    Passport,
+}
+
+impl TranslitScheme {
+    fn parse(value: &str) -> Option<TranslitScheme> {
+        match value.to_lowercase().as_str() {
+            "none" | "" => None,
+            "gost" => Some(TranslitScheme::Gost),
+            "passport" => Some(TranslitScheme::Passport),
+            _ => panic!("Unknown translit scheme: {}", value),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Table {
+    pub file: String,
+    pub entity: String,
+    #[serde(default)]
+    pub deps: Vec<String>,
+    /// Tables to wait for that aren't required: if one of them doesn't succeed
+    /// the table still runs, after all the others of the run
+    #[serde(default)]
+    pub soft_deps: Vec<String>,
+}
+
+/// Per-table overrides of the write settings, unset fields fall back to the globals
+#[derive(Deserialize, Clone, Default)]
+pub struct TableWriteSettings {
+    pub batch_size: Option<usize>,
+    pub writer_concurrency: Option<usize>,
+    pub use_copy: Option<bool>,
+}
+
+#[derive(Clone, Copy)]
+pub struct WriteSettings {
+    pub batch_size: usize,
+    pub writer_concurrency: usize,
+    pub use_copy: bool,
+}
+
+const DEFAULT_TABLES: &str = r#"[
+    {"file": "lib.libavtorname.sql", "entity": "author"},
+    {"file": "lib.libbook.sql", "entity": "book"},
+    {"file": "lib.libavtor.sql", "entity": "book_author", "deps": ["lib.libavtorname.sql", "lib.libbook.sql"]},
+    {"file": "lib.libtranslator.sql", "entity": "translator", "deps": ["lib.libavtorname.sql", "lib.libbook.sql"]},
+    {"file": "lib.libseqname.sql", "entity": "sequence"},
+    {"file": "lib.libseq.sql", "entity": "sequence_info", "deps": ["lib.libbook.sql", "lib.libseqname.sql"]},
+    {"file": "lib.b.annotations.sql", "entity": "book_annotation", "deps": ["lib.libbook.sql"]},
+    {"file": "lib.b.annotations_pics.sql", "entity": "book_annotation_pic", "deps": ["lib.libbook.sql"], "soft_deps": ["lib.b.annotations.sql"]},
+    {"file": "lib.a.annotations.sql", "entity": "author_annotation", "deps": ["lib.libavtorname.sql"]},
+    {"file": "lib.a.annotations_pics.sql", "entity": "author_annotation_pic", "deps": ["lib.libavtorname.sql"], "soft_deps": ["lib.a.annotations.sql"]},
+    {"file": "lib.libgenrelist.sql", "entity": "genre"},
+    {"file": "lib.libgenre.sql", "entity": "book_genre", "deps": ["lib.libgenrelist.sql", "lib.libbook.sql"]}
+]"#;
+
+fn parse_tables(value: &str) -> Vec<Table> {
+    let tables: Vec<Table> = serde_json::from_str(value).unwrap();
+
+    // Every dependency has to be declared earlier, so the graph can't have cycles
+    for (index, table) in tables.iter().enumerate() {
+        if tables[..index].iter().any(|t| t.file == table.file) {
+            panic!("Duplicate table: {}", table.file);
+        }
+
+        for dep in table.deps.iter().chain(table.soft_deps.iter()) {
+            if !tables[..index].iter().any(|t| &t.file == dep) {
+                panic!(
+                    "{} depends on {} which isn't declared before it",
+                    table.file, dep
+                );
+            }
+        }
+    }
+
+    tables
+}
+
+fn parse_retention_days(value: &str) -> HashMap<String, i32> {
+    let days: HashMap<String, i32> = serde_json::from_str(value).unwrap();
+
+    for name in days.keys() {
+        if !crate::retention::POLICIES
+            .iter()
+            .any(|(policy, _, _)| policy == name)
+        {
+            panic!("Unknown retention policy: {name}");
+        }
+    }
+
+    days
+}
+
+/// Parses a `02:00-07:00` window of UTC time into minutes since midnight
+fn parse_import_window(value: &str) -> (u32, u32) {
+    let minutes: Vec<u32> = value
+        .split('-')
+        .map(|time| {
+            let parts: Vec<Option<u32>> = time.trim().split(':').map(|v| v.parse().ok()).collect();
+
+            match parts[..] {
+                [Some(hours), Some(minutes)] if hours < 24 && minutes < 60 => hours * 60 + minutes,
+                _ => panic!("Wrong import window: {}", value),
+            }
+        })
+        .collect();
+
+    match minutes[..] {
+        [start, end] => (start, end),
+        _ => panic!("Wrong import window: {}", value),
+    }
+}
+
+/// Makes `library-updater/` into `/library-updater`, `/` into nothing
+fn parse_base_path(value: &str) -> String {
+    let value = value.trim().trim_matches('/');
+
+    if value.is_empty() {
+        String::new()
+    } else {
+        format!("/{value}")
+    }
+}
+
+/// Parses a `22-7` style window of UTC hours
+fn parse_quiet_hours(value: &str) -> (u32, u32) {
+    let hours: Vec<u32> = value
+        .split('-')
+        .map(|v| match v.trim().parse() {
+            Ok(hour) if hour < 24 => hour,
+            _ => panic!("Wrong quiet hours: {}", value),
+        })
+        .collect();
+
+    match hours[..] {
+        [start, end] => (start, end),
+        _ => panic!("Wrong quiet hours: {}", value),
+    }
 }
 
 pub struct Config {
-    pub api_key: String,
+    pub api_keys: Vec<ApiKey>,
+    /// Status, stats and logs are public only when this is turned off
+    pub require_read_key: bool,
+
+    pub max_request_body_bytes: usize,
+    /// Prefix of every route, like `/library-updater`, empty by default
+    pub base_path: String,
+
+    /// CORS is off while no origin is allowed, `*` allows any
+    pub cors_allowed_origins: Vec<String>,
+    pub cors_allowed_methods: Vec<String>,
+    pub cors_allowed_headers: Vec<String>,
+
+    /// Listen here instead of the TCP port when set
+    pub unix_socket_path: Option<String>,
+    pub unix_socket_mode: u32,
+
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    /// Client certificates signed by these CAs are let in on the admin endpoints
+    pub tls_client_ca_path: Option<String>,
 
     pub sentry_dsn: String,
+    /// The nightly job checks in to this Sentry cron monitor, none without it
+    pub sentry_monitor_slug: Option<String>,
+    pub sentry_monitor_margin_mins: u64,
+    pub sentry_monitor_max_runtime_mins: u64,
 
     pub postgres_db_name: String,
     pub postgres_host: String,
     pub postgres_port: u16,
     pub postgres_user: String,
     pub postgres_password: String,
+    pub postgres_replica_host: Option<String>,
+    pub postgres_replica_port: u16,
 
+    pub source_name: String,
     pub fl_base_url: String,
+    /// The default source first
+    pub sources: Vec<Source>,
+    pub dump_encoding: DumpEncoding,
+    pub download_retries: u32,
+    pub download_connect_timeout_secs: u64,
+    /// A download that gets no data for this long is aborted and retried, 0 waits forever
+    pub download_read_timeout_secs: u64,
+    /// 0 leaves `SO_KEEPALIVE` off
+    pub download_tcp_keepalive_secs: u64,
+    /// Checks downloads against the `.md5` files the source publishes
+    pub verify_source_checksums: bool,
+    /// A dump that failed to parse is skipped while the source serves the same one
+    pub skip_failed_dumps: bool,
+    /// Files the source serves as `.gz.partNN` chunks, see `http::download_parts`
+    pub split_dumps: Vec<String>,
+    /// Magnet links or `.torrent` URLs of files fetched over BitTorrent, with
+    /// the `torrent` feature. The torrent's `{file}.gz` is downloaded.
+    pub torrent_dumps: HashMap<String, String>,
+    pub torrent_timeout_mins: u64,
+    /// `.wasm` row transformers by entity, with the `wasm-plugins` feature,
+    /// see `plugins`
+    pub row_plugins: HashMap<String, Vec<String>>,
+    pub row_plugin_timeout_ms: u64,
+    /// Rhai scripts by entity and field, with the `field-scripts` feature,
+    /// see `scripts`
+    pub field_scripts: HashMap<String, BTreeMap<String, String>>,
+    /// Operations a script can run for a row before it's rejected
+    pub field_script_max_operations: u64,
+    /// Where the dumps of past days are kept, with the layout of the source:
+    /// `{source}` and `{date}` are replaced, `/sql/{file}.gz` is appended
+    pub dump_archive_url: Option<String>,
+    pub work_dir: String,
+    /// How old a dump of an earlier run can be to be imported again instead of
+    /// downloaded, off with 0
+    pub dump_reuse_hours: i64,
+    pub compress_scratch_files: bool,
+    /// Attempts of a table after a failure of the database or the network
+    pub table_retries: u32,
+    /// Follow-up attempts of a failed run, see `runs::finish`
+    pub run_retries: u32,
+    pub run_retry_delay_mins: u32,
+    pub full_sync: bool,
+    /// Full syncs write to shadow tables swapped in at the end, see `shadow`
+    pub shadow_import: bool,
+    /// Each run imports into a schema of its own, switched to once it's
+    /// validated, see `catalogs`
+    pub catalog_schemas: bool,
+    /// Catalog schemas kept for rollbacks, the current one aside
+    pub catalog_schemas_kept: i64,
+    /// How much smaller than the current one a table can be for the switch
+    pub catalog_max_shrink_percent: f64,
+    /// Changed entities go to `catalog_changes`, see `changes::prepare`. On
+    /// with `MEILISEARCH_URL` too.
+    pub change_capture: bool,
+    pub changes_retention_days: i32,
+    /// Days to keep rows for, by `retention::POLICIES` name
+    pub retention_days: HashMap<String, i32>,
+    pub retention_schedule: String,
+    /// `NOTIFY` of changed entities, see `changes::notify`
+    pub change_notify: bool,
+    pub notify_channel: String,
+    pub notify_batch_size: usize,
+    pub strip_control_chars: bool,
+    pub normalize_typography: bool,
+    pub translit_scheme: Option<TranslitScheme>,
+    pub file_types: Vec<String>,
+
+    pub tables: Vec<Table>,
+    /// Tables the updater can import without configuration, configured or not
+    pub known_tables: Vec<Table>,
+
+    pub dump_discovery: DumpDiscovery,
+    /// Dumps deliberately left out, discovery doesn't report them
+    pub dump_discovery_ignore: Vec<String>,
+
+    pub db_retries: u32,
+    pub db_retry_base_delay_ms: u64,
+    pub db_retry_max_delay_ms: u64,
+    /// `statement_timeout` and `lock_timeout` of the transactions writing rows,
+    /// 0 leaves the server's
+    pub statement_timeout_ms: u64,
+    pub lock_timeout_ms: u64,
+    /// Waits for a pooled connection longer than this are logged, 0 turns it off
+    pub pool_wait_warn_ms: u64,
 
     pub webhooks: Vec<Webhook>,
+    /// `production`, `staging` and so on, see `Webhook::environments`
+    pub deploy_env: Option<String>,
+    /// Outgoing requests become Sentry breadcrumbs of the run
+    pub http_breadcrumbs: bool,
+    /// The report of the last run is kept there as JSON, `{source}` is replaced
+    /// with the name of the source
+    pub report_path: Option<String>,
+
+    pub smtp_host: Option<String>,
+    pub smtp_port: u16,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    pub smtp_from: String,
+    pub smtp_recipients: Vec<String>,
+    pub smtp_notify_on_success: bool,
+
+    pub notify_on_change_only: bool,
+    pub escalate_after_failures: u32,
+    pub quiet_hours: Option<(u32, u32)>,
+
+    pub idempotency_key_ttl: u64,
+
+    pub rows_per_second: u64,
+    pub table_rows_per_second: HashMap<String, u64>,
+    /// Heavy tables are only written inside this window, minutes since midnight UTC
+    pub import_window: Option<(u32, u32)>,
+    /// Tables the window applies to, all of them when empty
+    pub heavy_tables: Vec<String>,
+
+    pub parse_workers: usize,
+    /// Fails a table once this many of its rows had parse issues, 0 for no limit
+    pub max_parse_issues: u64,
+    pub max_in_flight_rows: usize,
+    pub max_memory_mb: u64,
+    /// Tables imported at once, 0 for no limit. The slots go to the tables on
+    /// the longest path first.
+    pub table_concurrency: usize,
+
+    pub batch_size: usize,
+    /// Lets `BATCH_SIZE` follow the latency of the batches, see `BatchSizer`
+    pub adaptive_batch: bool,
+    pub batch_size_min: usize,
+    pub batch_size_max: usize,
+    pub batch_target_latency_ms: u64,
+    pub writer_concurrency: usize,
+    pub use_copy: bool,
+    pub table_write_settings: HashMap<String, TableWriteSettings>,
+
+    pub verify_sample_size: usize,
+
+    pub search_index_maintenance: bool,
+
+    pub duplicate_detection: bool,
+
+    pub meilisearch_url: Option<String>,
+    pub meilisearch_api_key: Option<String>,
+    pub meilisearch_batch_size: usize,
+    pub meilisearch_retries: u32,
+    /// How long to wait for Meilisearch to process a request
+    pub meilisearch_task_timeout_secs: u64,
+    pub meilisearch_settings: HashMap<String, serde_json::Value>,
+
+    pub sqlite_export_path: Option<String>,
+
+    /// See `onix_export::export`
+    pub onix_export_path: Option<String>,
+    pub onix_export_langs: Vec<String>,
+    pub onix_export_uploaded_from: Option<NaiveDate>,
+    pub onix_export_uploaded_to: Option<NaiveDate>,
+
+    pub opds_enabled: bool,
+    pub opds_download_url: String,
+    pub opds_new_arrivals_limit: i64,
 }
 
 fn get_env(env: &'static str) -> String {
     std::env::var(env).unwrap_or_else(|_| panic!("Cannot get the {} env variable", env))
 }
 
+fn get_env_or(env: &'static str, default: &str) -> String {
+    std::env::var(env).unwrap_or_else(|_| default.to_string())
+}
+
+fn get_optional_env(env: &'static str) -> Option<String> {
+    std::env::var(env).ok().filter(|v| !v.is_empty())
+}
+
+/// A comma-separated env variable
+fn get_list_env(env: &'static str, default: &str) -> Vec<String> {
+    get_env_or(env, default)
+        .split(',')
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
 impl Config {
     pub fn load() -> Config {
         Config {
-            api_key: get_env("API_KEY"),
+            api_keys: load_api_keys(),
+            require_read_key: get_env_or("REQUIRE_READ_KEY", "true").parse().unwrap(),
+
+            base_path: parse_base_path(&get_env_or("BASE_PATH", "")),
+            max_request_body_bytes: get_env_or("MAX_REQUEST_BODY_BYTES", "16777216")
+                .parse()
+                .unwrap(),
+
+            cors_allowed_origins: get_list_env("CORS_ALLOWED_ORIGINS", ""),
+            cors_allowed_methods: get_list_env("CORS_ALLOWED_METHODS", "GET,POST"),
+            cors_allowed_headers: get_list_env(
+                "CORS_ALLOWED_HEADERS",
+                "Authorization,Content-Type,Idempotency-Key",
+            ),
+
+            unix_socket_path: get_optional_env("UNIX_SOCKET_PATH"),
+            unix_socket_mode: u32::from_str_radix(&get_env_or("UNIX_SOCKET_MODE", "660"), 8)
+                .unwrap(),
+
+            tls_cert_path: get_optional_env("TLS_CERT_PATH"),
+            tls_key_path: get_optional_env("TLS_KEY_PATH"),
+            tls_client_ca_path: get_optional_env("TLS_CLIENT_CA_PATH"),
 
             sentry_dsn: get_env("SENTRY_DSN"),
+            sentry_monitor_slug: get_optional_env("SENTRY_MONITOR_SLUG"),
+            sentry_monitor_margin_mins: get_env_or("SENTRY_MONITOR_MARGIN_MINS", "30")
+                .parse()
+                .unwrap(),
+            sentry_monitor_max_runtime_mins: get_env_or("SENTRY_MONITOR_MAX_RUNTIME_MINS", "720")
+                .parse()
+                .unwrap(),
 
             postgres_db_name: get_env("POSTGRES_DB_NAME"),
             postgres_host: get_env("POSTGRES_HOST"),
             postgres_port: get_env("POSTGRES_PORT").parse().unwrap(),
             postgres_user: get_env("POSTGRES_USER"),
             postgres_password: get_env("POSTGRES_PASSWORD"),
+            postgres_replica_host: get_optional_env("POSTGRES_REPLICA_HOST"),
+            postgres_replica_port: match get_optional_env("POSTGRES_REPLICA_PORT") {
+                Some(v) => v.parse().unwrap(),
+                None => get_env("POSTGRES_PORT").parse().unwrap(),
+            },
 
+            source_name: get_env_or("SOURCE_NAME", "flibusta"),
             fl_base_url: get_env("FL_BASE_URL"),
+            sources: load_sources(),
+            dump_encoding: DumpEncoding::parse(&get_env_or("DUMP_ENCODING", "auto")),
+            download_retries: get_env_or("DOWNLOAD_RETRIES", "3").parse().unwrap(),
+            download_connect_timeout_secs: get_env_or("DOWNLOAD_CONNECT_TIMEOUT_SECS", "30")
+                .parse()
+                .unwrap(),
+            download_read_timeout_secs: get_env_or("DOWNLOAD_READ_TIMEOUT_SECS", "120")
+                .parse()
+                .unwrap(),
+            download_tcp_keepalive_secs: get_env_or("DOWNLOAD_TCP_KEEPALIVE_SECS", "60")
+                .parse()
+                .unwrap(),
+            verify_source_checksums: get_env_or("VERIFY_SOURCE_CHECKSUMS", "false")
+                .parse()
+                .unwrap(),
+            skip_failed_dumps: get_env_or("SKIP_FAILED_DUMPS", "true").parse().unwrap(),
+            split_dumps: serde_json::from_str(&get_env_or("SPLIT_DUMPS", "[]")).unwrap(),
+            torrent_dumps: serde_json::from_str(&get_env_or("TORRENT_DUMPS", "{}")).unwrap(),
+            torrent_timeout_mins: get_env_or("TORRENT_TIMEOUT_MINS", "240").parse().unwrap(),
+            row_plugins: serde_json::from_str(&get_env_or("ROW_PLUGINS", "{}")).unwrap(),
+            row_plugin_timeout_ms: get_env_or("ROW_PLUGIN_TIMEOUT_MS", "50").parse().unwrap(),
+            field_scripts: serde_json::from_str(&get_env_or("FIELD_SCRIPTS", "{}")).unwrap(),
+            field_script_max_operations: get_env_or("FIELD_SCRIPT_MAX_OPERATIONS", "100000")
+                .parse()
+                .unwrap(),
+            dump_archive_url: get_optional_env("DUMP_ARCHIVE_URL"),
+            work_dir: get_env_or("WORK_DIR", "."),
+            dump_reuse_hours: get_env_or("DUMP_REUSE_HOURS", "0").parse().unwrap(),
+            compress_scratch_files: get_env_or("COMPRESS_SCRATCH_FILES", "false")
+                .parse()
+                .unwrap(),
+            table_retries: get_env_or("TABLE_RETRIES", "2").parse().unwrap(),
+            run_retries: get_env_or("RUN_RETRIES", "0").parse().unwrap(),
+            run_retry_delay_mins: get_env_or("RUN_RETRY_DELAY_MINS", "120").parse().unwrap(),
+            full_sync: get_env_or("FULL_SYNC", "false").parse().unwrap(),
+            shadow_import: get_env_or("SHADOW_IMPORT", "false").parse().unwrap(),
+            catalog_schemas: get_env_or("CATALOG_SCHEMAS", "false").parse().unwrap(),
+            catalog_schemas_kept: get_env_or("CATALOG_SCHEMAS_KEPT", "3").parse().unwrap(),
+            catalog_max_shrink_percent: get_env_or("CATALOG_MAX_SHRINK_PERCENT", "10")
+                .parse()
+                .unwrap(),
+            change_capture: get_env_or("CHANGE_CAPTURE", "false").parse().unwrap(),
+            changes_retention_days: get_env_or("CHANGES_RETENTION_DAYS", "7").parse().unwrap(),
+            retention_days: parse_retention_days(&get_env_or("RETENTION_DAYS", "{}")),
+            retention_schedule: get_env_or("RETENTION_SCHEDULE", "0 30 4 * * *"),
+            change_notify: get_env_or("CHANGE_NOTIFY", "false").parse().unwrap(),
+            notify_channel: get_env_or("NOTIFY_CHANNEL", "catalog_update"),
+            notify_batch_size: get_env_or("NOTIFY_BATCH_SIZE", "1").parse().unwrap(),
+            strip_control_chars: get_env_or("STRIP_CONTROL_CHARS", "true").parse().unwrap(),
+            normalize_typography: get_env_or("NORMALIZE_TYPOGRAPHY", "false").parse().unwrap(),
+            translit_scheme: TranslitScheme::parse(&get_env_or("TRANSLIT_SCHEME", "none")),
+            file_types: get_env_or(
+                "FILE_TYPES",
+                "fb2,epub,djvu,pdf,doc,docx,rtf,txt,html,mobi,azw3,odt,chm,jpg,zip,rar",
+            )
+            .split(',')
+            .map(|v| v.trim().to_lowercase())
+            .filter(|v| !v.is_empty())
+            .collect(),
+
+            tables: parse_tables(&get_env_or("TABLES", DEFAULT_TABLES)),
+            known_tables: parse_tables(DEFAULT_TABLES),
+
+            dump_discovery: DumpDiscovery::parse(&get_env_or("DUMP_DISCOVERY", "off")),
+            dump_discovery_ignore: get_list_env("DUMP_DISCOVERY_IGNORE", ""),
+
+            db_retries: get_env_or("DB_RETRIES", "5").parse().unwrap(),
+            db_retry_base_delay_ms: get_env_or("DB_RETRY_BASE_DELAY_MS", "200").parse().unwrap(),
+            db_retry_max_delay_ms: get_env_or("DB_RETRY_MAX_DELAY_MS", "10000")
+                .parse()
+                .unwrap(),
+            statement_timeout_ms: get_env_or("STATEMENT_TIMEOUT_MS", "0").parse().unwrap(),
+            lock_timeout_ms: get_env_or("LOCK_TIMEOUT_MS", "0").parse().unwrap(),
+            pool_wait_warn_ms: get_env_or("POOL_WAIT_WARN_MS", "1000").parse().unwrap(),
 
             webhooks: serde_json::from_str(&get_env("WEBHOOKS")).unwrap(),
+            deploy_env: get_optional_env("DEPLOY_ENV"),
+            http_breadcrumbs: get_env_or("HTTP_BREADCRUMBS", "false").parse().unwrap(),
+            report_path: get_optional_env("REPORT_PATH"),
+
+            smtp_host: get_optional_env("SMTP_HOST"),
+            smtp_port: get_env_or("SMTP_PORT", "587").parse().unwrap(),
+            smtp_username: get_optional_env("SMTP_USERNAME"),
+            smtp_password: get_optional_env("SMTP_PASSWORD"),
+            smtp_from: get_env_or("SMTP_FROM", "library-updater@localhost"),
+            smtp_recipients: get_list_env("SMTP_RECIPIENTS", ""),
+            smtp_notify_on_success: get_env_or("SMTP_NOTIFY_ON_SUCCESS", "false")
+                .parse()
+                .unwrap(),
+
+            notify_on_change_only: get_env_or("NOTIFY_ON_CHANGE_ONLY", "false")
+                .parse()
+                .unwrap(),
+            escalate_after_failures: get_env_or("ESCALATE_AFTER_FAILURES", "0").parse().unwrap(),
+            quiet_hours: get_optional_env("QUIET_HOURS").map(|v| parse_quiet_hours(&v)),
+
+            idempotency_key_ttl: get_env_or("IDEMPOTENCY_KEY_TTL", "3600").parse().unwrap(),
+
+            rows_per_second: get_env_or("ROWS_PER_SECOND", "0").parse().unwrap(),
+            table_rows_per_second: serde_json::from_str(&get_env_or("TABLE_ROWS_PER_SECOND", "{}"))
+                .unwrap(),
+            import_window: get_optional_env("IMPORT_WINDOW").map(|v| parse_import_window(&v)),
+            heavy_tables: get_list_env("HEAVY_TABLES", ""),
+
+            parse_workers: get_env_or("PARSE_WORKERS", "1").parse().unwrap(),
+            max_parse_issues: get_env_or("MAX_PARSE_ISSUES", "0").parse().unwrap(),
+            max_in_flight_rows: get_env_or("MAX_IN_FLIGHT_ROWS", "0").parse().unwrap(),
+            table_concurrency: get_env_or("TABLE_CONCURRENCY", "0").parse().unwrap(),
+            max_memory_mb: get_env_or("MAX_MEMORY_MB", "0").parse().unwrap(),
+
+            batch_size: get_env_or("BATCH_SIZE", "1").parse().unwrap(),
+            adaptive_batch: get_env_or("ADAPTIVE_BATCH", "false").parse().unwrap(),
+            batch_size_min: get_env_or("BATCH_SIZE_MIN", "1").parse().unwrap(),
+            batch_size_max: get_env_or("BATCH_SIZE_MAX", "5000").parse().unwrap(),
+            batch_target_latency_ms: get_env_or("BATCH_TARGET_LATENCY_MS", "500")
+                .parse()
+                .unwrap(),
+            writer_concurrency: get_env_or("WRITER_CONCURRENCY", "1").parse().unwrap(),
+            use_copy: get_env_or("USE_COPY", "false").parse().unwrap(),
+            table_write_settings: serde_json::from_str(&get_env_or("TABLE_WRITE_SETTINGS", "{}"))
+                .unwrap(),
+
+            verify_sample_size: get_env_or("VERIFY_SAMPLE_SIZE", "0").parse().unwrap(),
+
+            search_index_maintenance: get_env_or("SEARCH_INDEX_MAINTENANCE", "false")
+                .parse()
+                .unwrap(),
+
+            duplicate_detection: get_env_or("DUPLICATE_DETECTION", "false").parse().unwrap(),
+
+            meilisearch_url: get_optional_env("MEILISEARCH_URL"),
+            meilisearch_api_key: get_optional_env("MEILISEARCH_API_KEY"),
+            meilisearch_batch_size: get_env_or("MEILISEARCH_BATCH_SIZE", "1000")
+                .parse()
+                .unwrap(),
+            meilisearch_retries: get_env_or("MEILISEARCH_RETRIES", "3").parse().unwrap(),
+            meilisearch_task_timeout_secs: get_env_or("MEILISEARCH_TASK_TIMEOUT_SECS", "600")
+                .parse()
+                .unwrap(),
+            meilisearch_settings: serde_json::from_str(&get_env_or("MEILISEARCH_SETTINGS", "{}"))
+                .unwrap(),
+
+            sqlite_export_path: get_optional_env("SQLITE_EXPORT_PATH"),
+
+            onix_export_path: get_optional_env("ONIX_EXPORT_PATH"),
+            onix_export_langs: get_list_env("ONIX_EXPORT_LANGS", ""),
+            onix_export_uploaded_from: get_optional_env("ONIX_EXPORT_UPLOADED_FROM")
+                .map(|v| v.parse().unwrap()),
+            onix_export_uploaded_to: get_optional_env("ONIX_EXPORT_UPLOADED_TO")
+                .map(|v| v.parse().unwrap()),
+
+            opds_enabled: get_env_or("OPDS_ENABLED", "false").parse().unwrap(),
+            opds_download_url: get_env_or(
+                "OPDS_DOWNLOAD_URL",
+                "{base_url}/b/{remote_id}/{file_type}",
+            ),
+            opds_new_arrivals_limit: get_env_or("OPDS_NEW_ARRIVALS_LIMIT", "100")
+                .parse()
+                .unwrap(),
+        }
+    }
+
+    /// The source called `name`, the default one without a name
+    pub fn source(&self, name: Option<&str>) -> Option<&Source> {
+        match name {
+            Some(name) => self.sources.iter().find(|source| source.name == name),
+            None => self.sources.first(),
+        }
+    }
+
+    pub fn write_settings(&self, file_name: &str) -> WriteSettings {
+        let overrides = self
+            .table_write_settings
+            .get(file_name)
+            .cloned()
+            .unwrap_or_default();
+
+        WriteSettings {
+            batch_size: overrides.batch_size.unwrap_or(self.batch_size).max(1),
+            writer_concurrency: overrides
+                .writer_concurrency
+                .unwrap_or(self.writer_concurrency)
+                .max(1),
+            use_copy: overrides.use_copy.unwrap_or(self.use_copy),
         }
     }
 }
@@ -59,3 +771,30 @@ impl Config {
 lazy_static! {
     pub static ref CONFIG: Config = Config::load();
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::config::parse_quiet_hours;
+
+    #[test]
+    fn test_parse_quiet_hours() {
+        let input = " 22 - 7 ";
+        let expected_result = (22, 7);
+
+        let result = parse_quiet_hours(input);
+
+        assert_eq!(result, expected_result);
+    }
+
+    #[test]
+    #[should_panic(expected = "Wrong quiet hours")]
+    fn test_parse_quiet_hours_wrong_hour() {
+        parse_quiet_hours("22-24");
+    }
+
+    #[test]
+    #[should_panic(expected = "Wrong quiet hours")]
+    fn test_parse_quiet_hours_single_hour() {
+        parse_quiet_hours("22");
+    }
+}
@@ -0,0 +1,279 @@
+use std::error::Error;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use deadpool_postgres::{
+    Config, CreatePoolError, ManagerConfig, Object, Pool, PoolError, RecyclingMethod, Runtime,
+};
+use tokio_postgres::error::SqlState;
+use tokio_postgres::NoTls;
+use tracing::log;
+
+use crate::config;
+use crate::errors::UpdaterError;
+use crate::shadow;
+
+/// Sessions show up in `pg_stat_activity` under this name, writes of a table
+/// as `library_updater:<file>`
+pub const APPLICATION_NAME: &str = "library_updater";
+
+fn create_pool(host: &str, port: u16, search_path: Option<&str>) -> Result<Pool, CreatePoolError> {
+    let mut config = Config::new();
+
+    config.host = Some(host.to_string());
+    config.port = Some(port);
+    config.dbname = Some(config::CONFIG.postgres_db_name.clone());
+    config.user = Some(config::CONFIG.postgres_user.clone());
+    config.password = Some(config::CONFIG.postgres_password.clone());
+    config.connect_timeout = Some(std::time::Duration::from_secs(5));
+    config.application_name = Some(APPLICATION_NAME.to_string());
+    config.options = search_path.map(|v| format!("-c search_path={v}"));
+    config.manager = Some(ManagerConfig {
+        recycling_method: RecyclingMethod::Verified,
+    });
+
+    config.create_pool(Some(Runtime::Tokio1), NoTls)
+}
+
+lazy_static! {
+    pub static ref POOL: Pool =
+        match create_pool(&config::CONFIG.postgres_host, config::CONFIG.postgres_port, None) {
+            Ok(pool) => pool,
+            Err(err) => panic!("{:?}", err),
+        };
+
+    /// Pool for SELECT-only work that tolerates replication lag (API reads),
+    /// the primary pool when no replica is configured.
+    pub static ref READ_POOL: Pool = match &config::CONFIG.postgres_replica_host {
+        Some(host) => match create_pool(host, config::CONFIG.postgres_replica_port, None) {
+            Ok(pool) => pool,
+            Err(err) => panic!("{:?}", err),
+        },
+        None => POOL.clone(),
+    };
+
+    /// Pool of shadow imports: its sessions find the shadow tables before the
+    /// live ones, see `shadow`
+    pub static ref SHADOW_POOL: Pool = match create_pool(
+        &config::CONFIG.postgres_host,
+        config::CONFIG.postgres_port,
+        Some(&format!("{},public", shadow::SCHEMA)),
+    ) {
+        Ok(pool) => pool,
+        Err(err) => panic!("{:?}", err),
+    };
+}
+
+/// Pool whose sessions find the tables of `schema` before the live ones
+pub fn schema_pool(schema: &str) -> Result<Pool, CreatePoolError> {
+    create_pool(
+        &config::CONFIG.postgres_host,
+        config::CONFIG.postgres_port,
+        Some(&format!("{schema},public")),
+    )
+}
+
+/// Waits for connections of all pools since the start
+struct Checkouts {
+    count: AtomicU64,
+    wait_us: AtomicU64,
+    max_wait_us: AtomicU64,
+    /// Waits longer than `POOL_WAIT_WARN_MS`
+    slow: AtomicU64,
+}
+
+static CHECKOUTS: Checkouts = Checkouts {
+    count: AtomicU64::new(0),
+    wait_us: AtomicU64::new(0),
+    max_wait_us: AtomicU64::new(0),
+    slow: AtomicU64::new(0),
+};
+
+/// `pool.get()` with retries of transient failures, instead of a panic a
+/// failure ends up as the error of the table or the request.
+pub async fn checkout(pool: &Pool) -> Result<Object, UpdaterError> {
+    let mut attempt = 0;
+
+    loop {
+        match timed_checkout(pool).await {
+            Ok(v) => return Ok(v),
+            Err(err) if attempt < config::CONFIG.db_retries && is_transient_pool_error(&err) => {
+                attempt += 1;
+                log::warn!("Transient pool error (attempt {attempt}): {:?}", err);
+                tokio::time::sleep(retry_delay(attempt)).await;
+            }
+            Err(err) => return Err(UpdaterError::Pool(err)),
+        }
+    }
+}
+
+pub fn is_transient_pool_error(err: &PoolError) -> bool {
+    match err {
+        PoolError::Timeout(_) => true,
+        PoolError::Backend(err) => is_transient(err),
+        _ => false,
+    }
+}
+
+/// `pool.get()` that keeps track of the wait and warns when the pool is
+/// running out of connections
+async fn timed_checkout(pool: &Pool) -> Result<Object, PoolError> {
+    let started_at = Instant::now();
+    let result = pool.get().await;
+    let wait = started_at.elapsed();
+
+    let wait_us = wait.as_micros() as u64;
+    CHECKOUTS.count.fetch_add(1, Ordering::Relaxed);
+    CHECKOUTS.wait_us.fetch_add(wait_us, Ordering::Relaxed);
+    CHECKOUTS.max_wait_us.fetch_max(wait_us, Ordering::Relaxed);
+
+    let threshold = config::CONFIG.pool_wait_warn_ms;
+    if threshold > 0 && wait >= Duration::from_millis(threshold) {
+        CHECKOUTS.slow.fetch_add(1, Ordering::Relaxed);
+
+        let status = pool.status();
+        log::warn!(
+            "Waited {} ms for a connection: {} of {} in use, {} waiting",
+            wait.as_millis(),
+            status.size - status.available,
+            status.max_size,
+            status.waiting
+        );
+    }
+
+    result
+}
+
+fn write_pool_metrics(metrics: &mut String, name: &str, pool: &Pool) {
+    let status = pool.status();
+
+    for (metric, value) in [
+        ("max_size", status.max_size),
+        ("size", status.size),
+        ("available", status.available),
+        ("waiting", status.waiting),
+    ] {
+        let _ = writeln!(
+            metrics,
+            "library_updater_pool_{metric}{{pool=\"{name}\"}} {value}"
+        );
+    }
+}
+
+/// The pools and the waits for them in the Prometheus text format
+pub fn pool_metrics() -> String {
+    let mut metrics = String::new();
+
+    write_pool_metrics(&mut metrics, "primary", &POOL);
+    if config::CONFIG.postgres_replica_host.is_some() {
+        write_pool_metrics(&mut metrics, "replica", &READ_POOL);
+    }
+
+    let seconds = |us: &AtomicU64| us.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+
+    let _ = writeln!(
+        metrics,
+        "library_updater_pool_checkouts_total {}",
+        CHECKOUTS.count.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        metrics,
+        "library_updater_pool_wait_seconds_total {}",
+        seconds(&CHECKOUTS.wait_us)
+    );
+    let _ = writeln!(
+        metrics,
+        "library_updater_pool_wait_seconds_max {}",
+        seconds(&CHECKOUTS.max_wait_us)
+    );
+    let _ = writeln!(
+        metrics,
+        "library_updater_pool_slow_checkouts_total {}",
+        CHECKOUTS.slow.load(Ordering::Relaxed)
+    );
+
+    metrics
+}
+
+const TRANSIENT_STATES: [SqlState; 9] = [
+    SqlState::T_R_SERIALIZATION_FAILURE,
+    SqlState::T_R_DEADLOCK_DETECTED,
+    SqlState::TOO_MANY_CONNECTIONS,
+    SqlState::CONNECTION_EXCEPTION,
+    SqlState::CONNECTION_FAILURE,
+    SqlState::SQLCLIENT_UNABLE_TO_ESTABLISH_SQLCONNECTION,
+    SqlState::ADMIN_SHUTDOWN,
+    SqlState::CANNOT_CONNECT_NOW,
+    // `lock_timeout`, the rows are likely to make it once the locks are gone
+    SqlState::LOCK_NOT_AVAILABLE,
+];
+
+/// `statement_timeout` cancels with the state of a cancel by a user, only the
+/// message tells them apart. A user's cancel is meant to stop the import.
+fn is_statement_timeout(err: &tokio_postgres::Error) -> bool {
+    err.code() == Some(&SqlState::QUERY_CANCELED)
+        && err
+            .as_db_error()
+            .is_some_and(|err| err.message().contains("statement timeout"))
+}
+
+pub fn is_transient(err: &tokio_postgres::Error) -> bool {
+    if err.is_closed() {
+        return true;
+    }
+
+    if is_statement_timeout(err) {
+        return true;
+    }
+
+    match err.code() {
+        Some(code) => TRANSIENT_STATES.contains(code),
+        None => err
+            .source()
+            .is_some_and(|source| source.is::<std::io::Error>()),
+    }
+}
+
+/// Class 23, the row breaks a constraint and will on every retry
+pub fn is_constraint_violation(err: &tokio_postgres::Error) -> bool {
+    err.code().is_some_and(|code| code.code().starts_with("23"))
+}
+
+/// A constraint violation or a data exception (class 22): the fault is in the
+/// row itself, the other rows of its batch are fine
+pub fn is_bad_row(err: &tokio_postgres::Error) -> bool {
+    is_constraint_violation(err) || err.code().is_some_and(|code| code.code().starts_with("22"))
+}
+
+/// `SET LOCAL` of the configured timeouts and of the session label
+pub fn session_settings(file_name: &str) -> String {
+    let mut settings = format!(
+        "SET LOCAL application_name = '{APPLICATION_NAME}:{}';",
+        file_name.replace('\'', "''")
+    );
+
+    if config::CONFIG.statement_timeout_ms > 0 {
+        settings.push_str(&format!(
+            "SET LOCAL statement_timeout = {};",
+            config::CONFIG.statement_timeout_ms
+        ));
+    }
+
+    if config::CONFIG.lock_timeout_ms > 0 {
+        settings.push_str(&format!(
+            "SET LOCAL lock_timeout = {};",
+            config::CONFIG.lock_timeout_ms
+        ));
+    }
+
+    settings
+}
+
+pub fn retry_delay(attempt: u32) -> Duration {
+    let delay = config::CONFIG
+        .db_retry_base_delay_ms
+        .saturating_mul(1 << attempt.min(16));
+
+    Duration::from_millis(delay.min(config::CONFIG.db_retry_max_delay_ms))
+}
@@ -0,0 +1,96 @@
+use std::collections::HashSet;
+
+use tracing::log;
+
+use crate::config::{self, DumpDiscovery, Source, Table};
+use crate::http;
+
+pub struct Discovery {
+    /// Dumps neither configured nor ignored, sorted
+    pub new_dumps: Vec<String>,
+    /// Known tables among them to import in this run
+    pub imported: Vec<&'static Table>,
+}
+
+/// Dump names linked from an index page, like `lib.libbook.sql` for `lib.libbook.sql.gz`
+fn parse_index(html: &str) -> Vec<String> {
+    html.split("href=\"")
+        .skip(1)
+        .filter_map(|part| part.split('"').next())
+        .filter_map(|href| href.rsplit('/').next())
+        .filter_map(|name| name.strip_suffix(".gz"))
+        .filter(|name| name.ends_with(".sql"))
+        .map(|name| name.to_string())
+        .collect()
+}
+
+async fn list_dumps(source: &Source) -> Result<Vec<String>, Box<dyn std::error::Error + Send>> {
+    let response = match http::get(&format!("{}/sql/", source.base_url)).await {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let response = match response.error_for_status() {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    match response.text().await {
+        Ok(v) => Ok(parse_index(&v)),
+        Err(err) => Err(Box::new(err)),
+    }
+}
+
+/// Compares the dumps the source publishes with the configured tables.
+pub async fn discover(source: &Source) -> Result<Discovery, Box<dyn std::error::Error + Send>> {
+    let available = match list_dumps(source).await {
+        Ok(v) => v,
+        Err(err) => return Err(err),
+    };
+
+    let mut known: HashSet<&str> = config::CONFIG
+        .tables
+        .iter()
+        .map(|table| table.file.as_str())
+        .chain(
+            config::CONFIG
+                .dump_discovery_ignore
+                .iter()
+                .map(|v| v.as_str()),
+        )
+        .collect();
+
+    let mut new_dumps: Vec<String> = available
+        .into_iter()
+        .filter(|file| !known.contains(file.as_str()))
+        .collect();
+    new_dumps.sort();
+    new_dumps.dedup();
+
+    for file in new_dumps.iter() {
+        log::warn!("New dump on {}: {file}", source.name);
+    }
+
+    let mut imported = vec![];
+
+    if config::CONFIG.dump_discovery == DumpDiscovery::Import {
+        // Known tables are in dependency order, so every dependency is settled first
+        for table in config::CONFIG.known_tables.iter() {
+            if !new_dumps.contains(&table.file)
+                || !table.deps.iter().all(|dep| known.contains(dep.as_str()))
+            {
+                continue;
+            }
+
+            log::info!("Import the new dump {} as {}", table.file, table.entity);
+
+            known.insert(table.file.as_str());
+            imported.push(table);
+        }
+    }
+
+    Ok(Discovery {
+        new_dumps,
+        imported,
+    })
+}
@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+use chrono::{NaiveDate, NaiveDateTime};
+use serde::Serialize;
+use sql_parse::{CreateDefinition, CreateTable, Expression};
+
+pub use dump_row_derive::DumpRow;
+/// The parser of the expressions `DumpRow` reads, crates deriving it don't need to depend on it
+pub use sql_parse;
+
+use crate::config::DumpEncoding;
+use crate::errors::UpdaterError;
+use crate::types::FromVecExpression;
+use crate::updater::{parse_options, parse_row, DumpStatements, ParsedRow};
+
+/// Where a row sits in its dump file, all counted from 1: the line, the
+/// `INSERT` statement and the tuple in it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct RowOrigin {
+    pub line: u64,
+    pub statement: u64,
+    pub tuple: u64,
+}
+
+impl fmt::Display for RowOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {}, statement {}, tuple {}",
+            self.line, self.statement, self.tuple
+        )
+    }
+}
+
+/// Column positions taken from the dump's `CREATE TABLE` statement.
+#[derive(Default)]
+pub struct Columns {
+    names: HashMap<String, usize>,
+}
+
+impl Columns {
+    pub fn from_create_table(table: &CreateTable) -> Columns {
+        let names = table
+            .create_definitions
+            .iter()
+            .filter_map(|definition| match definition {
+                CreateDefinition::ColumnDefinition { identifier, .. } => {
+                    Some(identifier.value.to_string())
+                }
+                _ => None,
+            })
+            .enumerate()
+            .map(|(index, name)| (name, index))
+            .collect();
+
+        Columns { names }
+    }
+
+    pub fn from_names(names: &[String]) -> Columns {
+        let names = names
+            .iter()
+            .enumerate()
+            .map(|(index, name)| (name.clone(), index))
+            .collect();
+
+        Columns { names }
+    }
+
+    pub fn index(&self, name: &str) -> Option<usize> {
+        self.names.get(name).copied()
+    }
+
+    /// Column names in table order
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<(&String, &usize)> = self.names.iter().collect();
+        names.sort_by_key(|(_, index)| **index);
+
+        names.into_iter().map(|(name, _)| name.clone()).collect()
+    }
+}
+
+pub enum Column {
+    Index(usize),
+    Name(&'static str),
+}
+
+pub trait FromExpression: Sized {
+    fn from_expression(value: &Expression) -> Option<Self>;
+}
+
+impl FromExpression for u64 {
+    fn from_expression(value: &Expression) -> Option<u64> {
+        match value {
+            Expression::Integer(v) => Some(v.0),
+            _ => None,
+        }
+    }
+}
+
+// Values out of range of the Postgres column make the row invalid
+impl FromExpression for i32 {
+    fn from_expression(value: &Expression) -> Option<i32> {
+        u64::from_expression(value).and_then(|v| i32::try_from(v).ok())
+    }
+}
+
+impl FromExpression for i16 {
+    fn from_expression(value: &Expression) -> Option<i16> {
+        u64::from_expression(value).and_then(|v| i16::try_from(v).ok())
+    }
+}
+
+impl FromExpression for String {
+    fn from_expression(value: &Expression) -> Option<String> {
+        match value {
+            Expression::String(v) => Some(v.value.to_string()),
+            _ => None,
+        }
+    }
+}
+
+impl FromExpression for bool {
+    fn from_expression(value: &Expression) -> Option<bool> {
+        match value {
+            Expression::String(v) => Some(v.value.eq("1")),
+            Expression::Integer(v) => Some(v.0 == 1),
+            _ => None,
+        }
+    }
+}
+
+impl FromExpression for NaiveDate {
+    fn from_expression(value: &Expression) -> Option<NaiveDate> {
+        match value {
+            Expression::String(v) => NaiveDateTime::parse_from_str(&v.value, "%Y-%m-%d %H:%M:%S")
+                .ok()
+                .map(|v| v.date()),
+            _ => None,
+        }
+    }
+}
+
+impl<T: FromExpression> FromExpression for Option<T> {
+    fn from_expression(value: &Expression) -> Option<Option<T>> {
+        match value {
+            Expression::Null(_) => Some(None),
+            _ => T::from_expression(value).map(Some),
+        }
+    }
+}
+
+/// Looks up a column in a `VALUES` tuple and parses it, used by `#[derive(DumpRow)]`.
+pub fn field<T>(
+    value: &[Expression],
+    columns: &Columns,
+    entity: &'static str,
+    field: &'static str,
+    column: Column,
+    parse: fn(&Expression) -> Option<T>,
+) -> Result<T, UpdaterError> {
+    let error = |message: String| UpdaterError::InvalidRow {
+        entity,
+        field,
+        message,
+    };
+
+    let index = match column {
+        Column::Index(index) => index,
+        Column::Name(name) => match columns.index(name) {
+            Some(v) => v,
+            None => return Err(error(format!("unknown column {name}"))),
+        },
+    };
+
+    let expression = match value.get(index) {
+        Some(v) => v,
+        None => {
+            return Err(error(format!(
+                "column {index} is out of range, row has {} values",
+                value.len()
+            )))
+        }
+    };
+
+    match parse(expression) {
+        Some(v) => Ok(v),
+        None => Err(error(format!("unexpected value {:?}", expression))),
+    }
+}
+
+/// Parses every row of a dump file at once with the statements and the row
+/// parser of an import. Only meant for small files, like the fixtures of the
+/// golden tests: a row the import would reject fails the whole file.
+pub fn parse_dump<T: FromVecExpression<T>>(
+    path: &Path,
+    encoding: DumpEncoding,
+) -> Result<Vec<T>, Box<dyn std::error::Error + Send>> {
+    let mut statements = match DumpStatements::open_path(path, encoding) {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let parse_options = parse_options();
+    let mut rows = vec![];
+
+    while let Some(statement) = statements.next_statement() {
+        let (origin, statement) = match statement {
+            Ok(v) => v,
+            Err(err) => return Err(Box::new(err)),
+        };
+
+        let message = match parse_row::<T>(&statement, &statements.columns, &parse_options) {
+            ParsedRow::Row(v, _) => {
+                rows.push(v);
+                continue;
+            }
+            ParsedRow::Unparsed(v) => v,
+            ParsedRow::Invalid(v) => v,
+        };
+
+        return Err(Box::new(UpdaterError::RowFailed {
+            file_name: path.display().to_string(),
+            first: origin,
+            last: origin,
+            message,
+        }));
+    }
+
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use sql_parse::Expression;
+
+    use crate::dump_row::FromExpression;
+
+    #[test]
+    fn test_from_expression_max_smallint() {
+        let input = Expression::Integer((i16::MAX as u64, 0..0));
+        let expected_result = Some(i16::MAX);
+
+        let result = i16::from_expression(&input);
+
+        assert_eq!(result, expected_result);
+    }
+
+    #[test]
+    fn test_from_expression_over_max_smallint() {
+        let input = Expression::Integer((i16::MAX as u64 + 1, 0..0));
+        let expected_result = None;
+
+        let result = i16::from_expression(&input);
+
+        assert_eq!(result, expected_result);
+    }
+}
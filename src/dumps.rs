@@ -0,0 +1,176 @@
+use std::io::Read;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::log;
+
+use crate::config;
+
+#[derive(Serialize, Deserialize)]
+struct DumpMeta {
+    sha256: String,
+    size: u64,
+    downloaded_at: i64,
+    /// Missing in the metadata of older versions
+    #[serde(default)]
+    url: Option<String>,
+    #[serde(default)]
+    md5: Option<String>,
+    #[serde(default)]
+    duration_ms: Option<u64>,
+}
+
+/// A dump as a run got it, see `runs::save_downloads`
+#[derive(Clone)]
+pub struct Download {
+    /// Redacted, see `http::redact`
+    pub url: Option<String>,
+    pub size: u64,
+    /// Of the downloaded `.gz`, the one the source publishes
+    pub md5: Option<String>,
+    pub duration_ms: Option<u64>,
+    /// Kept from an earlier run, see `DUMP_REUSE_HOURS`
+    pub reused: bool,
+    /// The table couldn't parse it
+    pub parse_failed: bool,
+}
+
+/// Name of the dump on disk, compressed dumps get a `.zst` suffix so they're
+/// never mistaken for plain ones after the setting changes.
+fn stored_name(file_name: &str) -> String {
+    if config::CONFIG.compress_scratch_files {
+        format!("{file_name}.zst")
+    } else {
+        file_name.to_string()
+    }
+}
+
+/// Sources share file names, so each one gets its own directory
+pub fn dir(source: &str) -> PathBuf {
+    PathBuf::from(&config::CONFIG.work_dir).join(source)
+}
+
+pub fn path(source: &str, file_name: &str) -> PathBuf {
+    dir(source).join(stored_name(file_name))
+}
+
+pub fn part_path(source: &str, file_name: &str) -> PathBuf {
+    dir(source).join(format!("{}.part", stored_name(file_name)))
+}
+
+/// Where a torrent puts its files before they're stored like downloaded ones
+pub fn torrent_dir(source: &str, file_name: &str) -> PathBuf {
+    dir(source).join(format!("{file_name}.torrent"))
+}
+
+fn meta_path(source: &str, file_name: &str) -> PathBuf {
+    dir(source).join(format!("{}.meta.json", stored_name(file_name)))
+}
+
+async fn checksum(path: PathBuf) -> std::io::Result<(String, u64)> {
+    let result = tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; 64 * 1024];
+        let mut size: u64 = 0;
+
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            size += n as u64;
+        }
+
+        Ok((format!("{:x}", hasher.finalize()), size))
+    })
+    .await;
+
+    match result {
+        Ok(v) => v,
+        Err(err) => Err(std::io::Error::other(err)),
+    }
+}
+
+async fn read_meta(source: &str, file_name: &str) -> Option<DumpMeta> {
+    let data = tokio::fs::read(meta_path(source, file_name)).await.ok()?;
+    serde_json::from_slice(&data).ok()
+}
+
+/// Returns true when a dump from a previous run is still fresh and its
+/// contents match the recorded checksum.
+pub async fn is_reusable(source: &str, file_name: &str) -> bool {
+    let max_age = config::CONFIG.dump_reuse_hours * 60 * 60;
+
+    if max_age == 0 {
+        return false;
+    }
+
+    let meta = match read_meta(source, file_name).await {
+        Some(v) => v,
+        None => return false,
+    };
+
+    if chrono::Utc::now().timestamp() - meta.downloaded_at > max_age {
+        return false;
+    }
+
+    match checksum(path(source, file_name)).await {
+        Ok((sha256, size)) => {
+            let is_valid = sha256 == meta.sha256 && size == meta.size;
+            if !is_valid {
+                log::warn!("Stored {file_name} doesn't match its checksum");
+            }
+            is_valid
+        }
+        Err(_) => false,
+    }
+}
+
+/// The stored dump as its download left it
+pub async fn download(source: &str, file_name: &str, reused: bool) -> Option<Download> {
+    let meta = read_meta(source, file_name).await?;
+
+    Some(Download {
+        url: meta.url,
+        size: meta.size,
+        md5: meta.md5,
+        duration_ms: meta.duration_ms,
+        reused,
+        parse_failed: false,
+    })
+}
+
+/// Moves a fully downloaded dump into place and records its checksum and
+/// where it came from.
+pub async fn commit(
+    source: &str,
+    file_name: &str,
+    url: String,
+    md5: String,
+    duration_ms: u64,
+) -> std::io::Result<()> {
+    let _ = tokio::fs::remove_file(meta_path(source, file_name)).await;
+
+    tokio::fs::rename(part_path(source, file_name), path(source, file_name)).await?;
+
+    let (sha256, size) = checksum(path(source, file_name)).await?;
+
+    let meta = DumpMeta {
+        sha256,
+        size,
+        downloaded_at: chrono::Utc::now().timestamp(),
+        url: Some(url),
+        md5: Some(md5),
+        duration_ms: Some(duration_ms),
+    };
+
+    let data = match serde_json::to_vec(&meta) {
+        Ok(v) => v,
+        Err(err) => return Err(std::io::Error::other(err)),
+    };
+
+    tokio::fs::write(meta_path(source, file_name), data).await
+}
@@ -0,0 +1,140 @@
+use deadpool_postgres::Pool;
+use serde::Serialize;
+use tokio_postgres::Client;
+use tracing::log;
+
+use crate::db;
+use crate::utils::page_offset;
+
+pub const PAGE_SIZE: i64 = 100;
+
+#[derive(Serialize)]
+pub struct PossibleDuplicate {
+    pub book_a: i32,
+    pub book_b: i32,
+    pub title: String,
+    pub file_type_a: String,
+    pub file_type_b: String,
+    pub detected_at: String,
+}
+
+/// Flags active books with the same normalized title and author set but a
+/// different file type. Pairs already in the table (reviewed or not) are kept.
+pub async fn detect(client: &Client) -> Result<(), Box<dyn std::error::Error + Send>> {
+    match client
+        .batch_execute(
+            "
+            CREATE TABLE IF NOT EXISTS possible_duplicates (
+                book_a integer NOT NULL,
+                book_b integer NOT NULL,
+                detected_at timestamptz NOT NULL DEFAULT now(),
+                dismissed boolean NOT NULL DEFAULT false,
+                PRIMARY KEY (book_a, book_b)
+            );
+            ",
+        )
+        .await
+    {
+        Ok(_) => (),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    match client
+        .execute(
+            "
+            WITH book_keys AS (
+                SELECT
+                    books.id,
+                    books.file_type,
+                    lower(regexp_replace(books.title, '[[:space:][:punct:]«»—–…]+', '', 'g')) AS title_key,
+                    (
+                        SELECT string_agg(book_authors.author::text, ',' ORDER BY book_authors.author)
+                        FROM book_authors WHERE book_authors.book = books.id
+                    ) AS authors_key
+                FROM books
+                WHERE NOT books.is_deleted
+            )
+            INSERT INTO possible_duplicates (book_a, book_b)
+                SELECT a.id, b.id FROM book_keys a
+                JOIN book_keys b ON a.title_key = b.title_key
+                    AND a.authors_key = b.authors_key
+                    AND a.id < b.id
+                    AND a.file_type <> b.file_type
+                WHERE a.title_key <> ''
+            ON CONFLICT DO NOTHING;
+            ",
+            &[],
+        )
+        .await
+    {
+        Ok(count) => {
+            log::info!("Found {count} new possible duplicates");
+            Ok(())
+        }
+        Err(err) => Err(Box::new(err)),
+    }
+}
+
+pub async fn list(
+    pool: &Pool,
+    page: Option<i64>,
+) -> Result<Vec<PossibleDuplicate>, Box<dyn std::error::Error + Send>> {
+    let client = match db::checkout(pool).await {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let rows = match client
+        .query(
+            "
+            SELECT d.book_a, d.book_b, a.title, a.file_type, b.file_type, d.detected_at::text
+            FROM possible_duplicates d
+            JOIN books a ON a.id = d.book_a
+            JOIN books b ON b.id = d.book_b
+            WHERE NOT d.dismissed
+            ORDER BY d.detected_at DESC, d.book_a, d.book_b
+            LIMIT $1 OFFSET $2;
+            ",
+            &[&PAGE_SIZE, &page_offset(page, PAGE_SIZE)],
+        )
+        .await
+    {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    Ok(rows
+        .iter()
+        .map(|row| PossibleDuplicate {
+            book_a: row.get(0),
+            book_b: row.get(1),
+            title: row.get(2),
+            file_type_a: row.get(3),
+            file_type_b: row.get(4),
+            detected_at: row.get(5),
+        })
+        .collect())
+}
+
+/// Marks a pair as reviewed, returns false if it doesn't exist.
+pub async fn dismiss(
+    pool: &Pool,
+    book_a: i32,
+    book_b: i32,
+) -> Result<bool, Box<dyn std::error::Error + Send>> {
+    let client = match db::checkout(pool).await {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    match client
+        .execute(
+            "UPDATE possible_duplicates SET dismissed = true WHERE book_a = $1 AND book_b = $2;",
+            &[&book_a, &book_b],
+        )
+        .await
+    {
+        Ok(count) => Ok(count > 0),
+        Err(err) => Err(Box::new(err)),
+    }
+}
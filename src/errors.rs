@@ -0,0 +1,137 @@
+use std::any::Any;
+use std::fmt;
+
+use deadpool_postgres::PoolError;
+use uuid::Uuid;
+
+use crate::dump_row::RowOrigin;
+
+#[derive(Debug)]
+pub enum UpdaterError {
+    Panic {
+        file_name: String,
+        message: String,
+    },
+    DependencyFailed {
+        file_name: String,
+    },
+    /// A soft dependency didn't succeed, the table runs at the end of the run
+    Deferred {
+        file_name: String,
+    },
+    ChecksumMismatch {
+        file_name: String,
+        expected: String,
+        actual: String,
+    },
+    /// The dump with this md5 failed to parse in the run, see `SKIP_FAILED_DUMPS`
+    KnownBadDump {
+        file_name: String,
+        md5: String,
+        run_id: Uuid,
+    },
+    InvalidRow {
+        entity: &'static str,
+        field: &'static str,
+        message: String,
+    },
+    TooManyParseIssues {
+        file_name: String,
+        count: u64,
+    },
+    /// A row, or a batch from `first` to `last` when the failed row is unknown
+    RowFailed {
+        file_name: String,
+        first: RowOrigin,
+        last: RowOrigin,
+        message: String,
+    },
+    /// The tables of a catalog schema failed `catalogs::validate`
+    CatalogRejected {
+        schema: String,
+        problems: Vec<String>,
+    },
+    /// No connection after the retries of `db::checkout`
+    Pool(PoolError),
+}
+
+impl UpdaterError {
+    pub fn panic(file_name: &str, payload: Box<dyn Any + Send>) -> UpdaterError {
+        let message = if let Some(v) = payload.downcast_ref::<&str>() {
+            v.to_string()
+        } else if let Some(v) = payload.downcast_ref::<String>() {
+            v.clone()
+        } else {
+            "unknown panic payload".to_string()
+        };
+
+        UpdaterError::Panic {
+            file_name: file_name.to_string(),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for UpdaterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpdaterError::Panic { file_name, message } => {
+                write!(f, "{file_name}: panicked: {message}")
+            }
+            UpdaterError::DependencyFailed { file_name } => {
+                write!(f, "{file_name}: dependency failed")
+            }
+            UpdaterError::Deferred { file_name } => {
+                write!(f, "{file_name}: deferred to the end of the run")
+            }
+            UpdaterError::ChecksumMismatch {
+                file_name,
+                expected,
+                actual,
+            } => {
+                write!(
+                    f,
+                    "{file_name}: md5 is {actual}, the source published {expected}"
+                )
+            }
+            UpdaterError::KnownBadDump {
+                file_name,
+                md5,
+                run_id,
+            } => {
+                write!(
+                    f,
+                    "{file_name}: skipped, the same dump (md5 {md5}) failed to parse in run {run_id}"
+                )
+            }
+            UpdaterError::InvalidRow {
+                entity,
+                field,
+                message,
+            } => {
+                write!(f, "{entity}.{field}: {message}")
+            }
+            UpdaterError::TooManyParseIssues { file_name, count } => {
+                write!(f, "{file_name}: {count} rows with parse issues")
+            }
+            UpdaterError::RowFailed {
+                file_name,
+                first,
+                last,
+                message,
+            } => {
+                if first == last {
+                    write!(f, "{file_name}, {first}: {message}")
+                } else {
+                    write!(f, "{file_name}, {first} to {last}: {message}")
+                }
+            }
+            UpdaterError::CatalogRejected { schema, problems } => {
+                write!(f, "{schema} isn't switched to: {}", problems.join("; "))
+            }
+            UpdaterError::Pool(err) => write!(f, "no database connection: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for UpdaterError {}
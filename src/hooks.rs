@@ -0,0 +1,129 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use crate::report::TableReport;
+
+/// Where a row of a hook comes from
+pub struct RowContext<'a> {
+    pub source: &'a str,
+    pub file_name: &'a str,
+}
+
+type ParsedHook = Arc<dyn Fn(&RowContext, &mut dyn Any) + Send + Sync>;
+type RowHook = Arc<dyn Fn(&RowContext, &dyn Any) + Send + Sync>;
+type TableHook = Arc<dyn Fn(&str, &TableReport) + Send + Sync>;
+
+/// Hooks of a row type, taken by a table when its import starts
+pub(crate) struct RowHooks {
+    parsed: Vec<ParsedHook>,
+    written: Vec<RowHook>,
+}
+
+#[derive(Default)]
+struct Hooks {
+    parsed: HashMap<TypeId, Vec<ParsedHook>>,
+    written: HashMap<TypeId, Vec<RowHook>>,
+    table_done: Vec<TableHook>,
+}
+
+lazy_static! {
+    static ref HOOKS: RwLock<Hooks> = RwLock::new(Hooks::default());
+}
+
+fn row_hook<T, F>(hook: F) -> RowHook
+where
+    T: 'static,
+    F: Fn(&RowContext, &T) + Send + Sync + 'static,
+{
+    Arc::new(move |context, row| {
+        if let Some(row) = row.downcast_ref::<T>() {
+            hook(context, row)
+        }
+    })
+}
+
+/// Calls `hook` with every row of type `T` parsed from a dump, it can change
+/// the row before the field scripts, the row plugins and the writes. Call it
+/// before `library_updater::start()`, like `registry::register`. Hooks run in
+/// the import: a slow one slows it down, a panic fails the table.
+pub fn on_row_parsed<T, F>(hook: F)
+where
+    T: 'static,
+    F: Fn(&RowContext, &mut T) + Send + Sync + 'static,
+{
+    let hook: ParsedHook = Arc::new(move |context, row| {
+        if let Some(row) = row.downcast_mut::<T>() {
+            hook(context, row)
+        }
+    });
+
+    HOOKS
+        .write()
+        .unwrap()
+        .parsed
+        .entry(TypeId::of::<T>())
+        .or_default()
+        .push(hook);
+}
+
+/// Calls `hook` with every row of type `T` once its batch is committed, the
+/// rejected rows aren't passed
+pub fn on_row_written<T, F>(hook: F)
+where
+    T: 'static,
+    F: Fn(&RowContext, &T) + Send + Sync + 'static,
+{
+    HOOKS
+        .write()
+        .unwrap()
+        .written
+        .entry(TypeId::of::<T>())
+        .or_default()
+        .push(row_hook(hook));
+}
+
+/// Calls `hook` with the source name and the report of every table when its
+/// import ends, failed ones included
+pub fn on_table_done<F>(hook: F)
+where
+    F: Fn(&str, &TableReport) + Send + Sync + 'static,
+{
+    HOOKS.write().unwrap().table_done.push(Arc::new(hook));
+}
+
+impl RowHooks {
+    pub(crate) fn of<T: 'static>() -> RowHooks {
+        let hooks = HOOKS.read().unwrap();
+        let id = TypeId::of::<T>();
+
+        RowHooks {
+            parsed: hooks.parsed.get(&id).cloned().unwrap_or_default(),
+            written: hooks.written.get(&id).cloned().unwrap_or_default(),
+        }
+    }
+
+    pub(crate) fn has_parsed(&self) -> bool {
+        !self.parsed.is_empty()
+    }
+
+    pub(crate) fn parsed<T: 'static>(&self, context: &RowContext, row: &mut T) {
+        for hook in self.parsed.iter() {
+            hook(context, row);
+        }
+    }
+
+    pub(crate) fn written<T: 'static>(&self, context: &RowContext, row: &T) {
+        for hook in self.written.iter() {
+            hook(context, row);
+        }
+    }
+}
+
+pub(crate) fn table_done(source: &str, report: &TableReport) {
+    let hooks = HOOKS.read().unwrap().table_done.clone();
+
+    for hook in hooks {
+        hook(source, report);
+    }
+}
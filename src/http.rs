@@ -0,0 +1,190 @@
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures::stream::{BoxStream, StreamExt, TryStreamExt};
+use reqwest::{RequestBuilder, Response, StatusCode, Url};
+use sentry::{Breadcrumb, Level};
+use tracing::{field, log, Instrument};
+
+use crate::config;
+
+const SECRET_PARAMS: [&str; 6] = ["token", "key", "secret", "password", "signature", "auth"];
+
+/// The URL without the user info and the values of parameters that look like
+/// secrets, fit for logs
+pub fn redact(url: &Url) -> String {
+    let mut url = url.clone();
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
+
+    if url.query().is_some() {
+        let pairs: Vec<(String, String)> = url
+            .query_pairs()
+            .map(|(key, value)| {
+                let lower = key.to_lowercase();
+                let value = match SECRET_PARAMS.iter().any(|v| lower.contains(v)) {
+                    true => "redacted".to_string(),
+                    false => value.to_string(),
+                };
+                (key.to_string(), value)
+            })
+            .collect();
+
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+
+    url.to_string()
+}
+
+lazy_static! {
+    /// Client of the dump downloads, see `download`
+    static ref DOWNLOAD_CLIENT: reqwest::Client = download_client();
+}
+
+fn download_client() -> reqwest::Client {
+    let mut builder = reqwest::Client::builder().connect_timeout(Duration::from_secs(
+        config::CONFIG.download_connect_timeout_secs,
+    ));
+
+    // Resets on every read, so only stalled transfers hit it
+    if config::CONFIG.download_read_timeout_secs > 0 {
+        builder = builder.read_timeout(Duration::from_secs(
+            config::CONFIG.download_read_timeout_secs,
+        ));
+    }
+
+    if config::CONFIG.download_tcp_keepalive_secs > 0 {
+        builder = builder.tcp_keepalive(Duration::from_secs(
+            config::CONFIG.download_tcp_keepalive_secs,
+        ));
+    }
+
+    builder.build().unwrap()
+}
+
+/// `reqwest::get` that goes through `send`
+pub async fn get(url: &str) -> reqwest::Result<Response> {
+    send(reqwest::Client::new().get(url)).await
+}
+
+/// `get` for long downloads: with `DOWNLOAD_CONNECT_TIMEOUT_SECS`,
+/// `DOWNLOAD_READ_TIMEOUT_SECS` and `DOWNLOAD_TCP_KEEPALIVE_SECS`. A stalled
+/// body fails with a timeout error instead of hanging.
+pub async fn download(url: &str) -> reqwest::Result<Response> {
+    send(DOWNLOAD_CLIENT.get(url)).await
+}
+
+/// The bytes of a dump split into `{link}.part00`, `{link}.part01` and so on,
+/// part after part until the next one is missing. Parts are downloaded one at
+/// a time, when the previous one is read to the end.
+pub async fn download_parts(
+    link: &str,
+) -> reqwest::Result<BoxStream<'static, reqwest::Result<Bytes>>> {
+    // `split -d` numbers the parts from 00, other tools from 01
+    let mut first = 0;
+    let response = loop {
+        let response = match download(&format!("{link}.part{first:02}")).await {
+            Ok(v) => v,
+            Err(err) => return Err(err),
+        };
+
+        if response.status() == StatusCode::NOT_FOUND && first == 0 {
+            first = 1;
+            continue;
+        }
+
+        match response.error_for_status() {
+            Ok(v) => break v,
+            Err(err) => return Err(err),
+        };
+    };
+
+    let link = link.to_string();
+    let rest = futures::stream::try_unfold(first + 1, move |index| {
+        let link = link.clone();
+
+        async move {
+            let response = match download(&format!("{link}.part{index:02}")).await {
+                Ok(v) => v,
+                Err(err) => return Err(err),
+            };
+
+            if response.status() == StatusCode::NOT_FOUND {
+                log::debug!("{} has {} parts", redact_link(&link), index - first);
+                return Ok(None);
+            }
+
+            match response.error_for_status() {
+                Ok(v) => Ok(Some((v.bytes_stream(), index + 1))),
+                Err(err) => Err(err),
+            }
+        }
+    })
+    .try_flatten();
+
+    Ok(response.bytes_stream().chain(rest).boxed())
+}
+
+/// `redact` of a link that may not parse
+pub fn redact_link(link: &str) -> String {
+    match Url::parse(link) {
+        Ok(url) => redact(&url),
+        Err(_) => link.to_string(),
+    }
+}
+
+/// Sends the request in a span with the method, the redacted URL, the status
+/// and the time to the response headers. With `HTTP_BREADCRUMBS` the request
+/// is also a Sentry breadcrumb, events of failed runs carry them.
+pub async fn send(builder: RequestBuilder) -> reqwest::Result<Response> {
+    let (client, request) = builder.build_split();
+    let request = match request {
+        Ok(v) => v,
+        Err(err) => return Err(err),
+    };
+
+    let method = request.method().to_string();
+    let url = redact(request.url());
+
+    let span = tracing::info_span!(
+        "http",
+        method = %method,
+        url = %url,
+        status = field::Empty,
+        latency_ms = field::Empty,
+    );
+
+    let started_at = Instant::now();
+    let result = client.execute(request).instrument(span.clone()).await;
+    let latency_ms = started_at.elapsed().as_millis() as u64;
+
+    let status = match &result {
+        Ok(response) => response.status().to_string(),
+        Err(err) => format!("error: {err}"),
+    };
+
+    span.record("status", status.as_str());
+    span.record("latency_ms", latency_ms);
+    log::debug!("{method} {url}: {status} in {latency_ms} ms");
+
+    if config::CONFIG.http_breadcrumbs {
+        let failed = !matches!(&result, Ok(response) if response.status().is_success());
+
+        sentry::add_breadcrumb(Breadcrumb {
+            ty: "http".to_string(),
+            category: Some("http".to_string()),
+            data: [
+                ("method".to_string(), method.into()),
+                ("url".to_string(), url.into()),
+                ("status".to_string(), status.into()),
+                ("latency_ms".to_string(), latency_ms.into()),
+            ]
+            .into_iter()
+            .collect(),
+            level: if failed { Level::Warning } else { Level::Info },
+            ..Default::default()
+        });
+    }
+
+    result
+}
@@ -0,0 +1,43 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+use crate::config;
+
+lazy_static! {
+    static ref SEEN_KEYS: Mutex<HashMap<String, (Uuid, Instant)>> = Mutex::new(HashMap::new());
+}
+
+/// Forgets the keys older than `IDEMPOTENCY_KEY_TTL`
+fn expire(seen_keys: &mut HashMap<String, (Uuid, Instant)>) {
+    let ttl = Duration::from_secs(config::CONFIG.idempotency_key_ttl);
+
+    seen_keys.retain(|_, (_, created_at)| created_at.elapsed() < ttl);
+}
+
+/// Returns the run id already associated with `key`, if any
+pub fn get(key: &str) -> Option<Uuid> {
+    let mut seen_keys = SEEN_KEYS.lock().unwrap();
+
+    expire(&mut seen_keys);
+
+    seen_keys.get(key).map(|(run_id, _)| *run_id)
+}
+
+/// Returns the run id already associated with `key`, or remembers `run_id`
+/// for it. The boolean is `true` when the key was seen before.
+pub fn get_or_insert(key: &str, run_id: Uuid) -> (Uuid, bool) {
+    let mut seen_keys = SEEN_KEYS.lock().unwrap();
+
+    expire(&mut seen_keys);
+
+    match seen_keys.get(key) {
+        Some((original_run_id, _)) => (*original_run_id, true),
+        None => {
+            seen_keys.insert(key.to_string(), (run_id, Instant::now()));
+            (run_id, false)
+        }
+    }
+}
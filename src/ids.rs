@@ -0,0 +1,87 @@
+use std::error::Error;
+
+use bytes::BytesMut;
+use serde::{Deserialize, Serialize};
+use sql_parse::Expression;
+use tokio_postgres::types::{to_sql_checked, IsNull, ToSql, Type};
+
+use crate::dump_row::FromExpression;
+
+macro_rules! remote_id {
+    ($name:ident) => {
+        /// Id of a row in the upstream dump, stored as `int` in Postgres.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+        pub struct $name(pub u64);
+
+        impl FromExpression for $name {
+            // Ids that don't fit into int make the row invalid, it's rejected
+            // before it gets to Postgres
+            fn from_expression(value: &Expression) -> Option<$name> {
+                u64::from_expression(value)
+                    .filter(|v| i32::try_from(*v).is_ok())
+                    .map($name)
+            }
+        }
+
+        impl ToSql for $name {
+            fn to_sql(
+                &self,
+                ty: &Type,
+                out: &mut BytesMut,
+            ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+                let value = match i32::try_from(self.0) {
+                    Ok(v) => v,
+                    Err(_) => {
+                        return Err(format!(
+                            "{} {} doesn't fit into int",
+                            stringify!($name),
+                            self.0
+                        )
+                        .into())
+                    }
+                };
+
+                value.to_sql(ty, out)
+            }
+
+            fn accepts(ty: &Type) -> bool {
+                <i32 as ToSql>::accepts(ty)
+            }
+
+            to_sql_checked!();
+        }
+    };
+}
+
+remote_id!(RemoteAuthorId);
+remote_id!(RemoteBookId);
+remote_id!(RemoteSequenceId);
+remote_id!(RemoteGenreId);
+
+#[cfg(test)]
+mod tests {
+    use sql_parse::Expression;
+
+    use crate::dump_row::FromExpression;
+    use crate::ids::RemoteBookId;
+
+    #[test]
+    fn test_from_expression_max_int() {
+        let input = Expression::Integer((i32::MAX as u64, 0..0));
+        let expected_result = Some(RemoteBookId(i32::MAX as u64));
+
+        let result = RemoteBookId::from_expression(&input);
+
+        assert_eq!(result, expected_result);
+    }
+
+    #[test]
+    fn test_from_expression_over_max_int() {
+        let input = Expression::Integer((i32::MAX as u64 + 1, 0..0));
+        let expected_result = None;
+
+        let result = RemoteBookId::from_expression(&input);
+
+        assert_eq!(result, expected_result);
+    }
+}
@@ -0,0 +1,443 @@
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+use deadpool_postgres::Pool;
+use serde_json::{json, Value};
+use tokio_postgres::Client;
+use tracing::log;
+
+use crate::config;
+use crate::db;
+use crate::http;
+
+/// Sources pushed to an index in full, later runs push only their changes
+const FILLS: &str = "
+    CREATE TABLE IF NOT EXISTS search_index_fills (
+        index varchar(32) NOT NULL,
+        source smallint NOT NULL,
+        filled_at timestamptz NOT NULL DEFAULT now(),
+        PRIMARY KEY (index, source)
+    );
+";
+
+struct IndexSpec {
+    /// The index and the table of its documents
+    name: &'static str,
+    /// The entity of the rows in `catalog_changes`
+    entity: &'static str,
+    /// Columns of the documents, `id` is the primary key
+    columns: &'static str,
+    settings: fn() -> Value,
+}
+
+const INDEXES: [IndexSpec; 3] = [
+    IndexSpec {
+        name: "books",
+        entity: "book",
+        columns: "id, remote_id, title, lang, file_type, year, is_deleted",
+        settings: || {
+            json!({
+                "searchableAttributes": ["title"],
+                "filterableAttributes": ["id", "lang", "file_type", "is_deleted"],
+                "sortableAttributes": ["year"],
+            })
+        },
+    },
+    IndexSpec {
+        name: "authors",
+        entity: "author",
+        columns: "id, remote_id, last_name, first_name, middle_name",
+        settings: || {
+            json!({
+                "searchableAttributes": ["last_name", "first_name", "middle_name"],
+                "filterableAttributes": ["id"],
+            })
+        },
+    },
+    IndexSpec {
+        name: "sequences",
+        entity: "sequence",
+        columns: "id, remote_id, name",
+        settings: || {
+            json!({
+                "searchableAttributes": ["name"],
+                "filterableAttributes": ["id"],
+            })
+        },
+    },
+];
+
+/// Sends the request and returns the response, Meilisearch answers with JSON
+async fn send(
+    method: reqwest::Method,
+    url: String,
+    body: Option<&Value>,
+) -> Result<Value, Box<dyn std::error::Error + Send>> {
+    let client = reqwest::Client::new();
+
+    let mut attempt = 0;
+
+    loop {
+        let mut builder = client.request(method.clone(), &url);
+
+        if let Some(body) = body {
+            builder = builder.json(body);
+        }
+
+        if let Some(api_key) = &config::CONFIG.meilisearch_api_key {
+            builder = builder.bearer_auth(api_key);
+        }
+
+        let result = match http::send(builder).await {
+            Ok(response) => match response.error_for_status() {
+                Ok(response) => response.json::<Value>().await,
+                Err(err) => Err(err),
+            },
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok(v) => return Ok(v),
+            Err(err) if attempt < config::CONFIG.meilisearch_retries => {
+                attempt += 1;
+                log::warn!("Meilisearch request failed (attempt {attempt}): {err}");
+                tokio::time::sleep(Duration::from_secs(1 << attempt.min(6))).await;
+            }
+            Err(err) => return Err(Box::new(err)),
+        }
+    }
+}
+
+/// The uid of the task Meilisearch queued for a request
+fn task_uid(response: &Value) -> Result<u64, Box<dyn std::error::Error + Send>> {
+    match response.get("taskUid").and_then(Value::as_u64) {
+        Some(v) => Ok(v),
+        None => Err(Box::new(std::io::Error::other(format!(
+            "Meilisearch didn't queue a task: {response}"
+        )))),
+    }
+}
+
+/// Waits for Meilisearch to process the task, a request is only accepted
+/// until then
+async fn wait(base_url: &str, uid: u64) -> Result<(), Box<dyn std::error::Error + Send>> {
+    let deadline =
+        Instant::now() + Duration::from_secs(config::CONFIG.meilisearch_task_timeout_secs);
+
+    loop {
+        let task = match send(
+            reqwest::Method::GET,
+            format!("{base_url}/tasks/{uid}"),
+            None,
+        )
+        .await
+        {
+            Ok(v) => v,
+            Err(err) => return Err(err),
+        };
+
+        match task.get("status").and_then(Value::as_str) {
+            Some("succeeded") => return Ok(()),
+            Some(status @ ("failed" | "canceled")) => {
+                return Err(Box::new(std::io::Error::other(format!(
+                    "Meilisearch task {uid} {status}: {}",
+                    task["error"]["message"]
+                ))))
+            }
+            _ => (),
+        };
+
+        if Instant::now() >= deadline {
+            return Err(Box::new(std::io::Error::other(format!(
+                "Meilisearch task {uid} isn't processed in time"
+            ))));
+        }
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+}
+
+/// Queues the documents and returns the task
+async fn push(
+    base_url: &str,
+    spec: &IndexSpec,
+    documents: Vec<Value>,
+) -> Result<u64, Box<dyn std::error::Error + Send>> {
+    match send(
+        reqwest::Method::POST,
+        format!("{base_url}/indexes/{}/documents?primaryKey=id", spec.name),
+        Some(&Value::Array(documents)),
+    )
+    .await
+    {
+        Ok(v) => task_uid(&v),
+        Err(err) => Err(err),
+    }
+}
+
+/// Queues the documents of all rows of the source, returns their tasks
+async fn push_all(
+    client: &Client,
+    base_url: &str,
+    source_id: i16,
+    spec: &IndexSpec,
+) -> Result<Vec<u64>, Box<dyn std::error::Error + Send>> {
+    let query = format!(
+        "
+        SELECT cast(t.id as bigint), to_jsonb(t) FROM (
+            SELECT {} FROM {} WHERE source = $1 AND id > cast($2 as bigint) ORDER BY id LIMIT $3
+        ) t;
+        ",
+        spec.columns, spec.name
+    );
+
+    let batch_size = config::CONFIG.meilisearch_batch_size as i64;
+    let mut last_id: i64 = 0;
+    let mut tasks = vec![];
+    let mut total = 0;
+
+    loop {
+        let rows = match client
+            .query(&query, &[&source_id, &last_id, &batch_size])
+            .await
+        {
+            Ok(v) => v,
+            Err(err) => return Err(Box::new(err)),
+        };
+
+        let documents: Vec<Value> = rows.iter().map(|row| row.get(1)).collect();
+
+        if let Some(row) = rows.last() {
+            last_id = row.get(0);
+        }
+
+        if documents.is_empty() {
+            break;
+        }
+
+        total += documents.len();
+
+        match push(base_url, spec, documents).await {
+            Ok(v) => tasks.push(v),
+            Err(err) => return Err(err),
+        };
+    }
+
+    log::info!("Pushing {total} documents to the {} index", spec.name);
+
+    Ok(tasks)
+}
+
+/// Queues the documents of the rows changed since the change `changes_from`
+/// and the removal of the deleted ones, returns their tasks
+async fn push_changed(
+    client: &Client,
+    base_url: &str,
+    spec: &IndexSpec,
+    changes_from: i64,
+) -> Result<Vec<u64>, Box<dyn std::error::Error + Send>> {
+    let changed: Vec<i32> = match client
+        .query(
+            "
+            SELECT DISTINCT entity_id FROM catalog_changes
+            WHERE entity = $1 AND id > $2 ORDER BY entity_id;
+            ",
+            &[&spec.entity, &changes_from],
+        )
+        .await
+    {
+        Ok(rows) => rows.iter().map(|row| row.get(0)).collect(),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let query = format!(
+        "SELECT cast(t.id as bigint), to_jsonb(t) FROM (SELECT {} FROM {} WHERE id = ANY(cast($1 as int[]))) t;",
+        spec.columns, spec.name
+    );
+
+    let mut tasks = vec![];
+    let mut pushed = 0;
+    let mut deleted: Vec<i32> = vec![];
+
+    for ids in changed.chunks(config::CONFIG.meilisearch_batch_size.max(1)) {
+        let rows = match client.query(&query, &[&ids]).await {
+            Ok(v) => v,
+            Err(err) => return Err(Box::new(err)),
+        };
+
+        let found: HashSet<i64> = rows.iter().map(|row| row.get(0)).collect();
+        deleted.extend(
+            ids.iter()
+                .filter(|id| !found.contains(&(**id as i64)))
+                .copied(),
+        );
+
+        let documents: Vec<Value> = rows.iter().map(|row| row.get(1)).collect();
+
+        if documents.is_empty() {
+            continue;
+        }
+
+        pushed += documents.len();
+
+        match push(base_url, spec, documents).await {
+            Ok(v) => tasks.push(v),
+            Err(err) => return Err(err),
+        };
+    }
+
+    for ids in deleted.chunks(config::CONFIG.meilisearch_batch_size.max(1)) {
+        let filter = json!({ "filter": format!("id IN {ids:?}") });
+
+        match send(
+            reqwest::Method::DELETE,
+            format!("{base_url}/indexes/{}/documents", spec.name),
+            Some(&filter),
+        )
+        .await
+        {
+            Ok(v) => match task_uid(&v) {
+                Ok(v) => tasks.push(v),
+                Err(err) => return Err(err),
+            },
+            Err(err) => return Err(err),
+        };
+    }
+
+    log::info!(
+        "Pushing {pushed} changed documents to the {} index, deleting {}",
+        spec.name,
+        deleted.len()
+    );
+
+    Ok(tasks)
+}
+
+/// Pushes the rows of the source in full the first time, or when the index is
+/// empty, and only the changes of the run after that
+async fn reindex(
+    pool: &Pool,
+    base_url: &str,
+    source_id: i16,
+    changes_from: Option<i64>,
+    spec: &IndexSpec,
+) -> Result<(), Box<dyn std::error::Error + Send>> {
+    let settings = match config::CONFIG.meilisearch_settings.get(spec.name) {
+        Some(v) => v.clone(),
+        None => (spec.settings)(),
+    };
+
+    // Creates the index if it's missing
+    let task = match send(
+        reqwest::Method::PATCH,
+        format!("{base_url}/indexes/{}/settings", spec.name),
+        Some(&settings),
+    )
+    .await
+    {
+        Ok(v) => task_uid(&v),
+        Err(err) => Err(err),
+    };
+
+    match task {
+        Ok(uid) => match wait(base_url, uid).await {
+            Ok(_) => (),
+            Err(err) => return Err(err),
+        },
+        Err(err) => return Err(err),
+    };
+
+    let stats = match send(
+        reqwest::Method::GET,
+        format!("{base_url}/indexes/{}/stats", spec.name),
+        None,
+    )
+    .await
+    {
+        Ok(v) => v,
+        Err(err) => return Err(err),
+    };
+    let empty = stats.get("numberOfDocuments").and_then(Value::as_u64) == Some(0);
+
+    let client = match db::checkout(pool).await {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    match client.batch_execute(FILLS).await {
+        Ok(_) => (),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let filled: bool = match client
+        .query_one(
+            "SELECT EXISTS (SELECT 1 FROM search_index_fills WHERE index = $1 AND source = $2);",
+            &[&spec.name, &source_id],
+        )
+        .await
+    {
+        Ok(row) => row.get(0),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let tasks = match changes_from {
+        Some(changes_from) if filled && !empty => {
+            push_changed(&client, base_url, spec, changes_from).await
+        }
+        _ => push_all(&client, base_url, source_id, spec).await,
+    };
+
+    let tasks = match tasks {
+        Ok(v) => v,
+        Err(err) => return Err(err),
+    };
+
+    for uid in tasks {
+        match wait(base_url, uid).await {
+            Ok(_) => (),
+            Err(err) => return Err(err),
+        };
+    }
+
+    match client
+        .execute(
+            "
+            INSERT INTO search_index_fills (index, source) VALUES ($1, $2)
+            ON CONFLICT (index, source) DO NOTHING;
+            ",
+            &[&spec.name, &source_id],
+        )
+        .await
+    {
+        Ok(_) => (),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    log::info!("The {} index is up to date", spec.name);
+
+    Ok(())
+}
+
+pub async fn reindex_all(
+    pool: &Pool,
+    source_id: i16,
+    changes_from: Option<i64>,
+) -> Result<(), Box<dyn std::error::Error + Send>> {
+    let base_url = match &config::CONFIG.meilisearch_url {
+        Some(v) => v.trim_end_matches('/'),
+        None => return Ok(()),
+    };
+
+    log::info!("Start search reindex...");
+
+    for spec in INDEXES.iter() {
+        match reindex(pool, base_url, source_id, changes_from, spec).await {
+            Ok(_) => (),
+            Err(err) => return Err(err),
+        };
+    }
+
+    log::info!("Search reindex finished!");
+
+    Ok(())
+}
@@ -0,0 +1,102 @@
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio_cron_scheduler::JobScheduler;
+use tracing::log;
+use uuid::Uuid;
+
+/// A job of `updater::cron_jobs`, as `GET /schedule` shows it
+#[derive(Serialize, Clone)]
+pub struct JobStatus {
+    pub name: &'static str,
+    /// Cron expression with seconds
+    pub schedule: String,
+    pub next_run_at: Option<DateTime<Utc>>,
+    pub last_started_at: Option<DateTime<Utc>>,
+    pub last_finished_at: Option<DateTime<Utc>>,
+    /// `running`, `success` or `failed`, nothing before the first run
+    pub last_outcome: Option<&'static str>,
+}
+
+struct Entry {
+    id: Uuid,
+    status: JobStatus,
+}
+
+lazy_static! {
+    /// Set once the jobs are added, for their next fire times
+    static ref SCHEDULER: Mutex<Option<JobScheduler>> = Mutex::new(None);
+    static ref JOBS: Mutex<Vec<Entry>> = Mutex::new(vec![]);
+}
+
+pub fn set_scheduler(scheduler: &JobScheduler) {
+    *SCHEDULER.lock().unwrap() = Some(scheduler.clone());
+}
+
+pub fn register(name: &'static str, schedule: &str, id: Uuid) {
+    JOBS.lock().unwrap().push(Entry {
+        id,
+        status: JobStatus {
+            name,
+            schedule: schedule.to_string(),
+            next_run_at: None,
+            last_started_at: None,
+            last_finished_at: None,
+            last_outcome: None,
+        },
+    });
+}
+
+fn update(name: &str, f: impl FnOnce(&mut JobStatus)) {
+    let mut jobs = JOBS.lock().unwrap();
+    if let Some(entry) = jobs.iter_mut().find(|entry| entry.status.name == name) {
+        f(&mut entry.status);
+    }
+}
+
+pub fn started(name: &str) {
+    update(name, |status| {
+        status.last_started_at = Some(Utc::now());
+        status.last_outcome = Some("running");
+    });
+}
+
+pub fn finished(name: &str, ok: bool) {
+    update(name, |status| {
+        status.last_finished_at = Some(Utc::now());
+        status.last_outcome = Some(if ok { "success" } else { "failed" });
+    });
+}
+
+/// The registered jobs with their next fire times
+pub async fn list() -> Vec<JobStatus> {
+    let entries: Vec<(Uuid, JobStatus)> = JOBS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|entry| (entry.id, entry.status.clone()))
+        .collect();
+
+    let scheduler = SCHEDULER.lock().unwrap().clone();
+    let mut scheduler = match scheduler {
+        Some(v) => v,
+        None => return entries.into_iter().map(|(_, status)| status).collect(),
+    };
+
+    let mut jobs = Vec::with_capacity(entries.len());
+
+    for (id, mut status) in entries {
+        status.next_run_at = match scheduler.next_tick_for_job(id).await {
+            Ok(v) => v,
+            Err(err) => {
+                log::error!("Can't get the next run of {}: {:?}", status.name, err);
+                None
+            }
+        };
+
+        jobs.push(status);
+    }
+
+    jobs
+}
@@ -0,0 +1,95 @@
+#[macro_use]
+extern crate lazy_static;
+
+// Lets `#[derive(DumpRow)]` refer to `::library_updater` from inside this crate
+extern crate self as library_updater;
+
+pub mod analyze;
+pub mod arrivals;
+pub mod audit;
+pub mod auth;
+pub mod batching;
+pub mod catalogs;
+pub mod changes;
+pub mod config;
+pub mod db;
+pub mod discovery;
+pub mod dump_row;
+pub mod dumps;
+pub mod duplicates;
+pub mod errors;
+pub mod hooks;
+pub mod http;
+pub mod idempotency;
+pub mod ids;
+pub mod indexer;
+pub mod jobs;
+pub mod limits;
+pub mod monitor;
+pub mod notify;
+pub mod onix_export;
+pub mod opds;
+#[cfg(feature = "wasm-plugins")]
+pub mod plugins;
+pub mod preview;
+pub mod progress;
+pub mod provenance;
+pub mod registry;
+pub mod rejects;
+pub mod report;
+pub mod retention;
+pub mod run_log;
+pub mod runs;
+#[cfg(feature = "field-scripts")]
+pub mod scripts;
+pub mod search_index;
+pub mod server;
+pub mod shadow;
+pub mod sqlite_export;
+pub mod stats;
+pub mod throttle;
+pub mod tls;
+#[cfg(feature = "torrent")]
+pub mod torrent;
+pub mod types;
+pub mod updater;
+pub mod upsert;
+pub mod utils;
+
+use sentry::{integrations::debug_images::DebugImagesIntegration, types::Dsn, ClientOptions};
+use sentry_tracing::EventFilter;
+use std::str::FromStr;
+use tracing_subscriber::filter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+pub async fn start() {
+    let options = ClientOptions {
+        dsn: Some(Dsn::from_str(&config::CONFIG.sentry_dsn).unwrap()),
+        default_integrations: false,
+        ..Default::default()
+    }
+    .add_integration(DebugImagesIntegration::new());
+
+    let _guard = sentry::init(options);
+
+    let sentry_layer = sentry_tracing::layer().event_filter(|md| match md.level() {
+        &tracing::Level::ERROR => EventFilter::Event,
+        _ => EventFilter::Ignore,
+    });
+
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_target(false))
+        .with(filter::LevelFilter::INFO)
+        .with(sentry_layer)
+        .with(run_log::RunLogLayer)
+        .init();
+
+    // A script or a plugin that doesn't compile fails here, not in a run
+    #[cfg(feature = "field-scripts")]
+    scripts::load();
+    #[cfg(feature = "wasm-plugins")]
+    plugins::load();
+
+    tokio::join![updater::cron_jobs(), server::start_app()];
+}
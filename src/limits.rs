@@ -0,0 +1,96 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config;
+
+lazy_static! {
+    /// Parsed rows that aren't written yet, shared by all tables
+    static ref IN_FLIGHT_ROWS: Arc<Semaphore> = Arc::new(Semaphore::new(capacity()));
+
+    /// Tables being imported, of all sources
+    static ref TABLE_SLOTS: Arc<Semaphore> = Arc::new(Semaphore::new(
+        match config::CONFIG.table_concurrency {
+            0 => Semaphore::MAX_PERMITS,
+            v => v,
+        }
+    ));
+}
+
+/// Waits for a free table slot. Slots are handed out in the order they were
+/// asked for, see `updater::schedule`.
+pub async fn table_slot() -> OwnedSemaphorePermit {
+    TABLE_SLOTS.clone().acquire_owned().await.unwrap()
+}
+
+fn capacity() -> usize {
+    match config::CONFIG.max_in_flight_rows {
+        0 => Semaphore::MAX_PERMITS,
+        v => v,
+    }
+}
+
+/// Rows the parser may reserve at once, so a single reservation always fits the limit.
+pub fn chunk_size(preferred: usize) -> usize {
+    preferred.min(capacity())
+}
+
+fn in_flight() -> usize {
+    capacity() - IN_FLIGHT_ROWS.available_permits()
+}
+
+/// Resident memory of the process, read from `/proc` (Linux only).
+fn resident_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kb: u64 = line
+        .trim_start_matches("VmRSS:")
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()?;
+
+    Some(kb * 1024)
+}
+
+fn is_memory_exceeded() -> bool {
+    let max_memory = config::CONFIG.max_memory_mb * 1024 * 1024;
+
+    if max_memory == 0 {
+        return false;
+    }
+
+    match resident_memory() {
+        Some(v) => v > max_memory,
+        None => false,
+    }
+}
+
+/// Reserves `rows` without waiting, `None` if a limit is reached.
+pub fn try_reserve_rows(rows: usize) -> Option<OwnedSemaphorePermit> {
+    if is_memory_exceeded() {
+        return None;
+    }
+
+    IN_FLIGHT_ROWS
+        .clone()
+        .try_acquire_many_owned(rows as u32)
+        .ok()
+}
+
+/// Waits until `rows` can be reserved. Memory is only waited for while other
+/// rows are still being written, they're the ones expected to free it.
+pub async fn reserve_rows(rows: usize) -> OwnedSemaphorePermit {
+    while is_memory_exceeded() && in_flight() > 0 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    IN_FLIGHT_ROWS
+        .clone()
+        .acquire_many_owned(rows as u32)
+        .await
+        .unwrap()
+}
@@ -1,85 +1,87 @@
-#[macro_use]
-extern crate lazy_static;
-
-pub mod config;
-pub mod types;
-pub mod updater;
-pub mod utils;
-
-use axum::{http::HeaderMap, routing::post, Router};
 use dotenvy::dotenv;
-use sentry::{integrations::debug_images::DebugImagesIntegration, types::Dsn, ClientOptions};
-use sentry_tracing::EventFilter;
-use std::{net::SocketAddr, str::FromStr};
-use tower_http::trace::{self, TraceLayer};
-use tracing::log;
-use tracing::Level;
-use tracing_subscriber::filter;
-use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::util::SubscriberInitExt;
-
-use crate::updater::cron_jobs;
-
-async fn update(headers: HeaderMap) -> &'static str {
-    let config_api_key = config::CONFIG.api_key.clone();
-
-    let api_key = match headers.get("Authorization") {
-        Some(v) => v,
-        None => return "No api-key!",
-    };
-
-    if config_api_key != api_key.to_str().unwrap() {
-        return "Wrong api-key!";
-    }
-
-    tokio::spawn(async {
-        match updater::update().await {
-            Ok(_) => log::info!("Updated!"),
-            Err(err) => log::info!("Updater err: {:?}", err),
-        };
-    });
-
-    "Update started"
-}
-
-async fn start_app() {
-    let app = Router::new().route("/update", post(update)).layer(
-        TraceLayer::new_for_http()
-            .make_span_with(trace::DefaultMakeSpan::new().level(Level::INFO))
-            .on_response(trace::DefaultOnResponse::new().level(Level::INFO)),
-    );
-
-    let addr = SocketAddr::from(([0, 0, 0, 0], 8080));
-
-    log::info!("Start webserver...");
-    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
-    log::info!("Webserver shutdown...")
-}
 
 #[tokio::main]
 async fn main() {
     dotenv().ok();
 
-    let options = ClientOptions {
-        dsn: Some(Dsn::from_str(&config::CONFIG.sentry_dsn).unwrap()),
-        default_integrations: false,
-        ..Default::default()
+    let args: Vec<String> = std::env::args().collect();
+
+    match args.get(1).map(String::as_str) {
+        Some("analyze") => {
+            let Some(target) = args.get(2) else {
+                eprintln!("Usage: library_updater analyze <file-or-url>");
+                std::process::exit(2);
+            };
+
+            match library_updater::analyze::run(target).await {
+                Ok(analysis) => analysis.print(),
+                Err(err) => {
+                    eprintln!("Can't analyze {target}: {err}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some("reprocess-rejects") => {
+            let source =
+                match library_updater::config::CONFIG.source(args.get(2).map(String::as_str)) {
+                    Some(v) => v,
+                    None => {
+                        eprintln!("Usage: library_updater reprocess-rejects [<source> [<file>]]");
+                        std::process::exit(2);
+                    }
+                };
+
+            match library_updater::updater::reprocess_rejects(source, args.get(3).cloned()).await {
+                Ok(reports) => {
+                    for report in reports {
+                        println!(
+                            "{}: {} applied, {} still rejected",
+                            report.file_name, report.applied, report.rejected
+                        );
+                    }
+                }
+                Err(err) => {
+                    eprintln!("Can't reprocess rejects of {}: {err}", source.name);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some("rollback-shadow") => {
+            let mut client = match library_updater::db::checkout(&library_updater::db::POOL).await {
+                Ok(v) => v,
+                Err(err) => {
+                    eprintln!("Can't connect to the database: {err}");
+                    std::process::exit(1);
+                }
+            };
+
+            match library_updater::shadow::rollback(&mut client).await {
+                Ok(tables) if tables.is_empty() => println!("Nothing to roll back"),
+                Ok(tables) => println!("Restored {}", tables.join(", ")),
+                Err(err) => {
+                    eprintln!("Can't roll back the last shadow swap: {err}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some("rollback-catalog") => {
+            let mut client = match library_updater::db::checkout(&library_updater::db::POOL).await {
+                Ok(v) => v,
+                Err(err) => {
+                    eprintln!("Can't connect to the database: {err}");
+                    std::process::exit(1);
+                }
+            };
+
+            match library_updater::catalogs::rollback(&mut client).await {
+                Ok(Some(schema)) => println!("Switched back to {schema}"),
+                Ok(None) => println!("Nothing to roll back"),
+                Err(err) => {
+                    eprintln!("Can't roll back the catalog switch: {err}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        _ => library_updater::start().await,
     }
-    .add_integration(DebugImagesIntegration::new());
-
-    let _guard = sentry::init(options);
-
-    let sentry_layer = sentry_tracing::layer().event_filter(|md| match md.level() {
-        &tracing::Level::ERROR => EventFilter::Event,
-        _ => EventFilter::Ignore,
-    });
-
-    tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer().with_target(false))
-        .with(filter::LevelFilter::INFO)
-        .with(sentry_layer)
-        .init();
-
-    tokio::join![cron_jobs(), start_app()];
 }
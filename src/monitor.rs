@@ -0,0 +1,84 @@
+use sentry::protocol::{
+    Envelope, MonitorCheckIn, MonitorCheckInStatus, MonitorConfig, MonitorSchedule,
+};
+use tracing::log;
+use uuid::Uuid;
+
+use crate::config;
+
+/// A run of a scheduled job reported to the Sentry cron monitor
+/// `SENTRY_MONITOR_SLUG`, so missed and failed runs raise alerts
+pub struct CheckIn {
+    id: Uuid,
+    slug: String,
+    started_at: std::time::Instant,
+}
+
+impl CheckIn {
+    /// Checks in as in progress, nothing without `SENTRY_MONITOR_SLUG`.
+    /// `schedule` is the job's schedule with seconds, it's kept on the monitor.
+    pub fn start(schedule: &str) -> Option<CheckIn> {
+        let slug = config::CONFIG.sentry_monitor_slug.clone()?;
+
+        // Sentry takes crontabs without the seconds field
+        let crontab = schedule.split_once(' ').map_or(schedule, |(_, rest)| rest);
+        let monitor_config = match MonitorSchedule::from_crontab(crontab) {
+            Ok(schedule) => Some(MonitorConfig {
+                schedule,
+                checkin_margin: Some(config::CONFIG.sentry_monitor_margin_mins),
+                max_runtime: Some(config::CONFIG.sentry_monitor_max_runtime_mins),
+                timezone: Some("UTC".to_string()),
+                failure_issue_threshold: None,
+                recovery_threshold: None,
+            }),
+            Err(err) => {
+                log::warn!("Monitor {slug} keeps its schedule: {err}");
+                None
+            }
+        };
+
+        let check_in = CheckIn {
+            id: Uuid::new_v4(),
+            slug,
+            started_at: std::time::Instant::now(),
+        };
+
+        check_in.send(MonitorCheckInStatus::InProgress, None, monitor_config);
+
+        Some(check_in)
+    }
+
+    /// Checks in as ok or error with the duration of the run
+    pub fn finish(self, ok: bool) {
+        let status = match ok {
+            true => MonitorCheckInStatus::Ok,
+            false => MonitorCheckInStatus::Error,
+        };
+
+        self.send(status, Some(self.started_at.elapsed().as_secs_f64()), None);
+    }
+
+    fn send(
+        &self,
+        status: MonitorCheckInStatus,
+        duration: Option<f64>,
+        monitor_config: Option<MonitorConfig>,
+    ) {
+        let client = match sentry::Hub::current().client() {
+            Some(v) => v,
+            None => return,
+        };
+
+        let mut envelope = Envelope::new();
+        envelope.add_item(MonitorCheckIn {
+            check_in_id: self.id,
+            monitor_slug: self.slug.clone(),
+            status,
+            environment: config::CONFIG.deploy_env.clone(),
+            duration,
+            monitor_config,
+        });
+
+        client.send_envelope(envelope);
+    }
+}
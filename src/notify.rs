@@ -0,0 +1,695 @@
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use chrono::{Timelike, Utc};
+use lettre::message::{header::ContentType, Mailbox};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde_json::json;
+use tokio_postgres::Client;
+use tracing::log;
+use uuid::Uuid;
+
+use crate::config::{self, Webhook, WebhookFormat};
+use crate::http;
+use crate::report::{Delivery, TableReport, TableStatus, UpdateReport};
+use crate::runs::RunStatus;
+
+/// What gets sent: the report of the run and the failure streak it's part of
+pub struct Notice<'a> {
+    pub report: &'a UpdateReport,
+    /// Failed runs in a row, this one included
+    pub failures: u32,
+    pub escalated: bool,
+}
+
+/// A channel the report of a run is sent to.
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Whether a run with this outcome should be reported
+    fn wants(&self, status: RunStatus) -> bool;
+
+    /// Alerts are meant for people, deduplication and quiet hours apply to them
+    fn is_alert(&self) -> bool;
+
+    /// Sends the notice to every target of the channel, returns the result of each
+    async fn notify(&self, notice: &Notice) -> Vec<(String, SendResult)>;
+}
+
+type SendResult = Result<(), Box<dyn std::error::Error + Send>>;
+
+fn duration(report: &UpdateReport) -> String {
+    let secs = report.duration_secs;
+    format!(
+        "{}h {:02}m {:02}s",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+fn table_line(table: &TableReport) -> String {
+    let status = match table.status {
+        TableStatus::Success => "ok",
+        TableStatus::Failed => "failed",
+        TableStatus::Skipped => "skipped",
+    };
+    let rows = &table.rows;
+
+    format!(
+        "{:<28} {:<8} +{} ~{} ={} -{}",
+        table.file_name, status, rows.inserted, rows.updated, rows.unchanged, rows.skipped
+    )
+}
+
+/// The first error of the run, cut to fit a chat message
+fn error_excerpt(report: &UpdateReport) -> Option<String> {
+    let error = report
+        .tables
+        .iter()
+        .find_map(|table| table.error.clone())
+        .or_else(|| report.errors.first().cloned())?;
+
+    Some(error.chars().take(500).collect())
+}
+
+fn title(notice: &Notice) -> String {
+    let title = format!(
+        "Library update ({}): {}",
+        notice.report.source,
+        notice.report.status.as_str()
+    );
+
+    if notice.escalated {
+        format!("{title}, failed {} times in a row", notice.failures)
+    } else {
+        title
+    }
+}
+
+/// The report as Slack blocks
+fn slack_message(notice: &Notice) -> serde_json::Value {
+    let report = notice.report;
+    let tables: Vec<String> = report.tables.iter().map(table_line).collect();
+
+    let mut blocks = vec![
+        json!({
+            "type": "header",
+            "text": {"type": "plain_text", "text": title(notice)},
+        }),
+        json!({
+            "type": "section",
+            "fields": [
+                {"type": "mrkdwn", "text": format!("*Status*\n{}", report.status.as_str())},
+                {"type": "mrkdwn", "text": format!("*Duration*\n{}", duration(report))},
+                {"type": "mrkdwn", "text": format!("*Run*\n{}", report.run_id)},
+            ],
+        }),
+        json!({
+            "type": "section",
+            "text": {"type": "mrkdwn", "text": format!("```{}```", tables.join("\n"))},
+        }),
+    ];
+
+    if !report.new_dumps.is_empty() {
+        blocks.push(json!({
+            "type": "section",
+            "text": {"type": "mrkdwn", "text": format!("*New dumps*\n{}", report.new_dumps.join(", "))},
+        }));
+    }
+
+    if let Some(error) = error_excerpt(report) {
+        blocks.push(json!({
+            "type": "section",
+            "text": {"type": "mrkdwn", "text": format!("*Error*\n```{error}```")},
+        }));
+    }
+
+    json!({"text": title(notice), "blocks": blocks})
+}
+
+/// The report as a Discord embed
+fn discord_message(notice: &Notice) -> serde_json::Value {
+    let report = notice.report;
+    let color = match report.status {
+        RunStatus::Success => 0x2eb67d,
+        RunStatus::PartialSuccess | RunStatus::Running => 0xecb22e,
+        RunStatus::Failed => 0xe01e5a,
+    };
+
+    let tables: Vec<String> = report.tables.iter().map(table_line).collect();
+
+    let mut fields = vec![
+        json!({"name": "Duration", "value": duration(report), "inline": true}),
+        json!({"name": "Run", "value": report.run_id.to_string(), "inline": true}),
+    ];
+
+    if !report.new_dumps.is_empty() {
+        fields.push(json!({"name": "New dumps", "value": report.new_dumps.join(", ")}));
+    }
+
+    if let Some(error) = error_excerpt(report) {
+        fields.push(json!({"name": "Error", "value": format!("```{error}```")}));
+    }
+
+    json!({
+        "embeds": [{
+            "title": title(notice),
+            "color": color,
+            "description": format!("```{}```", tables.join("\n")),
+            "fields": fields,
+        }],
+    })
+}
+
+/// Raw webhooks trigger the downstream services, chat ones (Slack, Discord) alert people
+struct Webhooks {
+    chat: bool,
+}
+
+#[async_trait]
+impl Notifier for Webhooks {
+    fn name(&self) -> &'static str {
+        if self.chat {
+            "chat webhooks"
+        } else {
+            "webhooks"
+        }
+    }
+
+    // Downstream services only need data to work with
+    fn wants(&self, status: RunStatus) -> bool {
+        self.chat || status != RunStatus::Failed
+    }
+
+    fn is_alert(&self) -> bool {
+        self.chat
+    }
+
+    async fn notify(&self, notice: &Notice) -> Vec<(String, SendResult)> {
+        let mut results = vec![];
+
+        // A failed webhook doesn't keep the ones after it from being called
+        for (index, webhook) in config::CONFIG.webhooks.iter().enumerate() {
+            if matches!(webhook.format, WebhookFormat::Raw) == self.chat {
+                continue;
+            }
+
+            if !webhook.is_active() {
+                log::debug!(
+                    "Webhook {} is off here",
+                    webhook_target(index, &webhook.url)
+                );
+                continue;
+            }
+
+            let result = send_webhook(webhook, notice).await;
+            results.push((webhook_target(index, &webhook.url), result));
+        }
+
+        results
+    }
+}
+
+/// The webhook by its place in `WEBHOOKS` and its host, the rest of the URL
+/// often holds a token
+fn webhook_target(index: usize, url: &str) -> String {
+    let host = reqwest::Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_else(|| "invalid url".to_string());
+
+    format!("#{} {host}", index + 1)
+}
+
+async fn send_webhook(webhook: &Webhook, notice: &Notice<'_>) -> SendResult {
+    let report = notice.report;
+
+    let Webhook {
+        method,
+        url,
+        headers,
+        format,
+        ..
+    } = webhook.clone();
+
+    let client = reqwest::Client::new();
+
+    let builder = match method {
+        config::Method::Get => client.get(url).query(&[
+            ("run_id", report.run_id.to_string()),
+            ("status", report.status.as_str().to_string()),
+        ]),
+        config::Method::Post => match format {
+            WebhookFormat::Raw => client.post(url).json(report),
+            WebhookFormat::Slack => client.post(url).json(&slack_message(notice)),
+            WebhookFormat::Discord => client.post(url).json(&discord_message(notice)),
+        },
+    };
+
+    let mut header_map = HeaderMap::new();
+
+    for (key, val) in headers.into_iter() {
+        let value = match val {
+            serde_json::Value::String(v) => v,
+            _ => {
+                let err: Box<dyn std::error::Error + Send + Sync> =
+                    format!("Header value of {key} not string!").into();
+                return Err(err);
+            }
+        };
+
+        let name = match HeaderName::from_str(key.as_ref()) {
+            Ok(v) => v,
+            Err(err) => return Err(Box::new(err)),
+        };
+
+        let value = match HeaderValue::from_str(&value) {
+            Ok(v) => v,
+            Err(err) => return Err(Box::new(err)),
+        };
+
+        header_map.insert(name, value);
+    }
+
+    let response = match http::send(builder.headers(header_map)).await {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    match response.error_for_status() {
+        Ok(_) => Ok(()),
+        Err(err) => Err(Box::new(err)),
+    }
+}
+
+struct Email {
+    host: String,
+}
+
+fn summary(report: &UpdateReport) -> String {
+    let mut lines = vec![
+        format!("Run: {}", report.run_id),
+        format!("Source: {}", report.source),
+        format!("Status: {}", report.status.as_str()),
+        format!("Duration: {}", duration(report)),
+        String::new(),
+        "Tables:".to_string(),
+    ];
+
+    for table in report.tables.iter() {
+        let mut line = format!("  {}", table_line(table));
+
+        if let Some(error) = &table.error {
+            line.push_str(&format!(" ({error})"));
+        }
+
+        if table.parse_issues.rows > 0 {
+            line.push_str(&format!(
+                ", {} rows with parse issues",
+                table.parse_issues.rows
+            ));
+        }
+
+        if table.rows.rejected > 0 {
+            line.push_str(&format!(", {} rows rejected", table.rows.rejected));
+        }
+
+        lines.push(line);
+    }
+
+    if !report.new_dumps.is_empty() {
+        lines.push(String::new());
+        lines.push(format!("New dumps: {}", report.new_dumps.join(", ")));
+    }
+
+    if !report.errors.is_empty() {
+        lines.push(String::new());
+        lines.push("Errors:".to_string());
+        lines.extend(report.errors.iter().map(|error| format!("  {error}")));
+    }
+
+    lines.join("\n")
+}
+
+#[async_trait]
+impl Notifier for Email {
+    fn name(&self) -> &'static str {
+        "email"
+    }
+
+    fn wants(&self, status: RunStatus) -> bool {
+        status != RunStatus::Success || config::CONFIG.smtp_notify_on_success
+    }
+
+    fn is_alert(&self) -> bool {
+        true
+    }
+
+    async fn notify(&self, notice: &Notice) -> Vec<(String, SendResult)> {
+        vec![(self.host.clone(), self.send(notice).await)]
+    }
+}
+
+impl Email {
+    async fn send(&self, notice: &Notice<'_>) -> SendResult {
+        let from: Mailbox = match config::CONFIG.smtp_from.parse() {
+            Ok(v) => v,
+            Err(err) => return Err(Box::new(err)),
+        };
+
+        let mut builder = Message::builder().from(from).subject(title(notice));
+
+        for recipient in config::CONFIG.smtp_recipients.iter() {
+            builder = match recipient.parse() {
+                Ok(v) => builder.to(v),
+                Err(err) => return Err(Box::new(err)),
+            };
+        }
+
+        let message = match builder
+            .header(ContentType::TEXT_PLAIN)
+            .body(summary(notice.report))
+        {
+            Ok(v) => v,
+            Err(err) => return Err(Box::new(err)),
+        };
+
+        let transport = match AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&self.host) {
+            Ok(v) => v,
+            Err(err) => return Err(Box::new(err)),
+        };
+
+        let mut transport = transport.port(config::CONFIG.smtp_port);
+
+        if let (Some(username), Some(password)) =
+            (&config::CONFIG.smtp_username, &config::CONFIG.smtp_password)
+        {
+            transport = transport.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        match transport.build().send(message).await {
+            Ok(_) => Ok(()),
+            Err(err) => Err(Box::new(err)),
+        }
+    }
+}
+
+fn notifiers() -> Vec<Box<dyn Notifier>> {
+    let mut notifiers: Vec<Box<dyn Notifier>> = vec![
+        Box::new(Webhooks { chat: false }),
+        Box::new(Webhooks { chat: true }),
+    ];
+
+    if let Some(host) = &config::CONFIG.smtp_host {
+        notifiers.push(Box::new(Email { host: host.clone() }));
+    }
+
+    notifiers
+}
+
+/// Outcome of the finished runs before this one
+#[derive(Default, Clone, Copy)]
+struct History {
+    last_failed: Option<bool>,
+    /// Failed runs in a row, the latest first
+    failures: u32,
+}
+
+async fn history(
+    client: &Client,
+    source_id: i16,
+    run_id: Uuid,
+) -> Result<History, Box<dyn std::error::Error + Send>> {
+    let rows = match client
+        .query(
+            "
+            SELECT status = 'success' FROM update_runs
+            WHERE source = $1 AND id <> $2 AND finished_at IS NOT NULL
+            ORDER BY started_at DESC
+            LIMIT 1000;
+            ",
+            &[&source_id, &run_id],
+        )
+        .await
+    {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let succeeded: Vec<bool> = rows.iter().map(|row| row.get(0)).collect();
+
+    Ok(History {
+        last_failed: succeeded.first().map(|v| !v),
+        failures: succeeded.iter().take_while(|v| !**v).count() as u32,
+    })
+}
+
+/// Whether the UTC `hour` is in the `QUIET_HOURS` window, which can wrap
+/// around midnight
+fn is_quiet_hour(quiet_hours: Option<(u32, u32)>, hour: u32) -> bool {
+    let (start, end) = match quiet_hours {
+        Some(v) => v,
+        None => return false,
+    };
+
+    if start <= end {
+        start <= hour && hour < end
+    } else {
+        hour >= start || hour < end
+    }
+}
+
+/// How the alerts of a run go out
+#[derive(Debug, PartialEq)]
+struct Alerting {
+    /// Failed runs in a row, this one included
+    failures: u32,
+    escalated: bool,
+    recovered: bool,
+    /// Why the alerts aren't sent
+    suppressed: Option<&'static str>,
+}
+
+/// Decides on the alerts of a run from the runs before it. An escalation
+/// goes out whatever the settings.
+fn alerting(
+    failed: bool,
+    history: History,
+    escalate_after: u32,
+    change_only: bool,
+    quiet_hour: bool,
+) -> Alerting {
+    let failures = if failed { history.failures + 1 } else { 0 };
+
+    // Repeat at every multiple of the threshold, so a long outage keeps reminding
+    let escalated = failed && escalate_after > 0 && failures % escalate_after == 0;
+
+    let changed = history.last_failed != Some(failed);
+    let recovered = !failed && history.last_failed == Some(true);
+
+    let suppressed = if escalated {
+        None
+    } else if change_only && !changed {
+        Some("no state change")
+    } else if quiet_hour {
+        Some("quiet hours")
+    } else {
+        None
+    };
+
+    Alerting {
+        failures,
+        escalated,
+        recovered,
+        suppressed,
+    }
+}
+
+/// Sends the report to every channel interested in its outcome, returns what
+/// happened to each target.
+pub async fn send(client: &Client, source_id: i16, report: &UpdateReport) -> Vec<Delivery> {
+    let history = match history(client, source_id, report.run_id).await {
+        Ok(v) => v,
+        Err(err) => {
+            log::error!("Can't read the run history: {:?}", err);
+            History::default()
+        }
+    };
+
+    let Alerting {
+        failures,
+        escalated,
+        recovered,
+        suppressed,
+    } = alerting(
+        report.status != RunStatus::Success,
+        history,
+        config::CONFIG.escalate_after_failures,
+        config::CONFIG.notify_on_change_only,
+        is_quiet_hour(config::CONFIG.quiet_hours, Utc::now().hour()),
+    );
+
+    let notice = Notice {
+        report,
+        failures,
+        escalated,
+    };
+
+    let mut deliveries = vec![];
+
+    for notifier in notifiers().iter() {
+        // A recovery is a state change worth an alert even where successes aren't
+        let wanted = notifier.wants(report.status) || (notifier.is_alert() && recovered);
+
+        if !wanted {
+            continue;
+        }
+
+        if let (true, Some(reason)) = (notifier.is_alert(), suppressed) {
+            log::info!("Notification suppressed ({reason}): {}", notifier.name());
+            continue;
+        }
+
+        for (target, result) in notifier.notify(&notice).await {
+            match &result {
+                Ok(_) => log::info!("Notification sent: {}, {target}", notifier.name()),
+                Err(err) => log::info!("Notification failed: {}, {target}: {err}", notifier.name()),
+            };
+
+            deliveries.push(Delivery {
+                channel: notifier.name().to_string(),
+                target,
+                error: result.err().map(|err| err.to_string()),
+            });
+        }
+    }
+
+    deliveries
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::notify::{alerting, is_quiet_hour, Alerting, History};
+
+    #[test]
+    fn test_is_quiet_hour() {
+        let input = [
+            (Some((1, 6)), 3),
+            (Some((1, 6)), 6),
+            (Some((1, 6)), 0),
+            (None, 3),
+        ];
+        let expected_result = vec![true, false, false, false];
+
+        let result: Vec<bool> = input
+            .iter()
+            .map(|(quiet_hours, hour)| is_quiet_hour(*quiet_hours, *hour))
+            .collect();
+
+        assert_eq!(result, expected_result);
+    }
+
+    #[test]
+    fn test_is_quiet_hour_over_midnight() {
+        let input = [22, 23, 0, 6, 7, 12];
+        let expected_result = vec![true, true, true, true, false, false];
+
+        let result: Vec<bool> = input
+            .iter()
+            .map(|hour| is_quiet_hour(Some((22, 7)), *hour))
+            .collect();
+
+        assert_eq!(result, expected_result);
+    }
+
+    #[test]
+    fn test_alerting_quiet_hours() {
+        let input = History {
+            last_failed: Some(false),
+            failures: 0,
+        };
+        let expected_result = Alerting {
+            failures: 1,
+            escalated: false,
+            recovered: false,
+            suppressed: Some("quiet hours"),
+        };
+
+        let result = alerting(true, input, 0, false, true);
+
+        assert_eq!(result, expected_result);
+    }
+
+    #[test]
+    fn test_alerting_no_state_change() {
+        let input = History {
+            last_failed: Some(true),
+            failures: 1,
+        };
+        let expected_result = Alerting {
+            failures: 2,
+            escalated: false,
+            recovered: false,
+            suppressed: Some("no state change"),
+        };
+
+        let result = alerting(true, input, 3, true, false);
+
+        assert_eq!(result, expected_result);
+    }
+
+    #[test]
+    fn test_alerting_escalation_ignores_suppression() {
+        let input = History {
+            last_failed: Some(true),
+            failures: 5,
+        };
+        let expected_result = Alerting {
+            failures: 6,
+            escalated: true,
+            recovered: false,
+            suppressed: None,
+        };
+
+        let result = alerting(true, input, 3, true, true);
+
+        assert_eq!(result, expected_result);
+    }
+
+    #[test]
+    fn test_alerting_recovery() {
+        let input = History {
+            last_failed: Some(true),
+            failures: 4,
+        };
+        let expected_result = Alerting {
+            failures: 0,
+            escalated: false,
+            recovered: true,
+            suppressed: None,
+        };
+
+        let result = alerting(false, input, 3, true, false);
+
+        assert_eq!(result, expected_result);
+    }
+
+    #[test]
+    fn test_alerting_first_run() {
+        let input = History::default();
+        let expected_result = Alerting {
+            failures: 0,
+            escalated: false,
+            recovered: false,
+            suppressed: None,
+        };
+
+        let result = alerting(false, input, 3, true, false);
+
+        assert_eq!(result, expected_result);
+    }
+}
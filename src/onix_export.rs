@@ -0,0 +1,252 @@
+use chrono::{NaiveDate, Utc};
+use deadpool_postgres::Pool;
+use futures::{pin_mut, TryStreamExt};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio_postgres::types::ToSql;
+use tokio_postgres::Row;
+use tracing::log;
+
+use crate::config;
+use crate::db;
+use crate::opds::{download_url, escape};
+
+const QUERY: &str = "
+    SELECT
+        cast(books.id as bigint), cast(books.remote_id as bigint), books.title, books.lang,
+        books.file_type, cast(books.pages as bigint), cast(books.year as bigint),
+        (
+            SELECT string_agg(concat_ws(' ', authors.last_name, authors.first_name, authors.middle_name), '|')
+            FROM book_authors JOIN authors ON authors.id = book_authors.author
+            WHERE book_authors.book = books.id
+        ),
+        (
+            SELECT string_agg(concat_ws(' ', authors.last_name, authors.first_name, authors.middle_name), '|' ORDER BY translations.position)
+            FROM translations JOIN authors ON authors.id = translations.author
+            WHERE translations.book = books.id
+        ),
+        (
+            SELECT string_agg(concat_ws('|', genres.code, genres.description), '||')
+            FROM book_genres JOIN genres ON genres.id = book_genres.genre
+            WHERE book_genres.book = books.id
+        ),
+        sequence.name, cast(sequence.position as bigint), annotation.text
+    FROM books
+    LEFT JOIN LATERAL (
+        SELECT sequences.name, book_sequences.position FROM book_sequences
+        JOIN sequences ON sequences.id = book_sequences.sequence
+        WHERE book_sequences.book = books.id
+        ORDER BY book_sequences.position
+        LIMIT 1
+    ) AS sequence ON true
+    LEFT JOIN book_annotations AS annotation ON annotation.book = books.id
+    WHERE NOT books.is_deleted
+        AND (cardinality($1::text[]) = 0 OR books.lang = ANY($1))
+        AND ($2::date IS NULL OR books.uploaded >= $2)
+        AND ($3::date IS NULL OR books.uploaded <= $3)
+    ORDER BY books.id;
+";
+
+/// Names joined with `|` by the query
+fn names(value: Option<String>) -> Vec<String> {
+    value
+        .unwrap_or_default()
+        .split('|')
+        .filter(|name| !name.is_empty())
+        .map(|name| name.to_string())
+        .collect()
+}
+
+fn contributors(authors: &[String], translators: &[String]) -> String {
+    // A01: by (author), B06: translated by
+    authors
+        .iter()
+        .map(|name| ("A01", name))
+        .chain(translators.iter().map(|name| ("B06", name)))
+        .enumerate()
+        .map(|(index, (role, name))| {
+            format!(
+                "<Contributor><SequenceNumber>{}</SequenceNumber><ContributorRole>{role}</ContributorRole><PersonName>{}</PersonName></Contributor>\n",
+                index + 1,
+                escape(name)
+            )
+        })
+        .collect()
+}
+
+fn product(row: &Row) -> String {
+    let id: i64 = row.get(0);
+    let remote_id: i64 = row.get(1);
+    let title: String = row.get(2);
+    let lang: String = row.get(3);
+    let file_type: String = row.get(4);
+    let pages: Option<i64> = row.get(5);
+    let year: Option<i64> = row.get(6);
+    let authors = names(row.get(7));
+    let translators = names(row.get(8));
+    let genres: Option<String> = row.get(9);
+    let sequence: Option<String> = row.get(10);
+    let position: Option<i64> = row.get(11);
+    let annotation: Option<String> = row.get(12);
+
+    let collection = match sequence {
+        Some(name) => format!(
+            "<Collection><CollectionType>10</CollectionType><TitleDetail><TitleType>01</TitleType><TitleElement><TitleElementLevel>02</TitleElementLevel>{}<TitleText>{}</TitleText></TitleElement></TitleDetail></Collection>\n",
+            match position {
+                Some(v) if v > 0 => format!("<PartNumber>{v}</PartNumber>"),
+                _ => String::new(),
+            },
+            escape(&name)
+        ),
+        None => String::new(),
+    };
+
+    let extent = match pages {
+        Some(v) if v > 0 => format!(
+            "<Extent><ExtentType>08</ExtentType><ExtentValue>{v}</ExtentValue><ExtentUnit>03</ExtentUnit></Extent>\n"
+        ),
+        _ => String::new(),
+    };
+
+    let subjects: String = genres
+        .unwrap_or_default()
+        .split("||")
+        .filter_map(|genre| genre.split_once('|'))
+        .map(|(code, description)| {
+            format!(
+                "<Subject><SubjectSchemeIdentifier>24</SubjectSchemeIdentifier><SubjectSchemeName>fb2</SubjectSchemeName><SubjectCode>{}</SubjectCode><SubjectHeadingText>{}</SubjectHeadingText></Subject>\n",
+                escape(code),
+                escape(description)
+            )
+        })
+        .collect();
+
+    let collateral = match annotation {
+        Some(text) if !text.trim().is_empty() => format!(
+            "<CollateralDetail><TextContent><TextType>03</TextType><ContentAudience>00</ContentAudience><Text>{}</Text></TextContent></CollateralDetail>\n",
+            escape(text.trim())
+        ),
+        _ => String::new(),
+    };
+
+    let publishing = match year {
+        Some(v) if v > 0 => format!(
+            "<PublishingDetail><PublishingDate><PublishingDateRole>01</PublishingDateRole><Date dateformat=\"05\">{v}</Date></PublishingDate></PublishingDetail>\n"
+        ),
+        _ => String::new(),
+    };
+
+    format!(
+        "<Product>\n\
+<RecordReference>library_updater:book:{id}</RecordReference>\n\
+<NotificationType>03</NotificationType>\n\
+<ProductIdentifier><ProductIDType>01</ProductIDType><IDTypeName>remote_id</IDTypeName><IDValue>{remote_id}</IDValue></ProductIdentifier>\n\
+<DescriptiveDetail>\n\
+<ProductComposition>00</ProductComposition>\n\
+<ProductForm>ED</ProductForm>\n\
+<ProductFormDescription>{}</ProductFormDescription>\n\
+{collection}\
+<TitleDetail><TitleType>01</TitleType><TitleElement><TitleElementLevel>01</TitleElementLevel><TitleText>{}</TitleText></TitleElement></TitleDetail>\n\
+{}\
+<Language><LanguageRole>01</LanguageRole><LanguageCode>{}</LanguageCode></Language>\n\
+{extent}\
+{subjects}\
+</DescriptiveDetail>\n\
+{collateral}\
+{publishing}\
+<ProductSupply><SupplyDetail><Supplier><SupplierRole>09</SupplierRole><SupplierName>{}</SupplierName><Website><WebsiteLink>{}</WebsiteLink></Website></Supplier><ProductAvailability>20</ProductAvailability></SupplyDetail></ProductSupply>\n\
+</Product>\n",
+        escape(&file_type),
+        escape(&title),
+        contributors(&authors, &translators),
+        escape(&lang),
+        escape(&config::CONFIG.fl_base_url),
+        escape(&download_url(remote_id, &file_type)),
+    )
+}
+
+/// Writes the books that aren't deleted as an ONIX 3.0 style message to
+/// `ONIX_EXPORT_PATH`, for partners that take standard book metadata. Only the
+/// languages of `ONIX_EXPORT_LANGS` and the books uploaded from
+/// `ONIX_EXPORT_UPLOADED_FROM` to `ONIX_EXPORT_UPLOADED_TO` when they're set.
+pub async fn export(pool: &Pool) -> Result<(), Box<dyn std::error::Error + Send>> {
+    let path = match &config::CONFIG.onix_export_path {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+
+    log::info!("Export ONIX feed to {path}...");
+
+    let tmp_path = format!("{path}.tmp");
+
+    let file = match tokio::fs::File::create(&tmp_path).await {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+    let mut file = BufWriter::new(file);
+
+    let client = match db::checkout(pool).await {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let from: Option<NaiveDate> = config::CONFIG.onix_export_uploaded_from;
+    let to: Option<NaiveDate> = config::CONFIG.onix_export_uploaded_to;
+    let params: [&(dyn ToSql + Sync); 3] = [&config::CONFIG.onix_export_langs, &from, &to];
+
+    let stream = match client.query_raw(QUERY, params).await {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+    pin_mut!(stream);
+
+    let header = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+<ONIXMessage release=\"3.0\" xmlns=\"http://ns.editeur.org/onix/3.0/reference\">\n\
+<Header><Sender><SenderName>library_updater</SenderName></Sender><SentDateTime>{}</SentDateTime></Header>\n",
+        Utc::now().format("%Y%m%dT%H%MZ")
+    );
+
+    match file.write_all(header.as_bytes()).await {
+        Ok(_) => (),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let mut total = 0;
+
+    loop {
+        let row = match stream.try_next().await {
+            Ok(v) => v,
+            Err(err) => return Err(Box::new(err)),
+        };
+
+        let row = match row {
+            Some(v) => v,
+            None => break,
+        };
+
+        match file.write_all(product(&row).as_bytes()).await {
+            Ok(_) => (),
+            Err(err) => return Err(Box::new(err)),
+        };
+        total += 1;
+    }
+
+    match file.write_all(b"</ONIXMessage>\n").await {
+        Ok(_) => (),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    match file.flush().await {
+        Ok(_) => (),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    match tokio::fs::rename(&tmp_path, path).await {
+        Ok(_) => (),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    log::info!("Exported {total} books to the ONIX feed");
+
+    Ok(())
+}
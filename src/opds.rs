@@ -0,0 +1,450 @@
+use axum::{
+    extract::{Path, Query},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use chrono::Utc;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use serde::Deserialize;
+use tokio_postgres::Row;
+use tracing::log;
+
+use crate::utils::page_offset;
+use crate::{config, db};
+
+const PREFIX: &str = "/opds";
+const PAGE_SIZE: i64 = 100;
+
+const NAVIGATION: &str = "application/atom+xml;profile=opds-catalog;kind=navigation";
+const ACQUISITION: &str = "application/atom+xml;profile=opds-catalog;kind=acquisition";
+
+#[derive(Deserialize)]
+pub struct PageQuery {
+    page: Option<i64>,
+}
+
+/// The chars a path segment can't hold as they are, as in the URL standard
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// The letter as a segment of a path, e.g. `/` or `?` of a name can't end
+/// the path there
+fn letter_segment(letter: &str) -> String {
+    utf8_percent_encode(letter, PATH_SEGMENT).to_string()
+}
+
+pub(crate) fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn mime_type(file_type: &str) -> &'static str {
+    match file_type {
+        "fb2" => "application/x-fictionbook+xml",
+        "epub" => "application/epub+zip",
+        "pdf" => "application/pdf",
+        "djvu" => "image/vnd.djvu",
+        "mobi" => "application/x-mobipocket-ebook",
+        "txt" => "text/plain",
+        "rtf" => "application/rtf",
+        "html" => "text/html",
+        _ => "application/octet-stream",
+    }
+}
+
+pub(crate) fn download_url(remote_id: i64, file_type: &str) -> String {
+    config::CONFIG
+        .opds_download_url
+        .replace("{base_url}", &config::CONFIG.fl_base_url)
+        .replace("{remote_id}", &remote_id.to_string())
+        .replace("{file_type}", file_type)
+}
+
+/// Links are absolute, so they have to carry the `BASE_PATH` the router is nested under
+fn link(href: &str) -> String {
+    format!("{}{href}", config::CONFIG.base_path)
+}
+
+fn feed(id: &str, title: &str, self_href: &str, kind: &str, entries: &[String]) -> Response {
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+<feed xmlns=\"http://www.w3.org/2005/Atom\" xmlns:dc=\"http://purl.org/dc/terms/\" xmlns:opds=\"http://opds-spec.org/2010/catalog\">\n\
+<id>urn:library_updater:{}</id>\n\
+<title>{}</title>\n\
+<updated>{}</updated>\n\
+<link rel=\"self\" href=\"{}\" type=\"{kind}\"/>\n\
+<link rel=\"start\" href=\"{}\" type=\"{NAVIGATION}\"/>\n\
+{}\
+</feed>\n",
+        escape(id),
+        escape(title),
+        Utc::now().format("%Y-%m-%dT%H:%M:%SZ"),
+        escape(&link(self_href)),
+        escape(&link(PREFIX)),
+        entries.concat(),
+    );
+
+    ([(header::CONTENT_TYPE, kind.to_string())], body).into_response()
+}
+
+fn navigation_entry(id: &str, title: &str, href: &str, kind: &str) -> String {
+    format!(
+        "<entry>\n\
+<title>{}</title>\n\
+<id>urn:library_updater:{}</id>\n\
+<updated>{}</updated>\n\
+<link rel=\"subsection\" href=\"{}\" type=\"{kind}\"/>\n\
+</entry>\n",
+        escape(title),
+        escape(id),
+        Utc::now().format("%Y-%m-%dT%H:%M:%SZ"),
+        escape(&link(href)),
+    )
+}
+
+fn book_entry(row: &Row) -> String {
+    let id: i64 = row.get(0);
+    let remote_id: i64 = row.get(1);
+    let title: String = row.get(2);
+    let lang: String = row.get(3);
+    let file_type: String = row.get(4);
+    let uploaded: String = row.get(5);
+    let authors: Option<String> = row.get(6);
+
+    let authors: String = authors
+        .unwrap_or_default()
+        .split('|')
+        .filter(|name| !name.is_empty())
+        .map(|name| format!("<author><name>{}</name></author>\n", escape(name)))
+        .collect();
+
+    format!(
+        "<entry>\n\
+<title>{}</title>\n\
+<id>urn:library_updater:book:{id}</id>\n\
+<updated>{}T00:00:00Z</updated>\n\
+{authors}\
+<dc:language>{}</dc:language>\n\
+<link rel=\"http://opds-spec.org/acquisition\" href=\"{}\" type=\"{}\"/>\n\
+</entry>\n",
+        escape(&title),
+        escape(&uploaded),
+        escape(&lang),
+        escape(&download_url(remote_id, &file_type)),
+        mime_type(&file_type),
+    )
+}
+
+const BOOK_COLUMNS: &str = "
+    cast(books.id as bigint), cast(books.remote_id as bigint), books.title, books.lang,
+    books.file_type, cast(books.uploaded as text),
+    (
+        SELECT string_agg(concat_ws(' ', authors.last_name, authors.first_name, authors.middle_name), '|')
+        FROM book_authors JOIN authors ON authors.id = book_authors.author
+        WHERE book_authors.book = books.id
+    )
+";
+
+async fn query(
+    sql: &str,
+    params: &[&(dyn tokio_postgres::types::ToSql + Sync)],
+) -> Option<Vec<Row>> {
+    let client = match db::checkout(&db::READ_POOL).await {
+        Ok(v) => v,
+        Err(err) => {
+            log::error!("OPDS: can't get connection: {:?}", err);
+            return None;
+        }
+    };
+
+    match client.query(sql, params).await {
+        Ok(v) => Some(v),
+        Err(err) => {
+            log::error!("OPDS: query failed: {:?}", err);
+            None
+        }
+    }
+}
+
+fn server_error() -> Response {
+    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+}
+
+fn with_next_page(
+    mut entries: Vec<String>,
+    href: &str,
+    page: &PageQuery,
+    rows: usize,
+) -> Vec<String> {
+    if rows as i64 == PAGE_SIZE {
+        entries.push(format!(
+            "<link rel=\"next\" href=\"{}?page={}\" type=\"{ACQUISITION}\"/>\n",
+            escape(&link(href)),
+            page.page.unwrap_or(0).max(0).saturating_add(1)
+        ));
+    }
+
+    entries
+}
+
+async fn root() -> Response {
+    let entries = vec![
+        navigation_entry(
+            "new",
+            "Новые поступления",
+            &format!("{PREFIX}/new"),
+            ACQUISITION,
+        ),
+        navigation_entry(
+            "authors",
+            "По авторам",
+            &format!("{PREFIX}/authors"),
+            NAVIGATION,
+        ),
+        navigation_entry(
+            "sequences",
+            "По сериям",
+            &format!("{PREFIX}/sequences"),
+            NAVIGATION,
+        ),
+    ];
+
+    feed("root", "Библиотека", PREFIX, NAVIGATION, &entries)
+}
+
+async fn new_arrivals() -> Response {
+    let sql = format!(
+        "SELECT {BOOK_COLUMNS} FROM books WHERE NOT books.is_deleted ORDER BY books.uploaded DESC, books.id DESC LIMIT $1;"
+    );
+
+    let rows = match query(&sql, &[&config::CONFIG.opds_new_arrivals_limit]).await {
+        Some(v) => v,
+        None => return server_error(),
+    };
+
+    let entries: Vec<String> = rows.iter().map(book_entry).collect();
+
+    feed(
+        "new",
+        "Новые поступления",
+        &format!("{PREFIX}/new"),
+        ACQUISITION,
+        &entries,
+    )
+}
+
+async fn letters(table: &str, column: &str, path: &str, title: &str) -> Response {
+    let sql = format!(
+        "SELECT DISTINCT upper(left({column}, 1)) AS letter FROM {table} WHERE {column} <> '' ORDER BY letter;"
+    );
+
+    let rows = match query(&sql, &[]).await {
+        Some(v) => v,
+        None => return server_error(),
+    };
+
+    let entries: Vec<String> = rows
+        .iter()
+        .map(|row| {
+            let letter: String = row.get(0);
+            navigation_entry(
+                &format!("{path}:{letter}"),
+                &letter,
+                &format!("{PREFIX}/{path}/letter/{}", letter_segment(&letter)),
+                NAVIGATION,
+            )
+        })
+        .collect();
+
+    feed(
+        path,
+        title,
+        &format!("{PREFIX}/{path}"),
+        NAVIGATION,
+        &entries,
+    )
+}
+
+async fn author_letters() -> Response {
+    letters("authors", "last_name", "authors", "По авторам").await
+}
+
+async fn sequence_letters() -> Response {
+    letters("sequences", "name", "sequences", "По сериям").await
+}
+
+async fn authors_by_letter(Path(letter): Path<String>, Query(page): Query<PageQuery>) -> Response {
+    let rows = match query(
+        "
+        SELECT cast(id as bigint), concat_ws(' ', last_name, first_name, middle_name) AS name
+        FROM authors WHERE upper(left(last_name, 1)) = $1
+        ORDER BY name, id LIMIT $2 OFFSET $3;
+        ",
+        &[&letter, &PAGE_SIZE, &page_offset(page.page, PAGE_SIZE)],
+    )
+    .await
+    {
+        Some(v) => v,
+        None => return server_error(),
+    };
+
+    let entries: Vec<String> = rows
+        .iter()
+        .map(|row| {
+            let id: i64 = row.get(0);
+            let name: String = row.get(1);
+            navigation_entry(
+                &format!("author:{id}"),
+                &name,
+                &format!("{PREFIX}/authors/{id}"),
+                ACQUISITION,
+            )
+        })
+        .collect();
+
+    let href = format!("{PREFIX}/authors/letter/{}", letter_segment(&letter));
+    let entries = with_next_page(entries, &href, &page, rows.len());
+
+    feed(
+        &format!("authors:{letter}"),
+        &letter,
+        &href,
+        NAVIGATION,
+        &entries,
+    )
+}
+
+async fn sequences_by_letter(
+    Path(letter): Path<String>,
+    Query(page): Query<PageQuery>,
+) -> Response {
+    let rows = match query(
+        "
+        SELECT cast(id as bigint), name FROM sequences WHERE upper(left(name, 1)) = $1
+        ORDER BY name, id LIMIT $2 OFFSET $3;
+        ",
+        &[&letter, &PAGE_SIZE, &page_offset(page.page, PAGE_SIZE)],
+    )
+    .await
+    {
+        Some(v) => v,
+        None => return server_error(),
+    };
+
+    let entries: Vec<String> = rows
+        .iter()
+        .map(|row| {
+            let id: i64 = row.get(0);
+            let name: String = row.get(1);
+            navigation_entry(
+                &format!("sequence:{id}"),
+                &name,
+                &format!("{PREFIX}/sequences/{id}"),
+                ACQUISITION,
+            )
+        })
+        .collect();
+
+    let href = format!("{PREFIX}/sequences/letter/{}", letter_segment(&letter));
+    let entries = with_next_page(entries, &href, &page, rows.len());
+
+    feed(
+        &format!("sequences:{letter}"),
+        &letter,
+        &href,
+        NAVIGATION,
+        &entries,
+    )
+}
+
+async fn author_books(Path(id): Path<i64>, Query(page): Query<PageQuery>) -> Response {
+    let sql = format!(
+        "
+        SELECT {BOOK_COLUMNS} FROM books
+        JOIN book_authors ON book_authors.book = books.id
+        WHERE book_authors.author = cast($1 as bigint) AND NOT books.is_deleted
+        ORDER BY books.title, books.id LIMIT $2 OFFSET $3;
+        "
+    );
+
+    let rows = match query(&sql, &[&id, &PAGE_SIZE, &page_offset(page.page, PAGE_SIZE)]).await {
+        Some(v) => v,
+        None => return server_error(),
+    };
+
+    let entries: Vec<String> = rows.iter().map(book_entry).collect();
+
+    let href = format!("{PREFIX}/authors/{id}");
+    let entries = with_next_page(entries, &href, &page, rows.len());
+
+    feed(
+        &format!("author:{id}"),
+        "Книги автора",
+        &href,
+        ACQUISITION,
+        &entries,
+    )
+}
+
+async fn sequence_books(Path(id): Path<i64>, Query(page): Query<PageQuery>) -> Response {
+    let sql = format!(
+        "
+        SELECT {BOOK_COLUMNS} FROM books
+        JOIN book_sequences ON book_sequences.book = books.id
+        WHERE book_sequences.sequence = cast($1 as bigint) AND NOT books.is_deleted
+        ORDER BY book_sequences.position, books.id LIMIT $2 OFFSET $3;
+        "
+    );
+
+    let rows = match query(&sql, &[&id, &PAGE_SIZE, &page_offset(page.page, PAGE_SIZE)]).await {
+        Some(v) => v,
+        None => return server_error(),
+    };
+
+    let entries: Vec<String> = rows.iter().map(book_entry).collect();
+
+    let href = format!("{PREFIX}/sequences/{id}");
+    let entries = with_next_page(entries, &href, &page, rows.len());
+
+    feed(
+        &format!("sequence:{id}"),
+        "Книги серии",
+        &href,
+        ACQUISITION,
+        &entries,
+    )
+}
+
+pub fn router() -> Router {
+    Router::new()
+        .route(PREFIX, get(root))
+        .route(&format!("{PREFIX}/new"), get(new_arrivals))
+        .route(&format!("{PREFIX}/authors"), get(author_letters))
+        .route(
+            &format!("{PREFIX}/authors/letter/:letter"),
+            get(authors_by_letter),
+        )
+        .route(&format!("{PREFIX}/authors/:id"), get(author_books))
+        .route(&format!("{PREFIX}/sequences"), get(sequence_letters))
+        .route(
+            &format!("{PREFIX}/sequences/letter/:letter"),
+            get(sequences_by_letter),
+        )
+        .route(&format!("{PREFIX}/sequences/:id"), get(sequence_books))
+}
@@ -0,0 +1,254 @@
+//! Row transformers in WebAssembly, run over the parsed rows of an entity
+//! before they're written. `ROW_PLUGINS` maps entities to `.wasm` files, applied
+//! in order.
+//!
+//! A module takes no imports and exports:
+//!
+//! - `memory`
+//! - `alloc(len: i32) -> i32`, a buffer of `len` bytes for the input
+//! - `transform(ptr: i32, len: i32) -> i64`, called with the input in the
+//!   buffer. Returns `(ptr << 32) | len` of the output in `memory`.
+//!
+//! The input is the UTF-8 JSON `{"entity": "book", "file": "lib.libbook",
+//! "row": {...}}`, the row as it's serialized. The output is the row to write,
+//! with the same fields, or `null` to reject it. A trap, a run longer than
+//! `ROW_PLUGIN_TIMEOUT_MS` or an output that isn't a row rejects the row too,
+//! `POST /rejects/reprocess` brings them back after a fix.
+//!
+//! A chunk of rows shares an instance, its memory is dropped after the chunk:
+//! buffers don't have to be freed.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Once};
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tracing::log;
+use wasmtime::{Engine, Instance, Memory, Module, Store, TypedFunc};
+
+use crate::config;
+use crate::updater::{self, ParsedRow};
+
+/// How often the engine's epoch advances, the timeouts are counted in these
+const TICK: Duration = Duration::from_millis(5);
+
+lazy_static! {
+    static ref ENGINE: Engine = {
+        let mut config = wasmtime::Config::new();
+        config.epoch_interruption(true);
+
+        match Engine::new(&config) {
+            Ok(v) => v,
+            Err(err) => panic!("{:?}", err),
+        }
+    };
+
+    /// Compiled once, a module that doesn't compile stops the start
+    static ref PLUGINS: HashMap<String, Arc<RowPlugins>> = config::CONFIG
+        .row_plugins
+        .iter()
+        .map(|(entity, paths)| {
+            let modules = paths
+                .iter()
+                .map(|path| match Module::from_file(&ENGINE, path) {
+                    Ok(module) => (path.clone(), module),
+                    Err(err) => panic!("Can't load the row plugin {path}: {:?}", err),
+                })
+                .collect();
+
+            let plugins = RowPlugins {
+                entity: entity.clone(),
+                modules,
+            };
+
+            (entity.clone(), Arc::new(plugins))
+        })
+        .collect();
+}
+
+static TICKER: Once = Once::new();
+
+/// The plugins of an entity
+pub(crate) struct RowPlugins {
+    entity: String,
+    modules: Vec<(String, Module)>,
+}
+
+/// Compiles the modules of `ROW_PLUGINS`
+pub fn load() {
+    lazy_static::initialize(&PLUGINS);
+}
+
+/// The plugins of the entity of a table
+pub(crate) fn for_file(file_name: &str) -> Option<Arc<RowPlugins>> {
+    let table = updater::find_table(file_name)?;
+    let plugins = PLUGINS.get(&table.entity).cloned();
+
+    if plugins.is_some() {
+        TICKER.call_once(|| {
+            std::thread::spawn(|| loop {
+                std::thread::sleep(TICK);
+                ENGINE.increment_epoch();
+            });
+        });
+    }
+
+    plugins
+}
+
+struct Plugin {
+    path: String,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    transform: TypedFunc<(i32, i32), i64>,
+}
+
+/// Instances of the plugins for a chunk of rows
+pub(crate) struct Session {
+    entity: String,
+    file_name: String,
+    store: Store<()>,
+    plugins: Result<Vec<Plugin>, String>,
+}
+
+fn instantiate(store: &mut Store<()>, path: &str, module: &Module) -> Result<Plugin, String> {
+    let instance = match Instance::new(&mut *store, module, &[]) {
+        Ok(v) => v,
+        Err(err) => return Err(format!("{path}: {err}")),
+    };
+
+    let memory = match instance.get_memory(&mut *store, "memory") {
+        Some(v) => v,
+        None => return Err(format!("{path}: no memory export")),
+    };
+
+    let alloc = match instance.get_typed_func(&mut *store, "alloc") {
+        Ok(v) => v,
+        Err(err) => return Err(format!("{path}: {err}")),
+    };
+
+    let transform = match instance.get_typed_func(&mut *store, "transform") {
+        Ok(v) => v,
+        Err(err) => return Err(format!("{path}: {err}")),
+    };
+
+    Ok(Plugin {
+        path: path.to_string(),
+        memory,
+        alloc,
+        transform,
+    })
+}
+
+impl RowPlugins {
+    pub(crate) fn session(&self, file_name: &str) -> Session {
+        let mut store = Store::new(&ENGINE, ());
+        store.epoch_deadline_trap();
+
+        let plugins = self
+            .modules
+            .iter()
+            .map(|(path, module)| instantiate(&mut store, path, module))
+            .collect();
+
+        Session {
+            entity: self.entity.clone(),
+            file_name: file_name.to_string(),
+            store,
+            plugins,
+        }
+    }
+}
+
+impl Plugin {
+    /// The output JSON for the input one
+    fn call(&self, store: &mut Store<()>, input: &[u8]) -> Result<Vec<u8>, String> {
+        let ticks = config::CONFIG.row_plugin_timeout_ms / TICK.as_millis() as u64 + 1;
+        store.set_epoch_deadline(ticks);
+
+        let len = match i32::try_from(input.len()) {
+            Ok(v) => v,
+            Err(_) => return Err(format!("{}: the row is too big", self.path)),
+        };
+
+        let ptr = match self.alloc.call(&mut *store, len) {
+            Ok(v) => v,
+            Err(err) => return Err(format!("{}: alloc: {err}", self.path)),
+        };
+
+        if let Err(err) = self.memory.write(&mut *store, ptr as u32 as usize, input) {
+            return Err(format!("{}: {err}", self.path));
+        }
+
+        let packed = match self.transform.call(&mut *store, (ptr, len)) {
+            Ok(v) => v as u64,
+            Err(err) => return Err(format!("{}: transform: {err}", self.path)),
+        };
+
+        let (ptr, len) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+        let mut output = vec![0; len];
+
+        match self.memory.read(&*store, ptr, &mut output) {
+            Ok(_) => Ok(output),
+            Err(err) => Err(format!("{}: {err}", self.path)),
+        }
+    }
+}
+
+impl Session {
+    /// The row as the plugins leave it, rejected if one of them fails
+    fn transform<T>(&mut self, row: T) -> Result<T, String>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        let plugins = match &self.plugins {
+            Ok(v) => v,
+            Err(err) => return Err(err.clone()),
+        };
+
+        let mut row = match serde_json::to_value(&row) {
+            Ok(v) => v,
+            Err(err) => return Err(err.to_string()),
+        };
+
+        for plugin in plugins.iter() {
+            let input = serde_json::json!({
+                "entity": self.entity,
+                "file": self.file_name,
+                "row": row,
+            });
+
+            let output = plugin.call(&mut self.store, input.to_string().as_bytes())?;
+
+            row = match serde_json::from_slice(&output) {
+                Ok(serde_json::Value::Null) => {
+                    return Err(format!("{}: rejected by the plugin", plugin.path))
+                }
+                Ok(v) => v,
+                Err(err) => return Err(format!("{}: {err}", plugin.path)),
+            };
+        }
+
+        match serde_json::from_value(row) {
+            Ok(v) => Ok(v),
+            Err(err) => Err(format!("row plugins: {err}")),
+        }
+    }
+
+    pub(crate) fn apply<T>(&mut self, parsed: ParsedRow<T>) -> ParsedRow<T>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        match parsed {
+            ParsedRow::Row(row, issue) => match self.transform(row) {
+                Ok(row) => ParsedRow::Row(row, issue),
+                Err(err) => {
+                    log::debug!("{}: {err}", self.file_name);
+                    ParsedRow::Invalid(err)
+                }
+            },
+            parsed => parsed,
+        }
+    }
+}
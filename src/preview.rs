@@ -0,0 +1,281 @@
+//! `POST /update/preview`: what an import of the current dumps would do to the
+//! tables, without writing. The dumps are parsed as in a run, field scripts and
+//! row plugins included, and compared with the rows in the database by their
+//! keys and the hash of their values.
+//!
+//! The comparison runs in a transaction that's rolled back, only temporary
+//! tables are filled. The downloaded dumps are kept: a run soon after imports the same
+//! ones. References resolve to the current rows, rows pointing at rows that
+//! are new in the dump count as skipped. The clean-up after a table (e.g. books
+//! of other languages marked deleted) isn't previewed.
+
+use std::{fmt::Debug, future::Future, pin::Pin, sync::Arc};
+
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::log;
+
+use crate::catalogs;
+use crate::config::Source;
+use crate::db;
+use crate::dump_row::{Columns, RowOrigin};
+use crate::types::{FromVecExpression, Update};
+use crate::updater::{
+    download_file, parse_options, parse_row, row_transform, DumpStatements, ParsedRow,
+};
+use crate::upsert::CopyRow;
+
+/// Rows compared per statement
+const BATCH_SIZE: usize = 10_000;
+
+#[derive(Serialize, Default)]
+pub struct PreviewSamples {
+    pub insert: Vec<serde_json::Value>,
+    pub update: Vec<serde_json::Value>,
+    pub delete: Vec<serde_json::Value>,
+}
+
+#[derive(Serialize, Default)]
+pub struct TablePreview {
+    pub file_name: String,
+    pub would_insert: u64,
+    pub would_update: u64,
+    /// Rows of the source the dump doesn't have, a full sync deletes or marks
+    /// them where the entity does. `None` for tables that can't tell.
+    pub would_delete: Option<u64>,
+    pub unchanged: u64,
+    /// Rows that wouldn't be written, e.g. a referenced row is missing
+    pub skipped: u64,
+    /// Rows that don't parse or that the scripts or plugins reject
+    pub rejected: u64,
+    pub samples: PreviewSamples,
+    /// Why the table couldn't be previewed, the counts are empty then
+    pub error: Option<String>,
+}
+
+impl TablePreview {
+    pub fn failed(file_name: &str, error: String) -> TablePreview {
+        TablePreview {
+            file_name: file_name.to_string(),
+            error: Some(error),
+            ..TablePreview::default()
+        }
+    }
+}
+
+pub(crate) type PreviewFuture =
+    Pin<Box<dyn Future<Output = Result<TablePreview, Box<dyn std::error::Error + Send>>> + Send>>;
+
+pub(crate) type Preview = fn(&'static Source, i16, &'static str, usize) -> PreviewFuture;
+
+pub(crate) fn preview<T>(
+    source: &'static Source,
+    source_id: i16,
+    file_name: &'static str,
+    samples: usize,
+) -> PreviewFuture
+where
+    T: Debug + FromVecExpression<T> + Update + Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    Box::pin(preview_table::<T>(source, source_id, file_name, samples))
+}
+
+/// Parses statements of the dump on the blocking pool. Returns the parameters
+/// of the rows that would be written, how many wouldn't and how many are
+/// rejected.
+async fn parse<T>(
+    file_name: &'static str,
+    statements: Vec<(RowOrigin, String)>,
+    columns: Arc<Columns>,
+) -> Result<(Vec<CopyRow>, u64, u64), Box<dyn std::error::Error + Send>>
+where
+    T: FromVecExpression<T> + Update + Serialize + DeserializeOwned + Send + 'static,
+{
+    let parsed = tokio::task::spawn_blocking(move || {
+        let parse_options = parse_options();
+        let mut transform = row_transform::<T>(file_name);
+
+        let mut rows = Vec::with_capacity(statements.len());
+        let mut skipped = 0;
+        let mut rejected = 0;
+
+        for (_, statement) in statements.iter() {
+            let mut parsed = parse_row::<T>(statement, &columns, &parse_options);
+            if let Some(transform) = transform.as_mut() {
+                parsed = transform(parsed);
+            }
+
+            match parsed {
+                ParsedRow::Row(value, _) => match value.preview_row() {
+                    Some(row) => rows.push(row),
+                    None => skipped += 1,
+                },
+                ParsedRow::Unparsed(_) | ParsedRow::Invalid(_) => rejected += 1,
+            };
+        }
+
+        (rows, skipped, rejected)
+    })
+    .await;
+
+    match parsed {
+        Ok(v) => Ok(v),
+        Err(err) => Err(Box::new(err)),
+    }
+}
+
+async fn preview_table<T>(
+    source: &'static Source,
+    source_id: i16,
+    file_name: &'static str,
+    samples: usize,
+) -> Result<TablePreview, Box<dyn std::error::Error + Send>>
+where
+    T: Debug + FromVecExpression<T> + Update + Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    let spec = match T::preview_spec() {
+        Some(v) => v,
+        None => {
+            return Err(Box::new(std::io::Error::other(format!(
+                "{file_name} can't be previewed"
+            ))))
+        }
+    };
+
+    match download_file(source, file_name).await {
+        Ok(_) => (),
+        Err(err) => return Err(err),
+    };
+
+    let mut statements = match DumpStatements::open(source, file_name) {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let mut client = match db::checkout(&db::POOL).await {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let transaction = match client.transaction().await {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    // The readers' rows are in the current catalog schema
+    if catalogs::enabled() {
+        match transaction
+            .batch_execute(&format!(
+                "SET LOCAL search_path TO {}, public;",
+                catalogs::CURRENT
+            ))
+            .await
+        {
+            Ok(_) => (),
+            Err(err) => return Err(Box::new(err)),
+        };
+    }
+
+    match spec.start_preview(&transaction).await {
+        Ok(_) => (),
+        Err(err) => return Err(err),
+    };
+
+    log::info!("Start preview of {file_name}...");
+
+    let mut preview = TablePreview {
+        file_name: file_name.to_string(),
+        ..TablePreview::default()
+    };
+    let mut chunk: Vec<(RowOrigin, String)> = Vec::with_capacity(BATCH_SIZE);
+
+    loop {
+        let is_last = match statements.next_statement() {
+            Some(Ok(statement)) => {
+                chunk.push(statement);
+                false
+            }
+            Some(Err(err)) => return Err(Box::new(err)),
+            None => true,
+        };
+
+        if chunk.len() < BATCH_SIZE && !is_last {
+            continue;
+        }
+
+        let statements_chunk = std::mem::replace(&mut chunk, Vec::with_capacity(BATCH_SIZE));
+        let (rows, skipped, rejected) =
+            match parse::<T>(file_name, statements_chunk, statements.columns.clone()).await {
+                Ok(v) => v,
+                Err(err) => return Err(err),
+            };
+
+        preview.skipped += skipped;
+        preview.rejected += rejected;
+
+        if !rows.is_empty() {
+            let compared = match spec
+                .preview(&transaction, source_id, &rows, samples as i64)
+                .await
+            {
+                Ok(v) => v,
+                Err(err) => return Err(err),
+            };
+
+            for rows in compared {
+                let (count, kept) = match rows.action.as_str() {
+                    "insert" => (&mut preview.would_insert, &mut preview.samples.insert),
+                    "update" => (&mut preview.would_update, &mut preview.samples.update),
+                    "unchanged" => {
+                        preview.unchanged += rows.count;
+                        continue;
+                    }
+                    _ => {
+                        preview.skipped += rows.count;
+                        continue;
+                    }
+                };
+
+                *count += rows.count;
+                if let serde_json::Value::Array(values) = rows.samples {
+                    kept.extend(values);
+                    kept.truncate(samples);
+                }
+            }
+        }
+
+        if is_last {
+            break;
+        }
+    }
+
+    match spec
+        .preview_deleted(&transaction, source_id, samples as i64)
+        .await
+    {
+        Ok(Some(rows)) => {
+            preview.would_delete = Some(rows.count);
+            if let serde_json::Value::Array(values) = rows.samples {
+                preview.samples.delete = values;
+            }
+        }
+        Ok(None) => (),
+        Err(err) => return Err(err),
+    };
+
+    match transaction.rollback().await {
+        Ok(_) => (),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    log::info!(
+        "Previewed {file_name}: {} to insert, {} to update, {:?} to delete, {} unchanged, {} skipped, {} rejected",
+        preview.would_insert,
+        preview.would_update,
+        preview.would_delete,
+        preview.unchanged,
+        preview.skipped,
+        preview.rejected
+    );
+
+    Ok(preview)
+}
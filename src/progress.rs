@@ -0,0 +1,224 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tokio::sync::watch;
+use tracing::log;
+use uuid::Uuid;
+
+use crate::config;
+use crate::runs::RunStatus;
+
+#[derive(Serialize, Clone, Default)]
+pub struct DownloadProgress {
+    pub bytes_downloaded: u64,
+    pub total_bytes: Option<u64>,
+    pub bytes_per_second: f64,
+    pub retries: u32,
+    pub finished: bool,
+    pub failed: bool,
+}
+
+#[derive(Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TableState {
+    /// Planned for the run, not started yet
+    #[default]
+    Pending,
+    Running,
+    Finished,
+    Failed,
+    /// A dependency failed
+    Skipped,
+}
+
+/// Estimates need the row count of a previous successful run of the table
+#[derive(Serialize, Clone, Default)]
+pub struct TableProgress {
+    pub state: TableState,
+    pub rows: u64,
+    pub expected_rows: Option<u64>,
+    pub percent: Option<f64>,
+    pub eta_secs: Option<u64>,
+    pub finished: bool,
+    #[serde(skip)]
+    started_at: Option<Instant>,
+}
+
+/// The latest run of a source, nothing until the first one starts
+#[derive(Serialize, Clone, Default)]
+pub struct SourceProgress {
+    pub run_id: Option<Uuid>,
+    pub status: Option<RunStatus>,
+    pub downloads: BTreeMap<String, DownloadProgress>,
+    pub tables: BTreeMap<String, TableProgress>,
+    pub percent: Option<f64>,
+    pub eta_secs: Option<u64>,
+    #[serde(skip)]
+    started_at: Option<Instant>,
+}
+
+/// Percent done and seconds left at the pace so far, never 100% before the end
+fn estimate(done: u64, expected: u64, elapsed: Duration) -> (f64, Option<u64>) {
+    let percent = (done as f64 / expected.max(1) as f64 * 100.0).min(99.9);
+
+    let eta_secs = (done > 0).then(|| {
+        (elapsed.as_secs_f64() * expected.saturating_sub(done) as f64 / done as f64) as u64
+    });
+
+    (percent, eta_secs)
+}
+
+impl SourceProgress {
+    fn estimate_run(&mut self) {
+        let (mut done, mut expected) = (0, 0);
+
+        for table in self.tables.values() {
+            if let Some(expected_rows) = table.expected_rows {
+                expected += expected_rows;
+                done += if table.finished {
+                    expected_rows
+                } else {
+                    table.rows.min(expected_rows)
+                };
+            }
+        }
+
+        let started_at = match self.started_at {
+            Some(v) if expected > 0 => v,
+            _ => return,
+        };
+
+        let (percent, eta_secs) = estimate(done, expected, started_at.elapsed());
+        self.percent = Some(percent);
+        self.eta_secs = eta_secs;
+    }
+}
+
+#[derive(Serialize, Clone, Default)]
+pub struct Progress {
+    pub sources: BTreeMap<String, SourceProgress>,
+}
+
+lazy_static! {
+    static ref PROGRESS: watch::Sender<Progress> = watch::channel(Progress {
+        sources: config::CONFIG
+            .sources
+            .iter()
+            .map(|source| (source.name.clone(), SourceProgress::default()))
+            .collect(),
+    })
+    .0;
+}
+
+/// Starts tracking a new run of `source`, dropping what its previous run left.
+pub fn start(source: &str, run_id: Uuid) {
+    update(source, |progress| {
+        *progress = SourceProgress {
+            run_id: Some(run_id),
+            status: Some(RunStatus::Running),
+            started_at: Some(Instant::now()),
+            ..Default::default()
+        }
+    });
+}
+
+/// Row counts of the tables in their latest successful runs
+pub fn expect(source: &str, expected_rows: HashMap<String, u64>) {
+    update(source, |progress| {
+        for (file_name, rows) in expected_rows {
+            progress.tables.entry(file_name).or_default().expected_rows = Some(rows);
+        }
+    });
+}
+
+/// The tables of the run, they're pending until their imports start
+pub fn plan<'a>(source: &str, file_names: impl Iterator<Item = &'a str>) {
+    update(source, |progress| {
+        for file_name in file_names {
+            progress.tables.entry(file_name.to_string()).or_default();
+        }
+    });
+}
+
+pub fn set_table_state(source: &str, file_name: &str, state: TableState) {
+    update(source, |progress| {
+        progress
+            .tables
+            .entry(file_name.to_string())
+            .or_default()
+            .state = state;
+    });
+}
+
+pub fn update_table(source: &str, file_name: &str, rows: u64) {
+    update(source, |progress| {
+        let table = progress.tables.entry(file_name.to_string()).or_default();
+        let started_at = *table.started_at.get_or_insert_with(Instant::now);
+
+        table.state = TableState::Running;
+        table.rows = rows;
+
+        if let Some(expected_rows) = table.expected_rows {
+            let previous = table.percent.unwrap_or(0.0);
+            let (percent, eta_secs) = estimate(rows, expected_rows, started_at.elapsed());
+
+            table.percent = Some(percent);
+            table.eta_secs = eta_secs;
+
+            if (percent / 10.0).floor() > (previous / 10.0).floor() {
+                log::info!(
+                    "{file_name}: {percent:.0}%, about {}s left",
+                    eta_secs.unwrap_or(0)
+                );
+            }
+        }
+
+        progress.estimate_run();
+    });
+}
+
+pub fn finish_table(source: &str, file_name: &str) {
+    update(source, |progress| {
+        let table = progress.tables.entry(file_name.to_string()).or_default();
+
+        table.state = TableState::Finished;
+        table.finished = true;
+        table.percent = Some(100.0);
+        table.eta_secs = Some(0);
+
+        progress.estimate_run();
+    });
+}
+
+pub fn finish(source: &str, status: RunStatus) {
+    update(source, |progress| {
+        progress.status = Some(status);
+        progress.eta_secs = None;
+    });
+}
+
+pub fn update<F>(source: &str, modify: F)
+where
+    F: FnOnce(&mut SourceProgress),
+{
+    PROGRESS
+        .send_modify(|progress| modify(progress.sources.entry(source.to_string()).or_default()));
+}
+
+pub fn update_download<F>(source: &str, file_name: &str, modify: F)
+where
+    F: FnOnce(&mut DownloadProgress),
+{
+    update(source, |progress| {
+        modify(progress.downloads.entry(file_name.to_string()).or_default())
+    });
+}
+
+pub fn snapshot() -> Progress {
+    PROGRESS.borrow().clone()
+}
+
+pub fn subscribe() -> watch::Receiver<Progress> {
+    PROGRESS.subscribe()
+}
@@ -0,0 +1,110 @@
+use deadpool_postgres::Pool;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::db;
+
+/// The run of the source that was going on at the change
+#[derive(Serialize)]
+pub struct ProvenanceRun {
+    pub id: Uuid,
+    pub status: String,
+    pub started_at: String,
+}
+
+/// Where a catalog row comes from and what the imports did to it, from the
+/// row and its latest `audit_log` entry
+#[derive(Serialize)]
+pub struct Provenance {
+    pub entity: &'static str,
+    pub id: i32,
+    pub source: String,
+    pub remote_id: i32,
+    pub is_deleted: bool,
+    /// The filter hiding the row, e.g. `language` for books of other
+    /// languages. Rows deleted upstream aren't filtered.
+    pub filtered: Option<String>,
+    /// Fields whose dump values were out of range and got clamped
+    pub clamped: Vec<&'static str>,
+    /// `inserted` or `updated`
+    pub last_action: Option<String>,
+    pub changed_at: Option<String>,
+    pub last_run: Option<ProvenanceRun>,
+    /// md5 of the row as the latest change left it
+    pub hash: Option<String>,
+    /// md5 of the row before the latest change, nothing after an insert
+    pub previous_hash: Option<String>,
+}
+
+/// Nothing if there's no book with the id
+pub async fn book(
+    pool: &Pool,
+    id: i32,
+) -> Result<Option<Provenance>, Box<dyn std::error::Error + Send>> {
+    let client = match db::checkout(pool).await {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let row = match client
+        .query_opt(
+            "
+            SELECT
+                books.id, sources.name, books.remote_id, books.is_deleted,
+                NULLIF(books.deleted_reason, 'upstream')::text, change.action::text,
+                change.created_at::text, run.id, run.status::text, run.started_at::text,
+                change.new_value, change.old_value, books.year_clamped
+            FROM books
+            JOIN sources ON sources.id = books.source
+            LEFT JOIN LATERAL (
+                SELECT action, old_value, new_value, created_at FROM audit_log
+                WHERE entity = 'book' AND source = books.source AND remote_id = books.remote_id
+                ORDER BY id DESC
+                LIMIT 1
+            ) AS change ON true
+            LEFT JOIN LATERAL (
+                SELECT id, status, started_at FROM update_runs
+                WHERE source = books.source AND started_at <= change.created_at
+                ORDER BY started_at DESC
+                LIMIT 1
+            ) AS run ON true
+            WHERE books.id = $1;
+            ",
+            &[&id],
+        )
+        .await
+    {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let row = match row {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+
+    let run_id: Option<Uuid> = row.get(7);
+    let year_clamped: bool = row.get(12);
+
+    Ok(Some(Provenance {
+        entity: "book",
+        id: row.get(0),
+        source: row.get(1),
+        remote_id: row.get(2),
+        is_deleted: row.get(3),
+        filtered: row.get(4),
+        clamped: match year_clamped {
+            true => vec!["year"],
+            false => vec![],
+        },
+        last_action: row.get(5),
+        changed_at: row.get(6),
+        last_run: run_id.map(|id| ProvenanceRun {
+            id,
+            status: row.get(8),
+            started_at: row.get(9),
+        }),
+        hash: row.get(10),
+        previous_hash: row.get(11),
+    }))
+}
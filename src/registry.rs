@@ -0,0 +1,109 @@
+use std::{collections::HashMap, fmt::Debug, sync::RwLock};
+
+use deadpool_postgres::Pool;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::config::Source;
+use crate::preview::{self, Preview};
+use crate::rejects::{self, Reprocess};
+use crate::types::{
+    Author, AuthorAnnotation, AuthorAnnotationPic, Book, BookAnnotation, BookAnnotationPic,
+    BookAuthor, BookGenre, FromVecExpression, Genre, Sequence, SequenceInfo, Translator, Update,
+};
+use crate::updater::{spawn_table, Status, TableHandle};
+
+pub(crate) type SpawnTable = fn(
+    &Pool,
+    &'static Source,
+    i16,
+    &'static str,
+    Vec<Status>,
+    Vec<Status>,
+    &Status,
+) -> (&'static str, TableHandle);
+
+/// What the updater needs to know about a type of rows
+#[derive(Clone, Copy)]
+struct Entity {
+    spawn: SpawnTable,
+    reprocess: Reprocess,
+    preview: Preview,
+}
+
+impl Entity {
+    fn of<T>() -> Entity
+    where
+        T: Debug
+            + FromVecExpression<T>
+            + Update
+            + Serialize
+            + DeserializeOwned
+            + Send
+            + Sync
+            + 'static,
+    {
+        Entity {
+            spawn: spawn_table::<T>,
+            reprocess: rejects::reprocess::<T>,
+            preview: preview::preview::<T>,
+        }
+    }
+}
+
+lazy_static! {
+    static ref ENTITIES: RwLock<HashMap<String, Entity>> = {
+        let mut entities: HashMap<String, Entity> = HashMap::new();
+
+        entities.insert("author".to_string(), Entity::of::<Author>());
+        entities.insert("book".to_string(), Entity::of::<Book>());
+        entities.insert("book_author".to_string(), Entity::of::<BookAuthor>());
+        entities.insert("translator".to_string(), Entity::of::<Translator>());
+        entities.insert("sequence".to_string(), Entity::of::<Sequence>());
+        entities.insert("sequence_info".to_string(), Entity::of::<SequenceInfo>());
+        entities.insert(
+            "book_annotation".to_string(),
+            Entity::of::<BookAnnotation>(),
+        );
+        entities.insert(
+            "book_annotation_pic".to_string(),
+            Entity::of::<BookAnnotationPic>(),
+        );
+        entities.insert(
+            "author_annotation".to_string(),
+            Entity::of::<AuthorAnnotation>(),
+        );
+        entities.insert(
+            "author_annotation_pic".to_string(),
+            Entity::of::<AuthorAnnotationPic>(),
+        );
+        entities.insert("genre".to_string(), Entity::of::<Genre>());
+        entities.insert("book_genre".to_string(), Entity::of::<BookGenre>());
+
+        RwLock::new(entities)
+    };
+}
+
+/// Makes `T` available as `entity` in the `TABLES` config. Call it before
+/// `library_updater::start()` to import site-specific tables. The row plugins
+/// get the rows through serde.
+pub fn register<T>(entity: &str)
+where
+    T: Debug + FromVecExpression<T> + Update + Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    ENTITIES
+        .write()
+        .unwrap()
+        .insert(entity.to_string(), Entity::of::<T>());
+}
+
+pub(crate) fn get(entity: &str) -> Option<SpawnTable> {
+    ENTITIES.read().unwrap().get(entity).map(|v| v.spawn)
+}
+
+pub(crate) fn reprocess(entity: &str) -> Option<Reprocess> {
+    ENTITIES.read().unwrap().get(entity).map(|v| v.reprocess)
+}
+
+pub(crate) fn preview(entity: &str) -> Option<Preview> {
+    ENTITIES.read().unwrap().get(entity).map(|v| v.preview)
+}
@@ -0,0 +1,345 @@
+use std::{fmt::Debug, future::Future, pin::Pin};
+
+use deadpool_postgres::Pool;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::OnceCell;
+use tracing::log;
+
+use crate::db;
+use crate::dump_row::{Columns, RowOrigin};
+use crate::types::{FromVecExpression, Update};
+use crate::updater::{parse_options, parse_row, row_transform, ParsedRow};
+use crate::utils::page_offset;
+
+const PAGE_SIZE: i64 = 50;
+
+static SCHEMA: OnceCell<()> = OnceCell::const_new();
+
+async fn prepare() -> Result<(), Box<dyn std::error::Error + Send>> {
+    SCHEMA
+        .get_or_try_init(|| async {
+            let client = match db::checkout(&db::POOL).await {
+                Ok(v) => v,
+                Err(err) => return Err(Box::new(err) as Box<dyn std::error::Error + Send>),
+            };
+
+            match client
+                .batch_execute(
+                    "
+                    CREATE TABLE IF NOT EXISTS import_rejects (
+                        id bigserial PRIMARY KEY,
+                        source smallint NOT NULL,
+                        file_name varchar(128) NOT NULL,
+                        line bigint NOT NULL,
+                        statement bigint NOT NULL,
+                        tuple bigint NOT NULL,
+                        raw text NOT NULL,
+                        error text NOT NULL,
+                        created_at timestamptz NOT NULL DEFAULT now()
+                    );
+
+                    CREATE INDEX IF NOT EXISTS import_rejects_file
+                        ON import_rejects (source, file_name);
+
+                    ALTER TABLE import_rejects
+                        ADD COLUMN IF NOT EXISTS columns text[] NOT NULL DEFAULT '{}';
+                    ",
+                )
+                .await
+            {
+                Ok(_) => Ok(()),
+                Err(err) => Err(Box::new(err) as Box<dyn std::error::Error + Send>),
+            }
+        })
+        .await
+        .map(|_| ())
+}
+
+/// A row the import couldn't take: it didn't parse or broke a constraint
+pub struct Reject {
+    pub origin: RowOrigin,
+    /// The row as a single-row `INSERT`, so it can be parsed again on its own
+    pub raw: String,
+    pub error: String,
+}
+
+/// Keeps the row for later analysis instead of only logging it, with the columns
+/// of the dump to parse it again. Failures are only logged, losing a reject
+/// must not fail the import.
+pub async fn record(
+    pool: &Pool,
+    source_id: i16,
+    file_name: &str,
+    columns: &Columns,
+    reject: &Reject,
+) {
+    log::warn!("{file_name}, {}: rejected: {}", reject.origin, reject.error);
+
+    if let Err(err) = prepare().await {
+        log::error!("Can't prepare import_rejects: {:?}", err);
+        return;
+    }
+
+    let client = match db::checkout(pool).await {
+        Ok(v) => v,
+        Err(err) => {
+            log::error!("Can't record a reject of {file_name}: {:?}", err);
+            return;
+        }
+    };
+
+    if let Err(err) = client
+        .execute(
+            "
+            INSERT INTO import_rejects
+                (source, file_name, line, statement, tuple, raw, error, columns)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8);
+            ",
+            &[
+                &source_id,
+                &file_name,
+                &(reject.origin.line as i64),
+                &(reject.origin.statement as i64),
+                &(reject.origin.tuple as i64),
+                &reject.raw,
+                &reject.error,
+                &columns.names(),
+            ],
+        )
+        .await
+    {
+        log::error!("Can't record a reject of {file_name}: {:?}", err);
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RejectsFilter {
+    pub source: Option<String>,
+    pub file_name: Option<String>,
+    pub page: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct RejectRow {
+    pub id: i64,
+    pub source: String,
+    pub file_name: String,
+    pub line: i64,
+    pub statement: i64,
+    pub tuple: i64,
+    pub raw: String,
+    pub error: String,
+    pub created_at: String,
+}
+
+/// The latest rejects first
+pub async fn list(
+    pool: &Pool,
+    filter: &RejectsFilter,
+) -> Result<Vec<RejectRow>, Box<dyn std::error::Error + Send>> {
+    match prepare().await {
+        Ok(_) => (),
+        Err(err) => return Err(err),
+    };
+
+    let client = match db::checkout(pool).await {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let rows = match client
+        .query(
+            "
+            SELECT
+                rejects.id, sources.name, rejects.file_name, rejects.line, rejects.statement,
+                rejects.tuple, rejects.raw, rejects.error, rejects.created_at::text
+            FROM import_rejects AS rejects
+            JOIN sources ON sources.id = rejects.source
+            WHERE ($1::text IS NULL OR sources.name = $1)
+                AND ($2::text IS NULL OR rejects.file_name = $2)
+            ORDER BY rejects.id DESC
+            LIMIT $3 OFFSET $4;
+            ",
+            &[
+                &filter.source,
+                &filter.file_name,
+                &PAGE_SIZE,
+                &page_offset(filter.page, PAGE_SIZE),
+            ],
+        )
+        .await
+    {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    Ok(rows
+        .iter()
+        .map(|row| RejectRow {
+            id: row.get(0),
+            source: row.get(1),
+            file_name: row.get(2),
+            line: row.get(3),
+            statement: row.get(4),
+            tuple: row.get(5),
+            raw: row.get(6),
+            error: row.get(7),
+            created_at: row.get(8),
+        })
+        .collect())
+}
+
+/// Files of the source that have rejects
+pub async fn files(
+    pool: &Pool,
+    source_id: i16,
+) -> Result<Vec<String>, Box<dyn std::error::Error + Send>> {
+    match prepare().await {
+        Ok(_) => (),
+        Err(err) => return Err(err),
+    };
+
+    let client = match db::checkout(pool).await {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let rows = match client
+        .query(
+            "SELECT DISTINCT file_name FROM import_rejects WHERE source = $1 ORDER BY file_name;",
+            &[&source_id],
+        )
+        .await
+    {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+/// What reprocessing did with the rejects of a file
+#[derive(Serialize)]
+pub struct ReprocessReport {
+    pub file_name: String,
+    /// Rows written and cleared from `import_rejects`
+    pub applied: u64,
+    /// Rows that still fail, with their error updated
+    pub rejected: u64,
+}
+
+pub(crate) type ReprocessFuture = Pin<
+    Box<dyn Future<Output = Result<ReprocessReport, Box<dyn std::error::Error + Send>>> + Send>,
+>;
+
+pub(crate) type Reprocess = fn(Pool, i16, String) -> ReprocessFuture;
+
+pub(crate) fn reprocess<T>(pool: Pool, source_id: i16, file_name: String) -> ReprocessFuture
+where
+    T: Debug + FromVecExpression<T> + Update + Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    Box::pin(reprocess_file::<T>(pool, source_id, file_name))
+}
+
+/// Parses the rejects of a file with the current code and writes the rows that
+/// make it, after a mapping fix they don't wait for a full re-import.
+async fn reprocess_file<T>(
+    pool: Pool,
+    source_id: i16,
+    file_name: String,
+) -> Result<ReprocessReport, Box<dyn std::error::Error + Send>>
+where
+    T: Debug + FromVecExpression<T> + Update + Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    match prepare().await {
+        Ok(_) => (),
+        Err(err) => return Err(err),
+    };
+
+    let client = match db::checkout(&pool).await {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    match T::before_update(&client).await {
+        Ok(_) => (),
+        Err(err) => return Err(err),
+    };
+
+    let rows = match client
+        .query(
+            "SELECT id, raw, columns FROM import_rejects WHERE source = $1 AND file_name = $2 ORDER BY id;",
+            &[&source_id, &file_name],
+        )
+        .await
+    {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let parse_options = parse_options();
+    let mut transform = row_transform::<T>(&file_name);
+    let mut report = ReprocessReport {
+        file_name,
+        applied: 0,
+        rejected: 0,
+    };
+
+    for row in rows.iter() {
+        let id: i64 = row.get(0);
+        let raw: String = row.get(1);
+        let columns = Columns::from_names(&row.get::<_, Vec<String>>(2));
+
+        let mut parsed = parse_row::<T>(&raw, &columns, &parse_options);
+        if let Some(transform) = transform.as_mut() {
+            parsed = transform(parsed);
+        }
+
+        let error = match parsed {
+            ParsedRow::Row(value, _) => match value.update(&client, source_id).await {
+                Ok(_) => None,
+                Err(err) if db::is_transient(&err) => return Err(err),
+                Err(err) => Some(format!("{:?}: {}", value, err)),
+            },
+            ParsedRow::Unparsed(error) | ParsedRow::Invalid(error) => Some(error),
+        };
+
+        let result = match &error {
+            None => {
+                report.applied += 1;
+                client
+                    .execute("DELETE FROM import_rejects WHERE id = $1;", &[&id])
+                    .await
+            }
+            Some(error) => {
+                report.rejected += 1;
+                client
+                    .execute(
+                        "UPDATE import_rejects SET error = $2 WHERE id = $1;",
+                        &[&id, error],
+                    )
+                    .await
+            }
+        };
+
+        if let Err(err) = result {
+            return Err(Box::new(err));
+        }
+    }
+
+    if report.applied > 0 {
+        match T::after_update(&client).await {
+            Ok(_) => (),
+            Err(err) => return Err(err),
+        };
+    }
+
+    log::info!(
+        "Reprocessed rejects of {}: {} applied, {} still rejected",
+        report.file_name,
+        report.applied,
+        report.rejected
+    );
+
+    Ok(report)
+}
@@ -0,0 +1,368 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use deadpool_postgres::Pool;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::db;
+use crate::dumps::Download;
+use crate::ids::RemoteBookId;
+use crate::runs::RunStatus;
+use crate::upsert::UpsertResult;
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TableStatus {
+    Success,
+    Failed,
+    Skipped,
+}
+
+impl TableStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TableStatus::Success => "success",
+            TableStatus::Failed => "failed",
+            TableStatus::Skipped => "skipped",
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Default)]
+pub struct RowCounts {
+    pub inserted: u64,
+    pub updated: u64,
+    pub unchanged: u64,
+    pub skipped: u64,
+    /// Rows set aside in `import_rejects`
+    pub rejected: u64,
+}
+
+impl RowCounts {
+    pub fn add(&mut self, result: UpsertResult) {
+        match result {
+            UpsertResult::Inserted => self.inserted += 1,
+            UpsertResult::Updated => self.updated += 1,
+            UpsertResult::Unchanged => self.unchanged += 1,
+            UpsertResult::Skipped => self.skipped += 1,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.inserted + self.updated + self.unchanged + self.skipped + self.rejected
+    }
+
+    pub fn merge(&mut self, other: &RowCounts) {
+        self.inserted += other.inserted;
+        self.updated += other.updated;
+        self.unchanged += other.unchanged;
+        self.skipped += other.skipped;
+        self.rejected += other.rejected;
+    }
+}
+
+const PARSE_ISSUE_SAMPLES: usize = 5;
+
+/// What `sql_parse` complained about in the rows of a file
+#[derive(Serialize, Clone, Default)]
+pub struct ParseIssues {
+    /// Rows with at least one issue, see `MAX_PARSE_ISSUES`
+    pub rows: u64,
+    /// Rows that couldn't be parsed at all, see `import_rejects`
+    pub unparsed: u64,
+    pub samples: Vec<String>,
+}
+
+impl ParseIssues {
+    pub fn add(&mut self, sample: impl FnOnce() -> String) {
+        self.rows += 1;
+
+        if self.samples.len() < PARSE_ISSUE_SAMPLES {
+            self.samples.push(sample());
+        }
+    }
+
+    /// Merges the issues of a chunk, returns its samples that were kept
+    pub fn merge(&mut self, other: ParseIssues) -> Vec<String> {
+        self.rows += other.rows;
+        self.unparsed += other.unparsed;
+
+        let free = PARSE_ISSUE_SAMPLES.saturating_sub(self.samples.len());
+        let kept: Vec<String> = other.samples.into_iter().take(free).collect();
+        self.samples.extend(kept.iter().cloned());
+
+        kept
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct TableReport {
+    pub file_name: String,
+    pub status: TableStatus,
+    pub error: Option<String>,
+    pub rows: RowCounts,
+    pub parse_issues: ParseIssues,
+    /// Time of the successful attempt, from the end of the wait for dependencies
+    pub duration_ms: Option<u64>,
+}
+
+#[derive(Serialize, Clone, Default)]
+pub struct ImportStats {
+    pub books_by_lang: BTreeMap<String, RowCounts>,
+    pub added_books_by_genre: BTreeMap<String, i64>,
+    pub unknown_file_types: BTreeMap<String, u64>,
+}
+
+#[derive(Default)]
+struct ImportCounters {
+    books_by_lang: BTreeMap<String, RowCounts>,
+    added_books: Vec<RemoteBookId>,
+    unknown_file_types: BTreeMap<String, u64>,
+    parse_issues: HashMap<String, ParseIssues>,
+    durations: HashMap<String, u64>,
+    downloads: HashMap<String, Download>,
+}
+
+lazy_static! {
+    /// Counters of the current run of every source
+    static ref COUNTERS: Mutex<HashMap<i16, ImportCounters>> = Mutex::new(HashMap::new());
+}
+
+pub fn reset_import_stats(source_id: i16) {
+    COUNTERS
+        .lock()
+        .unwrap()
+        .insert(source_id, ImportCounters::default());
+}
+
+pub fn record_book(source_id: i16, lang: &str, id: RemoteBookId, result: UpsertResult) {
+    let mut counters = COUNTERS.lock().unwrap();
+    let counters = counters.entry(source_id).or_default();
+
+    counters
+        .books_by_lang
+        .entry(lang.to_string())
+        .or_default()
+        .add(result);
+
+    if result == UpsertResult::Inserted {
+        counters.added_books.push(id);
+    }
+}
+
+pub fn record_unknown_file_type(source_id: i16, file_type: &str) {
+    *COUNTERS
+        .lock()
+        .unwrap()
+        .entry(source_id)
+        .or_default()
+        .unknown_file_types
+        .entry(file_type.to_string())
+        .or_default() += 1;
+}
+
+pub fn record_parse_issues(source_id: i16, file_name: &str, issues: &ParseIssues) {
+    COUNTERS
+        .lock()
+        .unwrap()
+        .entry(source_id)
+        .or_default()
+        .parse_issues
+        .insert(file_name.to_string(), issues.clone());
+}
+
+pub fn record_duration(source_id: i16, file_name: &str, duration_ms: u64) {
+    COUNTERS
+        .lock()
+        .unwrap()
+        .entry(source_id)
+        .or_default()
+        .durations
+        .insert(file_name.to_string(), duration_ms);
+}
+
+pub fn duration(source_id: i16, file_name: &str) -> Option<u64> {
+    COUNTERS
+        .lock()
+        .unwrap()
+        .get(&source_id)
+        .and_then(|counters| counters.durations.get(file_name).copied())
+}
+
+pub fn record_download(source_id: i16, file_name: &str, download: Download) {
+    COUNTERS
+        .lock()
+        .unwrap()
+        .entry(source_id)
+        .or_default()
+        .downloads
+        .insert(file_name.to_string(), download);
+}
+
+pub fn record_parse_failure(source_id: i16, file_name: &str) {
+    if let Some(download) = COUNTERS
+        .lock()
+        .unwrap()
+        .get_mut(&source_id)
+        .and_then(|counters| counters.downloads.get_mut(file_name))
+    {
+        download.parse_failed = true;
+    }
+}
+
+pub fn downloads(source_id: i16) -> HashMap<String, Download> {
+    COUNTERS
+        .lock()
+        .unwrap()
+        .get(&source_id)
+        .map(|counters| counters.downloads.clone())
+        .unwrap_or_default()
+}
+
+pub fn parse_issues(source_id: i16, file_name: &str) -> ParseIssues {
+    COUNTERS
+        .lock()
+        .unwrap()
+        .get(&source_id)
+        .and_then(|counters| counters.parse_issues.get(file_name).cloned())
+        .unwrap_or_default()
+}
+
+pub fn added_books(source_id: i16) -> Vec<RemoteBookId> {
+    match COUNTERS.lock().unwrap().get(&source_id) {
+        Some(counters) => counters.added_books.clone(),
+        None => vec![],
+    }
+}
+
+/// Collects the per-language counts and groups books added in this run by top-level genre.
+pub async fn import_stats(
+    pool: &Pool,
+    source_id: i16,
+) -> Result<ImportStats, Box<dyn std::error::Error + Send>> {
+    let (books_by_lang, added_books, unknown_file_types) =
+        match COUNTERS.lock().unwrap().get(&source_id) {
+            Some(counters) => (
+                counters.books_by_lang.clone(),
+                counters.added_books.clone(),
+                counters.unknown_file_types.clone(),
+            ),
+            None => Default::default(),
+        };
+
+    let client = match db::checkout(pool).await {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let rows = match client
+        .query(
+            "
+            SELECT genres.meta, count(DISTINCT books.id) FROM books
+            JOIN book_genres ON book_genres.book = books.id
+            JOIN genres ON genres.id = book_genres.genre
+            WHERE books.source = $1 AND books.remote_id = ANY($2)
+            GROUP BY genres.meta;
+            ",
+            &[&source_id, &added_books],
+        )
+        .await
+    {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    Ok(ImportStats {
+        books_by_lang,
+        added_books_by_genre: rows
+            .iter()
+            .map(|row| (row.get::<_, String>(0), row.get::<_, i64>(1)))
+            .collect(),
+        unknown_file_types,
+    })
+}
+
+#[derive(Serialize, Clone)]
+pub struct UpdateReport {
+    pub run_id: Uuid,
+    pub source: String,
+    pub status: RunStatus,
+    pub tables: Vec<TableReport>,
+    pub import: ImportStats,
+    pub errors: Vec<String>,
+    pub duration_secs: u64,
+    /// Dumps the source started to publish, see `DUMP_DISCOVERY`
+    pub new_dumps: Vec<String>,
+    /// Notifications of the run by target, filled in once they're sent
+    pub deliveries: Vec<Delivery>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct Delivery {
+    pub channel: String,
+    /// `#2 hooks.slack.com` for webhooks, the SMTP host for email
+    pub target: String,
+    pub error: Option<String>,
+}
+
+impl UpdateReport {
+    pub fn new(run_id: Uuid, source: &str, tables: Vec<TableReport>) -> UpdateReport {
+        let succeeded = tables
+            .iter()
+            .filter(|table| table.status == TableStatus::Success)
+            .count();
+
+        let status = if succeeded == tables.len() {
+            RunStatus::Success
+        } else if succeeded > 0 {
+            RunStatus::PartialSuccess
+        } else {
+            RunStatus::Failed
+        };
+
+        UpdateReport {
+            run_id,
+            source: source.to_string(),
+            status,
+            tables,
+            import: ImportStats::default(),
+            errors: vec![],
+            duration_secs: 0,
+            new_dumps: vec![],
+            deliveries: vec![],
+        }
+    }
+
+    pub fn add_error(&mut self, error: String) {
+        self.errors.push(error);
+
+        if self.status == RunStatus::Success {
+            self.status = RunStatus::PartialSuccess;
+        }
+    }
+}
+
+/// Writes the report next to its final place and renames it over the previous
+/// one, readers never see a half-written file.
+pub async fn write_file(
+    report: &UpdateReport,
+    path: &str,
+) -> Result<(), Box<dyn std::error::Error + Send>> {
+    let path = path.replace("{source}", &report.source);
+    let temporary = format!("{path}.tmp");
+
+    let data = match serde_json::to_vec_pretty(report) {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    if let Err(err) = tokio::fs::write(&temporary, data).await {
+        return Err(Box::new(err));
+    }
+
+    match tokio::fs::rename(&temporary, &path).await {
+        Ok(_) => Ok(()),
+        Err(err) => Err(Box::new(err)),
+    }
+}
@@ -0,0 +1,103 @@
+use tokio_postgres::Client;
+use tracing::log;
+
+use crate::config;
+use crate::db;
+
+/// What `RETENTION_DAYS` can purge: the name used there, the table and the
+/// column with the age of a row. Logs, tables and downloads of a run go with
+/// it. `catalog_changes` has `CHANGES_RETENTION_DAYS`.
+pub const POLICIES: [(&str, &str, &str); 4] = [
+    ("audit", "audit_log", "created_at"),
+    ("rejects", "import_rejects", "created_at"),
+    ("runs", "update_runs", "started_at"),
+    ("arrivals", "new_arrivals", "added_at"),
+];
+
+/// Rows deleted per statement, so imports aren't blocked for long
+const BATCH_SIZE: i64 = 10_000;
+
+/// Deletes the rows older than their policy allows. Returns the deleted count.
+async fn purge(
+    client: &Client,
+    table: &str,
+    column: &str,
+    days: i32,
+) -> Result<u64, Box<dyn std::error::Error + Send>> {
+    let exists: bool = match client
+        .query_one(
+            "SELECT to_regclass($1) IS NOT NULL;",
+            &[&format!("public.{table}")],
+        )
+        .await
+    {
+        Ok(row) => row.get(0),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    if !exists {
+        return Ok(0);
+    }
+
+    let statement = format!(
+        "
+        DELETE FROM {table} WHERE ctid = ANY(ARRAY(
+            SELECT ctid FROM {table}
+            WHERE {column} < now() - make_interval(days => $1)
+            LIMIT $2
+        ));
+        "
+    );
+
+    let mut total = 0;
+
+    loop {
+        let count = match client.execute(&statement, &[&days, &BATCH_SIZE]).await {
+            Ok(v) => v,
+            Err(err) => return Err(Box::new(err)),
+        };
+
+        total += count;
+
+        if count < BATCH_SIZE as u64 {
+            return Ok(total);
+        }
+    }
+}
+
+/// Applies the `RETENTION_DAYS` policies, a failed one doesn't stop the
+/// others. Returns whether all of them were applied.
+pub async fn run() -> bool {
+    if config::CONFIG.retention_days.is_empty() {
+        return true;
+    }
+
+    let client = match db::checkout(&db::POOL).await {
+        Ok(v) => v,
+        Err(err) => {
+            log::error!("Can't apply the retention policies: {:?}", err);
+            return false;
+        }
+    };
+
+    let mut ok = true;
+
+    for (name, table, column) in POLICIES {
+        let days = match config::CONFIG.retention_days.get(name) {
+            Some(v) => *v,
+            None => continue,
+        };
+
+        match purge(&client, table, column, days).await {
+            Ok(count) => {
+                log::info!("Retention: removed {count} {name} rows older than {days} days")
+            }
+            Err(err) => {
+                log::error!("Retention: can't purge {name}: {:?}", err);
+                ok = false;
+            }
+        };
+    }
+
+    ok
+}
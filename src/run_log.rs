@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use deadpool_postgres::Pool;
+use serde::Serialize;
+use tokio_postgres::Client;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+use uuid::Uuid;
+
+use crate::db;
+use crate::utils::page_offset;
+
+pub const PAGE_SIZE: i64 = 100;
+
+/// Lines kept per run, the rest are only counted
+const MAX_LINES: usize = 10_000;
+
+#[derive(Serialize)]
+pub struct LogLine {
+    pub logged_at: String,
+    pub level: String,
+    pub message: String,
+}
+
+struct BufferedLine {
+    logged_at: DateTime<Utc>,
+    level: String,
+    message: String,
+}
+
+#[derive(Default)]
+struct Buffer {
+    lines: Vec<BufferedLine>,
+    dropped: usize,
+}
+
+/// Target of the events marking the phases of a run
+const PHASE_TARGET: &str = "run_phase";
+
+lazy_static! {
+    /// Lines of the runs in progress, sources run side by side
+    static ref BUFFERS: Mutex<HashMap<Uuid, Buffer>> = Mutex::new(HashMap::new());
+}
+
+fn push(run_id: Uuid, level: &str, message: String) {
+    let mut buffers = BUFFERS.lock().unwrap();
+
+    let buffer = match buffers.get_mut(&run_id) {
+        Some(v) => v,
+        None => return,
+    };
+
+    if buffer.lines.len() >= MAX_LINES {
+        buffer.dropped += 1;
+        return;
+    }
+
+    buffer.lines.push(BufferedLine {
+        logged_at: Utc::now(),
+        level: level.to_string(),
+        message,
+    });
+}
+
+/// Starts collecting the significant log lines of a run, logged inside a span
+/// with its `run_id`.
+pub fn start(run_id: Uuid) {
+    BUFFERS.lock().unwrap().insert(run_id, Buffer::default());
+}
+
+pub fn stop(run_id: Uuid) {
+    BUFFERS.lock().unwrap().remove(&run_id);
+}
+
+pub fn phase(message: &str) {
+    tracing::info!(target: PHASE_TARGET, "{message}");
+}
+
+/// Stores the collected lines of the run, `runs::start` creates the table.
+pub async fn save(client: &Client, run_id: Uuid) -> Result<(), Box<dyn std::error::Error + Send>> {
+    let (mut lines, dropped) = match BUFFERS.lock().unwrap().get_mut(&run_id) {
+        Some(buffer) => (std::mem::take(&mut buffer.lines), buffer.dropped),
+        None => (vec![], 0),
+    };
+
+    if dropped > 0 {
+        lines.push(BufferedLine {
+            logged_at: Utc::now(),
+            level: "warn".to_string(),
+            message: format!("{dropped} more lines weren't stored"),
+        });
+    }
+
+    for (seq, line) in lines.iter().enumerate() {
+        match client
+            .execute(
+                "
+                INSERT INTO update_run_logs (run_id, seq, logged_at, level, message)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT DO NOTHING;
+                ",
+                &[
+                    &run_id,
+                    &(seq as i32),
+                    &line.logged_at,
+                    &line.level,
+                    &line.message,
+                ],
+            )
+            .await
+        {
+            Ok(_) => (),
+            Err(err) => return Err(Box::new(err)),
+        };
+    }
+
+    Ok(())
+}
+
+pub async fn list(
+    pool: &Pool,
+    run_id: Uuid,
+    page: Option<i64>,
+) -> Result<Vec<LogLine>, Box<dyn std::error::Error + Send>> {
+    let client = match db::checkout(pool).await {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let rows = match client
+        .query(
+            "
+            SELECT logged_at::text, level, message
+            FROM update_run_logs
+            WHERE run_id = $1
+            ORDER BY seq
+            LIMIT $2 OFFSET $3;
+            ",
+            &[&run_id, &PAGE_SIZE, &page_offset(page, PAGE_SIZE)],
+        )
+        .await
+    {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    Ok(rows
+        .iter()
+        .map(|row| LogLine {
+            logged_at: row.get(0),
+            level: row.get(1),
+            message: row.get(2),
+        })
+        .collect())
+}
+
+struct MessageVisitor(String);
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{:?}", value);
+        }
+    }
+}
+
+/// Run id of a span, kept in its extensions
+struct RunId(Uuid);
+
+struct RunIdVisitor(Option<Uuid>);
+
+impl Visit for RunIdVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == "run_id" {
+            self.0 = format!("{:?}", value).parse().ok();
+        }
+    }
+}
+
+/// Collects warnings, errors and phases into the log of the run whose span
+/// they happen in.
+pub struct RunLogLayer;
+
+impl<S> Layer<S> for RunLogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut visitor = RunIdVisitor(None);
+        attrs.record(&mut visitor);
+
+        if let (Some(run_id), Some(span)) = (visitor.0, ctx.span(id)) {
+            span.extensions_mut().insert(RunId(run_id));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let level = *event.metadata().level();
+        let is_phase = event.metadata().target() == PHASE_TARGET;
+
+        if level > Level::WARN && !is_phase {
+            return;
+        }
+
+        let run_id = ctx.event_scope(event).and_then(|scope| {
+            scope
+                .from_root()
+                .find_map(|span| span.extensions().get::<RunId>().map(|run_id| run_id.0))
+        });
+
+        let run_id = match run_id {
+            Some(v) => v,
+            None => return,
+        };
+
+        let mut visitor = MessageVisitor(String::new());
+        event.record(&mut visitor);
+
+        let level = if is_phase {
+            "phase".to_string()
+        } else {
+            level.as_str().to_lowercase()
+        };
+
+        push(run_id, &level, visitor.0);
+    }
+}
@@ -0,0 +1,403 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use deadpool_postgres::Pool;
+use serde::Serialize;
+use tokio_postgres::Client;
+use uuid::Uuid;
+
+use crate::config;
+use crate::db;
+use crate::dumps::Download;
+use crate::report::TableReport;
+use crate::utils::page_offset;
+
+pub const PAGE_SIZE: i64 = 50;
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStatus {
+    Running,
+    Success,
+    PartialSuccess,
+    Failed,
+}
+
+impl RunStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RunStatus::Running => "running",
+            RunStatus::Success => "success",
+            RunStatus::PartialSuccess => "partial_success",
+            RunStatus::Failed => "failed",
+        }
+    }
+}
+
+pub async fn start(
+    client: &Client,
+    run_id: Uuid,
+    source_id: i16,
+) -> Result<(), Box<dyn std::error::Error + Send>> {
+    match client
+        .batch_execute(
+            "
+            CREATE TABLE IF NOT EXISTS update_runs (
+                id uuid PRIMARY KEY,
+                source smallint NOT NULL,
+                status varchar(32) NOT NULL,
+                started_at timestamptz NOT NULL DEFAULT now(),
+                finished_at timestamptz
+            );
+
+            CREATE TABLE IF NOT EXISTS update_run_logs (
+                run_id uuid NOT NULL REFERENCES update_runs (id) ON DELETE CASCADE,
+                seq integer NOT NULL,
+                logged_at timestamptz NOT NULL,
+                level varchar(8) NOT NULL,
+                message text NOT NULL,
+                PRIMARY KEY (run_id, seq)
+            );
+
+            CREATE TABLE IF NOT EXISTS update_run_tables (
+                run_id uuid NOT NULL REFERENCES update_runs (id) ON DELETE CASCADE,
+                file_name varchar(128) NOT NULL,
+                status varchar(32) NOT NULL,
+                rows bigint NOT NULL,
+                PRIMARY KEY (run_id, file_name)
+            );
+
+            ALTER TABLE update_run_tables ADD COLUMN IF NOT EXISTS duration_ms bigint;
+
+            ALTER TABLE update_runs ADD COLUMN IF NOT EXISTS attempt smallint NOT NULL DEFAULT 1;
+            ALTER TABLE update_runs ADD COLUMN IF NOT EXISTS retry_at timestamptz;
+
+            CREATE TABLE IF NOT EXISTS update_run_downloads (
+                run_id uuid NOT NULL REFERENCES update_runs (id) ON DELETE CASCADE,
+                file_name varchar(128) NOT NULL,
+                url text,
+                size bigint NOT NULL,
+                md5 varchar(32),
+                duration_ms bigint,
+                reused boolean NOT NULL,
+                parse_failed boolean NOT NULL,
+                PRIMARY KEY (run_id, file_name)
+            );
+            CREATE INDEX IF NOT EXISTS update_run_downloads_failed
+                ON update_run_downloads (file_name, md5) WHERE parse_failed;
+            ",
+        )
+        .await
+    {
+        Ok(_) => (),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    // The run takes the place of a due follow-up of the source
+    match client
+        .execute(
+            "UPDATE update_runs SET retry_at = NULL WHERE source = $1 AND retry_at IS NOT NULL;",
+            &[&source_id],
+        )
+        .await
+    {
+        Ok(_) => (),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    match client
+        .execute(
+            "
+            INSERT INTO update_runs (id, source, status, attempt) VALUES ($1, $2, $3, COALESCE(
+                (
+                    SELECT CASE WHEN status = 'failed' THEN attempt + 1 ELSE 1 END
+                    FROM update_runs WHERE source = $2
+                    ORDER BY started_at DESC
+                    LIMIT 1
+                ),
+                1
+            ));
+            ",
+            &[&run_id, &source_id, &RunStatus::Running.as_str()],
+        )
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(err) => Err(Box::new(err)),
+    }
+}
+
+/// Closes the run. A failed one that can be `retried` gets a follow-up attempt
+/// `RUN_RETRY_DELAY_MINS` later, doubled with every attempt, until there were
+/// `RUN_RETRIES` of them. Returns when the follow-up is due.
+pub async fn finish(
+    client: &Client,
+    run_id: Uuid,
+    status: RunStatus,
+    retried: bool,
+) -> Result<Option<DateTime<Utc>>, Box<dyn std::error::Error + Send>> {
+    match client
+        .query_one(
+            "
+            UPDATE update_runs SET status = $2, finished_at = now(), retry_at = CASE
+                WHEN $2 = 'failed' AND $5 AND attempt <= $3
+                    THEN now() + make_interval(mins => $4 * (1 << (attempt - 1)))
+            END
+            WHERE id = $1
+            RETURNING retry_at;
+            ",
+            &[
+                &run_id,
+                &status.as_str(),
+                &(config::CONFIG.run_retries as i16),
+                &(config::CONFIG.run_retry_delay_mins as i32),
+                &retried,
+            ],
+        )
+        .await
+    {
+        Ok(row) => Ok(row.get(0)),
+        Err(err) => Err(Box::new(err)),
+    }
+}
+
+/// The sources whose latest run failed and has its follow-up due. It stays
+/// due until a run of the source starts, see `start`.
+pub async fn due_retries(
+    client: &Client,
+) -> Result<Vec<String>, Box<dyn std::error::Error + Send>> {
+    let rows = match client
+        .query(
+            "
+            SELECT sources.name::text FROM update_runs AS runs
+            JOIN sources ON sources.id = runs.source
+            WHERE runs.retry_at <= now()
+                AND NOT EXISTS (
+                    SELECT 1 FROM update_runs AS later
+                    WHERE later.source = runs.source AND later.started_at > runs.started_at
+                );
+            ",
+            &[],
+        )
+        .await
+    {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+#[derive(Serialize)]
+pub struct RunSummary {
+    pub id: Uuid,
+    pub source: String,
+    pub status: String,
+    pub started_at: String,
+    pub finished_at: Option<String>,
+    pub warnings: i64,
+    pub errors: i64,
+    /// 1 for a scheduled run, then counts the follow-ups of failed ones
+    pub attempt: i16,
+    pub retry_at: Option<String>,
+}
+
+/// The latest runs first, with the number of problems each one logged
+pub async fn list(
+    pool: &Pool,
+    page: Option<i64>,
+) -> Result<Vec<RunSummary>, Box<dyn std::error::Error + Send>> {
+    let client = match db::checkout(pool).await {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let rows = match client
+        .query(
+            "
+            SELECT
+                runs.id, sources.name, runs.status, runs.started_at::text, runs.finished_at::text,
+                count(*) FILTER (WHERE logs.level = 'warn'),
+                count(*) FILTER (WHERE logs.level = 'error'),
+                runs.attempt, runs.retry_at::text
+            FROM update_runs AS runs
+            JOIN sources ON sources.id = runs.source
+            LEFT JOIN update_run_logs AS logs ON logs.run_id = runs.id
+            GROUP BY runs.id, sources.name
+            ORDER BY runs.started_at DESC
+            LIMIT $1 OFFSET $2;
+            ",
+            &[&PAGE_SIZE, &page_offset(page, PAGE_SIZE)],
+        )
+        .await
+    {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    Ok(rows
+        .iter()
+        .map(|row| RunSummary {
+            id: row.get(0),
+            source: row.get(1),
+            status: row.get(2),
+            started_at: row.get(3),
+            finished_at: row.get(4),
+            warnings: row.get(5),
+            errors: row.get(6),
+            attempt: row.get(7),
+            retry_at: row.get(8),
+        })
+        .collect())
+}
+
+/// Keeps the row counts of the tables, later runs estimate their progress from them.
+pub async fn save_tables(
+    client: &Client,
+    run_id: Uuid,
+    tables: &[TableReport],
+) -> Result<(), Box<dyn std::error::Error + Send>> {
+    for table in tables.iter() {
+        match client
+            .execute(
+                "
+                INSERT INTO update_run_tables (run_id, file_name, status, rows, duration_ms)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT DO NOTHING;
+                ",
+                &[
+                    &run_id,
+                    &table.file_name,
+                    &table.status.as_str(),
+                    &(table.rows.total() as i64),
+                    &table.duration_ms.map(|v| v as i64),
+                ],
+            )
+            .await
+        {
+            Ok(_) => (),
+            Err(err) => return Err(Box::new(err)),
+        };
+    }
+
+    Ok(())
+}
+
+/// Journals the dumps of the run, later runs skip the ones that failed to parse
+pub async fn save_downloads(
+    client: &Client,
+    run_id: Uuid,
+    downloads: &HashMap<String, Download>,
+) -> Result<(), Box<dyn std::error::Error + Send>> {
+    for (file_name, download) in downloads.iter() {
+        match client
+            .execute(
+                "
+                INSERT INTO update_run_downloads
+                    (run_id, file_name, url, size, md5, duration_ms, reused, parse_failed)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                ON CONFLICT DO NOTHING;
+                ",
+                &[
+                    &run_id,
+                    file_name,
+                    &download.url,
+                    &(download.size as i64),
+                    &download.md5,
+                    &download.duration_ms.map(|v| v as i64),
+                    &download.reused,
+                    &download.parse_failed,
+                ],
+            )
+            .await
+        {
+            Ok(_) => (),
+            Err(err) => return Err(Box::new(err)),
+        };
+    }
+
+    Ok(())
+}
+
+/// The latest run of the source that failed to parse the dump with this md5
+pub async fn failed_download(
+    client: &Client,
+    source_id: i16,
+    file_name: &str,
+    md5: &str,
+) -> Result<Option<Uuid>, Box<dyn std::error::Error + Send>> {
+    match client
+        .query_opt(
+            "
+            SELECT runs.id FROM update_run_downloads AS downloads
+            JOIN update_runs AS runs ON runs.id = downloads.run_id
+            WHERE runs.source = $1 AND downloads.file_name = $2 AND downloads.md5 = $3
+                AND downloads.parse_failed
+            ORDER BY runs.started_at DESC
+            LIMIT 1;
+            ",
+            &[&source_id, &file_name, &md5],
+        )
+        .await
+    {
+        Ok(row) => Ok(row.map(|row| row.get(0))),
+        Err(err) => Err(Box::new(err)),
+    }
+}
+
+/// Row counts of the tables in their latest successful runs of the source
+pub async fn expected_rows(
+    client: &Client,
+    source_id: i16,
+) -> Result<HashMap<String, u64>, Box<dyn std::error::Error + Send>> {
+    let rows = match client
+        .query(
+            "
+            SELECT DISTINCT ON (tables.file_name) tables.file_name, tables.rows
+            FROM update_run_tables AS tables
+            JOIN update_runs AS runs ON runs.id = tables.run_id
+            WHERE runs.source = $1 AND tables.status = 'success'
+            ORDER BY tables.file_name, runs.started_at DESC;
+            ",
+            &[&source_id],
+        )
+        .await
+    {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    Ok(rows
+        .iter()
+        .map(|row| (row.get(0), row.get::<_, i64>(1) as u64))
+        .collect())
+}
+
+/// Durations of the tables in their latest successful runs of the source
+pub async fn durations(
+    client: &Client,
+    source_id: i16,
+) -> Result<HashMap<String, u64>, Box<dyn std::error::Error + Send>> {
+    let rows = match client
+        .query(
+            "
+            SELECT DISTINCT ON (tables.file_name) tables.file_name, tables.duration_ms
+            FROM update_run_tables AS tables
+            JOIN update_runs AS runs ON runs.id = tables.run_id
+            WHERE runs.source = $1 AND tables.status = 'success'
+                AND tables.duration_ms IS NOT NULL
+            ORDER BY tables.file_name, runs.started_at DESC;
+            ",
+            &[&source_id],
+        )
+        .await
+    {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    Ok(rows
+        .iter()
+        .map(|row| (row.get(0), row.get::<_, i64>(1) as u64))
+        .collect())
+}
@@ -0,0 +1,230 @@
+//! Rhai scripts rewriting fields of the parsed rows of an entity before the
+//! row plugins and the writes. `FIELD_SCRIPTS` maps entities to fields and
+//! their scripts, run in the order of the field names:
+//!
+//! ```json
+//! {"book": {"title": "value.trim(); value"}}
+//! ```
+//!
+//! A script sees the field as `value` and the whole row as the constant `row`,
+//! its result is the new value of the field. `throw` rejects the row, so does
+//! an error, a value the field can't take or a run longer than
+//! `FIELD_SCRIPT_MAX_OPERATIONS`. `POST /rejects/reprocess` brings them back
+//! after a fix.
+//!
+//! Scripts can't load modules or `eval`, and have limits on the sizes of their
+//! strings, arrays and maps.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tracing::log;
+
+use crate::config;
+use crate::updater::{self, ParsedRow};
+
+fn engine(max_operations: u64) -> Engine {
+    let mut engine = Engine::new();
+
+    engine
+        .set_module_resolver(DummyModuleResolver::new())
+        .disable_symbol("eval")
+        .set_max_operations(max_operations)
+        .set_max_call_levels(32)
+        .set_max_expr_depths(64, 32)
+        .set_max_string_size(1 << 20)
+        .set_max_array_size(10_000)
+        .set_max_map_size(1_000)
+        .on_print(|text| log::debug!("Field script: {text}"))
+        .on_debug(|text, _, position| log::debug!("Field script at {position}: {text}"));
+
+    engine
+}
+
+lazy_static! {
+    static ref ENGINE: Engine = engine(config::CONFIG.field_script_max_operations);
+
+    /// Compiled once, a script that doesn't compile stops the start
+    static ref SCRIPTS: HashMap<String, Arc<FieldScripts>> = config::CONFIG
+        .field_scripts
+        .iter()
+        .map(|(entity, fields)| match FieldScripts::compile(&ENGINE, entity, fields) {
+            Ok(scripts) => (entity.clone(), Arc::new(scripts)),
+            Err(err) => panic!("{err}"),
+        })
+        .collect();
+}
+
+/// The field scripts of an entity
+pub(crate) struct FieldScripts {
+    entity: String,
+    scripts: Vec<(String, AST)>,
+}
+
+/// Compiles the scripts of `FIELD_SCRIPTS`
+pub fn load() {
+    lazy_static::initialize(&SCRIPTS);
+}
+
+/// The field scripts of the entity of a table
+pub(crate) fn for_file(file_name: &str) -> Option<Arc<FieldScripts>> {
+    let table = updater::find_table(file_name)?;
+    SCRIPTS.get(&table.entity).cloned()
+}
+
+impl FieldScripts {
+    fn compile(
+        engine: &Engine,
+        entity: &str,
+        fields: &BTreeMap<String, String>,
+    ) -> Result<FieldScripts, String> {
+        let mut scripts = Vec::with_capacity(fields.len());
+
+        for (field, script) in fields.iter() {
+            match engine.compile(script) {
+                Ok(ast) => scripts.push((field.clone(), ast)),
+                Err(err) => {
+                    return Err(format!(
+                        "Can't compile the script of {entity}.{field}: {err}"
+                    ))
+                }
+            };
+        }
+
+        Ok(FieldScripts {
+            entity: entity.to_string(),
+            scripts,
+        })
+    }
+
+    /// The row as the scripts leave it
+    fn transform<T>(&self, engine: &Engine, row: T) -> Result<T, String>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        let mut row = match rhai::serde::to_dynamic(&row) {
+            Ok(v) => match v.try_cast::<Map>() {
+                Some(v) => v,
+                None => return Err(format!("{}: the row isn't a map", self.entity)),
+            },
+            Err(err) => return Err(err.to_string()),
+        };
+
+        for (field, ast) in self.scripts.iter() {
+            let value = match row.get(field.as_str()) {
+                Some(v) => v.clone(),
+                None => return Err(format!("{}.{field}: no such field", self.entity)),
+            };
+
+            let mut scope = Scope::new();
+            scope.push("value", value);
+            scope.push_constant("row", row.clone());
+
+            let value = match engine.eval_ast_with_scope::<Dynamic>(&mut scope, ast) {
+                Ok(v) => v,
+                Err(err) => return Err(format!("{}.{field}: {err}", self.entity)),
+            };
+
+            row.insert(field.as_str().into(), value);
+        }
+
+        match rhai::serde::from_dynamic(&Dynamic::from_map(row)) {
+            Ok(v) => Ok(v),
+            Err(err) => Err(format!("field scripts of {}: {err}", self.entity)),
+        }
+    }
+
+    pub(crate) fn apply<T>(&self, parsed: ParsedRow<T>) -> ParsedRow<T>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        match parsed {
+            ParsedRow::Row(row, issue) => match self.transform(&ENGINE, row) {
+                Ok(row) => ParsedRow::Row(row, issue),
+                Err(err) => {
+                    log::debug!("{err}");
+                    ParsedRow::Invalid(err)
+                }
+            },
+            parsed => parsed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde::{Deserialize, Serialize};
+
+    use crate::scripts::{engine, FieldScripts};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Row {
+        title: String,
+        year: u64,
+    }
+
+    fn row() -> Row {
+        Row {
+            title: "  Мастер и Маргарита ".to_string(),
+            year: 1967,
+        }
+    }
+
+    fn scripts(fields: &[(&str, &str)]) -> FieldScripts {
+        let fields: BTreeMap<String, String> = fields
+            .iter()
+            .map(|(field, script)| (field.to_string(), script.to_string()))
+            .collect();
+
+        FieldScripts::compile(&engine(10_000), "book", &fields).unwrap()
+    }
+
+    #[test]
+    fn test_transform_rewrites_fields() {
+        let input = row();
+        let expected_result = Ok(Row {
+            title: "Мастер и Маргарита (1967)".to_string(),
+            year: 1967,
+        });
+
+        let result = scripts(&[("title", "value.trim(); value + \" (\" + row.year + \")\"")])
+            .transform(&engine(10_000), input);
+
+        assert_eq!(result, expected_result);
+    }
+
+    #[test]
+    fn test_transform_throw_rejects_row() {
+        let input = row();
+
+        let result = scripts(&[("year", "if value < 2000 { throw \"too old\" } value")])
+            .transform(&engine(10_000), input);
+
+        assert!(result.unwrap_err().contains("too old"));
+    }
+
+    #[test]
+    fn test_transform_max_operations() {
+        let input = row();
+
+        let result =
+            scripts(&[("year", "let n = 0; loop { n += 1; }")]).transform(&engine(1_000), input);
+
+        assert!(result.unwrap_err().contains("Too many operations"));
+    }
+
+    #[test]
+    fn test_transform_wrong_type() {
+        let input = row();
+
+        let result = scripts(&[("year", "\"unknown\"")]).transform(&engine(10_000), input);
+
+        assert!(result.unwrap_err().starts_with("field scripts of book"));
+    }
+}
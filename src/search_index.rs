@@ -0,0 +1,153 @@
+use tokio_postgres::Client;
+use tracing::log;
+
+/// The tsvector columns: table, column and expression. Postgres keeps them up
+/// to date with the rows.
+const COLUMNS: [(&str, &str, &str); 3] = [
+    ("books", "title_tsv", "to_tsvector('russian', title)"),
+    (
+        "authors",
+        "name_tsv",
+        "to_tsvector('simple', coalesce(last_name, '') || ' ' || coalesce(first_name, '') || ' ' || coalesce(middle_name, ''))",
+    ),
+    ("sequences", "name_tsv", "to_tsvector('russian', name)"),
+];
+
+/// Name and definition of the indexes
+const INDEXES: [(&str, &str); 7] = [
+    (
+        "books_title_trgm_idx",
+        "books USING gin (title gin_trgm_ops)",
+    ),
+    (
+        "authors_last_name_trgm_idx",
+        "authors USING gin (last_name gin_trgm_ops)",
+    ),
+    (
+        "authors_first_name_trgm_idx",
+        "authors USING gin (first_name gin_trgm_ops)",
+    ),
+    (
+        "sequences_name_trgm_idx",
+        "sequences USING gin (name gin_trgm_ops)",
+    ),
+    ("books_title_tsv_idx", "books USING gin (title_tsv)"),
+    ("authors_name_tsv_idx", "authors USING gin (name_tsv)"),
+    ("sequences_name_tsv_idx", "sequences USING gin (name_tsv)"),
+];
+
+/// Adds the generated column unless it's there already: the table is locked and
+/// rewritten once, not on every run. A plain column of older versions is
+/// replaced.
+async fn add_column(
+    client: &Client,
+    table: &str,
+    column: &str,
+    expression: &str,
+) -> Result<(), Box<tokio_postgres::Error>> {
+    match client
+        .batch_execute(&format!(
+            "
+            DO $$ BEGIN
+                IF NOT EXISTS (
+                    SELECT 1 FROM pg_attribute
+                    WHERE attrelid = '{table}'::regclass AND attname = '{column}'
+                        AND attgenerated = 's' AND NOT attisdropped
+                ) THEN
+                    ALTER TABLE {table} DROP COLUMN IF EXISTS {column};
+                    ALTER TABLE {table} ADD COLUMN {column} tsvector
+                        GENERATED ALWAYS AS ({expression}) STORED;
+                END IF;
+            END $$;
+            "
+        ))
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(err) => Err(Box::new(err)),
+    }
+}
+
+/// Builds the index without blocking writes, out of a transaction. A build
+/// that failed leaves an invalid index, `IF NOT EXISTS` would keep it.
+async fn create_index(
+    client: &Client,
+    name: &str,
+    definition: &str,
+) -> Result<(), Box<tokio_postgres::Error>> {
+    let invalid: bool = match client
+        .query_one(
+            "
+            SELECT EXISTS (
+                SELECT 1 FROM pg_index JOIN pg_class ON pg_class.oid = pg_index.indexrelid
+                WHERE pg_class.oid = to_regclass($1) AND NOT pg_index.indisvalid
+            );
+            ",
+            &[&name],
+        )
+        .await
+    {
+        Ok(row) => row.get(0),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    if invalid {
+        log::warn!("Rebuild the invalid index {name}");
+
+        match client
+            .batch_execute(&format!("DROP INDEX CONCURRENTLY IF EXISTS {name};"))
+            .await
+        {
+            Ok(_) => (),
+            Err(err) => return Err(Box::new(err)),
+        };
+    }
+
+    match client
+        .batch_execute(&format!(
+            "CREATE INDEX CONCURRENTLY IF NOT EXISTS {name} ON {definition};"
+        ))
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(err) => Err(Box::new(err)),
+    }
+}
+
+pub async fn maintain(client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
+    log::info!("Refresh search indexes...");
+
+    match client
+        .batch_execute("CREATE EXTENSION IF NOT EXISTS pg_trgm;")
+        .await
+    {
+        Ok(_) => (),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    for (table, column, expression) in COLUMNS {
+        match add_column(client, table, column, expression).await {
+            Ok(_) => (),
+            Err(err) => return Err(err),
+        };
+    }
+
+    for (name, definition) in INDEXES {
+        match create_index(client, name, definition).await {
+            Ok(_) => (),
+            Err(err) => return Err(err),
+        };
+    }
+
+    match client
+        .batch_execute("ANALYZE books; ANALYZE authors; ANALYZE sequences;")
+        .await
+    {
+        Ok(_) => (),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    log::info!("Search indexes refreshed!");
+
+    Ok(())
+}
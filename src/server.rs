@@ -0,0 +1,544 @@
+use crate::auth::{Admin, Caller, ReadStatus, Trigger};
+use crate::tls::ClientCertificate;
+use crate::{
+    audit, config, db, duplicates, idempotency, jobs, opds, progress, provenance, rejects, run_log,
+    runs, stats, tls, updater,
+};
+use axum::{
+    extract::{Path, Query, Request},
+    http::{HeaderMap, HeaderName, Method, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse, Response,
+    },
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::NaiveDate;
+use futures::Stream;
+use hyper::body::Incoming;
+use hyper_util::rt::TokioIo;
+use serde::Deserialize;
+use std::os::unix::fs::PermissionsExt;
+use std::{convert::Infallible, net::SocketAddr, time::Duration};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::UnixListener;
+use tower_http::{
+    compression::CompressionLayer,
+    cors::{AllowOrigin, CorsLayer},
+    limit::RequestBodyLimitLayer,
+    trace::{self, TraceLayer},
+};
+use tower_service::Service;
+use tracing::log;
+use tracing::Level;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+struct UpdateQuery {
+    source: Option<String>,
+}
+
+fn update_running(source: &str) -> Response {
+    (
+        StatusCode::CONFLICT,
+        format!("An update of {source} is running!"),
+    )
+        .into_response()
+}
+
+async fn update(
+    caller: Caller<Trigger>,
+    headers: HeaderMap,
+    Query(query): Query<UpdateQuery>,
+) -> Response {
+    let source = match config::CONFIG.source(query.source.as_deref()) {
+        Some(v) => v,
+        None => return "Unknown source!".into_response(),
+    };
+
+    let key = match headers.get("Idempotency-Key").map(|key| key.to_str()) {
+        Some(Ok(v)) => Some(v),
+        Some(Err(_)) => return "Wrong idempotency key!".into_response(),
+        None => None,
+    };
+
+    if let Some(original_run_id) = key.and_then(idempotency::get) {
+        return format!("Update started: {original_run_id}").into_response();
+    }
+
+    let lock = match updater::lock(source) {
+        Some(v) => v,
+        None => return update_running(&source.name),
+    };
+
+    let run_id = Uuid::new_v4();
+
+    if let Some(key) = key {
+        let (original_run_id, seen) = idempotency::get_or_insert(key, run_id);
+
+        if seen {
+            return format!("Update started: {original_run_id}").into_response();
+        }
+    }
+
+    let value = format!("{} {run_id}", source.name);
+    audit::record(&db::POOL, &caller.name, "update", Some(value)).await;
+
+    tokio::spawn(async move {
+        match updater::update_locked(run_id, source, lock).await {
+            Ok(report) => log::info!("Updated: {}", report.status.as_str()),
+            Err(err) => log::info!("Updater err: {:?}", err),
+        };
+    });
+
+    format!("Update started: {run_id}").into_response()
+}
+
+#[derive(Deserialize)]
+struct ReplayQuery {
+    source: Option<String>,
+    date: NaiveDate,
+}
+
+async fn replay(caller: Caller<Trigger>, Query(query): Query<ReplayQuery>) -> Response {
+    let source = match config::CONFIG.source(query.source.as_deref()) {
+        Some(v) => v,
+        None => return (StatusCode::NOT_FOUND, "Unknown source!").into_response(),
+    };
+
+    let source = match updater::replay_source(source, query.date) {
+        Ok(v) => v,
+        Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    };
+
+    let run_id = Uuid::new_v4();
+
+    let value = format!("{} {run_id}", source.name);
+    audit::record(&db::POOL, &caller.name, "replay", Some(value)).await;
+
+    tokio::spawn(async move {
+        match updater::replay(run_id, source).await {
+            Ok(report) => log::info!("Replayed {}: {}", source.name, report.status.as_str()),
+            Err(err) => log::info!("Replay {} err: {:?}", source.name, err),
+        };
+    });
+
+    format!("Replay started: {run_id} into {}", source.name).into_response()
+}
+
+async fn update_table(
+    caller: Caller<Trigger>,
+    Path(name): Path<String>,
+    Query(query): Query<UpdateQuery>,
+) -> Response {
+    let source = match config::CONFIG.source(query.source.as_deref()) {
+        Some(v) => v,
+        None => return (StatusCode::NOT_FOUND, "Unknown source!").into_response(),
+    };
+
+    let table = match updater::find_table(&name) {
+        Some(v) => v,
+        None => return (StatusCode::NOT_FOUND, "Unknown table!").into_response(),
+    };
+
+    let lock = match updater::lock(source) {
+        Some(v) => v,
+        None => return update_running(&source.name),
+    };
+
+    let run_id = Uuid::new_v4();
+
+    let value = format!("{} {} {run_id}", source.name, table.file);
+    audit::record(&db::POOL, &caller.name, "update_table", Some(value)).await;
+
+    tokio::spawn(async move {
+        match updater::update_table(run_id, source, table, lock).await {
+            Ok(report) => log::info!("Updated {}: {}", table.file, report.status.as_str()),
+            Err(err) => log::info!("Update {} err: {:?}", table.file, err),
+        };
+    });
+
+    format!("Update of {} started: {run_id}", table.file).into_response()
+}
+
+#[derive(Deserialize)]
+struct PreviewQuery {
+    source: Option<String>,
+    /// A file or entity name, all tables if missing
+    table: Option<String>,
+    /// Sample rows per table and outcome
+    samples: Option<usize>,
+}
+
+/// Samples per table and outcome when the query doesn't say
+const PREVIEW_SAMPLES: usize = 5;
+
+async fn preview(caller: Caller<Trigger>, Query(query): Query<PreviewQuery>) -> Response {
+    let source = match config::CONFIG.source(query.source.as_deref()) {
+        Some(v) => v,
+        None => return (StatusCode::NOT_FOUND, "Unknown source!").into_response(),
+    };
+
+    let table = match query.table.as_deref().map(updater::find_table) {
+        Some(Some(v)) => Some(v),
+        Some(None) => return (StatusCode::NOT_FOUND, "Unknown table!").into_response(),
+        None => None,
+    };
+
+    let value = match table {
+        Some(table) => format!("{} {}", source.name, table.file),
+        None => source.name.clone(),
+    };
+    audit::record(&db::POOL, &caller.name, "preview", Some(value)).await;
+
+    let samples = query.samples.unwrap_or(PREVIEW_SAMPLES).min(100);
+
+    match updater::preview(source, table, samples).await {
+        Ok(v) => Json(v).into_response(),
+        Err(err) => {
+            log::error!("Can't preview the update: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+async fn pause(caller: Caller<Admin>) -> &'static str {
+    updater::pause();
+
+    audit::record(&db::POOL, &caller.name, "pause", None).await;
+
+    "Update paused"
+}
+
+async fn resume(caller: Caller<Admin>) -> &'static str {
+    updater::resume();
+
+    audit::record(&db::POOL, &caller.name, "resume", None).await;
+
+    "Update resumed"
+}
+
+async fn get_stats(_: Caller<ReadStatus>) -> Response {
+    match stats::get(&db::READ_POOL).await {
+        Ok(v) => Json(v).into_response(),
+        Err(err) => {
+            log::error!("Can't get stats: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PageQuery {
+    page: Option<i64>,
+}
+
+async fn get_duplicates(_: Caller<ReadStatus>, Query(query): Query<PageQuery>) -> Response {
+    match duplicates::list(&db::READ_POOL, query.page).await {
+        Ok(v) => Json(v).into_response(),
+        Err(err) => {
+            log::error!("Can't get duplicates: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+async fn dismiss_duplicate(
+    caller: Caller<Admin>,
+    Path((book_a, book_b)): Path<(i32, i32)>,
+) -> Response {
+    match duplicates::dismiss(&db::POOL, book_a, book_b).await {
+        Ok(true) => {
+            let pair = format!("{book_a},{book_b}");
+            audit::record(&db::POOL, &caller.name, "dismiss_duplicate", Some(pair)).await;
+
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            log::error!("Can't dismiss duplicate: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+async fn get_runs(_: Caller<ReadStatus>, Query(query): Query<PageQuery>) -> Response {
+    match runs::list(&db::READ_POOL, query.page).await {
+        Ok(v) => Json(v).into_response(),
+        Err(err) => {
+            log::error!("Can't get runs: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+async fn get_run_logs(
+    _: Caller<ReadStatus>,
+    Path(run_id): Path<Uuid>,
+    Query(query): Query<PageQuery>,
+) -> Response {
+    match run_log::list(&db::READ_POOL, run_id, query.page).await {
+        Ok(v) => Json(v).into_response(),
+        Err(err) => {
+            log::error!("Can't get run logs: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+async fn get_book_provenance(_: Caller<ReadStatus>, Path(id): Path<i32>) -> Response {
+    match provenance::book(&db::READ_POOL, id).await {
+        Ok(Some(v)) => Json(v).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "Unknown book!").into_response(),
+        Err(err) => {
+            log::error!("Can't get the provenance of book {id}: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+async fn get_rejects(
+    _: Caller<ReadStatus>,
+    Query(filter): Query<rejects::RejectsFilter>,
+) -> Response {
+    match rejects::list(&db::READ_POOL, &filter).await {
+        Ok(v) => Json(v).into_response(),
+        Err(err) => {
+            log::error!("Can't get rejects: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ReprocessQuery {
+    source: Option<String>,
+    file_name: Option<String>,
+}
+
+async fn reprocess_rejects(
+    caller: Caller<Trigger>,
+    Query(query): Query<ReprocessQuery>,
+) -> Response {
+    let source = match config::CONFIG.source(query.source.as_deref()) {
+        Some(v) => v,
+        None => return (StatusCode::NOT_FOUND, "Unknown source!").into_response(),
+    };
+
+    let value = match &query.file_name {
+        Some(file_name) => format!("{} {file_name}", source.name),
+        None => source.name.clone(),
+    };
+    audit::record(&db::POOL, &caller.name, "reprocess_rejects", Some(value)).await;
+
+    match updater::reprocess_rejects(source, query.file_name).await {
+        Ok(v) => Json(v).into_response(),
+        Err(err) => {
+            log::error!("Can't reprocess rejects: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// The page holds no data, it calls the API with the key typed in
+async fn admin_page() -> Html<&'static str> {
+    Html(include_str!("../assets/admin.html"))
+}
+
+async fn get_metrics(_: Caller<ReadStatus>) -> String {
+    db::pool_metrics()
+}
+
+async fn get_status(_: Caller<ReadStatus>) -> Json<progress::Progress> {
+    Json(progress::snapshot())
+}
+
+async fn get_schedule(_: Caller<ReadStatus>) -> Json<Vec<jobs::JobStatus>> {
+    Json(jobs::list().await)
+}
+
+async fn status_stream(
+    _: Caller<ReadStatus>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let mut receiver = progress::subscribe();
+    receiver.mark_changed();
+
+    let stream = futures::stream::unfold(receiver, |mut receiver| async move {
+        if receiver.changed().await.is_err() {
+            return None;
+        }
+
+        let data = receiver.borrow_and_update().clone();
+        let event = match Event::default().json_data(data) {
+            Ok(v) => v,
+            Err(_) => Event::default().comment("serialization error"),
+        };
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        Some((Ok(event), receiver))
+    });
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+fn cors_layer() -> CorsLayer {
+    let origins = &config::CONFIG.cors_allowed_origins;
+
+    let allow_origin = if origins.iter().any(|v| v == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(origins.iter().map(|v| v.parse().unwrap()))
+    };
+
+    CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods(
+            config::CONFIG
+                .cors_allowed_methods
+                .iter()
+                .map(|v| v.parse::<Method>().unwrap())
+                .collect::<Vec<_>>(),
+        )
+        .allow_headers(
+            config::CONFIG
+                .cors_allowed_headers
+                .iter()
+                .map(|v| v.parse::<HeaderName>().unwrap())
+                .collect::<Vec<_>>(),
+        )
+}
+
+/// Serves HTTP/1 on an accepted connection, for listeners `axum::serve` can't take.
+/// The client certificate is passed on to the handlers as a request extension.
+pub async fn serve_connection<I>(
+    io: I,
+    app: Router,
+    certificate: Option<ClientCertificate>,
+) -> Result<(), hyper::Error>
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = hyper::service::service_fn(move |mut request: Request<Incoming>| {
+        if let Some(certificate) = &certificate {
+            request.extensions_mut().insert(certificate.clone());
+        }
+
+        app.clone().call(request)
+    });
+
+    hyper::server::conn::http1::Builder::new()
+        .serve_connection(TokioIo::new(io), service)
+        .await
+}
+
+/// Only nginx on the same host needs to reach the socket, hence no TLS here
+async fn serve_unix(path: &str, app: Router) {
+    // A socket left by a previous process would fail the bind
+    if std::fs::metadata(path).is_ok() {
+        std::fs::remove_file(path).unwrap();
+    }
+
+    let listener = UnixListener::bind(path).unwrap();
+
+    std::fs::set_permissions(
+        path,
+        std::fs::Permissions::from_mode(config::CONFIG.unix_socket_mode),
+    )
+    .unwrap();
+
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(v) => v,
+            Err(err) => {
+                log::error!("Can't accept a connection: {err}");
+                continue;
+            }
+        };
+
+        let app = app.clone();
+
+        tokio::spawn(async move {
+            if let Err(err) = serve_connection(stream, app, None).await {
+                log::warn!("Connection on the unix socket failed: {err}");
+            }
+        });
+    }
+}
+
+pub async fn start_app() {
+    let mut app = Router::new()
+        .route("/update", post(update))
+        .route("/update/replay", post(replay))
+        .route("/update/preview", post(preview))
+        .route("/update/:table", post(update_table))
+        .route("/update/pause", post(pause))
+        .route("/update/resume", post(resume))
+        .route("/stats", get(get_stats))
+        .route("/duplicates", get(get_duplicates))
+        .route(
+            "/duplicates/:book_a/:book_b/dismiss",
+            post(dismiss_duplicate),
+        )
+        .route("/runs", get(get_runs))
+        .route("/runs/:id/logs", get(get_run_logs))
+        .route("/provenance/book/:id", get(get_book_provenance))
+        .route("/rejects", get(get_rejects))
+        .route("/rejects/reprocess", post(reprocess_rejects))
+        .route("/status", get(get_status))
+        .route("/schedule", get(get_schedule))
+        .route("/metrics", get(get_metrics))
+        .route("/status/stream", get(status_stream))
+        .route("/admin", get(admin_page));
+
+    if config::CONFIG.opds_enabled {
+        app = app.merge(opds::router());
+    }
+
+    if !config::CONFIG.base_path.is_empty() {
+        app = Router::new().nest(&config::CONFIG.base_path, app);
+    }
+
+    if !config::CONFIG.cors_allowed_origins.is_empty() {
+        app = app.layer(cors_layer());
+    }
+
+    // Event streams are left uncompressed by the default predicate
+    let app = app
+        .layer(CompressionLayer::new().gzip(true).br(true))
+        .layer(RequestBodyLimitLayer::new(
+            config::CONFIG.max_request_body_bytes,
+        ))
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(trace::DefaultMakeSpan::new().level(Level::INFO))
+                .on_response(trace::DefaultOnResponse::new().level(Level::INFO)),
+        );
+
+    log::info!("Start webserver...");
+
+    if let Some(path) = &config::CONFIG.unix_socket_path {
+        serve_unix(path, app).await;
+        log::info!("Webserver shutdown...");
+        return;
+    }
+
+    let addr = SocketAddr::from(([0, 0, 0, 0], 8080));
+    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
+
+    match (&config::CONFIG.tls_cert_path, &config::CONFIG.tls_key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            let tls_config = tls::server_config(
+                cert_path,
+                key_path,
+                config::CONFIG.tls_client_ca_path.as_deref(),
+            )
+            .unwrap();
+
+            tls::serve(listener, app, tls_config).await;
+        }
+        _ => axum::serve(listener, app).await.unwrap(),
+    };
+    log::info!("Webserver shutdown...")
+}
@@ -0,0 +1,370 @@
+use deadpool_postgres::Object;
+use tokio::sync::Mutex;
+use tokio_postgres::Client;
+use tracing::log;
+
+use crate::config;
+
+/// Shadow imports write copies of the live tables here, readers keep seeing the
+/// live ones until the swap
+pub const SCHEMA: &str = "library_updater_shadow";
+
+/// The live tables replaced by the last swap, a rollback brings them back
+pub const PREVIOUS_SCHEMA: &str = "library_updater_previous";
+
+lazy_static! {
+    /// Tables copied to `SCHEMA` for the current import
+    static ref TABLES: Mutex<Vec<&'static str>> = Mutex::new(vec![]);
+
+    /// The shadow tables hold the rows of all sources, one import at a time
+    pub static ref LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// A catalog schema per run takes the place of the shadow tables, see `catalogs`
+pub fn enabled() -> bool {
+    config::CONFIG.shadow_import && config::CONFIG.full_sync && !config::CONFIG.catalog_schemas
+}
+
+/// Foreign key of or to a swapped table, as the live catalog has it
+struct ForeignKey {
+    name: String,
+    table: String,
+    definition: String,
+    /// Whether `table` is swapped too, otherwise the key is moved over
+    swapped: bool,
+}
+
+/// Drops what's left of an earlier shadow import
+pub async fn start(client: &Client) -> Result<(), Box<dyn std::error::Error + Send>> {
+    TABLES.lock().await.clear();
+
+    match client
+        .batch_execute(&format!(
+            "DROP SCHEMA IF EXISTS {SCHEMA} CASCADE; CREATE SCHEMA {SCHEMA};"
+        ))
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(err) => Err(Box::new(err)),
+    }
+}
+
+/// Copies the live tables to the shadow schema with their rows, indexes and
+/// triggers, the ones copied before are left as they are. Columns keep the live
+/// sequences, ids don't change.
+pub async fn prepare(
+    client: &Client,
+    tables: &[&'static str],
+) -> Result<(), Box<dyn std::error::Error + Send>> {
+    let mut prepared = TABLES.lock().await;
+
+    for table in tables {
+        if prepared.contains(table) {
+            continue;
+        }
+
+        match copy_table(client, table, "public", SCHEMA).await {
+            Ok(_) => (),
+            Err(err) => return Err(err),
+        };
+
+        prepared.push(table);
+    }
+
+    Ok(())
+}
+
+/// Copies `table` of `from` to `to` with its rows, indexes and triggers
+pub(crate) async fn copy_table(
+    client: &Client,
+    table: &str,
+    from: &str,
+    to: &str,
+) -> Result<(), Box<dyn std::error::Error + Send>> {
+    log::info!("Copy {table} to {to}...");
+
+    // Generated columns can't be inserted, the copy computes them again
+    let columns: String = match client
+        .query_one(
+            "
+            SELECT string_agg(quote_ident(attname), ', ' ORDER BY attnum) FROM pg_attribute
+            WHERE attrelid = $1::text::regclass AND attnum > 0
+                AND NOT attisdropped AND attgenerated = '';
+            ",
+            &[&format!("{from}.{table}")],
+        )
+        .await
+    {
+        Ok(row) => row.get(0),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    match client
+        .batch_execute(&format!(
+            "
+            CREATE TABLE {to}.{table} (LIKE {from}.{table} INCLUDING ALL);
+            INSERT INTO {to}.{table} ({columns}) OVERRIDING SYSTEM VALUE
+                SELECT {columns} FROM {from}.{table};
+            "
+        ))
+        .await
+    {
+        Ok(_) => (),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    // `LIKE` leaves the triggers out
+    let triggers = match client
+        .query(
+            "
+            SELECT pg_get_triggerdef(oid) FROM pg_trigger
+            WHERE tgrelid = $1::text::regclass AND NOT tgisinternal;
+            ",
+            &[&format!("{from}.{table}")],
+        )
+        .await
+    {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    for row in triggers {
+        let definition: String = row.get(0);
+        let definition = definition.replace(
+            &format!(" ON {from}.{table} "),
+            &format!(" ON {to}.{table} "),
+        );
+
+        match client.batch_execute(&definition).await {
+            Ok(_) => (),
+            Err(err) => return Err(Box::new(err)),
+        };
+    }
+
+    Ok(())
+}
+
+/// Replaces the live tables with the shadow ones in one transaction, the live
+/// ones go to `PREVIOUS_SCHEMA`
+pub async fn swap(client: &mut Object) -> Result<(), Box<dyn std::error::Error + Send>> {
+    let tables = TABLES.lock().await.clone();
+    if tables.is_empty() {
+        return Ok(());
+    }
+
+    match replace(client, &tables, SCHEMA, PREVIOUS_SCHEMA).await {
+        Ok(_) => (),
+        Err(err) => return Err(err),
+    };
+
+    TABLES.lock().await.clear();
+
+    Ok(())
+}
+
+/// Brings back the tables replaced by the last swap, the replacing ones go to
+/// the shadow schema. Returns the restored tables.
+pub async fn rollback(
+    client: &mut Object,
+) -> Result<Vec<String>, Box<dyn std::error::Error + Send>> {
+    let _lock = LOCK.lock().await;
+
+    let rows = match client
+        .query(
+            "SELECT table_name::text FROM information_schema.tables WHERE table_schema = $1;",
+            &[&PREVIOUS_SCHEMA],
+        )
+        .await
+    {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let tables: Vec<String> = rows.iter().map(|row| row.get(0)).collect();
+    if tables.is_empty() {
+        return Ok(tables);
+    }
+
+    let names: Vec<&str> = tables.iter().map(String::as_str).collect();
+    match replace(client, &names, PREVIOUS_SCHEMA, SCHEMA).await {
+        Ok(_) => Ok(tables),
+        Err(err) => Err(err),
+    }
+}
+
+/// Moves `tables` of `from` into `public` and the ones they replace to `to`.
+/// Foreign keys of the live tables are added to the new ones beforehand, keys
+/// of other tables are moved over to them. Views keep the replaced tables.
+async fn replace(
+    client: &mut Object,
+    tables: &[&str],
+    from: &str,
+    to: &str,
+) -> Result<(), Box<dyn std::error::Error + Send>> {
+    log::info!("Swap {} into public from {from}...", tables.join(", "));
+
+    let keys = match foreign_keys(client, tables).await {
+        Ok(v) => v,
+        Err(err) => return Err(err),
+    };
+
+    // Validated before the swap, so the live tables aren't locked meanwhile
+    let transaction = match client.transaction().await {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    match transaction
+        .batch_execute(&format!("SET LOCAL search_path = {from}, public;"))
+        .await
+    {
+        Ok(_) => (),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    for key in keys.iter().filter(|key| key.swapped) {
+        match transaction
+            .batch_execute(&format!(
+                "
+                DO $$ BEGIN
+                    ALTER TABLE {from}.{} ADD CONSTRAINT {} {};
+                EXCEPTION
+                    WHEN duplicate_object THEN NULL;
+                END $$;
+                ",
+                key.table, key.name, key.definition
+            ))
+            .await
+        {
+            Ok(_) => (),
+            Err(err) => return Err(Box::new(err)),
+        };
+    }
+
+    match transaction.commit().await {
+        Ok(_) => (),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let transaction = match client.transaction().await {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let mut statements = format!("DROP SCHEMA IF EXISTS {to} CASCADE; CREATE SCHEMA {to};");
+
+    // A sequence belongs to a table of its own schema, the new table takes it over after the move
+    let mut sequences = vec![];
+    for table in tables {
+        let rows = match transaction
+            .query(
+                "
+                SELECT s.oid::regclass::text, quote_ident(a.attname) FROM pg_depend d
+                JOIN pg_class s ON s.oid = d.objid AND s.relkind = 'S'
+                JOIN pg_attribute a ON a.attrelid = d.refobjid AND a.attnum = d.refobjsubid
+                WHERE d.refobjid = $1::text::regclass AND d.deptype = 'a';
+                ",
+                &[&format!("public.{table}")],
+            )
+            .await
+        {
+            Ok(v) => v,
+            Err(err) => return Err(Box::new(err)),
+        };
+
+        for row in rows {
+            let (sequence, column): (String, String) = (row.get(0), row.get(1));
+            statements.push_str(&format!("ALTER SEQUENCE {sequence} OWNED BY NONE;"));
+            sequences.push(format!(
+                "ALTER SEQUENCE {sequence} OWNED BY public.{table}.{column};"
+            ));
+        }
+    }
+
+    for key in keys.iter().filter(|key| !key.swapped) {
+        statements.push_str(&format!(
+            "ALTER TABLE {} DROP CONSTRAINT {};",
+            key.table, key.name
+        ));
+    }
+
+    for table in tables {
+        statements.push_str(&format!(
+            "ALTER TABLE public.{table} SET SCHEMA {to}; ALTER TABLE {from}.{table} SET SCHEMA public;"
+        ));
+    }
+
+    statements.push_str(&sequences.concat());
+
+    // Checked after the commit, the swap shouldn't wait for it
+    for key in keys.iter().filter(|key| !key.swapped) {
+        statements.push_str(&format!(
+            "ALTER TABLE {} ADD CONSTRAINT {} {} NOT VALID;",
+            key.table, key.name, key.definition
+        ));
+    }
+
+    match transaction.batch_execute(&statements).await {
+        Ok(_) => (),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    match transaction.commit().await {
+        Ok(_) => (),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    for key in keys.iter().filter(|key| !key.swapped) {
+        if let Err(err) = client
+            .batch_execute(&format!(
+                "ALTER TABLE {} VALIDATE CONSTRAINT {};",
+                key.table, key.name
+            ))
+            .await
+        {
+            log::error!("Can't validate {} of {}: {:?}", key.name, key.table, err);
+        }
+    }
+
+    log::info!("Swapped {} tables", tables.len());
+
+    Ok(())
+}
+
+/// Foreign keys of the live `tables` and of other tables to them
+async fn foreign_keys(
+    client: &Client,
+    tables: &[&str],
+) -> Result<Vec<ForeignKey>, Box<dyn std::error::Error + Send>> {
+    let rows = match client
+        .query(
+            "
+            WITH swapped AS (
+                SELECT c.oid FROM pg_class c
+                JOIN pg_namespace n ON n.oid = c.relnamespace
+                WHERE n.nspname = 'public' AND c.relname = ANY($1)
+            )
+            SELECT quote_ident(conname), conrelid::regclass::text, pg_get_constraintdef(oid),
+                conrelid IN (SELECT oid FROM swapped)
+            FROM pg_constraint
+            WHERE contype = 'f'
+                AND (conrelid IN (SELECT oid FROM swapped) OR confrelid IN (SELECT oid FROM swapped));
+            ",
+            &[&tables],
+        )
+        .await
+    {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    Ok(rows
+        .iter()
+        .map(|row| ForeignKey {
+            name: row.get(0),
+            table: row.get(1),
+            definition: row.get(2),
+            swapped: row.get(3),
+        })
+        .collect())
+}
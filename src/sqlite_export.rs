@@ -0,0 +1,312 @@
+use deadpool_postgres::Pool;
+use futures::{pin_mut, TryStreamExt};
+use rusqlite::{params_from_iter, types::Value, Connection};
+use tracing::log;
+
+use crate::config;
+use crate::db;
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Integer,
+    Boolean,
+    Text,
+}
+
+struct TableSpec {
+    name: &'static str,
+    columns: &'static [(&'static str, Kind)],
+}
+
+const TABLES: [TableSpec; 8] = [
+    TableSpec {
+        name: "authors",
+        columns: &[
+            ("id", Kind::Integer),
+            ("remote_id", Kind::Integer),
+            ("first_name", Kind::Text),
+            ("last_name", Kind::Text),
+            ("middle_name", Kind::Text),
+        ],
+    },
+    TableSpec {
+        name: "books",
+        columns: &[
+            ("id", Kind::Integer),
+            ("remote_id", Kind::Integer),
+            ("title", Kind::Text),
+            ("lang", Kind::Text),
+            ("file_type", Kind::Text),
+            ("uploaded", Kind::Text),
+            ("is_deleted", Kind::Boolean),
+            ("pages", Kind::Integer),
+            ("year", Kind::Integer),
+        ],
+    },
+    TableSpec {
+        name: "sequences",
+        columns: &[
+            ("id", Kind::Integer),
+            ("remote_id", Kind::Integer),
+            ("name", Kind::Text),
+        ],
+    },
+    TableSpec {
+        name: "genres",
+        columns: &[
+            ("id", Kind::Integer),
+            ("remote_id", Kind::Integer),
+            ("code", Kind::Text),
+            ("description", Kind::Text),
+            ("meta", Kind::Text),
+        ],
+    },
+    TableSpec {
+        name: "book_authors",
+        columns: &[("book", Kind::Integer), ("author", Kind::Integer)],
+    },
+    TableSpec {
+        name: "translations",
+        columns: &[
+            ("book", Kind::Integer),
+            ("author", Kind::Integer),
+            ("position", Kind::Integer),
+        ],
+    },
+    TableSpec {
+        name: "book_sequences",
+        columns: &[
+            ("book", Kind::Integer),
+            ("sequence", Kind::Integer),
+            ("position", Kind::Integer),
+        ],
+    },
+    TableSpec {
+        name: "book_genres",
+        columns: &[("book", Kind::Integer), ("genre", Kind::Integer)],
+    },
+];
+
+const BATCH_SIZE: usize = 10_000;
+
+impl TableSpec {
+    fn select_query(&self) -> String {
+        let columns: Vec<String> = self
+            .columns
+            .iter()
+            .map(|(name, kind)| match kind {
+                Kind::Integer => format!("cast({name} as bigint)"),
+                Kind::Boolean => format!("cast(cast({name} as int) as bigint)"),
+                Kind::Text => format!("cast({name} as text)"),
+            })
+            .collect();
+
+        format!("SELECT {} FROM {};", columns.join(", "), self.name)
+    }
+
+    fn create_query(&self) -> String {
+        let columns: Vec<String> = self
+            .columns
+            .iter()
+            .map(|(name, kind)| match kind {
+                Kind::Integer | Kind::Boolean => format!("{name} INTEGER"),
+                Kind::Text => format!("{name} TEXT"),
+            })
+            .collect();
+
+        format!("CREATE TABLE {} ({});", self.name, columns.join(", "))
+    }
+
+    fn insert_query(&self) -> String {
+        let names: Vec<&str> = self.columns.iter().map(|(name, _)| *name).collect();
+        let placeholders: Vec<String> = (1..=names.len()).map(|i| format!("?{i}")).collect();
+
+        format!(
+            "INSERT INTO {} ({}) VALUES ({});",
+            self.name,
+            names.join(", "),
+            placeholders.join(", ")
+        )
+    }
+}
+
+fn write_batch(
+    mut connection: Connection,
+    query: String,
+    rows: Vec<Vec<Value>>,
+) -> Result<Connection, rusqlite::Error> {
+    let transaction = connection.transaction()?;
+
+    {
+        let mut statement = transaction.prepare_cached(&query)?;
+        for row in rows.into_iter() {
+            statement.execute(params_from_iter(row))?;
+        }
+    }
+
+    transaction.commit()?;
+
+    Ok(connection)
+}
+
+/// Runs a call of SQLite on the blocking pool, it does disk IO
+async fn blocking<F, T>(f: F) -> Result<T, Box<dyn std::error::Error + Send>>
+where
+    F: FnOnce() -> Result<T, rusqlite::Error> + Send + 'static,
+    T: Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(Ok(v)) => Ok(v),
+        Ok(Err(err)) => Err(Box::new(err)),
+        Err(err) => Err(Box::new(err)),
+    }
+}
+
+async fn write(
+    connection: Connection,
+    query: String,
+    rows: Vec<Vec<Value>>,
+) -> Result<Connection, Box<dyn std::error::Error + Send>> {
+    blocking(move || write_batch(connection, query, rows)).await
+}
+
+async fn export_table(
+    pool: &Pool,
+    connection: Connection,
+    spec: &TableSpec,
+) -> Result<Connection, Box<dyn std::error::Error + Send>> {
+    let create_query = spec.create_query();
+    let mut connection = match blocking(move || {
+        connection.execute(&create_query, [])?;
+        Ok(connection)
+    })
+    .await
+    {
+        Ok(v) => v,
+        Err(err) => return Err(err),
+    };
+
+    let client = match db::checkout(pool).await {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let stream = match client
+        .query_raw(&spec.select_query(), Vec::<i16>::new())
+        .await
+    {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+    pin_mut!(stream);
+
+    let insert_query = spec.insert_query();
+    let mut batch: Vec<Vec<Value>> = Vec::with_capacity(BATCH_SIZE);
+    let mut total = 0;
+
+    loop {
+        let row = match stream.try_next().await {
+            Ok(v) => v,
+            Err(err) => return Err(Box::new(err)),
+        };
+
+        let row = match row {
+            Some(v) => v,
+            None => break,
+        };
+
+        let values: Vec<Value> = spec
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(index, (_, kind))| match kind {
+                Kind::Integer | Kind::Boolean => match row.get::<_, Option<i64>>(index) {
+                    Some(v) => Value::Integer(v),
+                    None => Value::Null,
+                },
+                Kind::Text => match row.get::<_, Option<String>>(index) {
+                    Some(v) => Value::Text(v),
+                    None => Value::Null,
+                },
+            })
+            .collect();
+
+        batch.push(values);
+
+        if batch.len() >= BATCH_SIZE {
+            total += batch.len();
+            connection = match write(connection, insert_query.clone(), batch).await {
+                Ok(v) => v,
+                Err(err) => return Err(err),
+            };
+            batch = Vec::with_capacity(BATCH_SIZE);
+        }
+    }
+
+    total += batch.len();
+    connection = match write(connection, insert_query, batch).await {
+        Ok(v) => v,
+        Err(err) => return Err(err),
+    };
+
+    log::info!("Exported {total} rows of {}", spec.name);
+
+    Ok(connection)
+}
+
+pub async fn export(pool: &Pool) -> Result<(), Box<dyn std::error::Error + Send>> {
+    let path = match &config::CONFIG.sqlite_export_path {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+
+    log::info!("Export SQLite snapshot to {path}...");
+
+    let tmp_path = format!("{path}.tmp");
+
+    match tokio::fs::remove_file(&tmp_path).await {
+        Ok(_) => (),
+        Err(err) => log::debug!("Can't remove file: {:?}", err),
+    };
+
+    let open_path = tmp_path.clone();
+    let mut connection = match blocking(move || Connection::open(open_path)).await {
+        Ok(v) => v,
+        Err(err) => return Err(err),
+    };
+
+    for spec in TABLES.iter() {
+        connection = match export_table(pool, connection, spec).await {
+            Ok(v) => v,
+            Err(err) => return Err(err),
+        };
+    }
+
+    // Closing flushes the file too
+    match blocking(move || {
+        connection.execute_batch(
+            "
+            CREATE INDEX books_remote_id_idx ON books (remote_id);
+            CREATE INDEX authors_remote_id_idx ON authors (remote_id);
+            CREATE INDEX book_authors_book_idx ON book_authors (book);
+            CREATE INDEX book_sequences_book_idx ON book_sequences (book);
+            CREATE INDEX book_genres_book_idx ON book_genres (book);
+            ",
+        )?;
+        connection.close().map_err(|(_, err)| err)
+    })
+    .await
+    {
+        Ok(_) => (),
+        Err(err) => return Err(err),
+    };
+
+    match tokio::fs::rename(&tmp_path, path).await {
+        Ok(_) => (),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    log::info!("SQLite snapshot exported!");
+
+    Ok(())
+}
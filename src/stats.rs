@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+
+use chrono::Utc;
+use deadpool_postgres::Pool;
+use serde::Serialize;
+use tokio::sync::RwLock;
+use tracing::log;
+
+use crate::db;
+
+#[derive(Serialize, Clone)]
+pub struct Stats {
+    pub computed_at: String,
+    pub books_active: i64,
+    pub books_deleted: i64,
+    pub books_deleted_by_reason: HashMap<String, i64>,
+    pub books_by_lang: HashMap<String, i64>,
+    pub authors: i64,
+    pub sequences: i64,
+    pub genres: i64,
+    pub book_annotations: i64,
+    pub author_annotations: i64,
+}
+
+lazy_static! {
+    static ref STATS: RwLock<Option<Stats>> = RwLock::new(None);
+}
+
+async fn compute(pool: &Pool) -> Result<Stats, Box<dyn std::error::Error + Send>> {
+    let client = match db::checkout(pool).await {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let counts = match client
+        .query_one(
+            "
+            SELECT
+                (SELECT count(*) FROM books WHERE NOT is_deleted),
+                (SELECT count(*) FROM books WHERE is_deleted),
+                (SELECT count(*) FROM authors),
+                (SELECT count(*) FROM sequences),
+                (SELECT count(*) FROM genres),
+                (SELECT count(*) FROM book_annotations),
+                (SELECT count(*) FROM author_annotations);
+            ",
+            &[],
+        )
+        .await
+    {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let langs = match client
+        .query(
+            "SELECT lang, count(*) FROM books WHERE NOT is_deleted GROUP BY lang;",
+            &[],
+        )
+        .await
+    {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let deleted_reasons = match client
+        .query(
+            "
+            SELECT COALESCE(deleted_reason::text, 'unknown'), count(*) FROM books
+            WHERE is_deleted GROUP BY 1;
+            ",
+            &[],
+        )
+        .await
+    {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    Ok(Stats {
+        computed_at: Utc::now().to_rfc3339(),
+        books_active: counts.get(0),
+        books_deleted: counts.get(1),
+        books_deleted_by_reason: deleted_reasons
+            .iter()
+            .map(|row| (row.get::<_, String>(0), row.get::<_, i64>(1)))
+            .collect(),
+        books_by_lang: langs
+            .iter()
+            .map(|row| (row.get::<_, String>(0), row.get::<_, i64>(1)))
+            .collect(),
+        authors: counts.get(2),
+        sequences: counts.get(3),
+        genres: counts.get(4),
+        book_annotations: counts.get(5),
+        author_annotations: counts.get(6),
+    })
+}
+
+pub async fn refresh(pool: &Pool) -> Result<Stats, Box<dyn std::error::Error + Send>> {
+    let stats = match compute(pool).await {
+        Ok(v) => v,
+        Err(err) => return Err(err),
+    };
+
+    *STATS.write().await = Some(stats.clone());
+
+    log::info!("Stats refreshed!");
+
+    Ok(stats)
+}
+
+pub async fn get(pool: &Pool) -> Result<Stats, Box<dyn std::error::Error + Send>> {
+    if let Some(stats) = STATS.read().await.as_ref() {
+        return Ok(stats.clone());
+    }
+
+    refresh(pool).await
+}
@@ -0,0 +1,150 @@
+use std::time::Duration;
+
+use chrono::{Timelike, Utc};
+use tokio::time::Instant;
+use tracing::log;
+
+use crate::config;
+
+pub struct Throttle {
+    rows_per_second: u64,
+    started_at: Instant,
+    rows: u64,
+    /// Whether `IMPORT_WINDOW` applies to the table
+    is_heavy: bool,
+}
+
+/// Time left until the import window opens, nothing while it's open
+fn until_window() -> Option<Duration> {
+    let (start, end) = config::CONFIG.import_window?;
+
+    let now = Utc::now();
+    let minute = now.hour() * 60 + now.minute();
+
+    // An equal start and end make the window the whole day
+    let is_open = if start == end {
+        true
+    } else if start < end {
+        start <= minute && minute < end
+    } else {
+        minute >= start || minute < end
+    };
+
+    if is_open {
+        return None;
+    }
+
+    let minutes = (start + 24 * 60 - minute) % (24 * 60);
+
+    Some(Duration::from_secs(
+        minutes as u64 * 60 - now.second() as u64,
+    ))
+}
+
+impl Throttle {
+    pub fn new(file_name: &str) -> Throttle {
+        let rows_per_second = match config::CONFIG.table_rows_per_second.get(file_name) {
+            Some(v) => *v,
+            None => config::CONFIG.rows_per_second,
+        };
+
+        let heavy_tables = &config::CONFIG.heavy_tables;
+
+        Throttle {
+            rows_per_second,
+            started_at: Instant::now(),
+            rows: 0,
+            is_heavy: heavy_tables.is_empty() || heavy_tables.iter().any(|v| v == file_name),
+        }
+    }
+
+    /// Holds heavy tables outside of the import window, so a manual run at
+    /// the peak hours waits instead of loading the database.
+    pub async fn wait_for_window(&mut self, file_name: &str) {
+        if !self.is_heavy {
+            return;
+        }
+
+        let wait = match until_window() {
+            Some(v) => v,
+            None => return,
+        };
+
+        log::info!(
+            "Update {file_name} waits {} minutes for the import window...",
+            wait.as_secs() / 60
+        );
+
+        tokio::time::sleep(wait).await;
+
+        log::info!("Update {file_name} continues in the import window...");
+
+        self.reset();
+    }
+
+    pub fn reset(&mut self) {
+        self.started_at = Instant::now();
+        self.rows = 0;
+    }
+
+    pub async fn tick(&mut self) {
+        if self.rows_per_second == 0 {
+            return;
+        }
+
+        self.rows += 1;
+
+        let expected = Duration::from_secs_f64(self.rows as f64 / self.rows_per_second as f64);
+        let elapsed = self.started_at.elapsed();
+
+        if expected > elapsed {
+            tokio::time::sleep(expected - elapsed).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use crate::throttle::Throttle;
+
+    fn throttle(rows_per_second: u64) -> Throttle {
+        Throttle {
+            rows_per_second,
+            started_at: Instant::now(),
+            rows: 0,
+            is_heavy: false,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tick() {
+        let mut input = throttle(10);
+        let expected_result = Duration::from_secs(2);
+
+        let started_at = Instant::now();
+        for _ in 0..20 {
+            input.tick().await;
+        }
+        let result = started_at.elapsed();
+
+        assert_eq!(result, expected_result);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tick_no_limit() {
+        let mut input = throttle(0);
+        let expected_result = Duration::ZERO;
+
+        let started_at = Instant::now();
+        for _ in 0..1000 {
+            input.tick().await;
+        }
+        let result = started_at.elapsed();
+
+        assert_eq!(result, expected_result);
+    }
+}
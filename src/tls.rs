@@ -0,0 +1,154 @@
+use std::{fs::File, io::BufReader, net::SocketAddr, sync::Arc};
+
+use axum::Router;
+use sha2::{Digest, Sha256};
+use tokio::net::TcpListener;
+use tokio_rustls::{
+    rustls::{
+        self,
+        crypto::ring,
+        pki_types::{CertificateDer, PrivateKeyDer},
+        server::WebPkiClientVerifier,
+        RootCertStore, ServerConfig,
+    },
+    TlsAcceptor,
+};
+use tracing::log;
+
+use crate::server;
+
+/// A client certificate verified against `TLS_CLIENT_CA_PATH`, added to the
+/// extensions of every request on its connection.
+#[derive(Clone)]
+pub struct ClientCertificate {
+    /// SHA-256 of the certificate, names the caller in the audit log
+    pub fingerprint: String,
+}
+
+fn read_certificates(
+    path: &str,
+) -> Result<Vec<CertificateDer<'static>>, Box<dyn std::error::Error>> {
+    let file = match File::open(path) {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    match rustls_pemfile::certs(&mut BufReader::new(file)).collect() {
+        Ok(v) => Ok(v),
+        Err(err) => Err(Box::new(err)),
+    }
+}
+
+fn read_private_key(path: &str) -> Result<PrivateKeyDer<'static>, Box<dyn std::error::Error>> {
+    let file = match File::open(path) {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    match rustls_pemfile::private_key(&mut BufReader::new(file)) {
+        Ok(Some(v)) => Ok(v),
+        Ok(None) => Err(format!("No private key in {path}").into()),
+        Err(err) => Err(Box::new(err)),
+    }
+}
+
+/// Client certificates are optional: without one the API keys still work.
+pub fn server_config(
+    cert_path: &str,
+    key_path: &str,
+    client_ca_path: Option<&str>,
+) -> Result<Arc<ServerConfig>, Box<dyn std::error::Error>> {
+    let provider = Arc::new(ring::default_provider());
+
+    let builder = match ServerConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(rustls::DEFAULT_VERSIONS)
+    {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let builder = match client_ca_path {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+
+            for certificate in read_certificates(path)? {
+                if let Err(err) = roots.add(certificate) {
+                    return Err(Box::new(err));
+                }
+            }
+
+            let verifier =
+                match WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                    .allow_unauthenticated()
+                    .build()
+                {
+                    Ok(v) => v,
+                    Err(err) => return Err(Box::new(err)),
+                };
+
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    match builder.with_single_cert(read_certificates(cert_path)?, read_private_key(key_path)?) {
+        Ok(v) => Ok(Arc::new(v)),
+        Err(err) => Err(Box::new(err)),
+    }
+}
+
+fn fingerprint(certificate: &CertificateDer) -> String {
+    Sha256::digest(certificate.as_ref())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+async fn serve_connection(
+    acceptor: TlsAcceptor,
+    stream: tokio::net::TcpStream,
+    addr: SocketAddr,
+    app: Router,
+) {
+    let stream = match acceptor.accept(stream).await {
+        Ok(v) => v,
+        Err(err) => {
+            log::warn!("TLS handshake with {addr} failed: {err}");
+            return;
+        }
+    };
+
+    let certificate = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certificates| certificates.first())
+        .map(|certificate| ClientCertificate {
+            fingerprint: fingerprint(certificate),
+        });
+
+    if let Err(err) = server::serve_connection(stream, app, certificate).await {
+        log::warn!("Connection with {addr} failed: {err}");
+    }
+}
+
+pub async fn serve(listener: TcpListener, app: Router, config: Arc<ServerConfig>) {
+    let acceptor = TlsAcceptor::from(config);
+
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(v) => v,
+            Err(err) => {
+                log::error!("Can't accept a connection: {err}");
+                continue;
+            }
+        };
+
+        tokio::spawn(serve_connection(
+            acceptor.clone(),
+            stream,
+            addr,
+            app.clone(),
+        ));
+    }
+}
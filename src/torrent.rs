@@ -0,0 +1,135 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use librqbit::api::TorrentIdOrHash;
+use librqbit::{AddTorrent, AddTorrentOptions, Session, SessionOptions};
+use tokio::fs::File;
+use tokio::sync::OnceCell;
+use tracing::log;
+
+use crate::config;
+
+lazy_static! {
+    /// Shared by the downloads, it holds the DHT and the peer connections
+    static ref SESSION: OnceCell<Arc<Session>> = OnceCell::const_new();
+}
+
+/// Leeches only: no listening port, no upload, nothing persisted between runs
+async fn start_session() -> Result<Arc<Session>, Box<dyn std::error::Error + Send>> {
+    let options = SessionOptions {
+        disable_dht_persistence: true,
+        fastresume: false,
+        persistence: None,
+        listen_port_range: None,
+        enable_upnp_port_forwarding: false,
+        disable_upload: true,
+        ..Default::default()
+    };
+
+    match Session::new_with_opts(PathBuf::from(&config::CONFIG.work_dir), options).await {
+        Ok(v) => Ok(v),
+        Err(err) => Err(error(err)),
+    }
+}
+
+fn error(
+    err: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+) -> Box<dyn std::error::Error + Send> {
+    err.into() as Box<dyn std::error::Error + Send>
+}
+
+fn escape_regex(value: &str) -> String {
+    value
+        .chars()
+        .flat_map(|c| match "\\.+*?()|[]{}^$".contains(c) {
+            true => vec!['\\', c],
+            false => vec![c],
+        })
+        .collect()
+}
+
+/// Downloads `{file_name}.gz` of the torrent (a magnet link or a `.torrent`
+/// URL) into `folder` and opens it. The torrent is removed once it completes or
+/// `TORRENT_TIMEOUT_MINS` pass, so it's never seeded. The folder is removed
+/// too: the open file keeps the data until it's read.
+pub async fn open(
+    torrent: &str,
+    file_name: &str,
+    folder: PathBuf,
+) -> Result<File, Box<dyn std::error::Error + Send>> {
+    let session = match SESSION.get_or_try_init(start_session).await {
+        Ok(v) => v,
+        Err(err) => return Err(err),
+    };
+
+    let wanted = format!("{file_name}.gz");
+
+    let _ = tokio::fs::remove_dir_all(&folder).await;
+
+    let options = AddTorrentOptions {
+        only_files_regex: Some(format!("(^|/){}$", escape_regex(&wanted))),
+        overwrite: true,
+        output_folder: Some(folder.to_string_lossy().to_string()),
+        ..Default::default()
+    };
+
+    let handle = match session
+        .add_torrent(AddTorrent::from_url(torrent), Some(options))
+        .await
+    {
+        Ok(response) => match response.into_handle() {
+            Some(v) => v,
+            None => return Err(error(format!("{file_name}: the torrent wasn't added"))),
+        },
+        Err(err) => return Err(error(err)),
+    };
+
+    log::info!("Download {wanted} over BitTorrent...");
+
+    let timeout = Duration::from_secs(config::CONFIG.torrent_timeout_mins * 60);
+    let completed = tokio::time::timeout(timeout, handle.wait_until_completed()).await;
+
+    let relative_path = handle
+        .with_metadata(|metadata| {
+            metadata
+                .file_infos
+                .iter()
+                .map(|file| file.relative_filename.clone())
+                .find(|path| path.ends_with(&wanted))
+        })
+        .ok()
+        .flatten();
+
+    // Files of an unfinished download are of no use
+    let is_complete = matches!(completed, Ok(Ok(_)));
+    let id: TorrentIdOrHash = handle.id().into();
+    if let Err(err) = session.delete(id, !is_complete).await {
+        log::warn!("Can't remove the torrent of {wanted}: {:?}", err);
+    }
+
+    match completed {
+        Ok(Ok(_)) => (),
+        Ok(Err(err)) => return Err(error(err)),
+        Err(_) => {
+            return Err(error(format!(
+                "{file_name}: the torrent didn't complete in {} minutes",
+                config::CONFIG.torrent_timeout_mins
+            )))
+        }
+    };
+
+    let path = match relative_path {
+        Some(v) => folder.join(v),
+        None => return Err(error(format!("{file_name}: the torrent has no {wanted}"))),
+    };
+
+    let file = match File::open(&path).await {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let _ = tokio::fs::remove_dir_all(&folder).await;
+
+    Ok(file)
+}
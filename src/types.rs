@@ -1,12 +1,139 @@
 use async_trait::async_trait;
-use chrono::{NaiveDate, NaiveDateTime};
-use sql_parse::Expression;
-use tokio_postgres::Client;
+use bytes::BytesMut;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sql_parse::{Expression, UnaryOperator};
+use tokio_postgres::{
+    types::{to_sql_checked, IsNull, ToSql, Type},
+    Client,
+};
+
+use crate::audit;
+use crate::config;
+use crate::dump_row::{Columns, DumpRow, FromExpression};
+use crate::errors::UpdaterError;
+use crate::ids::{RemoteAuthorId, RemoteBookId, RemoteGenreId, RemoteSequenceId};
+use crate::report;
+use crate::upsert::{Column, CopyRow, UpsertResult, UpsertSpec};
+use crate::utils::{
+    fix_annotation_text, normalize_file_type, normalize_typography, parse_lang, remove_wrong_chars,
+    strip_control_chars, transliterate,
+};
+use tracing::log;
+
+fn sanitize(value: &str) -> String {
+    if config::CONFIG.strip_control_chars {
+        strip_control_chars(value)
+    } else {
+        value.to_string()
+    }
+}
+
+fn typography(value: String) -> String {
+    if config::CONFIG.normalize_typography {
+        normalize_typography(&value)
+    } else {
+        value
+    }
+}
+
+fn sanitized(value: String) -> String {
+    sanitize(&value)
+}
+
+fn clean(value: String) -> String {
+    remove_wrong_chars(&sanitize(&value))
+}
+
+fn title(value: String) -> String {
+    typography(clean(value))
+}
+
+fn file_type(value: String) -> String {
+    normalize_file_type(&sanitize(&value))
+}
+
+fn is_allowed_file_type(value: &str) -> bool {
+    config::CONFIG.file_types.iter().any(|v| v == value)
+}
+
+/// Books in other languages are kept deleted, see `DeletedReason::Language`
+const ALLOWED_LANGS: [&str; 3] = ["ru", "be", "uk"];
+
+fn lang(value: String) -> String {
+    parse_lang(&sanitize(&value))
+}
+
+fn annotation_body(value: Option<String>) -> Option<String> {
+    value.map(|v| typography(fix_annotation_text(&sanitize(&v))))
+}
+
+fn year(value: &Expression) -> Option<i16> {
+    match value {
+        Expression::Integer(_) => i16::from_expression(value),
+        Expression::Unary { .. } => Some(0),
+        _ => None,
+    }
+}
+
+/// Whether `year` turned the value into 0
+fn year_clamped(value: &Expression) -> Option<bool> {
+    match value {
+        Expression::Integer(_) => Some(false),
+        Expression::Unary { .. } => Some(true),
+        _ => None,
+    }
+}
+
+fn position(value: &Expression) -> Option<i16> {
+    match value {
+        Expression::Integer(_) => i16::from_expression(value),
+        Expression::Unary {
+            op: UnaryOperator::Minus,
+            operand,
+            ..
+        } => i16::from_expression(operand),
+        _ => None,
+    }
+}
 
-use crate::utils::{fix_annotation_text, parse_lang, remove_wrong_chars};
+/// Why a book ended up with `is_deleted`, stored in `books.deleted_reason`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeletedReason {
+    Upstream,
+    Language,
+}
+
+impl DeletedReason {
+    pub const ALL: [DeletedReason; 2] = [DeletedReason::Upstream, DeletedReason::Language];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeletedReason::Upstream => "upstream",
+            DeletedReason::Language => "language",
+        }
+    }
+}
+
+impl ToSql for DeletedReason {
+    fn to_sql(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn std::error::Error + Sync + Send>> {
+        out.extend_from_slice(self.as_str().as_bytes());
+        Ok(IsNull::No)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        ty.name() == "book_deleted_reason"
+    }
+
+    to_sql_checked!();
+}
 
 pub trait FromVecExpression<T> {
-    fn from_vec_expression(value: &[Expression]) -> T;
+    fn from_vec_expression(value: &[Expression], columns: &Columns) -> Result<T, UpdaterError>;
 }
 
 #[async_trait]
@@ -17,64 +144,139 @@ pub trait Update {
         &self,
         client: &Client,
         source_id: i16,
-    ) -> Result<(), Box<tokio_postgres::Error>>;
+    ) -> Result<UpsertResult, Box<tokio_postgres::Error>>;
 
     async fn after_update(client: &Client) -> Result<(), Box<tokio_postgres::Error>>;
+
+    /// Tables the rows are written to, copied for shadow imports. Rows of
+    /// entities without them go to the live tables right away.
+    fn tables() -> &'static [&'static str] {
+        &[]
+    }
+
+    /// The spec used to load rows with `COPY`, `None` if rows need per-row handling.
+    fn copy_spec() -> Option<&'static UpsertSpec> {
+        None
+    }
+
+    /// Parameters of the row for `copy_spec`.
+    fn copy_row(&self) -> CopyRow {
+        Vec::new()
+    }
+
+    /// The spec `POST /update/preview` compares the rows with, `None` if they
+    /// can't be previewed.
+    fn preview_spec() -> Option<&'static UpsertSpec> {
+        Self::copy_spec()
+    }
+
+    /// Parameters of the row for `preview_spec`, `None` if it wouldn't be written.
+    fn preview_row(&self) -> Option<CopyRow> {
+        Some(self.copy_row())
+    }
+
+    /// Runs after a full sync without rejected rows; rows not written since
+    /// `started_at` are gone from the dump.
+    async fn after_full_sync(
+        _client: &Client,
+        _source_id: i16,
+        _started_at: DateTime<Utc>,
+    ) -> Result<(), Box<tokio_postgres::Error>> {
+        Ok(())
+    }
+
+    async fn verify(
+        &self,
+        client: &Client,
+        source_id: i16,
+    ) -> Result<bool, Box<tokio_postgres::Error>>;
+}
+
+async fn check(
+    client: &Client,
+    query: &str,
+    params: &[&(dyn ToSql + Sync)],
+) -> Result<bool, Box<tokio_postgres::Error>> {
+    match client.query_one(query, params).await {
+        Ok(row) => Ok(row.get(0)),
+        Err(err) => Err(Box::new(err)),
+    }
+}
+
+/// Transliterations of `values` with `TRANSLIT_SCHEME`, none without one
+fn translit(values: &[&str]) -> Vec<String> {
+    match config::CONFIG.translit_scheme {
+        Some(scheme) => values.iter().map(|v| transliterate(v, scheme)).collect(),
+        None => vec![],
+    }
 }
 
-#[derive(Debug)]
+const AUTHORS: UpsertSpec = UpsertSpec {
+    table: "authors",
+    keys: &[Column::Source, Column::Value("remote_id", "int")],
+    values: &[
+        Column::Value("first_name", "varchar"),
+        Column::Value("last_name", "varchar"),
+        Column::Value("middle_name", "varchar"),
+    ],
+    insert: true,
+    insert_defaults: &[],
+};
+
+/// `AUTHORS` with the transliterations, the values are followed by them
+const AUTHORS_TRANSLIT: UpsertSpec = UpsertSpec {
+    table: "authors",
+    keys: &[Column::Source, Column::Value("remote_id", "int")],
+    values: &[
+        Column::Value("first_name", "varchar"),
+        Column::Value("last_name", "varchar"),
+        Column::Value("middle_name", "varchar"),
+        Column::Value("first_name_translit", "varchar"),
+        Column::Value("last_name_translit", "varchar"),
+        Column::Value("middle_name_translit", "varchar"),
+    ],
+    insert: true,
+    insert_defaults: &[],
+};
+
+fn authors() -> &'static UpsertSpec {
+    match config::CONFIG.translit_scheme {
+        Some(_) => &AUTHORS_TRANSLIT,
+        None => &AUTHORS,
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, DumpRow)]
 pub struct Author {
-    pub id: u64,
+    #[column(index = 0)]
+    pub id: RemoteAuthorId,
+    #[column(index = 3, map = clean)]
     pub last_name: String,
+    #[column(index = 1, map = clean)]
     pub first_name: String,
+    #[column(index = 2, map = clean)]
     pub middle_name: String,
 }
 
-impl FromVecExpression<Author> for Author {
-    fn from_vec_expression(value: &[Expression]) -> Author {
-        Author {
-            id: match &value[0] {
-                sql_parse::Expression::Integer(v) => v.0,
-                _ => panic!("Author.id"),
-            },
-            last_name: match &value[3] {
-                sql_parse::Expression::String(v) => remove_wrong_chars(&v.value),
-                _ => panic!("Author.last_name"),
-            },
-            first_name: match &value[1] {
-                sql_parse::Expression::String(v) => remove_wrong_chars(&v.value),
-                _ => panic!("Author.first_name"),
-            },
-            middle_name: match &value[2] {
-                sql_parse::Expression::String(v) => remove_wrong_chars(&v.value),
-                _ => panic!("Author.middle_name"),
-            },
-        }
-    }
-}
-
 #[async_trait]
 impl Update for Author {
     async fn before_update(client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
-        match client.execute(
-            "
-            CREATE OR REPLACE FUNCTION update_author(
-                source_ smallint, remote_id_ int, first_name_ varchar, last_name_ varchar, middle_name_ varchar
-            ) RETURNS void AS $$
-                BEGIN
-                    IF EXISTS (SELECT * FROM authors WHERE source = source_ AND remote_id = remote_id_) THEN
-                        UPDATE authors SET first_name = first_name_, last_name = last_name_, middle_name = middle_name_
-                        WHERE source = source_ AND remote_id = remote_id_;
-                        RETURN;
-                    END IF;
-                    INSERT INTO authors (source, remote_id, first_name, last_name, middle_name)
-                        VALUES (source_, remote_id_, first_name_, last_name_, middle_name_);
-                END;
-            $$ LANGUAGE plpgsql;
-            "
-            , &[]).await {
-                Ok(_) => Ok(()),
-                Err(err) => Err(Box::new(err)),
+        if config::CONFIG.translit_scheme.is_none() {
+            return Ok(());
+        }
+
+        match client
+            .batch_execute(
+                "
+                ALTER TABLE authors ADD COLUMN IF NOT EXISTS first_name_translit varchar;
+                ALTER TABLE authors ADD COLUMN IF NOT EXISTS last_name_translit varchar;
+                ALTER TABLE authors ADD COLUMN IF NOT EXISTS middle_name_translit varchar;
+                ",
+            )
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(err) => Err(Box::new(err)),
         }
     }
 
@@ -82,73 +284,151 @@ impl Update for Author {
         &self,
         client: &Client,
         source_id: i16,
-    ) -> Result<(), Box<tokio_postgres::Error>> {
-        match client.execute(
-            "SELECT update_author($1, $2, cast($3 as varchar), cast($4 as varchar), cast($5 as varchar));",
-            &[&source_id, &(self.id as i32), &self.first_name, &self.last_name, &self.middle_name]
-        ).await {
-            Ok(_) => Ok(()),
-            Err(err) => Err(Box::new(err)),
+    ) -> Result<UpsertResult, Box<tokio_postgres::Error>> {
+        let translit = translit(&[&self.first_name, &self.last_name, &self.middle_name]);
+
+        let mut params: Vec<&(dyn ToSql + Sync)> = vec![
+            &self.id,
+            &self.first_name,
+            &self.last_name,
+            &self.middle_name,
+        ];
+        params.extend(translit.iter().map(|v| v as &(dyn ToSql + Sync)));
+
+        authors().execute(client, source_id, &params).await
+    }
+
+    fn copy_spec() -> Option<&'static UpsertSpec> {
+        Some(authors())
+    }
+
+    fn copy_row(&self) -> CopyRow {
+        let mut row: CopyRow = vec![
+            Box::new(self.id),
+            Box::new(self.first_name.clone()),
+            Box::new(self.last_name.clone()),
+            Box::new(self.middle_name.clone()),
+        ];
+        for value in translit(&[&self.first_name, &self.last_name, &self.middle_name]) {
+            row.push(Box::new(value));
         }
+        row
+    }
+
+    fn tables() -> &'static [&'static str] {
+        &[AUTHORS.table]
     }
 
     async fn after_update(_client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
         Ok(())
     }
+
+    async fn verify(
+        &self,
+        client: &Client,
+        source_id: i16,
+    ) -> Result<bool, Box<tokio_postgres::Error>> {
+        check(
+            client,
+            "
+            SELECT EXISTS (
+                SELECT 1 FROM authors
+                WHERE source = $1 AND remote_id = $2
+                    AND first_name IS NOT DISTINCT FROM cast($3 as varchar)
+                    AND last_name IS NOT DISTINCT FROM cast($4 as varchar)
+                    AND middle_name IS NOT DISTINCT FROM cast($5 as varchar)
+            );
+            ",
+            &[
+                &source_id,
+                &self.id,
+                &self.first_name,
+                &self.last_name,
+                &self.middle_name,
+            ],
+        )
+        .await
+    }
+}
+
+const BOOKS: UpsertSpec = UpsertSpec {
+    table: "books",
+    keys: &[Column::Source, Column::Value("remote_id", "int")],
+    values: &[
+        Column::Value("title", "varchar"),
+        Column::Value("lang", "varchar"),
+        Column::Value("file_type", "varchar"),
+        Column::Value("uploaded", "date"),
+        Column::Value("is_deleted", "boolean"),
+        Column::Value("deleted_reason", "book_deleted_reason"),
+        Column::Value("pages", "int"),
+        Column::Value("year", "smallint"),
+        Column::Value("year_clamped", "boolean"),
+    ],
+    insert: true,
+    insert_defaults: &[],
+};
+
+/// `BOOKS` with the transliteration of the title after the values
+const BOOKS_TRANSLIT: UpsertSpec = UpsertSpec {
+    table: "books",
+    keys: &[Column::Source, Column::Value("remote_id", "int")],
+    values: &[
+        Column::Value("title", "varchar"),
+        Column::Value("lang", "varchar"),
+        Column::Value("file_type", "varchar"),
+        Column::Value("uploaded", "date"),
+        Column::Value("is_deleted", "boolean"),
+        Column::Value("deleted_reason", "book_deleted_reason"),
+        Column::Value("pages", "int"),
+        Column::Value("year", "smallint"),
+        Column::Value("year_clamped", "boolean"),
+        Column::Value("title_translit", "varchar"),
+    ],
+    insert: true,
+    insert_defaults: &[],
+};
+
+fn books() -> &'static UpsertSpec {
+    match config::CONFIG.translit_scheme {
+        Some(_) => &BOOKS_TRANSLIT,
+        None => &BOOKS,
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize, DumpRow)]
 pub struct Book {
-    pub id: u64,
+    #[column(index = 0)]
+    pub id: RemoteBookId,
+    #[column(index = 3, map = title)]
     pub title: String,
+    #[column(index = 5, map = lang)]
     pub lang: String,
+    #[column(index = 8, map = file_type)]
     pub file_type: String,
+    #[column(index = 2)]
     pub uploaded: NaiveDate,
+    #[column(index = 11)]
     pub is_deleted: bool,
-    pub pages: u64,
-    pub year: u64,
+    #[column(index = 20)]
+    pub pages: i32,
+    #[column(index = 10, with = year)]
+    pub year: i16,
+    /// A negative year is stored as 0, see `Provenance::clamped`
+    #[column(index = 10, with = year_clamped)]
+    pub year_clamped: bool,
 }
 
-impl FromVecExpression<Book> for Book {
-    fn from_vec_expression(value: &[Expression]) -> Book {
-        Book {
-            id: match &value[0] {
-                sql_parse::Expression::Integer(v) => v.0,
-                _ => panic!("Book.id"),
-            },
-            title: match &value[3] {
-                sql_parse::Expression::String(v) => remove_wrong_chars(&v.value),
-                _ => panic!("Book.title"),
-            },
-            lang: match &value[5] {
-                sql_parse::Expression::String(v) => parse_lang(&v.value),
-                _ => panic!("Book.lang"),
-            },
-            file_type: match &value[8] {
-                sql_parse::Expression::String(v) => v.value.to_string(),
-                _ => panic!("Book.file_type"),
-            },
-            uploaded: match &value[2] {
-                sql_parse::Expression::String(v) => {
-                    NaiveDateTime::parse_from_str(&v.value, "%Y-%m-%d %H:%M:%S")
-                        .unwrap()
-                        .date()
-                }
-                _ => panic!("Book.uploaded"),
-            },
-            is_deleted: match &value[11] {
-                sql_parse::Expression::String(v) => v.value.eq("1"),
-                _ => panic!("Book.is_deleted"),
-            },
-            pages: match &value[20] {
-                sql_parse::Expression::Integer(v) => v.0,
-                _ => panic!("Book.id"),
-            },
-            year: match &value[10] {
-                sql_parse::Expression::Integer(v) => v.0,
-                sql_parse::Expression::Unary { .. } => 0,
-                _ => panic!("Book.year"),
-            },
+impl Book {
+    /// `is_deleted` and `deleted_reason` as written, the upstream flag wins
+    /// over the language filter
+    fn deleted(&self) -> (bool, Option<DeletedReason>) {
+        if self.is_deleted {
+            (true, Some(DeletedReason::Upstream))
+        } else if !ALLOWED_LANGS.contains(&self.lang.as_str()) {
+            (true, Some(DeletedReason::Language))
+        } else {
+            (false, None)
         }
     }
 }
@@ -156,29 +436,89 @@ impl FromVecExpression<Book> for Book {
 #[async_trait]
 impl Update for Book {
     async fn before_update(client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
-        match client.execute(
-            "
-            CREATE OR REPLACE FUNCTION update_book(
-                source_ smallint, remote_id_ int, title_ varchar, lang_ varchar,
-                file_type_ varchar, uploaded_ date, is_deleted_ boolean, pages_ int,
-                year_ smallint
-            ) RETURNS void AS $$
-                BEGIN
-                    IF EXISTS (SELECT * FROM books WHERE source = source_ AND remote_id = remote_id_) THEN
-                        UPDATE books SET title = title_, lang = lang_, file_type = file_type_,
-                                         uploaded = uploaded_, is_deleted = is_deleted_, pages = pages_,
-                                         year = year_
-                        WHERE source = source_ AND remote_id = remote_id_;
-                        RETURN;
-                    END IF;
-                    INSERT INTO books (source, remote_id, title, lang, file_type, uploaded, is_deleted, pages, year)
-                        VALUES (source_, remote_id_, title_, lang_, file_type_, uploaded_, is_deleted_, pages_, year_);
-                END;
-            $$ LANGUAGE plpgsql;
-            "
-            , &[]).await {
-                Ok(_) => Ok(()),
-                Err(err) => Err(Box::new(err)),
+        match client
+            .batch_execute(
+                "
+                DO $$ BEGIN
+                    CREATE TYPE book_deleted_reason AS ENUM ();
+                EXCEPTION
+                    WHEN duplicate_object THEN NULL;
+                END $$;
+                ALTER TABLE books ADD COLUMN IF NOT EXISTS deleted_reason book_deleted_reason;
+                ALTER TABLE books ADD COLUMN IF NOT EXISTS year_clamped boolean NOT NULL DEFAULT false;
+                ",
+            )
+            .await
+        {
+            Ok(_) => (),
+            Err(err) => return Err(Box::new(err)),
+        };
+
+        for reason in DeletedReason::ALL {
+            match client
+                .batch_execute(&format!(
+                    "ALTER TYPE book_deleted_reason ADD VALUE IF NOT EXISTS '{}';",
+                    reason.as_str()
+                ))
+                .await
+            {
+                Ok(_) => (),
+                Err(err) => return Err(Box::new(err)),
+            };
+        }
+
+        match audit::prepare(client).await {
+            Ok(_) => (),
+            Err(err) => return Err(err),
+        };
+
+        // Digests of the row before and after every change, for `provenance`
+        match client
+            .batch_execute(
+                "
+                CREATE OR REPLACE FUNCTION audit_book_change() RETURNS trigger AS $$
+                    DECLARE
+                        old_hash text;
+                    BEGIN
+                        IF TG_OP = 'UPDATE' THEN
+                            old_hash := md5(to_jsonb(OLD)::text);
+                        END IF;
+
+                        INSERT INTO audit_log (source, entity, remote_id, action, old_value, new_value)
+                            VALUES (
+                                NEW.source, 'book', NEW.remote_id,
+                                CASE TG_OP WHEN 'INSERT' THEN 'inserted' ELSE 'updated' END,
+                                old_hash, md5(to_jsonb(NEW)::text)
+                            );
+                        RETURN NEW;
+                    END;
+                $$ LANGUAGE plpgsql;
+
+                DROP TRIGGER IF EXISTS books_insert_audit ON books;
+                CREATE TRIGGER books_insert_audit AFTER INSERT ON books
+                    FOR EACH ROW EXECUTE FUNCTION audit_book_change();
+                DROP TRIGGER IF EXISTS books_update_audit ON books;
+                CREATE TRIGGER books_update_audit AFTER UPDATE ON books
+                    FOR EACH ROW WHEN (OLD.* IS DISTINCT FROM NEW.*)
+                    EXECUTE FUNCTION audit_book_change();
+                ",
+            )
+            .await
+        {
+            Ok(_) => (),
+            Err(err) => return Err(Box::new(err)),
+        };
+
+        if config::CONFIG.translit_scheme.is_none() {
+            return Ok(());
+        }
+
+        match client
+            .batch_execute("ALTER TABLE books ADD COLUMN IF NOT EXISTS title_translit varchar;")
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(err) => Err(Box::new(err)),
         }
     }
 
@@ -186,229 +526,344 @@ impl Update for Book {
         &self,
         client: &Client,
         source_id: i16,
-    ) -> Result<(), Box<tokio_postgres::Error>> {
-        match client.execute(
-            "SELECT update_book($1, $2, cast($3 as varchar), cast($4 as varchar), cast($5 as varchar), $6, $7, $8, $9);",
-            &[&source_id, &(self.id as i32), &self.title, &self.lang, &self.file_type, &self.uploaded, &self.is_deleted, &(self.pages as i32), &(self.year as i16)]
-        ).await {
-            Ok(_) => Ok(()),
-            Err(err) => Err(Box::new(err)),
+    ) -> Result<UpsertResult, Box<tokio_postgres::Error>> {
+        if !is_allowed_file_type(&self.file_type) {
+            report::record_unknown_file_type(source_id, &self.file_type);
+            return Ok(UpsertResult::Skipped);
         }
+
+        let (is_deleted, deleted_reason) = self.deleted();
+        let translit = translit(&[&self.title]);
+
+        let mut params: Vec<&(dyn ToSql + Sync)> = vec![
+            &self.id,
+            &self.title,
+            &self.lang,
+            &self.file_type,
+            &self.uploaded,
+            &is_deleted,
+            &deleted_reason,
+            &self.pages,
+            &self.year,
+            &self.year_clamped,
+        ];
+        params.extend(translit.iter().map(|v| v as &(dyn ToSql + Sync)));
+
+        let result = match books().execute(client, source_id, &params).await {
+            Ok(v) => v,
+            Err(err) => return Err(err),
+        };
+
+        report::record_book(source_id, &self.lang, self.id, result);
+
+        Ok(result)
     }
 
-    async fn after_update(client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
-        match client
-            .execute(
-                "UPDATE books SET is_deleted = 't' WHERE lang NOT IN ('ru', 'be', 'uk');",
-                &[],
-            )
-            .await
-        {
-            Ok(_) => Ok(()),
-            Err(err) => Err(Box::new(err)),
+    fn preview_spec() -> Option<&'static UpsertSpec> {
+        Some(books())
+    }
+
+    fn preview_row(&self) -> Option<CopyRow> {
+        if !is_allowed_file_type(&self.file_type) {
+            return None;
+        }
+
+        let (is_deleted, deleted_reason) = self.deleted();
+
+        let mut row: CopyRow = vec![
+            Box::new(self.id),
+            Box::new(self.title.clone()),
+            Box::new(self.lang.clone()),
+            Box::new(self.file_type.clone()),
+            Box::new(self.uploaded),
+            Box::new(is_deleted),
+            Box::new(deleted_reason),
+            Box::new(self.pages),
+            Box::new(self.year),
+            Box::new(self.year_clamped),
+        ];
+        for value in translit(&[&self.title]) {
+            row.push(Box::new(value));
         }
+        Some(row)
     }
-}
 
-#[derive(Debug)]
-pub struct BookAuthor {
-    pub book_id: u64,
-    pub author_id: u64,
-    // TODO: position
-}
+    fn tables() -> &'static [&'static str] {
+        &[BOOKS.table]
+    }
+
+    async fn after_update(_client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
+        Ok(())
+    }
 
-impl FromVecExpression<BookAuthor> for BookAuthor {
-    fn from_vec_expression(value: &[Expression]) -> BookAuthor {
-        BookAuthor {
-            book_id: match &value[0] {
-                sql_parse::Expression::Integer(v) => v.0,
-                _ => panic!("BookAuthor.book_id"),
-            },
-            author_id: match &value[1] {
-                sql_parse::Expression::Integer(v) => v.0,
-                _ => panic!("BookAuthor.author_id"),
-            },
+    async fn verify(
+        &self,
+        client: &Client,
+        source_id: i16,
+    ) -> Result<bool, Box<tokio_postgres::Error>> {
+        if !is_allowed_file_type(&self.file_type) {
+            return Ok(true);
         }
+
+        check(
+            client,
+            "
+            SELECT EXISTS (
+                SELECT 1 FROM books
+                WHERE source = $1 AND remote_id = $2
+                    AND title IS NOT DISTINCT FROM cast($3 as varchar)
+                    AND lang IS NOT DISTINCT FROM cast($4 as varchar)
+                    AND file_type IS NOT DISTINCT FROM cast($5 as varchar)
+                    AND uploaded IS NOT DISTINCT FROM cast($6 as date)
+                    AND pages IS NOT DISTINCT FROM cast($7 as int)
+                    AND year IS NOT DISTINCT FROM cast($8 as smallint)
+            );
+            ",
+            &[
+                &source_id,
+                &self.id,
+                &self.title,
+                &self.lang,
+                &self.file_type,
+                &self.uploaded,
+                &self.pages,
+                &self.year,
+            ],
+        )
+        .await
     }
 }
 
+const BOOK_AUTHORS: UpsertSpec = UpsertSpec {
+    table: "book_authors",
+    keys: &[
+        Column::Ref("book", "books"),
+        Column::Ref("author", "authors"),
+    ],
+    values: &[],
+    insert: true,
+    insert_defaults: &[],
+};
+
+#[derive(Debug, Serialize, Deserialize, DumpRow)]
+pub struct BookAuthor {
+    #[column(index = 0)]
+    pub book_id: RemoteBookId,
+    #[column(index = 1)]
+    pub author_id: RemoteAuthorId,
+    // TODO: position
+}
+
 #[async_trait]
 impl Update for BookAuthor {
-    async fn before_update(client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
-        match client.execute(
-            "
-            CREATE OR REPLACE FUNCTION update_book_author(source_ smallint, book_ integer, author_ integer) RETURNS void AS $$
-                DECLARE
-                    book_id integer := -1;
-                    author_id integer := -1;
-                BEGIN
-                    SELECT id INTO book_id FROM books WHERE source = source_ AND remote_id = book_;
-                    SELECT id INTO author_id FROM authors WHERE source = source_ AND remote_id = author_;
-
-                    IF book_id IS NULL OR author_id IS NULL THEN
-                        RETURN;
-                    END IF;
-
-                    IF EXISTS (SELECT * FROM book_authors WHERE book = book_id AND author = author_id) THEN
-                        RETURN;
-                    END IF;
-
-                    INSERT INTO book_authors (book, author) VALUES (book_id, author_id);
-                END;
-            $$ LANGUAGE plpgsql;
-            "
-            , &[]).await {
-                Ok(_) => Ok(()),
-                Err(err) => Err(Box::new(err)),
-        }
+    async fn before_update(_client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
+        Ok(())
     }
 
     async fn update(
         &self,
         client: &Client,
         source_id: i16,
-    ) -> Result<(), Box<tokio_postgres::Error>> {
-        match client
-            .execute(
-                "SELECT update_book_author($1, $2, $3);",
-                &[&source_id, &(self.book_id as i32), &(self.author_id as i32)],
-            )
+    ) -> Result<UpsertResult, Box<tokio_postgres::Error>> {
+        BOOK_AUTHORS
+            .execute(client, source_id, &[&self.book_id, &self.author_id])
             .await
-        {
-            Ok(_) => Ok(()),
-            Err(err) => Err(Box::new(err)),
-        }
+    }
+
+    fn copy_spec() -> Option<&'static UpsertSpec> {
+        Some(&BOOK_AUTHORS)
+    }
+
+    fn copy_row(&self) -> CopyRow {
+        vec![Box::new(self.book_id), Box::new(self.author_id)]
+    }
+
+    fn tables() -> &'static [&'static str] {
+        &[BOOK_AUTHORS.table]
     }
 
     async fn after_update(_client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
         Ok(())
     }
+
+    async fn verify(
+        &self,
+        client: &Client,
+        source_id: i16,
+    ) -> Result<bool, Box<tokio_postgres::Error>> {
+        check(
+            client,
+            "
+            SELECT NOT EXISTS (SELECT 1 FROM books WHERE source = $1 AND remote_id = $2)
+                OR NOT EXISTS (SELECT 1 FROM authors WHERE source = $1 AND remote_id = $3)
+                OR EXISTS (
+                    SELECT 1 FROM book_authors
+                    JOIN books ON books.id = book_authors.book
+                    JOIN authors ON authors.id = book_authors.author
+                    WHERE books.source = $1 AND books.remote_id = $2
+                        AND authors.source = $1 AND authors.remote_id = $3
+                );
+            ",
+            &[&source_id, &self.book_id, &self.author_id],
+        )
+        .await
+    }
 }
 
-#[derive(Debug)]
+const TRANSLATIONS: UpsertSpec = UpsertSpec {
+    table: "translations",
+    keys: &[
+        Column::Ref("book", "books"),
+        Column::Ref("author", "authors"),
+    ],
+    values: &[Column::Value("position", "smallint")],
+    insert: true,
+    insert_defaults: &[],
+};
+
+#[derive(Debug, Serialize, Deserialize, DumpRow)]
 pub struct Translator {
-    pub book_id: u64,
-    pub author_id: u64,
-    pub position: u64,
-}
-
-impl FromVecExpression<Translator> for Translator {
-    fn from_vec_expression(value: &[Expression]) -> Translator {
-        Translator {
-            book_id: match &value[0] {
-                sql_parse::Expression::Integer(v) => v.0,
-                _ => panic!("Translator.book_id"),
-            },
-            author_id: match &value[1] {
-                sql_parse::Expression::Integer(v) => v.0,
-                _ => panic!("Translator.author_id"),
-            },
-            position: match &value[2] {
-                sql_parse::Expression::Integer(v) => v.0,
-                _ => panic!("Translator.pos"),
-            },
-        }
-    }
+    #[column(index = 0)]
+    pub book_id: RemoteBookId,
+    #[column(index = 1)]
+    pub author_id: RemoteAuthorId,
+    #[column(index = 2)]
+    pub position: i16,
 }
 
 #[async_trait]
 impl Update for Translator {
-    async fn before_update(client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
-        match client.execute(
-            "
-            CREATE OR REPLACE FUNCTION update_translation(source_ smallint, book_ integer, author_ integer, position_ smallint) RETURNS void AS $$
-                DECLARE
-                    book_id integer := -1;
-                    author_id integer := -1;
-                BEGIN
-                    SELECT id INTO book_id FROM books WHERE source = source_ AND remote_id = book_;
-
-                    IF book_id IS NULL OR author_id IS NULL THEN
-                        RETURN;
-                    END IF;
-
-                    SELECT id INTO author_id FROM authors WHERE source = source_ AND remote_id = author_;
-                    IF EXISTS (SELECT * FROM translations WHERE book = book_id AND author = author_id) THEN
-                        UPDATE translations SET position = position_
-                        WHERE book = book_id AND author = author_id;
-                        RETURN;
-                    END IF;
-                    INSERT INTO translations (book, author, position) VALUES (book_id, author_id, position_);
-                END;
-            $$ LANGUAGE plpgsql;
-            "
-            , &[]).await {
-                Ok(_) => Ok(()),
-                Err(err) => Err(Box::new(err)),
-        }
+    async fn before_update(_client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
+        Ok(())
     }
 
     async fn update(
         &self,
         client: &Client,
         source_id: i16,
-    ) -> Result<(), Box<tokio_postgres::Error>> {
+    ) -> Result<UpsertResult, Box<tokio_postgres::Error>> {
+        TRANSLATIONS
+            .execute(
+                client,
+                source_id,
+                &[&self.book_id, &self.author_id, &self.position],
+            )
+            .await
+    }
+
+    fn copy_spec() -> Option<&'static UpsertSpec> {
+        Some(&TRANSLATIONS)
+    }
+
+    fn copy_row(&self) -> CopyRow {
+        vec![
+            Box::new(self.book_id),
+            Box::new(self.author_id),
+            Box::new(self.position),
+        ]
+    }
+
+    fn tables() -> &'static [&'static str] {
+        &[TRANSLATIONS.table]
+    }
+
+    async fn after_update(client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
         match client
             .execute(
-                "SELECT update_translation($1, $2, $3, $4);",
-                &[
-                    &source_id,
-                    &(self.book_id as i32),
-                    &(self.author_id as i32),
-                    &(self.position as i16),
-                ],
+                "
+                DELETE FROM translations
+                WHERE NOT EXISTS (SELECT 1 FROM books WHERE books.id = translations.book)
+                    OR NOT EXISTS (SELECT 1 FROM authors WHERE authors.id = translations.author);
+                ",
+                &[],
             )
             .await
         {
-            Ok(_) => Ok(()),
+            Ok(count) => {
+                log::info!("Removed {count} orphaned translations");
+                Ok(())
+            }
             Err(err) => Err(Box::new(err)),
         }
     }
 
-    async fn after_update(_client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
-        Ok(())
+    async fn verify(
+        &self,
+        client: &Client,
+        source_id: i16,
+    ) -> Result<bool, Box<tokio_postgres::Error>> {
+        check(
+            client,
+            "
+            SELECT NOT EXISTS (SELECT 1 FROM books WHERE source = $1 AND remote_id = $2)
+                OR NOT EXISTS (SELECT 1 FROM authors WHERE source = $1 AND remote_id = $3)
+                OR EXISTS (
+                    SELECT 1 FROM translations
+                    JOIN books ON books.id = translations.book
+                    JOIN authors ON authors.id = translations.author
+                    WHERE books.source = $1 AND books.remote_id = $2
+                        AND authors.source = $1 AND authors.remote_id = $3
+                        AND translations.position = cast($4 as smallint)
+                );
+            ",
+            &[&source_id, &self.book_id, &self.author_id, &self.position],
+        )
+        .await
     }
 }
 
-#[derive(Debug)]
+const SEQUENCES: UpsertSpec = UpsertSpec {
+    table: "sequences",
+    keys: &[Column::Source, Column::Value("remote_id", "int")],
+    values: &[
+        Column::Value("name", "varchar"),
+        Column::Sql("is_deleted", "false"),
+        Column::Sql("seen_at", "now()"),
+    ],
+    insert: true,
+    insert_defaults: &[],
+};
+
+#[derive(Debug, Serialize, Deserialize, DumpRow)]
 pub struct Sequence {
-    pub id: u64,
+    #[column(index = 0)]
+    pub id: RemoteSequenceId,
+    #[column(index = 1, map = clean)]
     pub name: String,
 }
 
-impl FromVecExpression<Sequence> for Sequence {
-    fn from_vec_expression(value: &[Expression]) -> Sequence {
-        Sequence {
-            id: match &value[0] {
-                sql_parse::Expression::Integer(v) => v.0,
-                _ => panic!("Sequence.id"),
-            },
-            name: match &value[1] {
-                sql_parse::Expression::String(v) => remove_wrong_chars(&v.value),
-                _ => panic!("Sequence.name"),
-            },
-        }
-    }
-}
-
 #[async_trait]
 impl Update for Sequence {
     async fn before_update(client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
-        match client.execute(
-            "
-            CREATE OR REPLACE FUNCTION update_sequences(source_ smallint, remote_id_ int, name_ varchar) RETURNS void AS $$
-                BEGIN
-                    IF EXISTS (SELECT * FROM sequences WHERE source = source_ AND remote_id = remote_id_) THEN
-                        UPDATE sequences SET name = name_ WHERE source = source_ AND remote_id = remote_id_;
-                        RETURN;
-                    END IF;
-                    INSERT INTO sequences (source, remote_id, name) VALUES (source_, remote_id_, name_);
-                END;
-            $$ LANGUAGE plpgsql;
-            "
-            , &[]).await {
-                Ok(_) => Ok(()),
-                Err(err) => Err(Box::new(err)),
+        match audit::prepare(client).await {
+            Ok(_) => (),
+            Err(err) => return Err(err),
+        };
+
+        match client
+            .batch_execute(
+                "
+                ALTER TABLE sequences ADD COLUMN IF NOT EXISTS is_deleted boolean NOT NULL DEFAULT false;
+                ALTER TABLE sequences ADD COLUMN IF NOT EXISTS seen_at timestamptz;
+
+                CREATE OR REPLACE FUNCTION audit_sequence_rename() RETURNS trigger AS $$
+                    BEGIN
+                        INSERT INTO audit_log (source, entity, remote_id, action, old_value, new_value)
+                            VALUES (NEW.source, 'sequence', NEW.remote_id, 'renamed', OLD.name, NEW.name);
+                        RETURN NEW;
+                    END;
+                $$ LANGUAGE plpgsql;
+
+                DROP TRIGGER IF EXISTS sequences_rename_audit ON sequences;
+                CREATE TRIGGER sequences_rename_audit AFTER UPDATE OF name ON sequences
+                    FOR EACH ROW WHEN (OLD.name IS DISTINCT FROM NEW.name)
+                    EXECUTE FUNCTION audit_sequence_rename();
+                ",
+            )
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(err) => Err(Box::new(err)),
         }
     }
 
@@ -416,220 +871,273 @@ impl Update for Sequence {
         &self,
         client: &Client,
         source_id: i16,
+    ) -> Result<UpsertResult, Box<tokio_postgres::Error>> {
+        SEQUENCES
+            .execute(client, source_id, &[&self.id, &self.name])
+            .await
+    }
+
+    fn copy_spec() -> Option<&'static UpsertSpec> {
+        Some(&SEQUENCES)
+    }
+
+    fn copy_row(&self) -> CopyRow {
+        vec![Box::new(self.id), Box::new(self.name.clone())]
+    }
+
+    fn tables() -> &'static [&'static str] {
+        &[SEQUENCES.table]
+    }
+
+    async fn after_update(_client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
+        Ok(())
+    }
+
+    async fn after_full_sync(
+        client: &Client,
+        source_id: i16,
+        started_at: DateTime<Utc>,
     ) -> Result<(), Box<tokio_postgres::Error>> {
         match client
             .execute(
-                "SELECT update_sequences($1, $2, cast($3 as varchar));",
-                &[&source_id, &(self.id as i32), &self.name],
+                "
+                WITH deleted AS (
+                    UPDATE sequences SET is_deleted = true
+                    WHERE source = $1 AND NOT is_deleted
+                        AND (seen_at IS NULL OR seen_at < $2)
+                    RETURNING source, remote_id, name
+                )
+                INSERT INTO audit_log (source, entity, remote_id, action, old_value)
+                    SELECT source, 'sequence', remote_id, 'deleted', name FROM deleted;
+                ",
+                &[&source_id, &started_at],
             )
             .await
         {
-            Ok(_) => Ok(()),
+            Ok(count) => {
+                log::info!("Marked {count} sequences as deleted");
+                Ok(())
+            }
             Err(err) => Err(Box::new(err)),
         }
     }
 
-    async fn after_update(_client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
-        Ok(())
+    async fn verify(
+        &self,
+        client: &Client,
+        source_id: i16,
+    ) -> Result<bool, Box<tokio_postgres::Error>> {
+        check(
+            client,
+            "
+            SELECT EXISTS (
+                SELECT 1 FROM sequences
+                WHERE source = $1 AND remote_id = $2 AND name IS NOT DISTINCT FROM cast($3 as varchar)
+            );
+            ",
+            &[&source_id, &self.id, &self.name],
+        )
+        .await
     }
 }
 
-#[derive(Debug)]
+const BOOK_SEQUENCES: UpsertSpec = UpsertSpec {
+    table: "book_sequences",
+    keys: &[
+        Column::Ref("book", "books"),
+        Column::Ref("sequence", "sequences"),
+    ],
+    values: &[Column::Value("position", "smallint")],
+    insert: true,
+    insert_defaults: &[],
+};
+
+#[derive(Debug, Serialize, Deserialize, DumpRow)]
 pub struct SequenceInfo {
-    pub book_id: u64,
-    pub sequence_id: u64,
-    pub position: u64,
-}
-
-impl FromVecExpression<SequenceInfo> for SequenceInfo {
-    fn from_vec_expression(value: &[Expression]) -> SequenceInfo {
-        SequenceInfo {
-            book_id: match &value[0] {
-                sql_parse::Expression::Integer(v) => v.0,
-                _ => panic!("SequenceInfo.book_id"),
-            },
-            sequence_id: match &value[1] {
-                sql_parse::Expression::Integer(v) => v.0,
-                _ => panic!("SequenceInfo.sequence_id"),
-            },
-            position: match &value[2] {
-                sql_parse::Expression::Integer(v) => v.0,
-                sql_parse::Expression::Unary {
-                    op,
-                    op_span: _,
-                    operand,
-                } => match (op, operand.as_ref()) {
-                    (sql_parse::UnaryOperator::Minus, Expression::Integer(v)) => v.0,
-                    (_, _) => panic!("SequenceInfo.position = {:?}", &value[2]),
-                },
-                _ => panic!("SequenceInfo.position = {:?}", &value[2]),
-            },
-        }
-    }
+    #[column(index = 0)]
+    pub book_id: RemoteBookId,
+    #[column(index = 1)]
+    pub sequence_id: RemoteSequenceId,
+    #[column(index = 2, with = position)]
+    pub position: i16,
 }
 
 #[async_trait]
 impl Update for SequenceInfo {
-    async fn before_update(client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
-        match client.execute(
-            "
-            CREATE OR REPLACE FUNCTION update_book_sequence(source_ smallint, book_ integer, sequence_ integer, position_ smallint) RETURNS void AS $$
-                DECLARE
-                    book_id integer := -1;
-                    sequence_id integer := -1;
-                BEGIN
-                    SELECT id INTO book_id FROM books WHERE source = source_ AND remote_id = book_;
-
-                    IF book_id IS NULL THEN
-                        RETURN;
-                    END IF;
-
-                    SELECT id INTO sequence_id FROM sequences WHERE source = source_ AND remote_id = sequence_;
-
-                    IF sequence_id IS NULL THEN
-                        RETURN;
-                    END IF;
-
-                    IF EXISTS (SELECT * FROM book_sequences WHERE book = book_id AND sequence = sequence_id) THEN
-                        UPDATE book_sequences SET position = ABS(position_) WHERE book = book_id AND sequence = sequence_id;
-                        RETURN;
-                    END IF;
-                    INSERT INTO book_sequences (book, sequence, position) VALUES (book_id, sequence_id, ABS(position_));
-                END;
-            $$ LANGUAGE plpgsql;
-            "
-            , &[]).await {
-                Ok(_) => Ok(()),
-                Err(err) => Err(Box::new(err)),
-        }
+    async fn before_update(_client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
+        Ok(())
     }
 
     async fn update(
         &self,
         client: &Client,
         source_id: i16,
-    ) -> Result<(), Box<tokio_postgres::Error>> {
-        match client
+    ) -> Result<UpsertResult, Box<tokio_postgres::Error>> {
+        BOOK_SEQUENCES
             .execute(
-                "SELECT update_book_sequence($1, $2, $3, $4);",
-                &[
-                    &source_id,
-                    &(self.book_id as i32),
-                    &(self.sequence_id as i32),
-                    &(self.position as i16),
-                ],
+                client,
+                source_id,
+                &[&self.book_id, &self.sequence_id, &self.position],
             )
             .await
-        {
-            Ok(_) => Ok(()),
-            Err(err) => Err(Box::new(err)),
-        }
+    }
+
+    fn copy_spec() -> Option<&'static UpsertSpec> {
+        Some(&BOOK_SEQUENCES)
+    }
+
+    fn copy_row(&self) -> CopyRow {
+        vec![
+            Box::new(self.book_id),
+            Box::new(self.sequence_id),
+            Box::new(self.position),
+        ]
+    }
+
+    fn tables() -> &'static [&'static str] {
+        &[BOOK_SEQUENCES.table]
     }
 
     async fn after_update(_client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
         Ok(())
     }
+
+    async fn verify(
+        &self,
+        client: &Client,
+        source_id: i16,
+    ) -> Result<bool, Box<tokio_postgres::Error>> {
+        check(
+            client,
+            "
+            SELECT NOT EXISTS (SELECT 1 FROM books WHERE source = $1 AND remote_id = $2)
+                OR NOT EXISTS (SELECT 1 FROM sequences WHERE source = $1 AND remote_id = $3)
+                OR EXISTS (
+                    SELECT 1 FROM book_sequences
+                    JOIN books ON books.id = book_sequences.book
+                    JOIN sequences ON sequences.id = book_sequences.sequence
+                    WHERE books.source = $1 AND books.remote_id = $2
+                        AND sequences.source = $1 AND sequences.remote_id = $3
+                        AND book_sequences.position = ABS(cast($4 as smallint))
+                );
+            ",
+            &[&source_id, &self.book_id, &self.sequence_id, &self.position],
+        )
+        .await
+    }
 }
 
-#[derive(Debug)]
+const BOOK_ANNOTATIONS: UpsertSpec = UpsertSpec {
+    table: "book_annotations",
+    keys: &[Column::Ref("book", "books")],
+    values: &[
+        Column::Value("title", "varchar"),
+        Column::Value("text", "text"),
+    ],
+    insert: true,
+    insert_defaults: &[],
+};
+
+#[derive(Debug, Serialize, Deserialize, DumpRow)]
 pub struct BookAnnotation {
-    pub book_id: u64,
+    #[column(index = 0)]
+    pub book_id: RemoteBookId,
+    #[column(index = 2, map = sanitized)]
     pub title: String,
+    #[column(index = 3, map = annotation_body)]
     pub body: Option<String>,
 }
 
-impl FromVecExpression<BookAnnotation> for BookAnnotation {
-    fn from_vec_expression(value: &[Expression]) -> BookAnnotation {
-        BookAnnotation {
-            book_id: match &value[0] {
-                sql_parse::Expression::Integer(v) => v.0,
-                _ => panic!("BookAnnotation.book_id"),
-            },
-            title: match &value[2] {
-                sql_parse::Expression::String(v) => v.value.to_string(),
-                _ => panic!("BookAnnotation.title"),
-            },
-            body: match &value[3] {
-                sql_parse::Expression::String(v) => Some(fix_annotation_text(&v.value)),
-                sql_parse::Expression::Null(_) => None,
-                _ => panic!("BookAnnotation.body"),
-            },
-        }
-    }
-}
-
 #[async_trait]
 impl Update for BookAnnotation {
-    async fn before_update(client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
-        match client.execute(
-            "
-            CREATE OR REPLACE FUNCTION update_book_annotation(source_ smallint, book_ integer, title_ varchar, text_ text) RETURNS void AS $$
-                DECLARE
-                    book_id integer := -1;
-                BEGIN
-                    SELECT id INTO book_id FROM books WHERE source = source_ AND remote_id = book_;
-                    IF EXISTS (SELECT * FROM book_annotations WHERE book = book_id) THEN
-                        UPDATE book_annotations SET title = title_, text = text_ WHERE book = book_id;
-                        RETURN;
-                    END IF;
-
-                    IF book_id IS NULL THEN
-                        RETURN;
-                    END IF;
-
-                    INSERT INTO book_annotations (book, title, text) VALUES (book_id, title_, text_);
-                END;
-            $$ LANGUAGE plpgsql;
-            "
-            , &[]).await {
-                Ok(_) => Ok(()),
-                Err(err) => Err(Box::new(err)),
-        }
+    async fn before_update(_client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
+        Ok(())
     }
 
     async fn update(
         &self,
         client: &Client,
         source_id: i16,
-    ) -> Result<(), Box<tokio_postgres::Error>> {
+    ) -> Result<UpsertResult, Box<tokio_postgres::Error>> {
+        BOOK_ANNOTATIONS
+            .execute(client, source_id, &[&self.book_id, &self.title, &self.body])
+            .await
+    }
+
+    fn copy_spec() -> Option<&'static UpsertSpec> {
+        Some(&BOOK_ANNOTATIONS)
+    }
+
+    fn copy_row(&self) -> CopyRow {
+        vec![
+            Box::new(self.book_id),
+            Box::new(self.title.clone()),
+            Box::new(self.body.clone()),
+        ]
+    }
+
+    fn tables() -> &'static [&'static str] {
+        &[BOOK_ANNOTATIONS.table]
+    }
+
+    async fn after_update(client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
         match client
             .execute(
-                "SELECT update_book_annotation($1, $2, cast($3 as varchar), cast($4 as text));",
-                &[&source_id, &(self.book_id as i32), &self.title, &self.body],
+                "DELETE FROM book_annotations WHERE NOT EXISTS (SELECT 1 FROM books WHERE books.id = book_annotations.book);",
+                &[],
             )
             .await
         {
-            Ok(_) => Ok(()),
+            Ok(count) => {
+                if count > 0 {
+                    log::warn!("Removed {count} orphaned book annotations");
+                }
+                Ok(())
+            }
             Err(err) => Err(Box::new(err)),
         }
     }
 
-    async fn after_update(_client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
-        Ok(())
+    async fn verify(
+        &self,
+        client: &Client,
+        source_id: i16,
+    ) -> Result<bool, Box<tokio_postgres::Error>> {
+        check(
+            client,
+            "
+            SELECT NOT EXISTS (SELECT 1 FROM books WHERE source = $1 AND remote_id = $2)
+                OR EXISTS (
+                    SELECT 1 FROM book_annotations
+                    JOIN books ON books.id = book_annotations.book
+                    WHERE books.source = $1 AND books.remote_id = $2
+                        AND book_annotations.title IS NOT DISTINCT FROM cast($3 as varchar)
+                        AND book_annotations.text IS NOT DISTINCT FROM cast($4 as text)
+                );
+            ",
+            &[&source_id, &self.book_id, &self.title, &self.body],
+        )
+        .await
     }
 }
 
-#[derive(Debug)]
+// Pics can come without an annotation, keep them on a stub row until the text shows up
+const BOOK_ANNOTATION_PICS: UpsertSpec = UpsertSpec {
+    table: "book_annotations",
+    keys: &[Column::Ref("book", "books")],
+    values: &[Column::Value("file", "varchar")],
+    insert: true,
+    insert_defaults: &[Column::Sql("title", "''")],
+};
+
+#[derive(Debug, Serialize, Deserialize, DumpRow)]
 pub struct BookAnnotationPic {
-    pub book_id: u64,
+    #[column(index = 0)]
+    pub book_id: RemoteBookId,
+    #[column(index = 2, map = sanitized)]
     pub file: String,
 }
 
-impl FromVecExpression<BookAnnotationPic> for BookAnnotationPic {
-    fn from_vec_expression(value: &[Expression]) -> BookAnnotationPic {
-        BookAnnotationPic {
-            book_id: match &value[0] {
-                sql_parse::Expression::Integer(v) => v.0,
-                _ => panic!("BookAnnotationPic.book_id"),
-            },
-            file: match &value[2] {
-                sql_parse::Expression::String(v) => v.value.to_string(),
-                _ => panic!("BookAnnotationPic.file"),
-            },
-        }
-    }
-}
-
 #[async_trait]
 impl Update for BookAnnotationPic {
     async fn before_update(_client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
@@ -640,128 +1148,168 @@ impl Update for BookAnnotationPic {
         &self,
         client: &Client,
         source_id: i16,
-    ) -> Result<(), Box<tokio_postgres::Error>> {
-        match client
-            .execute(
-                "\
-UPDATE book_annotations \
-SET file = cast($3 as varchar) \
-FROM (SELECT id FROM books WHERE source = $1 AND remote_id = $2) as books \
-WHERE book = books.id;\
-            ",
-                &[&source_id, &(self.book_id as i32), &self.file],
-            )
+    ) -> Result<UpsertResult, Box<tokio_postgres::Error>> {
+        BOOK_ANNOTATION_PICS
+            .execute(client, source_id, &[&self.book_id, &self.file])
             .await
-        {
-            Ok(_) => Ok(()),
-            Err(err) => Err(Box::new(err)),
-        }
+    }
+
+    fn copy_spec() -> Option<&'static UpsertSpec> {
+        Some(&BOOK_ANNOTATION_PICS)
+    }
+
+    fn copy_row(&self) -> CopyRow {
+        vec![Box::new(self.book_id), Box::new(self.file.clone())]
+    }
+
+    fn tables() -> &'static [&'static str] {
+        &[BOOK_ANNOTATION_PICS.table]
     }
 
     async fn after_update(_client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
         Ok(())
     }
+
+    async fn verify(
+        &self,
+        client: &Client,
+        source_id: i16,
+    ) -> Result<bool, Box<tokio_postgres::Error>> {
+        check(
+            client,
+            "
+            SELECT NOT EXISTS (
+                    SELECT 1 FROM book_annotations
+                    JOIN books ON books.id = book_annotations.book
+                    WHERE books.source = $1 AND books.remote_id = $2
+                )
+                OR EXISTS (
+                    SELECT 1 FROM book_annotations
+                    JOIN books ON books.id = book_annotations.book
+                    WHERE books.source = $1 AND books.remote_id = $2
+                        AND book_annotations.file IS NOT DISTINCT FROM cast($3 as varchar)
+                );
+            ",
+            &[&source_id, &self.book_id, &self.file],
+        )
+        .await
+    }
 }
 
-#[derive(Debug)]
+const AUTHOR_ANNOTATIONS: UpsertSpec = UpsertSpec {
+    table: "author_annotations",
+    keys: &[Column::Ref("author", "authors")],
+    values: &[
+        Column::Value("title", "varchar"),
+        Column::Value("text", "text"),
+    ],
+    insert: true,
+    insert_defaults: &[],
+};
+
+#[derive(Debug, Serialize, Deserialize, DumpRow)]
 pub struct AuthorAnnotation {
-    pub author_id: u64,
+    #[column(index = 0)]
+    pub author_id: RemoteAuthorId,
+    #[column(index = 2, map = sanitized)]
     pub title: String,
+    #[column(index = 3, map = annotation_body)]
     pub body: Option<String>,
 }
 
-impl FromVecExpression<AuthorAnnotation> for AuthorAnnotation {
-    fn from_vec_expression(value: &[Expression]) -> AuthorAnnotation {
-        AuthorAnnotation {
-            author_id: match &value[0] {
-                sql_parse::Expression::Integer(v) => v.0,
-                _ => panic!("AuthorAnnotation.author_id"),
-            },
-            title: match &value[2] {
-                sql_parse::Expression::String(v) => v.value.to_string(),
-                _ => panic!("AuthorAnnotation.title"),
-            },
-            body: match &value[3] {
-                sql_parse::Expression::String(v) => Some(fix_annotation_text(&v.value)),
-                sql_parse::Expression::Null(_) => None,
-                _ => panic!("AuthorAnnotation.body"),
-            },
-        }
-    }
-}
-
 #[async_trait]
 impl Update for AuthorAnnotation {
-    async fn before_update(client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
-        match client.execute(
-            "
-            CREATE OR REPLACE FUNCTION update_author_annotation(source_ smallint, author_ integer, title_ varchar, text_ text) RETURNS void AS $$
-                DECLARE
-                    author_id integer := -1;
-                BEGIN
-                    SELECT id INTO author_id FROM authors WHERE source = source_ AND remote_id = author_;
-                    IF EXISTS (SELECT * FROM author_annotations WHERE author = author_id) THEN
-                        UPDATE author_annotations SET title = title_, text = text_ WHERE author = author_id;
-                        RETURN;
-                    END IF;
-                    INSERT INTO author_annotations (author, title, text) VALUES (author_id, title_, text_);
-                END;
-            $$ LANGUAGE plpgsql;
-            "
-            , &[]).await {
-                Ok(_) => Ok(()),
-                Err(err) => Err(Box::new(err)),
-        }
+    async fn before_update(_client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
+        Ok(())
     }
 
     async fn update(
         &self,
         client: &Client,
         source_id: i16,
-    ) -> Result<(), Box<tokio_postgres::Error>> {
+    ) -> Result<UpsertResult, Box<tokio_postgres::Error>> {
+        AUTHOR_ANNOTATIONS
+            .execute(
+                client,
+                source_id,
+                &[&self.author_id, &self.title, &self.body],
+            )
+            .await
+    }
+
+    fn copy_spec() -> Option<&'static UpsertSpec> {
+        Some(&AUTHOR_ANNOTATIONS)
+    }
+
+    fn copy_row(&self) -> CopyRow {
+        vec![
+            Box::new(self.author_id),
+            Box::new(self.title.clone()),
+            Box::new(self.body.clone()),
+        ]
+    }
+
+    fn tables() -> &'static [&'static str] {
+        &[AUTHOR_ANNOTATIONS.table]
+    }
+
+    async fn after_update(client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
         match client
             .execute(
-                "SELECT update_author_annotation($1, $2, cast($3 as varchar), cast($4 as text));",
-                &[
-                    &source_id,
-                    &(self.author_id as i32),
-                    &self.title,
-                    &self.body,
-                ],
+                "DELETE FROM author_annotations WHERE NOT EXISTS (SELECT 1 FROM authors WHERE authors.id = author_annotations.author);",
+                &[],
             )
             .await
         {
-            Ok(_) => Ok(()),
+            Ok(count) => {
+                if count > 0 {
+                    log::warn!("Removed {count} orphaned author annotations");
+                }
+                Ok(())
+            }
             Err(err) => Err(Box::new(err)),
         }
     }
 
-    async fn after_update(_client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
-        Ok(())
+    async fn verify(
+        &self,
+        client: &Client,
+        source_id: i16,
+    ) -> Result<bool, Box<tokio_postgres::Error>> {
+        check(
+            client,
+            "
+            SELECT NOT EXISTS (SELECT 1 FROM authors WHERE source = $1 AND remote_id = $2)
+                OR EXISTS (
+                    SELECT 1 FROM author_annotations
+                    JOIN authors ON authors.id = author_annotations.author
+                    WHERE authors.source = $1 AND authors.remote_id = $2
+                        AND author_annotations.title IS NOT DISTINCT FROM cast($3 as varchar)
+                        AND author_annotations.text IS NOT DISTINCT FROM cast($4 as text)
+                );
+            ",
+            &[&source_id, &self.author_id, &self.title, &self.body],
+        )
+        .await
     }
 }
 
-#[derive(Debug)]
+const AUTHOR_ANNOTATION_PICS: UpsertSpec = UpsertSpec {
+    table: "author_annotations",
+    keys: &[Column::Ref("author", "authors")],
+    values: &[Column::Value("file", "varchar")],
+    insert: true,
+    insert_defaults: &[Column::Sql("title", "''")],
+};
+
+#[derive(Debug, Serialize, Deserialize, DumpRow)]
 pub struct AuthorAnnotationPic {
-    pub author_id: u64,
+    #[column(index = 0)]
+    pub author_id: RemoteAuthorId,
+    #[column(index = 2, map = sanitized)]
     pub file: String,
 }
 
-impl FromVecExpression<AuthorAnnotationPic> for AuthorAnnotationPic {
-    fn from_vec_expression(value: &[Expression]) -> AuthorAnnotationPic {
-        AuthorAnnotationPic {
-            author_id: match &value[0] {
-                sql_parse::Expression::Integer(v) => v.0,
-                _ => panic!("AuthorAnnotationPic.book_id"),
-            },
-            file: match &value[2] {
-                sql_parse::Expression::String(v) => v.value.to_string(),
-                _ => panic!("AuthorAnnotationPic.file"),
-            },
-        }
-    }
-}
-
 #[async_trait]
 impl Update for AuthorAnnotationPic {
     async fn before_update(_client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
@@ -772,129 +1320,161 @@ impl Update for AuthorAnnotationPic {
         &self,
         client: &Client,
         source_id: i16,
-    ) -> Result<(), Box<tokio_postgres::Error>> {
-        match client
-            .execute(
-                "\
-UPDATE author_annotations \
-SET file = cast($3 as varchar) \
-FROM (SELECT id FROM authors WHERE source = $1 AND remote_id = $2) as authors \
-WHERE author = authors.id;",
-                &[&source_id, &(self.author_id as i32), &self.file],
-            )
+    ) -> Result<UpsertResult, Box<tokio_postgres::Error>> {
+        AUTHOR_ANNOTATION_PICS
+            .execute(client, source_id, &[&self.author_id, &self.file])
             .await
-        {
-            Ok(_) => Ok(()),
-            Err(err) => Err(Box::new(err)),
-        }
+    }
+
+    fn copy_spec() -> Option<&'static UpsertSpec> {
+        Some(&AUTHOR_ANNOTATION_PICS)
+    }
+
+    fn copy_row(&self) -> CopyRow {
+        vec![Box::new(self.author_id), Box::new(self.file.clone())]
+    }
+
+    fn tables() -> &'static [&'static str] {
+        &[AUTHOR_ANNOTATION_PICS.table]
     }
 
     async fn after_update(_client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
         Ok(())
     }
+
+    async fn verify(
+        &self,
+        client: &Client,
+        source_id: i16,
+    ) -> Result<bool, Box<tokio_postgres::Error>> {
+        check(
+            client,
+            "
+            SELECT NOT EXISTS (
+                    SELECT 1 FROM author_annotations
+                    JOIN authors ON authors.id = author_annotations.author
+                    WHERE authors.source = $1 AND authors.remote_id = $2
+                )
+                OR EXISTS (
+                    SELECT 1 FROM author_annotations
+                    JOIN authors ON authors.id = author_annotations.author
+                    WHERE authors.source = $1 AND authors.remote_id = $2
+                        AND author_annotations.file IS NOT DISTINCT FROM cast($3 as varchar)
+                );
+            ",
+            &[&source_id, &self.author_id, &self.file],
+        )
+        .await
+    }
 }
 
-#[derive(Debug)]
+const GENRES: UpsertSpec = UpsertSpec {
+    table: "genres",
+    keys: &[Column::Source, Column::Value("remote_id", "int")],
+    values: &[
+        Column::Value("code", "varchar"),
+        Column::Value("description", "varchar"),
+        Column::Value("meta", "varchar"),
+    ],
+    insert: true,
+    insert_defaults: &[],
+};
+
+#[derive(Debug, Serialize, Deserialize, DumpRow)]
 pub struct Genre {
-    pub id: u64,
+    #[column(index = 0)]
+    pub id: RemoteGenreId,
+    #[column(index = 1, map = sanitized)]
     pub code: String,
+    #[column(index = 2, map = sanitized)]
     pub description: String,
+    #[column(index = 3, map = sanitized)]
     pub meta: String,
 }
 
-impl FromVecExpression<Genre> for Genre {
-    fn from_vec_expression(value: &[Expression]) -> Genre {
-        Genre {
-            id: match &value[0] {
-                sql_parse::Expression::Integer(v) => v.0,
-                _ => panic!("Genre.id"),
-            },
-            code: match &value[1] {
-                sql_parse::Expression::String(v) => v.value.to_string(),
-                _ => panic!("Genre.code = {:?}", &value[1]),
-            },
-            description: match &value[2] {
-                sql_parse::Expression::String(v) => v.value.to_string(),
-                _ => panic!("Genre.description = {:?}", &value[2]),
-            },
-            meta: match &value[3] {
-                sql_parse::Expression::String(v) => v.value.to_string(),
-                _ => panic!("Genre.meta"),
-            },
-        }
-    }
-}
-
 #[async_trait]
 impl Update for Genre {
-    async fn before_update(client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
-        match client.execute(
-            "
-            CREATE OR REPLACE FUNCTION update_book_sequence(source_ smallint, book_ integer, genre_ integer) RETURNS void AS $$
-                DECLARE
-                    book_id integer := -1;
-                    genre_id integer := -1;
-                BEGIN
-                    SELECT id INTO book_id FROM books WHERE source = source_ AND remote_id = book_;
-
-                    IF book_id IS NULL THEN
-                        RETURN;
-                    END IF;
-
-                    SELECT id INTO genre_id FROM genres WHERE source = source_ AND remote_id = genre_;
-                    IF EXISTS (SELECT * FROM book_genres WHERE book = book_id AND genre = genre_id) THEN
-                        RETURN;
-                    END IF;
-                    INSERT INTO book_genres (book, genre) VALUES (book_id, genre_id);
-                END;
-            $$ LANGUAGE plpgsql;
-            "
-            , &[]).await {
-                Ok(_) => Ok(()),
-                Err(err) => Err(Box::new(err)),
-        }
+    async fn before_update(_client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
+        Ok(())
     }
 
     async fn update(
         &self,
         client: &Client,
         source_id: i16,
-    ) -> Result<(), Box<tokio_postgres::Error>> {
-        match client
+    ) -> Result<UpsertResult, Box<tokio_postgres::Error>> {
+        GENRES
             .execute(
-                "SELECT update_genre($1, $2, cast($3 as varchar), cast($4 as varchar), cast($5 as varchar));",
-                &[&source_id, &(self.id as i32), &self.code, &self.description, &self.meta]
-            ).await
-        {
-            Ok(_) => Ok(()),
-            Err(err) => Err(Box::new(err)),
-        }
+                client,
+                source_id,
+                &[&self.id, &self.code, &self.description, &self.meta],
+            )
+            .await
+    }
+
+    fn copy_spec() -> Option<&'static UpsertSpec> {
+        Some(&GENRES)
+    }
+
+    fn copy_row(&self) -> CopyRow {
+        vec![
+            Box::new(self.id),
+            Box::new(self.code.clone()),
+            Box::new(self.description.clone()),
+            Box::new(self.meta.clone()),
+        ]
+    }
+
+    fn tables() -> &'static [&'static str] {
+        &[GENRES.table]
     }
 
     async fn after_update(_client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
         Ok(())
     }
+
+    async fn verify(
+        &self,
+        client: &Client,
+        source_id: i16,
+    ) -> Result<bool, Box<tokio_postgres::Error>> {
+        check(
+            client,
+            "
+            SELECT EXISTS (
+                SELECT 1 FROM genres
+                WHERE source = $1 AND remote_id = $2
+                    AND code IS NOT DISTINCT FROM cast($3 as varchar)
+                    AND description IS NOT DISTINCT FROM cast($4 as varchar)
+                    AND meta IS NOT DISTINCT FROM cast($5 as varchar)
+            );
+            ",
+            &[
+                &source_id,
+                &self.id,
+                &self.code,
+                &self.description,
+                &self.meta,
+            ],
+        )
+        .await
+    }
 }
 
-#[derive(Debug)]
+const BOOK_GENRES: UpsertSpec = UpsertSpec {
+    table: "book_genres",
+    keys: &[Column::Ref("book", "books"), Column::Ref("genre", "genres")],
+    values: &[],
+    insert: true,
+    insert_defaults: &[],
+};
+
+#[derive(Debug, Serialize, Deserialize, DumpRow)]
 pub struct BookGenre {
-    pub book_id: u64,
-    pub genre_id: u64,
-}
-
-impl FromVecExpression<BookGenre> for BookGenre {
-    fn from_vec_expression(value: &[Expression]) -> BookGenre {
-        BookGenre {
-            book_id: match &value[1] {
-                sql_parse::Expression::Integer(v) => v.0,
-                _ => panic!("BookGenre.book_id"),
-            },
-            genre_id: match &value[2] {
-                sql_parse::Expression::Integer(v) => v.0,
-                _ => panic!("BookGenre.genre_id"),
-            },
-        }
-    }
+    #[column(index = 1)]
+    pub book_id: RemoteBookId,
+    #[column(index = 2)]
+    pub genre_id: RemoteGenreId,
 }
 
 #[async_trait]
@@ -907,20 +1487,83 @@ impl Update for BookGenre {
         &self,
         client: &Client,
         source_id: i16,
-    ) -> Result<(), Box<tokio_postgres::Error>> {
-        match client
-            .execute(
-                "SELECT update_book_sequence($1, $2, $3);",
-                &[&source_id, &(self.book_id as i32), &(self.genre_id as i32)],
-            )
+    ) -> Result<UpsertResult, Box<tokio_postgres::Error>> {
+        BOOK_GENRES
+            .execute(client, source_id, &[&self.book_id, &self.genre_id])
             .await
-        {
-            Ok(_) => Ok(()),
-            Err(err) => Err(Box::new(err)),
-        }
+    }
+
+    fn copy_spec() -> Option<&'static UpsertSpec> {
+        Some(&BOOK_GENRES)
+    }
+
+    fn copy_row(&self) -> CopyRow {
+        vec![Box::new(self.book_id), Box::new(self.genre_id)]
+    }
+
+    fn tables() -> &'static [&'static str] {
+        &[BOOK_GENRES.table]
     }
 
     async fn after_update(_client: &Client) -> Result<(), Box<tokio_postgres::Error>> {
         Ok(())
     }
+
+    async fn verify(
+        &self,
+        client: &Client,
+        source_id: i16,
+    ) -> Result<bool, Box<tokio_postgres::Error>> {
+        check(
+            client,
+            "
+            SELECT NOT EXISTS (SELECT 1 FROM books WHERE source = $1 AND remote_id = $2)
+                OR NOT EXISTS (SELECT 1 FROM genres WHERE source = $1 AND remote_id = $3)
+                OR EXISTS (
+                    SELECT 1 FROM book_genres
+                    JOIN books ON books.id = book_genres.book
+                    JOIN genres ON genres.id = book_genres.genre
+                    WHERE books.source = $1 AND books.remote_id = $2
+                        AND genres.source = $1 AND genres.remote_id = $3
+                );
+            ",
+            &[&source_id, &self.book_id, &self.genre_id],
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use crate::ids::RemoteBookId;
+    use crate::types::{Book, DeletedReason};
+
+    fn book(lang: &str, is_deleted: bool) -> Book {
+        Book {
+            id: RemoteBookId(1),
+            title: "Title".to_string(),
+            lang: lang.to_string(),
+            file_type: "fb2".to_string(),
+            uploaded: NaiveDate::from_ymd_opt(2024, 1, 1).unwrap(),
+            is_deleted,
+            pages: 10,
+            year: 2000,
+            year_clamped: false,
+        }
+    }
+
+    #[test]
+    fn test_book_deleted() {
+        assert_eq!(book("ru", false).deleted(), (false, None));
+        assert_eq!(
+            book("en", false).deleted(),
+            (true, Some(DeletedReason::Language))
+        );
+        assert_eq!(
+            book("en", true).deleted(),
+            (true, Some(DeletedReason::Upstream))
+        );
+    }
 }
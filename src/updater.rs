@@ -1,51 +1,256 @@
-use std::{fmt::Debug, str::FromStr, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    path::Path,
+    sync::Arc,
+};
 
-use crate::config::{self, Webhook};
-use deadpool_postgres::{Config, CreatePoolError, ManagerConfig, Pool, RecyclingMethod, Runtime};
-use futures::{io::copy, TryStreamExt};
-use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use crate::config::{self, DumpDiscovery, DumpEncoding, Source, Table, WriteSettings};
+use chrono::{DateTime, NaiveDate, Utc};
+use deadpool_postgres::{Object, Pool};
+use futures::{io::copy, AsyncWrite, AsyncWriteExt, Future, StreamExt, TryStreamExt};
+use md5::{Digest, Md5};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use sentry::{Breadcrumb, Hub, Level, SentryFutureExt};
+use serde::{de::DeserializeOwned, Serialize};
 use tokio::fs::{remove_file, File};
-use tokio::sync::Mutex;
+use tokio::sync::{watch, Mutex, OwnedSemaphorePermit};
+use tokio::task::{JoinHandle, JoinSet};
 use tokio_cron_scheduler::{Job, JobScheduler};
-use tokio_postgres::NoTls;
-use tracing::log;
-
-use async_compression::futures::bufread::GzipDecoder;
-
-use crate::types::{
-    Author, AuthorAnnotation, AuthorAnnotationPic, BookAnnotation, BookAnnotationPic, BookAuthor,
-    BookGenre, FromVecExpression, Genre, Sequence, SequenceInfo, Translator, Update,
-};
-use crate::utils::read_lines;
+use tracing::{log, Instrument};
+use uuid::Uuid;
+
+use async_compression::futures::{bufread::GzipDecoder, write::ZstdEncoder};
+
+use crate::arrivals;
+use crate::batching::BatchSizer;
+use crate::catalogs;
+use crate::changes;
+use crate::db;
+use crate::discovery;
+use crate::dump_row::{Columns, RowOrigin};
+use crate::dumps;
+use crate::duplicates;
+use crate::errors::UpdaterError;
+use crate::hooks::{self, RowContext, RowHooks};
+use crate::http;
+use crate::indexer;
+use crate::jobs;
+use crate::limits;
+use crate::monitor;
+use crate::notify;
+use crate::onix_export;
+#[cfg(feature = "wasm-plugins")]
+use crate::plugins;
+use crate::preview::TablePreview;
+use crate::progress::{self, TableState};
+use crate::registry;
+use crate::rejects::{self, Reject, ReprocessReport};
+use crate::report::{self, ParseIssues, RowCounts, TableReport, TableStatus, UpdateReport};
+use crate::retention;
+use crate::run_log;
+use crate::runs::{self, RunStatus};
+#[cfg(feature = "field-scripts")]
+use crate::scripts;
+use crate::search_index;
+use crate::shadow;
+use crate::sqlite_export;
+use crate::stats;
+use crate::throttle::Throttle;
+use crate::types::{FromVecExpression, Update};
+use crate::upsert::CopyRow;
+use crate::utils::{insert_tuples, read_lines, InsertTuples, Lines};
 use sql_parse::{
-    parse_statement, InsertReplace, InsertReplaceType, Issues, ParseOptions, SQLArguments,
-    SQLDialect, Statement,
+    parse_statement, InsertReplace, Issues, ParseOptions, SQLArguments, SQLDialect, Statement,
 };
 use tokio_util::compat::TokioAsyncReadCompatExt;
 
-use crate::types::Book;
+/// Returns whether the dump of an earlier run is reused
+pub(crate) async fn download_file(
+    source: &Source,
+    filename_str: &str,
+) -> Result<bool, Box<dyn std::error::Error + Send>> {
+    if dumps::is_reusable(&source.name, filename_str).await {
+        log::info!("Reuse downloaded {filename_str}");
+        progress::update_download(&source.name, filename_str, |download| {
+            download.finished = true
+        });
+        return Ok(true);
+    }
 
-async fn download_file(filename_str: &str) -> Result<(), Box<dyn std::error::Error + Send>> {
-    log::info!("Download {filename_str}...");
+    let mut retries = 0;
+
+    loop {
+        match try_download_file(source, filename_str).await {
+            Ok(_) => {
+                progress::update_download(&source.name, filename_str, |download| {
+                    download.finished = true
+                });
+                return Ok(false);
+            }
+            Err(err) if retries < config::CONFIG.download_retries => {
+                retries += 1;
+                log::warn!(
+                    "Download {filename_str} failed (retry {retries}): {:?}",
+                    err
+                );
+                progress::update_download(&source.name, filename_str, |download| {
+                    download.retries = retries
+                });
+                tokio::time::sleep(std::time::Duration::from_secs(5 << retries.min(6))).await;
+            }
+            Err(err) => {
+                progress::update_download(&source.name, filename_str, |download| {
+                    download.failed = true
+                });
+                return Err(err);
+            }
+        }
+    }
+}
 
-    let link = format!("{}/sql/{filename_str}.gz", &config::CONFIG.fl_base_url);
+/// The md5 the source publishes for a dump, not every dump has one
+async fn source_checksum(
+    source: &Source,
+    filename_str: &str,
+) -> Result<Option<String>, Box<dyn std::error::Error + Send>> {
+    let link = format!("{}/sql/{filename_str}.gz.md5", &source.base_url);
 
-    let response = match reqwest::get(link).await {
+    let response = match http::get(&link).await {
         Ok(v) => v,
         Err(err) => return Err(Box::new(err)),
     };
 
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+
     let response = match response.error_for_status() {
         Ok(v) => v,
         Err(err) => return Err(Box::new(err)),
     };
 
-    match remove_file(filename_str).await {
+    // `md5sum` output: the hash, then the file name
+    match response.text().await {
+        Ok(v) => Ok(v.split_whitespace().next().map(|v| v.to_lowercase())),
+        Err(err) => Err(Box::new(err)),
+    }
+}
+
+/// Error of a download stream, a stalled one is aborted with a timeout
+fn stream_error(file_name: &str, err: reqwest::Error) -> std::io::Error {
+    if err.is_timeout() {
+        log::warn!("Download {file_name} stalled, abort");
+    }
+
+    std::io::Error::other(err)
+}
+
+async fn try_download_file(
+    source: &Source,
+    filename_str: &str,
+) -> Result<(), Box<dyn std::error::Error + Send>> {
+    log::info!("Download {filename_str}...");
+
+    let expected_checksum = if config::CONFIG.verify_source_checksums {
+        match source_checksum(source, filename_str).await {
+            Ok(v) => v,
+            Err(err) => return Err(err),
+        }
+    } else {
+        None
+    };
+
+    let link = format!("{}/sql/{filename_str}.gz", &source.base_url);
+
+    // An archive keeps whole `.gz` files
+    let is_archive = source.archive_date.is_some();
+    let torrent = config::CONFIG
+        .torrent_dumps
+        .get(filename_str)
+        .filter(|_| !is_archive);
+    let is_split = !is_archive && config::CONFIG.split_dumps.iter().any(|v| v == filename_str);
+
+    #[cfg(not(feature = "torrent"))]
+    if torrent.is_some() {
+        log::warn!("{filename_str}: built without the torrent feature, download over HTTP");
+    }
+
+    let (stream, total_bytes, url) = match torrent {
+        #[cfg(feature = "torrent")]
+        Some(torrent) => {
+            let folder = dumps::torrent_dir(&source.name, filename_str);
+
+            let file = match crate::torrent::open(torrent, filename_str, folder).await {
+                Ok(v) => v,
+                Err(err) => return Err(err),
+            };
+
+            let total_bytes = match file.metadata().await {
+                Ok(v) => Some(v.len()),
+                Err(err) => return Err(Box::new(err)),
+            };
+
+            (
+                tokio_util::io::ReaderStream::new(file).boxed(),
+                total_bytes,
+                http::redact_link(torrent),
+            )
+        }
+        // The parts of a split dump together are the `.gz` file
+        _ if is_split => match http::download_parts(&link).await {
+            Ok(v) => (
+                v.map_err(|err| stream_error(filename_str, err)).boxed(),
+                None,
+                format!("{}.partNN", http::redact_link(&link)),
+            ),
+            Err(err) => return Err(Box::new(err)),
+        },
+        _ => {
+            let response = match http::download(&link).await {
+                Ok(v) => v,
+                Err(err) => return Err(Box::new(err)),
+            };
+
+            let response = match response.error_for_status() {
+                Ok(v) => v,
+                Err(err) => return Err(Box::new(err)),
+            };
+
+            let total_bytes = response.content_length();
+            let url = http::redact(response.url());
+            (
+                response
+                    .bytes_stream()
+                    .map_err(|err| stream_error(filename_str, err))
+                    .boxed(),
+                total_bytes,
+                url,
+            )
+        }
+    };
+
+    let started_at = std::time::Instant::now();
+
+    progress::update_download(&source.name, filename_str, |download| {
+        download.bytes_downloaded = 0;
+        download.total_bytes = total_bytes;
+        download.bytes_per_second = 0.0;
+    });
+
+    if let Err(err) = tokio::fs::create_dir_all(dumps::dir(&source.name)).await {
+        log::error!("Can't create the dumps directory: {:?}", err);
+        return Err(Box::new(err));
+    }
+
+    let part_path = dumps::part_path(&source.name, filename_str);
+
+    match remove_file(&part_path).await {
         Ok(_) => (),
         Err(err) => log::debug!("Can't remove file: {:?}", err),
     };
 
-    let mut file = match File::create(filename_str).await {
+    let file = match File::create(&part_path).await {
         Ok(v) => v.compat(),
         Err(err) => {
             log::error!("Can't create {filename_str}: {:?}", err);
@@ -53,9 +258,23 @@ async fn download_file(filename_str: &str) -> Result<(), Box<dyn std::error::Err
         }
     };
 
-    let data = response
-        .bytes_stream()
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+    let mut file: Box<dyn AsyncWrite + Unpin + Send> = if config::CONFIG.compress_scratch_files {
+        Box::new(ZstdEncoder::new(file))
+    } else {
+        Box::new(file)
+    };
+
+    let mut hasher = Md5::new();
+
+    let data = stream
+        .inspect_ok(|chunk| {
+            hasher.update(chunk);
+            progress::update_download(&source.name, filename_str, |download| {
+                download.bytes_downloaded += chunk.len() as u64;
+                download.bytes_per_second =
+                    download.bytes_downloaded as f64 / started_at.elapsed().as_secs_f64();
+            })
+        })
         .into_async_read();
 
     let decoder = GzipDecoder::new(data);
@@ -68,23 +287,227 @@ async fn download_file(filename_str: &str) -> Result<(), Box<dyn std::error::Err
         }
     };
 
+    match file.close().await {
+        Ok(_) => (),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let actual = format!("{:x}", hasher.finalize());
+
+    if let Some(expected) = expected_checksum {
+        // Corrupted data never reaches the place the import reads from
+        if actual != expected {
+            return Err(Box::new(UpdaterError::ChecksumMismatch {
+                file_name: filename_str.to_string(),
+                expected,
+                actual,
+            }));
+        }
+    }
+
+    let duration_ms = started_at.elapsed().as_millis() as u64;
+    match dumps::commit(&source.name, filename_str, url, actual, duration_ms).await {
+        Ok(_) => (),
+        Err(err) => {
+            log::error!("Can't store {filename_str}: {:?}", err);
+            return Err(Box::new(err));
+        }
+    };
+
     log::info!("{filename_str} downloaded!");
 
     Ok(())
 }
 
+lazy_static! {
+    static ref PAUSED: watch::Sender<bool> = watch::channel(false).0;
+}
+
+pub fn pause() {
+    PAUSED.send_replace(true);
+}
+
+pub fn resume() {
+    PAUSED.send_replace(false);
+}
+
+async fn wait_if_paused(paused: &mut watch::Receiver<bool>, file_name: &str) -> bool {
+    if !*paused.borrow() {
+        return false;
+    }
+
+    log::info!("Update {file_name} paused...");
+
+    // The sender lives in a static, so the channel is never closed
+    let _ = paused.wait_for(|is_paused| !*is_paused).await;
+
+    log::info!("Update {file_name} resumed...");
+
+    true
+}
+
+/// The `INSERT` line being read, with where its next tuple starts
+struct CurrentInsert {
+    line: String,
+    line_index: usize,
+    /// Length of the head, up to and including `VALUES `
+    head: usize,
+    offset: usize,
+    tuples: u64,
+}
+
+/// The tuples of the `INSERT`s of a dump as single-tuple statements, one at a
+/// time, with the columns of its `CREATE TABLE`. Only the current line is held.
+pub(crate) struct DumpStatements {
+    file_name: String,
+    lines: std::iter::Enumerate<Lines>,
+    parse_options: ParseOptions,
+    statement_index: u64,
+    insert: Option<CurrentInsert>,
+    pub(crate) columns: Arc<Columns>,
+}
+
+impl DumpStatements {
+    pub(crate) fn open(source: &Source, file_name: &str) -> std::io::Result<DumpStatements> {
+        let lines = read_lines(
+            dumps::path(&source.name, file_name),
+            config::CONFIG.dump_encoding,
+        )?;
+
+        Ok(DumpStatements::new(file_name, lines))
+    }
+
+    /// Same as `open`, for a dump file outside of the dumps directory
+    pub(crate) fn open_path(
+        path: &Path,
+        encoding: DumpEncoding,
+    ) -> std::io::Result<DumpStatements> {
+        let lines = read_lines(path, encoding)?;
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+
+        Ok(DumpStatements::new(&file_name, lines))
+    }
+
+    fn new(file_name: &str, lines: Lines) -> DumpStatements {
+        DumpStatements {
+            file_name: file_name.to_string(),
+            lines: lines.enumerate(),
+            parse_options: parse_options(),
+            statement_index: 0,
+            insert: None,
+            columns: Arc::new(Columns::default()),
+        }
+    }
+
+    /// The next tuple of the current `INSERT`, `None` at its end
+    fn next_tuple(&mut self) -> Option<(RowOrigin, String)> {
+        let insert = self.insert.as_mut()?;
+
+        let mut tuples = InsertTuples::resume(&insert.line[insert.offset..]);
+        let tuple = match tuples.next() {
+            Some(v) => v,
+            None => {
+                self.insert = None;
+                return None;
+            }
+        };
+
+        insert.offset = insert.line.len() - tuples.rest().len();
+        insert.tuples += 1;
+
+        let origin = RowOrigin {
+            line: insert.line_index as u64 + 1,
+            statement: self.statement_index,
+            tuple: insert.tuples,
+        };
+
+        Some((origin, format!("{}{tuple};", &insert.line[..insert.head])))
+    }
+
+    /// Reads up to the next `INSERT` line, learning the columns on the way.
+    /// Returns whether there is one.
+    fn next_line(&mut self) -> std::io::Result<bool> {
+        let mut create_table: Option<String> = None;
+
+        for (line_index, line) in self.lines.by_ref() {
+            let line = line?;
+
+            // `CREATE TABLE` spans several lines, collect it to learn the column names
+            if line.starts_with("CREATE TABLE") {
+                create_table = Some(String::new());
+            }
+
+            if let Some(mut statement) = create_table.take() {
+                statement.push_str(&line);
+                statement.push('\n');
+
+                if !line.trim_end().ends_with(';') {
+                    create_table = Some(statement);
+                    continue;
+                }
+
+                let mut issues = Issues::new(&statement);
+                match parse_statement(&statement, &mut issues, &self.parse_options) {
+                    Some(Statement::CreateTable(v)) => {
+                        self.columns = Arc::new(Columns::from_create_table(&v))
+                    }
+                    _ => log::warn!("Can't parse CREATE TABLE in {}", self.file_name),
+                };
+
+                continue;
+            }
+
+            let head = match insert_tuples(&line) {
+                Some((head, _)) => head.len(),
+                None => continue,
+            };
+
+            self.statement_index += 1;
+            self.insert = Some(CurrentInsert {
+                line,
+                line_index,
+                head,
+                offset: head,
+                tuples: 0,
+            });
+
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    /// The next single-tuple statement of the dump
+    pub(crate) fn next_statement(&mut self) -> Option<std::io::Result<(RowOrigin, String)>> {
+        loop {
+            if let Some(statement) = self.next_tuple() {
+                return Some(Ok(statement));
+            }
+
+            match self.next_line() {
+                Ok(true) => (),
+                Ok(false) => return None,
+                Err(err) => return Some(Err(err)),
+            };
+        }
+    }
+}
+
 async fn process<T>(
     pool: Pool,
+    source: &'static Source,
     source_id: i16,
     file_name: &str,
-    deps: Vec<Arc<Mutex<Option<UpdateStatus>>>>,
-) -> Result<(), Box<dyn std::error::Error + Send>>
+    deps: Vec<Status>,
+    soft_deps: Vec<Status>,
+) -> Result<RowCounts, Box<dyn std::error::Error + Send>>
 where
-    T: Debug + FromVecExpression<T> + Update,
+    T: Debug + FromVecExpression<T> + Update + Serialize + DeserializeOwned + Send + Sync + 'static,
 {
-    if !deps.is_empty() {
+    if !deps.is_empty() || !soft_deps.is_empty() {
         loop {
             let mut some_failed = false;
+            let mut some_deferred = false;
             let mut some_none = false;
 
             for dep in deps.iter() {
@@ -93,12 +516,35 @@ where
                     Some(status) => match status {
                         UpdateStatus::Success => (),
                         UpdateStatus::Fail => some_failed = true,
+                        UpdateStatus::Deferred => some_deferred = true,
                     },
                     None => some_none = true,
                 }
             }
 
-            if !some_failed && !some_none {
+            // A soft dependency that didn't succeed only moves the table to the end
+            for dep in soft_deps.iter() {
+                let status = dep.lock().await;
+                match &*status {
+                    Some(UpdateStatus::Success) => (),
+                    Some(_) => some_deferred = true,
+                    None => some_none = true,
+                }
+            }
+
+            if some_failed {
+                return Err(Box::new(UpdaterError::DependencyFailed {
+                    file_name: file_name.to_string(),
+                }));
+            }
+
+            if !some_none && some_deferred {
+                return Err(Box::new(UpdaterError::Deferred {
+                    file_name: file_name.to_string(),
+                }));
+            }
+
+            if !some_none {
                 break;
             }
 
@@ -106,421 +552,2208 @@ where
         }
     }
 
-    match download_file(file_name).await {
-        Ok(_) => (),
+    let _slot = limits::table_slot().await;
+    let started = std::time::Instant::now();
+
+    breadcrumb(format!("Download {file_name}"));
+    let reused = match download_file(source, file_name).await {
+        Ok(v) => v,
         Err(err) => return Err(err),
     };
 
-    let parse_options = ParseOptions::new()
-        .dialect(SQLDialect::MariaDB)
-        .arguments(SQLArguments::QuestionMark)
-        .warn_unquoted_identifiers(true);
+    if let Some(download) = dumps::download(&source.name, file_name, reused).await {
+        let md5 = download.md5.clone();
+        report::record_download(source_id, file_name, download);
 
-    let lines = read_lines(file_name);
+        if let (true, Some(md5)) = (config::CONFIG.skip_failed_dumps, md5) {
+            match check_failed_dump(source_id, file_name, md5).await {
+                Ok(_) => (),
+                Err(err) => return Err(err),
+            };
+        }
+    }
+
+    let mut statements = match DumpStatements::open(source, file_name) {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
 
-    let lines = match lines {
+    // The hooks change the schema, that goes to the live tables also in shadow imports
+    let client = match db::checkout(&db::POOL).await {
         Ok(v) => v,
         Err(err) => return Err(Box::new(err)),
     };
 
-    match T::before_update(&pool.get().await.unwrap()).await {
+    let started_at: DateTime<Utc> = match client.query_one("SELECT now();", &[]).await {
+        Ok(row) => row.get(0),
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    match T::before_update(&client).await {
         Ok(_) => (),
         Err(err) => return Err(err),
     };
 
+    if shadow::enabled() {
+        if T::tables().is_empty() {
+            log::warn!("{file_name}: no tables to shadow, rows go to the live tables");
+        }
+
+        match shadow::prepare(&client, T::tables()).await {
+            Ok(_) => (),
+            Err(err) => return Err(err),
+        };
+    }
+
+    if catalogs::enabled() {
+        match catalogs::prepare(&client, T::tables()).await {
+            Ok(_) => (),
+            Err(err) => return Err(err),
+        };
+    }
+    drop(client);
+
     log::info!("Start update {file_name}...");
+    progress::set_table_state(&source.name, file_name, TableState::Running);
+    breadcrumb(format!("Parse {file_name}"));
 
-    for line in lines.into_iter() {
-        let line = match line {
-            Ok(line) => line,
+    let mut writer: RowWriter<T> = RowWriter::new(&pool, source, source_id, file_name);
+    let mut parsing: ParseQueue<T> = ParseQueue::new(source, source_id, file_name);
+
+    let chunk_size = limits::chunk_size(PARSE_CHUNK_SIZE);
+    let mut chunk: Vec<(RowOrigin, String)> = Vec::with_capacity(chunk_size);
+
+    // Tuples are parsed one by one (the AST of a whole huge INSERT doesn't fit
+    // in memory) in chunks spread over the workers. Chunks are written in the
+    // order they were read.
+    while let Some(statement) = statements.next_statement() {
+        let statement = match statement {
+            Ok(v) => v,
             Err(err) => return Err(Box::new(err)),
         };
+        chunk.push(statement);
 
-        let mut issues = Issues::new(&line);
-        let ast = parse_statement(&line, &mut issues, &parse_options);
+        if chunk.len() < chunk_size {
+            continue;
+        }
 
-        if let Some(Statement::InsertReplace(
-            i @ InsertReplace {
-                type_: InsertReplaceType::Insert(_),
-                ..
-            },
-        )) = ast
+        let full = std::mem::replace(&mut chunk, Vec::with_capacity(chunk_size));
+        match parsing
+            .push(full, statements.columns.clone(), &mut writer)
+            .await
         {
-            for value in i.values.into_iter() {
-                for t_value in value.1.into_iter() {
-                    let value = T::from_vec_expression(&t_value);
-                    let client = pool.get().await.unwrap();
+            Ok(_) => (),
+            Err(err) => return Err(err),
+        };
+    }
 
-                    match value.update(&client, source_id).await {
-                        Ok(_) => {
-                            // log::info!("{:?}", value);
-                        }
-                        Err(err) => {
-                            log::error!("Update error: {:?} : {:?}", value, err);
-                            return Err(err);
-                        }
-                    }
-                }
-            }
-        }
+    if !chunk.is_empty() {
+        match parsing
+            .push(chunk, statements.columns.clone(), &mut writer)
+            .await
+        {
+            Ok(_) => (),
+            Err(err) => return Err(err),
+        };
     }
 
-    match T::after_update(&pool.get().await.unwrap()).await {
+    match parsing.finish(&mut writer).await {
         Ok(_) => (),
         Err(err) => return Err(err),
     };
 
-    log::info!("Updated {file_name}...");
-
-    Ok(())
-}
-
-async fn get_postgres_pool() -> Result<Pool, CreatePoolError> {
-    let mut config = Config::new();
-
-    config.host = Some(config::CONFIG.postgres_host.clone());
-    config.port = Some(config::CONFIG.postgres_port);
-    config.dbname = Some(config::CONFIG.postgres_db_name.clone());
-    config.user = Some(config::CONFIG.postgres_user.clone());
-    config.password = Some(config::CONFIG.postgres_password.clone());
-    config.connect_timeout = Some(std::time::Duration::from_secs(5));
-    config.manager = Some(ManagerConfig {
-        recycling_method: RecyclingMethod::Verified,
-    });
+    let (counts, samples) = match writer.finish().await {
+        Ok(v) => v,
+        Err(err) => return Err(err),
+    };
 
-    match config.create_pool(Some(Runtime::Tokio1), NoTls) {
-        Ok(pool) => Ok(pool),
-        Err(err) => Err(err),
+    if parsing.issues.rows > 0 {
+        log::warn!(
+            "{file_name}: {} rows with parse issues, {} of them rejected",
+            parsing.issues.rows,
+            parsing.issues.unparsed
+        );
     }
-}
 
-async fn get_source(pool: Pool) -> Result<i16, Box<dyn std::error::Error>> {
-    let client = pool.get().await.unwrap();
+    progress::finish_table(&source.name, file_name);
 
-    let row = match client
-        .query_one("SELECT id FROM sources WHERE name = 'flibusta';", &[])
-        .await
-    {
+    breadcrumb(format!("Clean up {file_name}"));
+    let client = match db::checkout(&pool).await {
         Ok(v) => v,
         Err(err) => return Err(Box::new(err)),
     };
 
-    let id = row.get(0);
-
-    Ok(id)
-}
-
-enum UpdateStatus {
-    Success,
-    Fail,
-}
-
-async fn send_webhooks() -> Result<(), Box<reqwest::Error>> {
-    for webhook in config::CONFIG.webhooks.clone().into_iter() {
-        let Webhook {
-            method,
-            url,
-            headers,
-        } = webhook;
-
-        let client = reqwest::Client::new();
-
-        let builder = match method {
-            config::Method::Get => client.get(url),
-            config::Method::Post => client.post(url),
-        };
-
-        let t_headers: Vec<(HeaderName, HeaderValue)> = headers
-            .into_iter()
-            .map(|(key, val)| {
-                let value = match val {
-                    serde_json::Value::String(v) => v,
-                    _ => panic!("Header value not string!"),
-                };
-
-                (
-                    HeaderName::from_str(key.as_ref()).unwrap(),
-                    HeaderValue::from_str(&value).unwrap(),
-                )
-            })
-            .collect();
-
-        let headers = HeaderMap::from_iter(t_headers.into_iter());
-
-        let response = builder.headers(headers).send().await;
+    match T::after_update(&client).await {
+        Ok(_) => (),
+        Err(err) => return Err(err),
+    };
 
-        let response = match response {
-            Ok(v) => v,
-            Err(err) => return Err(Box::new(err)),
+    // Rejected rows are in the dump but weren't written, they'd look gone
+    if config::CONFIG.full_sync && counts.rejected > 0 {
+        log::warn!(
+            "{file_name}: {} rows rejected, rows missing from the dump are kept",
+            counts.rejected
+        );
+    } else if config::CONFIG.full_sync {
+        match T::after_full_sync(&client, source_id, started_at).await {
+            Ok(_) => (),
+            Err(err) => return Err(err),
         };
+    }
+    drop(client);
 
-        match response.error_for_status() {
+    if !samples.is_empty() {
+        breadcrumb(format!("Verify {file_name}"));
+        match verify_samples(&pool, source_id, file_name, &samples).await {
             Ok(_) => (),
-            Err(err) => return Err(Box::new(err)),
+            Err(err) => return Err(err),
         };
     }
 
-    Ok(())
-}
+    // Changes of a shadow import become visible with the swap, of a catalog
+    // schema with the switch
+    if !shadow::enabled() && !catalogs::enabled() {
+        notify_changes().await;
+    }
 
-lazy_static! {
-    pub static ref UPDATE_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+    log::info!(
+        "Updated {file_name}: {} inserted, {} updated, {} unchanged, {} skipped, {} rejected",
+        counts.inserted,
+        counts.updated,
+        counts.unchanged,
+        counts.skipped,
+        counts.rejected
+    );
+
+    report::record_duration(source_id, file_name, started.elapsed().as_millis() as u64);
+
+    Ok(counts)
 }
 
-pub async fn update() -> Result<(), Box<dyn std::error::Error>> {
-    let _lock = match UPDATE_LOCK.try_lock() {
+/// Fails when the same dump already failed to parse, it would fail the same way
+async fn check_failed_dump(
+    source_id: i16,
+    file_name: &str,
+    md5: String,
+) -> Result<(), Box<dyn std::error::Error + Send>> {
+    let client = match db::checkout(&db::POOL).await {
         Ok(v) => v,
         Err(err) => return Err(Box::new(err)),
     };
 
-    log::info!("Start update...");
-
-    let pool = match get_postgres_pool().await {
-        Ok(pool) => pool,
-        Err(err) => panic!("{:?}", err),
+    let run_id = match runs::failed_download(&client, source_id, file_name, &md5).await {
+        Ok(v) => v,
+        Err(err) => return Err(err),
     };
 
-    let source_id = match get_source(pool.clone()).await {
-        Ok(v) => Arc::new(v),
-        Err(err) => panic!("{:?}", err),
-    };
+    match run_id {
+        Some(run_id) => {
+            let err = UpdaterError::KnownBadDump {
+                file_name: file_name.to_string(),
+                md5,
+                run_id,
+            };
+            log::error!("{err}");
+            Err(Box::new(err))
+        }
+        None => Ok(()),
+    }
+}
 
-    let author_status: Arc<Mutex<Option<UpdateStatus>>> = Arc::new(Mutex::new(None));
-    let book_status: Arc<Mutex<Option<UpdateStatus>>> = Arc::new(Mutex::new(None));
-    let sequence_status: Arc<Mutex<Option<UpdateStatus>>> = Arc::new(Mutex::new(None));
-    let book_annotation_status: Arc<Mutex<Option<UpdateStatus>>> = Arc::new(Mutex::new(None));
-    let author_annotation_status: Arc<Mutex<Option<UpdateStatus>>> = Arc::new(Mutex::new(None));
-    let genre_status: Arc<Mutex<Option<UpdateStatus>>> = Arc::new(Mutex::new(None));
+/// Sends the pending catalog changes, after a failure they wait for the next table
+async fn notify_changes() {
+    if !config::CONFIG.change_notify {
+        return;
+    }
 
-    let pool_clone = pool.clone();
-    let author_status_clone = author_status.clone();
-    let source_id_clone = source_id.clone();
-    let author_process = tokio::spawn(async move {
-        match process::<Author>(pool_clone, *source_id_clone, "lib.libavtorname.sql", vec![]).await
-        {
-            Ok(_) => {
-                let mut status = author_status_clone.lock().await;
-                *status = Some(UpdateStatus::Success);
-                Ok(())
-            }
+    let mut client = match db::checkout(&db::POOL).await {
+        Ok(v) => v,
+        Err(err) => {
+            log::error!("Can't notify catalog changes: {:?}", err);
+            return;
+        }
+    };
+
+    if let Err(err) = changes::notify(&mut client).await {
+        log::error!("Can't notify catalog changes: {:?}", err);
+    }
+}
+
+/// When the nightly job runs, with seconds
+const UPDATE_SCHEDULE: &str = "0 0 3 * * *";
+
+/// How often due follow-ups of failed runs are looked for
+const RETRY_SCHEDULE: &str = "0 */5 * * * *";
+
+const PARSE_CHUNK_SIZE: usize = 1000;
+
+/// Parsed rows with their statements, the rows that were rejected and the
+/// reservation of in-flight rows for the parsed ones
+struct ParsedChunk<T> {
+    rows: Vec<(RowOrigin, String, T)>,
+    issues: ParseIssues,
+    rejects: Vec<Reject>,
+    columns: Arc<Columns>,
+    permit: OwnedSemaphorePermit,
+}
+
+pub(crate) enum ParsedRow<T> {
+    /// The row with the first issue the parser reported, if any
+    Row(T, Option<String>),
+    /// Not a single-row statement `sql_parse` could read
+    Unparsed(String),
+    /// Parsed, but the values don't fit the table
+    Invalid(String),
+}
+
+const ISSUE_SAMPLE_LENGTH: usize = 200;
+
+/// The first issue of a row, with the start of the row for context
+fn issue_sample(issues: &Issues, statement: &str) -> String {
+    let statement: String = statement.chars().take(ISSUE_SAMPLE_LENGTH).collect();
+
+    match issues.get().first() {
+        Some(issue) => {
+            let segment: String = issue
+                .sql_segment
+                .chars()
+                .take(ISSUE_SAMPLE_LENGTH)
+                .collect();
+            format!(
+                "{:?}: {} at `{segment}` in {statement}",
+                issue.level, issue.message
+            )
+        }
+        None => format!("Not a single-row INSERT: {statement}"),
+    }
+}
+
+pub(crate) fn parse_options() -> ParseOptions {
+    ParseOptions::new()
+        .dialect(SQLDialect::MariaDB)
+        .arguments(SQLArguments::QuestionMark)
+        .warn_unquoted_identifiers(true)
+}
+
+/// Parses a single-row `INSERT` or `REPLACE` statement into a row of the table.
+pub(crate) fn parse_row<T: FromVecExpression<T>>(
+    statement: &str,
+    columns: &Columns,
+    parse_options: &ParseOptions,
+) -> ParsedRow<T> {
+    let mut issues = Issues::new(statement);
+
+    let t_value = match parse_statement(statement, &mut issues, parse_options) {
+        Some(Statement::InsertReplace(InsertReplace {
+            values: Some((_, mut values)),
+            ..
+        })) if values.len() == 1 => values.pop().unwrap(),
+        _ => return ParsedRow::Unparsed(issue_sample(&issues, statement)),
+    };
+
+    let issue = match issues.get().is_empty() {
+        true => None,
+        false => Some(issue_sample(&issues, statement)),
+    };
+
+    match T::from_vec_expression(&t_value, columns) {
+        Ok(v) => ParsedRow::Row(v, issue),
+        Err(err) => ParsedRow::Invalid(err.to_string()),
+    }
+}
+
+/// Rewrites the parsed rows of a chunk before they're written, see `scripts`
+/// and `plugins`
+pub(crate) type RowTransform<T> = Box<dyn FnMut(ParsedRow<T>) -> ParsedRow<T> + Send>;
+
+/// The field scripts of the table
+#[cfg(feature = "field-scripts")]
+fn field_scripts<T>(file_name: &str) -> Option<RowTransform<T>>
+where
+    T: Serialize + DeserializeOwned + 'static,
+{
+    let scripts = scripts::for_file(file_name)?;
+    Some(Box::new(move |parsed| scripts.apply(parsed)))
+}
+
+#[cfg(not(feature = "field-scripts"))]
+fn field_scripts<T>(_: &str) -> Option<RowTransform<T>> {
+    None
+}
+
+/// The row plugins of the table, instantiated on the first row
+#[cfg(feature = "wasm-plugins")]
+fn row_plugins<T>(file_name: &str) -> Option<RowTransform<T>>
+where
+    T: Serialize + DeserializeOwned + 'static,
+{
+    let plugins = plugins::for_file(file_name)?;
+    let file_name = file_name.to_string();
+    let mut session = None;
+
+    Some(Box::new(move |parsed| {
+        session
+            .get_or_insert_with(|| plugins.session(&file_name))
+            .apply(parsed)
+    }))
+}
+
+#[cfg(not(feature = "wasm-plugins"))]
+fn row_plugins<T>(_: &str) -> Option<RowTransform<T>> {
+    None
+}
+
+/// The transforms applied one after another
+fn chain<T: 'static>(transforms: [Option<RowTransform<T>>; 2]) -> Option<RowTransform<T>> {
+    let mut transforms: Vec<RowTransform<T>> = transforms.into_iter().flatten().collect();
+
+    match transforms.len() {
+        0 => None,
+        1 => transforms.pop(),
+        _ => Some(Box::new(move |parsed| {
+            transforms
+                .iter_mut()
+                .fold(parsed, |parsed, transform| transform(parsed))
+        })),
+    }
+}
+
+/// The field scripts then the row plugins of the table, for a chunk
+pub(crate) fn row_transform<T>(file_name: &str) -> Option<RowTransform<T>>
+where
+    T: Serialize + DeserializeOwned + 'static,
+{
+    chain([field_scripts(file_name), row_plugins(file_name)])
+}
+
+/// The `on_row_parsed` hooks of the rows of an import
+fn parsed_hooks<T: 'static>(source: &'static str, file_name: &str) -> Option<RowTransform<T>> {
+    let hooks = RowHooks::of::<T>();
+    if !hooks.has_parsed() {
+        return None;
+    }
+
+    let file_name = file_name.to_string();
+
+    Some(Box::new(move |parsed| match parsed {
+        ParsedRow::Row(mut row, issue) => {
+            let context = RowContext {
+                source,
+                file_name: &file_name,
+            };
+            hooks.parsed(&context, &mut row);
+
+            ParsedRow::Row(row, issue)
+        }
+        parsed => parsed,
+    }))
+}
+
+/// Parses single-tuple statements on the blocking pool. Rows that don't parse
+/// are rejected instead of failing the table.
+fn spawn_parse<T>(
+    statements: Vec<(RowOrigin, String)>,
+    columns: Arc<Columns>,
+    mut permit: OwnedSemaphorePermit,
+    mut transform: Option<RowTransform<T>>,
+) -> JoinHandle<ParsedChunk<T>>
+where
+    T: FromVecExpression<T> + Send + 'static,
+{
+    // Keeps the warnings in the log of the run
+    let span = tracing::Span::current();
+
+    tokio::task::spawn_blocking(move || {
+        let _entered = span.enter();
+
+        let parse_options = parse_options();
+        let statements_count = statements.len();
+        let mut rows = Vec::with_capacity(statements_count);
+        let mut issues = ParseIssues::default();
+        let mut rejects = Vec::new();
+
+        for (origin, statement) in statements.into_iter() {
+            let mut parsed = parse_row::<T>(&statement, &columns, &parse_options);
+            if let Some(transform) = transform.as_mut() {
+                parsed = transform(parsed);
+            }
+
+            match parsed {
+                ParsedRow::Row(value, issue) => {
+                    if let Some(issue) = issue {
+                        issues.add(|| format!("{origin}: {issue}"));
+                    }
+                    rows.push((origin, statement, value));
+                }
+                ParsedRow::Unparsed(error) => {
+                    issues.unparsed += 1;
+                    issues.add(|| format!("{origin}: {error}"));
+                    rejects.push(Reject {
+                        origin,
+                        raw: statement,
+                        error,
+                    });
+                }
+                ParsedRow::Invalid(error) => rejects.push(Reject {
+                    origin,
+                    raw: statement,
+                    error,
+                }),
+            };
+        }
+
+        // Rejected rows won't be written
+        drop(permit.split(statements_count - rows.len()));
+
+        ParsedChunk {
+            rows,
+            issues,
+            rejects,
+            columns,
+            permit,
+        }
+    })
+}
+
+/// Chunks being parsed by up to `parse_workers` workers, handed to the writer
+/// in the order they were pushed.
+struct ParseQueue<T> {
+    source: &'static str,
+    source_id: i16,
+    file_name: String,
+    workers: usize,
+    parsing: VecDeque<JoinHandle<ParsedChunk<T>>>,
+    issues: ParseIssues,
+}
+
+impl<T> ParseQueue<T>
+where
+    T: Debug + FromVecExpression<T> + Update + Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    fn new(source: &'static Source, source_id: i16, file_name: &str) -> ParseQueue<T> {
+        // Issues of a failed attempt don't count
+        report::record_parse_issues(source_id, file_name, &ParseIssues::default());
+
+        #[cfg(not(feature = "wasm-plugins"))]
+        if !config::CONFIG.row_plugins.is_empty() {
+            log::warn!(
+                "{file_name}: built without the wasm-plugins feature, ROW_PLUGINS are ignored"
+            );
+        }
+
+        #[cfg(not(feature = "field-scripts"))]
+        if !config::CONFIG.field_scripts.is_empty() {
+            log::warn!(
+                "{file_name}: built without the field-scripts feature, FIELD_SCRIPTS are ignored"
+            );
+        }
+
+        ParseQueue {
+            source: &source.name,
+            source_id,
+            file_name: file_name.to_string(),
+            workers: config::CONFIG.parse_workers.max(1),
+            parsing: VecDeque::new(),
+            issues: ParseIssues::default(),
+        }
+    }
+
+    async fn push(
+        &mut self,
+        statements: Vec<(RowOrigin, String)>,
+        columns: Arc<Columns>,
+        writer: &mut RowWriter<T>,
+    ) -> Result<(), Box<dyn std::error::Error + Send>> {
+        if self.parsing.len() >= self.workers {
+            if let Some(Err(err)) = self.write_next(writer).await {
+                return Err(err);
+            }
+        }
+
+        let permit = match limits::try_reserve_rows(statements.len()) {
+            Some(v) => v,
+            None => {
+                // Hand everything this table holds to the writers first, so the
+                // limit can't wait on rows that are never written
+                match self.finish(writer).await {
+                    Ok(_) => (),
+                    Err(err) => return Err(err),
+                };
+                match writer.flush().await {
+                    Ok(_) => (),
+                    Err(err) => return Err(err),
+                };
+
+                log::debug!(
+                    "{}: waiting for in-flight rows to be written",
+                    self.file_name
+                );
+                limits::reserve_rows(statements.len()).await
+            }
+        };
+
+        self.parsing.push_back(spawn_parse(
+            statements,
+            columns,
+            permit,
+            chain([
+                parsed_hooks(self.source, &self.file_name),
+                row_transform(&self.file_name),
+            ]),
+        ));
+
+        Ok(())
+    }
+
+    async fn write_next(
+        &mut self,
+        writer: &mut RowWriter<T>,
+    ) -> Option<Result<(), Box<dyn std::error::Error + Send>>> {
+        let ParsedChunk {
+            rows,
+            issues,
+            rejects,
+            columns,
+            mut permit,
+        } = match self.parsing.pop_front()?.await {
+            Ok(v) => v,
+            Err(err) => return Some(Err(Box::new(err))),
+        };
+
+        if issues.rows > 0 {
+            for sample in self.issues.merge(issues) {
+                log::warn!("{}: {sample}", self.file_name);
+            }
+
+            report::record_parse_issues(self.source_id, &self.file_name, &self.issues);
+
+            let limit = config::CONFIG.max_parse_issues;
+            if limit > 0 && self.issues.rows >= limit {
+                return Some(Err(Box::new(UpdaterError::TooManyParseIssues {
+                    file_name: self.file_name.clone(),
+                    count: self.issues.rows,
+                })));
+            }
+        }
+
+        for reject in rejects.iter() {
+            rejects::record(&db::POOL, self.source_id, &self.file_name, &columns, reject).await;
+        }
+        writer.counts.rejected += rejects.len() as u64;
+        writer.columns = columns;
+
+        for (origin, raw, value) in rows.into_iter() {
+            let row_permit = permit.split(1).unwrap();
+
+            match writer.push(origin, raw, value, row_permit).await {
+                Ok(_) => (),
+                Err(err) => return Some(Err(err)),
+            };
+        }
+
+        Some(Ok(()))
+    }
+
+    async fn finish(
+        &mut self,
+        writer: &mut RowWriter<T>,
+    ) -> Result<(), Box<dyn std::error::Error + Send>> {
+        while let Some(result) = self.write_next(writer).await {
+            match result {
+                Ok(_) => (),
+                Err(err) => return Err(err),
+            };
+        }
+
+        Ok(())
+    }
+}
+
+/// A written batch with how long its last attempt took and how many failed
+struct WrittenBatch<T> {
+    rows: Vec<T>,
+    counts: RowCounts,
+    latency: std::time::Duration,
+    errors: u32,
+}
+
+type BatchResult<T> = Result<WrittenBatch<T>, Box<dyn std::error::Error + Send>>;
+
+/// Groups rows into batches written by up to `writer_concurrency` tasks and
+/// keeps a uniform sample of the written rows for verification.
+struct RowWriter<T> {
+    file_name: String,
+    source: &'static str,
+    pool: Pool,
+    source_id: i16,
+    settings: WriteSettings,
+    use_copy: bool,
+    paused: watch::Receiver<bool>,
+    throttle: Throttle,
+    batch: Vec<T>,
+    /// Where the rows in `batch` come from and their statements, kept in case
+    /// one is rejected
+    sources: Vec<(RowOrigin, String)>,
+    /// Columns of the dump, recorded with the rejects
+    columns: Arc<Columns>,
+    /// In-flight reservation of the rows in `batch`, released once they're written
+    permit: Option<OwnedSemaphorePermit>,
+    writers: JoinSet<BatchResult<T>>,
+    sizer: BatchSizer,
+    sample_size: usize,
+    samples: Vec<T>,
+    rows_count: usize,
+    counts: RowCounts,
+    rng: StdRng,
+    hooks: RowHooks,
+}
+
+impl<T> RowWriter<T>
+where
+    T: Debug + Update + Send + Sync + 'static,
+{
+    fn new(pool: &Pool, source: &'static Source, source_id: i16, file_name: &str) -> RowWriter<T> {
+        let settings = config::CONFIG.write_settings(file_name);
+        let use_copy = settings.use_copy && T::copy_spec().is_some();
+        if settings.use_copy && !use_copy {
+            log::warn!("{file_name} rows need per-row handling, COPY is disabled for it");
+        }
+
+        let sample_size = config::CONFIG.verify_sample_size;
+
+        RowWriter {
+            file_name: file_name.to_string(),
+            source: &source.name,
+            pool: pool.clone(),
+            source_id,
+            settings,
+            use_copy,
+            paused: PAUSED.subscribe(),
+            throttle: Throttle::new(file_name),
+            batch: Vec::with_capacity(settings.batch_size),
+            sources: Vec::with_capacity(settings.batch_size),
+            sizer: BatchSizer::new(&settings),
+            columns: Arc::new(Columns::default()),
+            permit: None,
+            writers: JoinSet::new(),
+            sample_size,
+            samples: Vec::with_capacity(sample_size),
+            rows_count: 0,
+            counts: RowCounts::default(),
+            rng: StdRng::from_entropy(),
+            hooks: RowHooks::of::<T>(),
+        }
+    }
+
+    async fn push(
+        &mut self,
+        origin: RowOrigin,
+        raw: String,
+        value: T,
+        permit: OwnedSemaphorePermit,
+    ) -> Result<(), Box<dyn std::error::Error + Send>> {
+        if wait_if_paused(&mut self.paused, &self.file_name).await {
+            self.throttle.reset();
+        }
+
+        self.throttle.wait_for_window(&self.file_name).await;
+        self.throttle.tick().await;
+
+        self.batch.push(value);
+        self.sources.push((origin, raw));
+        match self.permit.as_mut() {
+            Some(v) => v.merge(permit),
+            None => self.permit = Some(permit),
+        };
+
+        if self.batch.len() < self.sizer.size() {
+            return Ok(());
+        }
+
+        self.flush().await
+    }
+
+    /// Starts writing the current batch, even if it isn't full yet.
+    async fn flush(&mut self) -> Result<(), Box<dyn std::error::Error + Send>> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+
+        if self.writers.len() >= self.settings.writer_concurrency {
+            if let Some(Err(err)) = self.join_next().await {
+                return Err(err);
+            }
+        }
+
+        let write = write_batch(
+            self.pool.clone(),
+            self.file_name.clone(),
+            std::mem::take(&mut self.batch),
+            std::mem::take(&mut self.sources),
+            self.columns.clone(),
+            self.source_id,
+            self.use_copy,
+        );
+        let permit = self.permit.take();
+
+        self.writers.spawn(
+            async move {
+                let result = write.await;
+                drop(permit);
+                result
+            }
+            .in_current_span()
+            .bind_hub(Hub::current()),
+        );
+
+        Ok(())
+    }
+
+    async fn finish(mut self) -> Result<(RowCounts, Vec<T>), Box<dyn std::error::Error + Send>> {
+        match self.flush().await {
+            Ok(_) => (),
+            Err(err) => return Err(err),
+        };
+
+        while let Some(result) = self.join_next().await {
+            match result {
+                Ok(_) => (),
+                Err(err) => return Err(err),
+            };
+        }
+
+        Ok((self.counts, self.samples))
+    }
+
+    async fn join_next(&mut self) -> Option<Result<(), Box<dyn std::error::Error + Send>>> {
+        let written = match self.writers.join_next().await? {
+            Ok(Ok(v)) => v,
+            Ok(Err(err)) => return Some(Err(err)),
+            Err(err) => return Some(Err(Box::new(err))),
+        };
+
+        self.counts.merge(&written.counts);
+        self.sizer.observe(
+            &self.file_name,
+            written.rows.len() + written.counts.rejected as usize,
+            written.latency,
+            written.errors,
+        );
+
+        let context = RowContext {
+            source: self.source,
+            file_name: &self.file_name,
+        };
+        for value in written.rows.iter() {
+            self.hooks.written(&context, value);
+        }
+
+        for value in written.rows.into_iter() {
+            self.rows_count += 1;
+
+            if self.samples.len() < self.sample_size {
+                self.samples.push(value);
+            } else if self.sample_size > 0 {
+                let index = self.rng.gen_range(0..self.rows_count);
+                if index < self.sample_size {
+                    self.samples[index] = value;
+                }
+            }
+        }
+
+        progress::update_table(self.source, &self.file_name, self.rows_count as u64);
+
+        Some(Ok(()))
+    }
+}
+
+/// Writes a batch, retrying transient errors. When a row breaks a constraint or
+/// holds bad data the batch is written again with a savepoint per row, the bad
+/// rows are rejected and the rest of the batch commits.
+async fn write_batch<T>(
+    pool: Pool,
+    file_name: String,
+    mut batch: Vec<T>,
+    mut sources: Vec<(RowOrigin, String)>,
+    columns: Arc<Columns>,
+    source_id: i16,
+    mut use_copy: bool,
+) -> BatchResult<T>
+where
+    T: Debug + Update + Send + Sync,
+{
+    let mut rejected = 0;
+    let mut attempt = 0;
+    let mut errors = 0;
+    let mut latency = std::time::Duration::ZERO;
+    let mut isolate = false;
+
+    loop {
+        if batch.is_empty() {
+            let counts = RowCounts {
+                rejected,
+                ..RowCounts::default()
+            };
+            return Ok(WrittenBatch {
+                rows: batch,
+                counts,
+                latency,
+                errors,
+            });
+        }
+
+        let mut client = match db::checkout(&pool).await {
+            Ok(v) => v,
+            Err(err) => return Err(Box::new(err)),
+        };
+
+        let started = std::time::Instant::now();
+        let result = try_write_batch(
+            &mut client,
+            &file_name,
+            &batch,
+            source_id,
+            use_copy,
+            isolate,
+        )
+        .await;
+        latency = started.elapsed();
+
+        if result.is_err() {
+            errors += 1;
+        }
+
+        match result {
+            Ok((mut counts, failed)) => {
+                // Backwards, so the indexes stay valid
+                for (index, err) in failed.into_iter().rev() {
+                    let value = batch.remove(index);
+                    let (origin, raw) = sources.remove(index);
+                    let reject = Reject {
+                        origin,
+                        raw,
+                        error: format!("{:?}: {}", value, err),
+                    };
+                    rejects::record(&pool, source_id, &file_name, &columns, &reject).await;
+                    rejected += 1;
+                }
+
+                counts.rejected += rejected;
+                return Ok(WrittenBatch {
+                    rows: batch,
+                    counts,
+                    latency,
+                    errors,
+                });
+            }
+            Err((_, err)) if attempt < config::CONFIG.db_retries && db::is_transient(&err) => {
+                attempt += 1;
+                log::warn!(
+                    "Transient batch update error (attempt {attempt}, {} rows): {:?}",
+                    batch.len(),
+                    err
+                );
+                tokio::time::sleep(db::retry_delay(attempt)).await;
+            }
+            Err((Some(index), err)) if !isolate && db::is_bad_row(&err) => {
+                log::warn!(
+                    "{file_name}: {} broke a batch of {} rows, writing them with savepoints: {:?}",
+                    sources[index].0,
+                    batch.len(),
+                    err
+                );
+                isolate = true;
+            }
+            // `COPY` doesn't tell which row broke it, find it row by row
+            Err((None, err)) if use_copy && db::is_bad_row(&err) => {
+                log::warn!(
+                    "{file_name}: a row failed in a COPY of {} rows, writing them with savepoints: {:?}",
+                    batch.len(),
+                    err
+                );
+                use_copy = false;
+                isolate = true;
+            }
+            // The failed row is known unless the batch went in with `COPY`
+            Err((Some(index), err)) => {
+                let origin = sources[index].0;
+                set_statement_tag(&format!("{file_name}, {origin}: {:?}", batch[index]));
+                log::error!(
+                    "Batch update error in {file_name}, {origin}: {:?} : {:?}",
+                    batch[index],
+                    err
+                );
+                return Err(Box::new(UpdaterError::RowFailed {
+                    file_name,
+                    first: origin,
+                    last: origin,
+                    message: err.to_string(),
+                }));
+            }
+            Err((None, err)) => {
+                let (first, last) = (sources[0].0, sources[sources.len() - 1].0);
+                set_statement_tag(&format!("{file_name}, {first} to {last}"));
+                log::error!(
+                    "Batch update error in {file_name}, {first} to {last}: {:?}..{:?} : {:?}",
+                    batch.first(),
+                    batch.last(),
+                    err
+                );
+                return Err(Box::new(UpdaterError::RowFailed {
+                    file_name,
+                    first,
+                    last,
+                    message: err.to_string(),
+                }));
+            }
+        }
+    }
+}
+
+/// Rows of a batch rolled back to their savepoint, with their errors
+type FailedRows = Vec<(usize, Box<tokio_postgres::Error>)>;
+
+/// Writes the whole batch in one transaction, with `COPY` if enabled. With
+/// `isolate` every row gets a savepoint and bad rows are rolled back and
+/// returned instead of failing the batch. Errors come with the index of the
+/// failed row when it's known.
+async fn try_write_batch<T>(
+    client: &mut Object,
+    file_name: &str,
+    batch: &[T],
+    source_id: i16,
+    use_copy: bool,
+    isolate: bool,
+) -> Result<(RowCounts, FailedRows), (Option<usize>, Box<tokio_postgres::Error>)>
+where
+    T: Debug + Update + Sync,
+{
+    let transaction = match client.transaction().await {
+        Ok(v) => v,
+        Err(err) => return Err((None, Box::new(err))),
+    };
+
+    if let Err(err) = transaction
+        .batch_execute(&db::session_settings(file_name))
+        .await
+    {
+        return Err((None, Box::new(err)));
+    }
+
+    let mut failed = FailedRows::new();
+
+    let counts = match T::copy_spec() {
+        Some(spec) if use_copy => {
+            let rows: Vec<CopyRow> = batch.iter().map(|value| value.copy_row()).collect();
+            match spec.copy(&transaction, source_id, &rows).await {
+                Ok(v) => v,
+                Err(err) => return Err((None, err)),
+            }
+        }
+        _ => {
+            let mut counts = RowCounts::default();
+            for (index, value) in batch.iter().enumerate() {
+                if isolate {
+                    if let Err(err) = transaction.batch_execute("SAVEPOINT row").await {
+                        return Err((Some(index), Box::new(err)));
+                    }
+                }
+
+                let result = value.update(transaction.client(), source_id).await;
+
+                if isolate {
+                    let release = match &result {
+                        Err(err) if db::is_bad_row(err) => "ROLLBACK TO SAVEPOINT row",
+                        _ => "RELEASE SAVEPOINT row",
+                    };
+                    if let Err(err) = transaction.batch_execute(release).await {
+                        return Err((Some(index), Box::new(err)));
+                    }
+                }
+
+                match result {
+                    Ok(v) => counts.add(v),
+                    Err(err) if isolate && db::is_bad_row(&err) => failed.push((index, err)),
+                    Err(err) => return Err((Some(index), err)),
+                };
+            }
+            counts
+        }
+    };
+
+    match transaction.commit().await {
+        Ok(_) => Ok((counts, failed)),
+        Err(err) => Err((None, Box::new(err))),
+    }
+}
+
+async fn verify_samples<T>(
+    pool: &Pool,
+    source_id: i16,
+    file_name: &str,
+    samples: &[T],
+) -> Result<(), Box<dyn std::error::Error + Send>>
+where
+    T: Debug + Update,
+{
+    log::info!("Verify {} samples of {file_name}...", samples.len());
+
+    let client = match db::checkout(pool).await {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let mut mismatches = 0;
+
+    for sample in samples.iter() {
+        match sample.verify(&client, source_id).await {
+            Ok(true) => (),
+            Ok(false) => {
+                log::warn!("Verification mismatch in {file_name}: {:?}", sample);
+                mismatches += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    if mismatches > 0 {
+        let err: Box<dyn std::error::Error + Send + Sync> = format!(
+            "{file_name}: {mismatches} of {} sampled rows don't match the database",
+            samples.len()
+        )
+        .into();
+        return Err(err);
+    }
+
+    log::info!("{file_name} verified!");
+
+    Ok(())
+}
+
+async fn get_source(pool: Pool, name: &str) -> Result<i16, Box<dyn std::error::Error + Send>> {
+    let client = match db::checkout(&pool).await {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let row = match client
+        .query_one(
+            "
+            WITH existing AS (
+                SELECT id FROM sources WHERE name = $1
+            ), created AS (
+                INSERT INTO sources (name) SELECT $1 WHERE NOT EXISTS (SELECT 1 FROM existing)
+                RETURNING id
+            )
+            SELECT id FROM existing UNION ALL SELECT id FROM created;
+            ",
+            &[&name],
+        )
+        .await
+    {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let id = row.get(0);
+
+    Ok(id)
+}
+
+pub(crate) enum UpdateStatus {
+    Success,
+    Fail,
+    /// Waits for the end of the run, see `Table::soft_deps`
+    Deferred,
+}
+
+lazy_static! {
+    /// One run per source at a time, different sources don't wait for each other
+    static ref UPDATE_LOCKS: HashMap<String, tokio::sync::Mutex<()>> = config::CONFIG
+        .sources
+        .iter()
+        .map(|source| (source.name.clone(), tokio::sync::Mutex::new(())))
+        .collect();
+
+    /// One replay at a time, they don't wait for the runs of the sources
+    static ref REPLAY_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+
+    /// Sources of the replayed dates, created once per date
+    static ref REPLAY_SOURCES: std::sync::Mutex<HashMap<String, &'static Source>> =
+        std::sync::Mutex::new(HashMap::new());
+}
+
+/// Tags the current Sentry scope with the row that failed; tag values are
+/// limited to 200 characters.
+fn set_statement_tag(statement: &str) {
+    let statement: String = statement.chars().take(200).collect();
+    sentry::configure_scope(|scope| scope.set_tag("statement", statement));
+}
+
+fn breadcrumb(message: String) {
+    run_log::phase(&message);
+
+    sentry::add_breadcrumb(Breadcrumb {
+        category: Some("phase".to_string()),
+        message: Some(message),
+        level: Level::Info,
+        ..Default::default()
+    });
+}
+
+fn spawn_in_run<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(future.in_current_span().bind_hub(Hub::current()))
+}
+
+/// Held for the whole run of a source, see `lock`
+pub type UpdateLock = tokio::sync::MutexGuard<'static, ()>;
+
+/// Takes the lock of the source, `None` while one of its runs holds it
+pub fn lock(source: &Source) -> Option<UpdateLock> {
+    UPDATE_LOCKS[&source.name].try_lock().ok()
+}
+
+pub async fn update(
+    run_id: Uuid,
+    source: &'static Source,
+) -> Result<UpdateReport, Box<dyn std::error::Error + Send>> {
+    let lock = match UPDATE_LOCKS[&source.name].try_lock() {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    update_locked(run_id, source, lock).await
+}
+
+/// `update` with the lock of the source taken already
+pub async fn update_locked(
+    run_id: Uuid,
+    source: &'static Source,
+    _lock: UpdateLock,
+) -> Result<UpdateReport, Box<dyn std::error::Error + Send>> {
+    traced_run(run_id, source, None).await
+}
+
+/// The source the dumps of `source` archived on `date` are imported as, it
+/// keeps their rows apart from the live ones
+pub fn replay_source(
+    source: &Source,
+    date: NaiveDate,
+) -> Result<&'static Source, Box<dyn std::error::Error + Send>> {
+    let archive_url = match &config::CONFIG.dump_archive_url {
+        Some(v) => v,
+        None => {
+            return Err(Box::new(std::io::Error::other(
+                "DUMP_ARCHIVE_URL isn't set",
+            )))
+        }
+    };
+
+    let name = format!("{}@{date}", source.name);
+
+    let mut sources = REPLAY_SOURCES.lock().unwrap();
+    let replay = sources.entry(name.clone()).or_insert_with(|| {
+        Box::leak(Box::new(Source {
+            base_url: archive_url
+                .replace("{source}", &source.name)
+                .replace("{date}", &date.to_string()),
+            name,
+            archive_date: Some(date),
+        }))
+    });
+
+    Ok(*replay)
+}
+
+/// Runs the import against the dumps archived on a date, into the source of
+/// `replay_source`. Shows when a regression of the metadata came in.
+pub async fn replay(
+    run_id: Uuid,
+    source: &'static Source,
+) -> Result<UpdateReport, Box<dyn std::error::Error + Send>> {
+    let _lock = match REPLAY_LOCK.try_lock() {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    traced_run(run_id, source, None).await
+}
+
+/// The table of `TABLES` or of the known ones with this file or entity name
+pub fn find_table(name: &str) -> Option<&'static Table> {
+    let tables = || {
+        config::CONFIG
+            .tables
+            .iter()
+            .chain(config::CONFIG.known_tables.iter())
+    };
+
+    tables()
+        .find(|table| table.file == name)
+        .or_else(|| tables().find(|table| table.entity == name))
+}
+
+/// Runs the import of one table alone, with the lock of the source taken.
+/// Its dependencies aren't imported again, their rows of earlier runs are used.
+pub async fn update_table(
+    run_id: Uuid,
+    source: &'static Source,
+    table: &'static Table,
+    _lock: UpdateLock,
+) -> Result<UpdateReport, Box<dyn std::error::Error + Send>> {
+    traced_run(run_id, source, Some(table)).await
+}
+
+async fn traced_run(
+    run_id: Uuid,
+    source: &'static Source,
+    only: Option<&'static Table>,
+) -> Result<UpdateReport, Box<dyn std::error::Error + Send>> {
+    let hub = Arc::new(Hub::new_from_top(Hub::current()));
+    hub.configure_scope(|scope| scope.set_tag("run_id", run_id));
+
+    let span = tracing::info_span!("update", %run_id, source = %source.name);
+
+    run_log::start(run_id);
+    progress::start(&source.name, run_id);
+
+    let result = run(run_id, source, only)
+        .instrument(span)
+        .bind_hub(hub)
+        .await;
+
+    run_log::stop(run_id);
+
+    let status = match &result {
+        Ok(report) => report.status,
+        Err(_) => RunStatus::Failed,
+    };
+    progress::finish(&source.name, status);
+
+    result
+}
+
+/// Runs the rejects of a source, or of one of its files, through the current
+/// parsing and writing again. Waits for no import: it fails if one is running.
+pub async fn reprocess_rejects(
+    source: &'static Source,
+    file_name: Option<String>,
+) -> Result<Vec<ReprocessReport>, Box<dyn std::error::Error + Send>> {
+    let _lock = match UPDATE_LOCKS[&source.name].try_lock() {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let pool = db::POOL.clone();
+
+    let source_id = match get_source(pool.clone(), &source.name).await {
+        Ok(v) => v,
+        Err(err) => return Err(err),
+    };
+
+    let files = match file_name {
+        Some(v) => vec![v],
+        None => match rejects::files(&pool, source_id).await {
+            Ok(v) => v,
+            Err(err) => return Err(err),
+        },
+    };
+
+    let mut reports = vec![];
+
+    for file_name in files {
+        let table = config::CONFIG
+            .tables
+            .iter()
+            .chain(config::CONFIG.known_tables.iter())
+            .find(|table| table.file == file_name);
+
+        let reprocess = match table.and_then(|table| registry::reprocess(&table.entity)) {
+            Some(v) => v,
+            None => {
+                log::warn!("No entity for {file_name}, its rejects are kept");
+                continue;
+            }
+        };
+
+        match reprocess(pool.clone(), source_id, file_name).await {
+            Ok(v) => reports.push(v),
+            Err(err) => return Err(err),
+        };
+    }
+
+    Ok(reports)
+}
+
+/// What an import of the tables of the source, or only of `only`, would do,
+/// without writing. Fails if an import of the source is running.
+pub async fn preview(
+    source: &'static Source,
+    only: Option<&'static Table>,
+    samples: usize,
+) -> Result<Vec<TablePreview>, Box<dyn std::error::Error + Send>> {
+    let _lock = match UPDATE_LOCKS[&source.name].try_lock() {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let source_id = match get_source(db::POOL.clone(), &source.name).await {
+        Ok(v) => v,
+        Err(err) => return Err(err),
+    };
+
+    let tables: Vec<&'static Table> = match only {
+        Some(table) => vec![table],
+        None => config::CONFIG.tables.iter().collect(),
+    };
+
+    let mut previews = vec![];
+
+    for table in tables {
+        let preview = match registry::preview(&table.entity) {
+            Some(v) => v,
+            None => {
+                previews.push(TablePreview::failed(
+                    &table.file,
+                    format!("Unknown entity {}", table.entity),
+                ));
+                continue;
+            }
+        };
+
+        match preview(source, source_id, &table.file, samples).await {
+            Ok(v) => previews.push(v),
             Err(err) => {
-                let mut status = author_status_clone.lock().await;
-                *status = Some(UpdateStatus::Success);
-                Err(err)
+                log::error!("Can't preview {}: {:?}", table.file, err);
+                previews.push(TablePreview::failed(&table.file, err.to_string()));
+            }
+        };
+    }
+
+    Ok(previews)
+}
+
+/// Imports the tables of the source, or only `only`
+async fn run(
+    run_id: Uuid,
+    source: &'static Source,
+    only: Option<&'static Table>,
+) -> Result<UpdateReport, Box<dyn std::error::Error + Send>> {
+    log::info!("Start update...");
+    let started_at = std::time::Instant::now();
+
+    let pool = db::POOL.clone();
+
+    let source_id = match get_source(pool.clone(), &source.name).await {
+        Ok(v) => v,
+        Err(err) => return Err(err),
+    };
+
+    sentry::configure_scope(|scope| scope.set_tag("source", &source.name));
+
+    report::reset_import_stats(source_id);
+
+    let client = match db::checkout(&pool).await {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    match runs::start(&client, run_id, source_id).await {
+        Ok(_) => (),
+        Err(err) => return Err(err),
+    };
+
+    match runs::expected_rows(&client, source_id).await {
+        Ok(v) => progress::expect(&source.name, v),
+        Err(err) => log::error!("Can't load row counts of previous runs: {:?}", err),
+    };
+
+    let durations = match runs::durations(&client, source_id).await {
+        Ok(v) => v,
+        Err(err) => {
+            log::error!("Can't load table durations of previous runs: {:?}", err);
+            HashMap::new()
+        }
+    };
+
+    match changes::prepare(&client).await {
+        Ok(_) => (),
+        Err(err) => return Err(err),
+    };
+
+    // Changes above it are made by the run, the search indexer pushes them
+    let changes_from = match changes::last_id(&client).await {
+        Ok(v) => v,
+        Err(err) => return Err(err),
+    };
+    drop(client);
+
+    let mut tables: Vec<&'static Table> = match only {
+        Some(table) => vec![table],
+        None => config::CONFIG.tables.iter().collect(),
+    };
+    let mut new_dumps = vec![];
+
+    if config::CONFIG.dump_discovery != DumpDiscovery::Off && only.is_none() {
+        breadcrumb("Discover dumps".to_string());
+        match discovery::discover(source).await {
+            Ok(v) => {
+                new_dumps = v.new_dumps;
+                tables.extend(v.imported);
             }
+            Err(err) => log::error!("Can't discover dumps: {:?}", err),
+        };
+    }
+
+    progress::plan(&source.name, tables.iter().map(|table| table.file.as_str()));
+
+    // Sessions of the shadow pool write to the shadow tables
+    let mut table_pool = pool.clone();
+    let _shadow = match shadow::enabled() {
+        true => {
+            let lock = shadow::LOCK.lock().await;
+
+            let client = match db::checkout(&pool).await {
+                Ok(v) => v,
+                Err(err) => return Err(Box::new(err)),
+            };
+
+            match shadow::start(&client).await {
+                Ok(_) => (),
+                Err(err) => return Err(err),
+            };
+
+            table_pool = db::SHADOW_POOL.clone();
+            Some(lock)
         }
-    });
+        false => None,
+    };
 
-    let pool_clone = pool.clone();
-    let book_status_clone = book_status.clone();
-    let source_id_clone = source_id.clone();
-    let book_process = tokio::spawn(async move {
-        match process::<Book>(pool_clone, *source_id_clone, "lib.libbook.sql", vec![]).await {
-            Ok(_) => {
-                let mut status = book_status_clone.lock().await;
-                *status = Some(UpdateStatus::Success);
-                Ok(())
-            }
-            Err(err) => {
-                let mut status = book_status_clone.lock().await;
-                *status = Some(UpdateStatus::Fail);
-                Err(err)
-            }
+    // Sessions of the catalog pool write to the catalog schema of the run
+    let _catalog = match catalogs::enabled() {
+        true => {
+            let lock = catalogs::LOCK.lock().await;
+
+            let client = match db::checkout(&pool).await {
+                Ok(v) => v,
+                Err(err) => return Err(Box::new(err)),
+            };
+
+            let schema = catalogs::schema_name(Utc::now());
+            match catalogs::start(&client, run_id, &schema).await {
+                Ok(_) => (),
+                Err(err) => return Err(err),
+            };
+
+            table_pool = match db::schema_pool(&schema) {
+                Ok(v) => v,
+                Err(err) => return Err(Box::new(err)),
+            };
+            Some(lock)
         }
-    });
+        false => None,
+    };
 
-    let pool_clone = pool.clone();
-    let deps = vec![author_status.clone(), book_status.clone()];
-    let source_id_clone = source_id.clone();
-    let book_author_process = tokio::spawn(async move {
-        process::<BookAuthor>(pool_clone, *source_id_clone, "lib.libavtor.sql", deps).await
-    });
+    breadcrumb("Update tables".to_string());
+    let tables = update_tables(table_pool.clone(), source, source_id, &tables, &durations).await;
 
-    let pool_clone = pool.clone();
-    let deps = vec![author_status.clone(), book_status.clone()];
-    let source_id_clone = source_id.clone();
-    let translator_process = tokio::spawn(async move {
-        process::<Translator>(pool_clone, *source_id_clone, "lib.libtranslator.sql", deps).await
-    });
+    let mut report = UpdateReport::new(run_id, &source.name, tables);
+    report.new_dumps = new_dumps;
 
-    let pool_clone = pool.clone();
-    let sequence_status_clone = sequence_status.clone();
-    let source_id_clone = source_id.clone();
-    let sequence_process = tokio::spawn(async move {
-        match process::<Sequence>(pool_clone, *source_id_clone, "lib.libseqname.sql", vec![]).await
-        {
-            Ok(_) => {
-                let mut status = sequence_status_clone.lock().await;
-                *status = Some(UpdateStatus::Success);
-                Ok(())
+    if shadow::enabled() {
+        match report.status {
+            RunStatus::Success => {
+                breadcrumb("Swap shadow tables".to_string());
+                match db::checkout(&pool).await {
+                    Ok(mut client) => match shadow::swap(&mut client).await {
+                        Ok(_) => notify_changes().await,
+                        Err(err) => {
+                            log::error!("Can't swap the shadow tables: {:?}", err);
+                            report.add_error(format!("shadow swap: {err}"));
+                        }
+                    },
+                    Err(err) => {
+                        log::error!("Can't swap the shadow tables: {:?}", err);
+                        report.add_error(format!("shadow swap: {err}"));
+                    }
+                };
             }
-            Err(err) => {
-                let mut status = sequence_status_clone.lock().await;
-                *status = Some(UpdateStatus::Fail);
-                Err(err)
+            _ => {
+                log::warn!("Not every table is imported, the live tables are left as they are");
+                report.add_error("shadow swap: skipped, some tables failed".to_string());
             }
         }
-    });
+    }
 
-    let pool_clone = pool.clone();
-    let deps = vec![book_status.clone(), sequence_status.clone()];
-    let source_id_clone = source_id.clone();
-    let sequence_info_process = tokio::spawn(async move {
-        process::<SequenceInfo>(pool_clone, *source_id_clone, "lib.libseq.sql", deps).await
-    });
+    // Exports read the catalog schema once it's switched to
+    let mut post_update_pool = pool.clone();
 
-    let pool_clone = pool.clone();
-    let deps = vec![book_status.clone()];
-    let book_annotation_status_clone = book_annotation_status.clone();
-    let source_id_clone = source_id.clone();
-    let book_annotation_process = tokio::spawn(async move {
-        match process::<BookAnnotation>(pool_clone, *source_id_clone, "lib.b.annotations.sql", deps)
-            .await
-        {
-            Ok(_) => {
-                let mut status = book_annotation_status_clone.lock().await;
-                *status = Some(UpdateStatus::Success);
-                Ok(())
+    if catalogs::enabled() {
+        match report.status {
+            RunStatus::Success => {
+                breadcrumb("Switch catalog schema".to_string());
+                match db::checkout(&pool).await {
+                    Ok(mut client) => match catalogs::switch(&mut client).await {
+                        Ok(_) => {
+                            post_update_pool = table_pool.clone();
+                            notify_changes().await
+                        }
+                        Err(err) => {
+                            log::error!("Can't switch the catalog schema: {:?}", err);
+                            report.add_error(format!("catalog switch: {err}"));
+                        }
+                    },
+                    Err(err) => {
+                        log::error!("Can't switch the catalog schema: {:?}", err);
+                        report.add_error(format!("catalog switch: {err}"));
+                    }
+                };
             }
-            Err(err) => {
-                let mut status = book_annotation_status_clone.lock().await;
-                *status = Some(UpdateStatus::Fail);
-                Err(err)
+            _ => {
+                catalogs::abandon().await;
+                log::warn!("Not every table is imported, the current catalog is left as it is");
+                report.add_error("catalog switch: skipped, some tables failed".to_string());
             }
         }
-    });
+    }
 
-    let pool_clone = pool.clone();
-    let deps = vec![book_annotation_status.clone()];
-    let source_id_clone = source_id.clone();
-    let book_annotation_pics_process = tokio::spawn(async move {
-        process::<BookAnnotationPic>(
-            pool_clone,
-            *source_id_clone,
-            "lib.b.annotations_pics.sql",
-            deps,
-        )
-        .await
-    });
+    match db::checkout(&pool).await {
+        Ok(client) => {
+            if let Err(err) = runs::save_tables(&client, run_id, &report.tables).await {
+                log::error!("Can't save table row counts: {:?}", err);
+            }
 
-    let pool_clone = pool.clone();
-    let deps = vec![author_status.clone()];
-    let author_annotation_status_clone = author_annotation_status.clone();
-    let source_id_clone = source_id.clone();
-    let author_annotation_process = tokio::spawn(async move {
-        match process::<AuthorAnnotation>(
-            pool_clone,
-            *source_id_clone,
-            "lib.a.annotations.sql",
-            deps,
-        )
-        .await
-        {
-            Ok(_) => {
-                let mut status = author_annotation_status_clone.lock().await;
-                *status = Some(UpdateStatus::Success);
-                Ok(())
+            let downloads = report::downloads(source_id);
+            if let Err(err) = runs::save_downloads(&client, run_id, &downloads).await {
+                log::error!("Can't save the downloads: {:?}", err);
             }
-            Err(err) => {
-                let mut status = author_annotation_status_clone.lock().await;
-                *status = Some(UpdateStatus::Fail);
-                Err(err)
+
+            // Books of a replay aren't new to the readers
+            if source.archive_date.is_none() {
+                breadcrumb("Record new arrivals".to_string());
+                let added_books = report::added_books(source_id);
+                match arrivals::record(&client, run_id, source_id, &added_books).await {
+                    Ok(_) => (),
+                    Err(err) => {
+                        log::error!("Can't record new arrivals: {:?}", err);
+                        report.add_error(format!("new arrivals: {err}"));
+                    }
+                };
             }
         }
-    });
+        Err(err) => {
+            log::error!("Can't save row counts and new arrivals: {:?}", err);
+            report.add_error(format!("new arrivals: {err}"));
+        }
+    };
 
-    let pool_clone = pool.clone();
-    let deps = vec![author_annotation_status.clone()];
-    let source_id_clone = source_id.clone();
-    let author_annotation_pics_process = tokio::spawn(async move {
-        process::<AuthorAnnotationPic>(
-            pool_clone,
-            *source_id_clone,
-            "lib.a.annotations_pics.sql",
-            deps,
-        )
-        .await
-    });
+    match report::import_stats(&pool, source_id).await {
+        Ok(v) => report.import = v,
+        Err(err) => log::error!("Can't collect import stats: {:?}", err),
+    };
 
-    let pool_clone = pool.clone();
-    let genre_status_clone = genre_status.clone();
-    let source_id_clone = source_id.clone();
-    let genre_annotation_process = tokio::spawn(async move {
-        match process::<Genre>(pool_clone, *source_id_clone, "lib.libgenrelist.sql", vec![]).await {
-            Ok(_) => {
-                let mut status = genre_status_clone.lock().await;
-                *status = Some(UpdateStatus::Success);
-                Ok(())
-            }
-            Err(err) => {
-                let mut status = genre_status_clone.lock().await;
-                *status = Some(UpdateStatus::Fail);
-                Err(err)
+    if report.status != RunStatus::Failed && source.archive_date.is_none() {
+        breadcrumb("Post update".to_string());
+        if let Err(err) = post_update(post_update_pool, source_id, changes_from).await {
+            log::error!("Post update failed: {:?}", err);
+            report.add_error(format!("post update: {err}"));
+        }
+    }
+
+    report.duration_secs = started_at.elapsed().as_secs();
+
+    let client = match db::checkout(&pool).await {
+        Ok(v) => v,
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    if source.archive_date.is_none() {
+        breadcrumb("Send notifications".to_string());
+        let deliveries = notify::send(&client, source_id, &report).await;
+        for delivery in deliveries.iter() {
+            if let Some(err) = &delivery.error {
+                report.add_error(format!("{}, {}: {err}", delivery.channel, delivery.target));
             }
         }
-    });
+        report.deliveries = deliveries;
+    }
 
-    let pool_clone = pool.clone();
-    let deps = vec![genre_status.clone(), book_status.clone()];
-    let source_id_clone = source_id.clone();
-    let book_genre_process = tokio::spawn(async move {
-        process::<BookGenre>(pool_clone, *source_id_clone, "lib.libgenre.sql", deps).await
-    });
+    if let Some(path) = &config::CONFIG.report_path {
+        if let Err(err) = report::write_file(&report, path).await {
+            log::error!("Can't write the report to {path}: {:?}", err);
+        }
+    }
+
+    if let Err(err) = run_log::save(&client, run_id).await {
+        log::error!("Can't save the run log: {:?}", err);
+    }
+
+    // A run of one table or of archived dumps isn't a failed update to retry
+    let retried = only.is_none() && source.archive_date.is_none();
+
+    match runs::finish(&client, run_id, report.status, retried).await {
+        Ok(Some(retry_at)) => log::warn!("The run failed, the next attempt is at {retry_at}"),
+        Ok(None) => (),
+        Err(err) => return Err(err),
+    };
+
+    Ok(report)
+}
 
-    for process in [
-        author_process,
-        book_process,
-        book_author_process,
-        translator_process,
-        sequence_process,
-        sequence_info_process,
-        book_annotation_process,
-        book_annotation_pics_process,
-        author_annotation_process,
-        author_annotation_pics_process,
-        genre_annotation_process,
-        book_genre_process,
-    ] {
-        let process_result = match process.await {
+async fn post_update(
+    pool: Pool,
+    source_id: i16,
+    changes_from: Option<i64>,
+) -> Result<(), Box<dyn std::error::Error + Send>> {
+    if config::CONFIG.search_index_maintenance {
+        let client = match db::checkout(&pool).await {
             Ok(v) => v,
             Err(err) => return Err(Box::new(err)),
         };
 
-        match process_result {
+        match search_index::maintain(&client).await {
             Ok(_) => (),
-            Err(err) => panic!("{:?}", err),
+            Err(err) => return Err(err),
+        };
+    }
+
+    if config::CONFIG.duplicate_detection {
+        let client = match db::checkout(&pool).await {
+            Ok(v) => v,
+            Err(err) => return Err(Box::new(err)),
+        };
+
+        match duplicates::detect(&client).await {
+            Ok(_) => (),
+            Err(err) => return Err(err),
+        };
+    }
+
+    match indexer::reindex_all(&pool, source_id, changes_from).await {
+        Ok(_) => (),
+        Err(err) => return Err(err),
+    };
+
+    match sqlite_export::export(&pool).await {
+        Ok(_) => (),
+        Err(err) => return Err(err),
+    };
+
+    match onix_export::export(&pool).await {
+        Ok(_) => (),
+        Err(err) => return Err(err),
+    };
+
+    match stats::refresh(&pool).await {
+        Ok(_) => (),
+        Err(err) => log::error!("Can't refresh stats: {:?}", err),
+    };
+
+    Ok(())
+}
+
+pub(crate) type Status = Arc<Mutex<Option<UpdateStatus>>>;
+pub(crate) type TableHandle = JoinHandle<Result<RowCounts, Box<dyn std::error::Error + Send>>>;
+
+/// Whether another attempt of a table can go better: the database or the
+/// network failed for a moment. A bad dump or row fails the same way again.
+fn is_transient_failure(err: &(dyn std::error::Error + 'static)) -> bool {
+    if let Some(err) = err.downcast_ref::<tokio_postgres::Error>() {
+        return db::is_transient(err);
+    }
+
+    if let Some(err) = err.downcast_ref::<Box<tokio_postgres::Error>>() {
+        return db::is_transient(err);
+    }
+
+    if let Some(UpdaterError::Pool(err)) = err.downcast_ref::<UpdaterError>() {
+        return db::is_transient_pool_error(err);
+    }
+
+    if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+        return err.is_timeout()
+            || err.is_connect()
+            || err.is_request()
+            || err.is_body()
+            || err
+                .status()
+                .is_some_and(|status| status.is_server_error() || status.as_u16() == 429);
+    }
+
+    // Errors of ours are `Other`, only the ones of the connection count
+    if let Some(err) = err.downcast_ref::<std::io::Error>() {
+        return matches!(
+            err.kind(),
+            std::io::ErrorKind::ConnectionReset
+                | std::io::ErrorKind::ConnectionAborted
+                | std::io::ErrorKind::ConnectionRefused
+                | std::io::ErrorKind::BrokenPipe
+                | std::io::ErrorKind::TimedOut
+                | std::io::ErrorKind::UnexpectedEof
+                | std::io::ErrorKind::Interrupted
+        );
+    }
+
+    false
+}
+
+pub(crate) fn spawn_table<T>(
+    pool: &Pool,
+    source: &'static Source,
+    source_id: i16,
+    file_name: &'static str,
+    deps: Vec<Status>,
+    soft_deps: Vec<Status>,
+    status: &Status,
+) -> (&'static str, TableHandle)
+where
+    T: Debug + FromVecExpression<T> + Update + Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    let pool = pool.clone();
+    let status = status.clone();
+
+    // Every table gets its own scope, so its tags and breadcrumbs don't mix with the others
+    let hub = Arc::new(Hub::new_from_top(Hub::current()));
+    hub.configure_scope(|scope| scope.set_tag("file_name", file_name));
+
+    let handle = tokio::spawn(
+        async move {
+            let mut attempt = 0;
+
+            let result = loop {
+                let result = match spawn_in_run(process::<T>(
+                    pool.clone(),
+                    source,
+                    source_id,
+                    file_name,
+                    deps.clone(),
+                    soft_deps.clone(),
+                ))
+                .await
+                {
+                    Ok(v) => v,
+                    Err(err) if err.is_panic() => {
+                        let err = UpdaterError::panic(file_name, err.into_panic());
+                        log::error!("{err}");
+                        sentry::capture_error(&err);
+                        Err(Box::new(err) as Box<dyn std::error::Error + Send>)
+                    }
+                    Err(err) => Err(Box::new(err) as Box<dyn std::error::Error + Send>),
+                };
+
+                let err = match result {
+                    Ok(_) => break result,
+                    Err(err) => err,
+                };
+
+                if attempt >= config::CONFIG.table_retries || !is_transient_failure(err.as_ref()) {
+                    break Err(err);
+                }
+
+                attempt += 1;
+
+                log::warn!(
+                    "Update {file_name} failed (attempt {attempt}/{}): {:?}",
+                    config::CONFIG.table_retries,
+                    err
+                );
+
+                tokio::time::sleep(std::time::Duration::from_secs(5 << attempt.min(6))).await;
+            };
+
+            let deferred = matches!(
+                &result,
+                Err(err) if matches!(
+                    err.downcast_ref::<UpdaterError>(),
+                    Some(UpdaterError::Deferred { .. })
+                )
+            );
+
+            match &result {
+                Ok(_) => (),
+                Err(err) if deferred => log::info!("{err}"),
+                Err(err) => log::error!("Table update failed: {:?}", err),
+            };
+
+            *status.lock().await = match result {
+                Ok(_) => Some(UpdateStatus::Success),
+                Err(_) if deferred => Some(UpdateStatus::Deferred),
+                Err(_) => Some(UpdateStatus::Fail),
+            };
+
+            result
+        }
+        .in_current_span()
+        .bind_hub(hub),
+    );
+
+    (file_name, handle)
+}
+
+/// Orders the tables so the ones on the longest path of dependencies, by the
+/// durations of the previous runs, start first. Dependencies still come before
+/// the tables that need them.
+fn schedule(tables: &[&'static Table], durations: &HashMap<String, u64>) -> Vec<&'static Table> {
+    // What's left to import after a table is done, the table included
+    let mut path: HashMap<&str, u64> = HashMap::new();
+
+    for table in tables.iter().rev() {
+        let after = tables
+            .iter()
+            .filter(|other| {
+                other.deps.contains(&table.file) || other.soft_deps.contains(&table.file)
+            })
+            .filter_map(|other| path.get(other.file.as_str()))
+            .max()
+            .copied()
+            .unwrap_or(0);
+        let own = durations.get(&table.file).copied().unwrap_or(0);
+
+        path.insert(table.file.as_str(), own + after);
+    }
+
+    let mut pending: Vec<&'static Table> = tables.to_vec();
+    let mut ordered = Vec::with_capacity(tables.len());
+
+    while !pending.is_empty() {
+        let ready = |table: &&'static Table| {
+            table
+                .deps
+                .iter()
+                .chain(table.soft_deps.iter())
+                .all(|dep| ordered.iter().any(|done: &&Table| &done.file == dep))
+        };
+
+        let index = pending
+            .iter()
+            .enumerate()
+            .filter(|(_, table)| ready(table))
+            .max_by_key(|(index, table)| (path[table.file.as_str()], std::cmp::Reverse(*index)))
+            .map(|(index, _)| index)
+            // A dependency outside of the run, keep the configured order
+            .unwrap_or(0);
+
+        ordered.push(pending.remove(index));
+    }
+
+    ordered
+}
+
+/// Imports the tables, each after its dependencies. Tables deferred by a soft
+/// dependency run again once all the others are done, without waiting for it.
+async fn update_tables(
+    pool: Pool,
+    source: &'static Source,
+    source_id: i16,
+    tables: &[&'static Table],
+    durations: &HashMap<String, u64>,
+) -> Vec<TableReport> {
+    let mut statuses: HashMap<&'static str, Status> = HashMap::new();
+
+    // Dependencies out of the run were imported by earlier ones
+    for table in tables.iter() {
+        for dep in table.deps.iter() {
+            if !tables.iter().any(|table| &table.file == dep) {
+                statuses
+                    .entry(dep.as_str())
+                    .or_insert_with(|| Arc::new(Mutex::new(Some(UpdateStatus::Success))));
+            }
+        }
+    }
+
+    let processes = spawn_tables(
+        &pool,
+        source,
+        source_id,
+        &schedule(tables, durations),
+        &mut statuses,
+        true,
+    )
+    .await;
+
+    let mut reports = vec![];
+    let mut deferred = vec![];
+
+    for (table, process) in processes {
+        match process.await {
+            Ok(Err(err))
+                if matches!(
+                    err.downcast_ref::<UpdaterError>(),
+                    Some(UpdaterError::Deferred { .. })
+                ) =>
+            {
+                progress::set_table_state(&source.name, &table.file, TableState::Pending);
+                deferred.push(table)
+            }
+            result => reports.push(table_report(source, source_id, &table.file, result)),
+        };
+    }
+
+    for report in reports.iter() {
+        hooks::table_done(&source.name, report);
+    }
+
+    if deferred.is_empty() {
+        return reports;
+    }
+
+    log::info!(
+        "Update the deferred tables: {}",
+        deferred
+            .iter()
+            .map(|table| table.file.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    let processes = spawn_tables(&pool, source, source_id, &deferred, &mut statuses, false).await;
+
+    for (table, process) in processes {
+        let report = table_report(source, source_id, &table.file, process.await);
+        hooks::table_done(&source.name, &report);
+        reports.push(report);
+    }
+
+    reports
+}
+
+/// Spawns the tables in order, `statuses` gets theirs. The soft dependencies
+/// are left out when `soft_deps` is false.
+async fn spawn_tables(
+    pool: &Pool,
+    source: &'static Source,
+    source_id: i16,
+    tables: &[&'static Table],
+    statuses: &mut HashMap<&'static str, Status>,
+    soft_deps: bool,
+) -> Vec<(&'static Table, TableHandle)> {
+    let mut processes = vec![];
+
+    for table in tables.iter().copied() {
+        let file_name = table.file.as_str();
+        let status: Status = Arc::new(Mutex::new(None));
+
+        let deps = table
+            .deps
+            .iter()
+            .map(|dep| statuses[dep.as_str()].clone())
+            .collect();
+
+        // Unlike `deps` these don't have to be in the run
+        let soft = match soft_deps {
+            true => table
+                .soft_deps
+                .iter()
+                .filter_map(|dep| statuses.get(dep.as_str()).cloned())
+                .collect(),
+            false => vec![],
+        };
+
+        let spawn = match registry::get(&table.entity) {
+            Some(v) => v,
+            None => {
+                log::error!("Unknown entity {} for {file_name}", table.entity);
+
+                let process = spawn_in_run(async move {
+                    let err: Box<dyn std::error::Error + Send + Sync> =
+                        format!("Unknown entity: {}", table.entity).into();
+                    Err(err as Box<dyn std::error::Error + Send>)
+                });
+
+                *status.lock().await = Some(UpdateStatus::Fail);
+                statuses.insert(file_name, status);
+                processes.push((table, process));
+                continue;
+            }
+        };
+
+        let (_, process) = spawn(pool, source, source_id, file_name, deps, soft, &status);
+
+        statuses.insert(file_name, status);
+        processes.push((table, process));
+    }
+
+    processes
+}
+
+fn table_report(
+    source: &Source,
+    source_id: i16,
+    file_name: &str,
+    result: Result<Result<RowCounts, Box<dyn std::error::Error + Send>>, tokio::task::JoinError>,
+) -> TableReport {
+    let process_result = match result {
+        Ok(v) => v,
+        Err(err) => Err(Box::new(err) as Box<dyn std::error::Error + Send>),
+    };
+
+    match process_result {
+        Ok(rows) => TableReport {
+            file_name: file_name.to_string(),
+            status: TableStatus::Success,
+            error: None,
+            rows,
+            parse_issues: report::parse_issues(source_id, file_name),
+            duration_ms: report::duration(source_id, file_name),
+        },
+        Err(err) => {
+            let status = match err.downcast_ref::<UpdaterError>() {
+                Some(UpdaterError::DependencyFailed { .. }) => TableStatus::Skipped,
+                _ => TableStatus::Failed,
+            };
+
+            // Undecodable lines fail with `InvalidData`
+            let parse_failed = match err.downcast_ref::<UpdaterError>() {
+                Some(UpdaterError::TooManyParseIssues { .. }) => true,
+                Some(UpdaterError::KnownBadDump { .. }) => true,
+                _ => matches!(
+                    err.downcast_ref::<std::io::Error>(),
+                    Some(err) if err.kind() == std::io::ErrorKind::InvalidData
+                ),
+            };
+            if parse_failed {
+                report::record_parse_failure(source_id, file_name);
+            }
+
+            let state = match status {
+                TableStatus::Skipped => TableState::Skipped,
+                _ => TableState::Failed,
+            };
+            progress::set_table_state(&source.name, file_name, state);
+
+            TableReport {
+                file_name: file_name.to_string(),
+                status,
+                error: Some(err.to_string()),
+                rows: RowCounts::default(),
+                parse_issues: report::parse_issues(source_id, file_name),
+                duration_ms: None,
+            }
         }
     }
+}
 
-    match send_webhooks().await {
-        Ok(_) => {
-            log::info!("Webhooks sended!");
+/// Starts the follow-ups of failed runs that are due. Returns whether all of
+/// them succeeded.
+async fn retry_failed_runs() -> bool {
+    let client = match db::checkout(&db::POOL).await {
+        Ok(v) => v,
+        Err(err) => {
+            log::error!("Can't check for failed runs to retry: {:?}", err);
+            return false;
         }
+    };
+
+    let sources = match runs::due_retries(&client).await {
+        Ok(v) => v,
         Err(err) => {
-            log::info!("Webhooks send failed : {err}");
-            return Err(Box::new(err));
+            log::error!("Can't check for failed runs to retry: {:?}", err);
+            return false;
         }
     };
+    drop(client);
+
+    let updates = sources
+        .iter()
+        .filter_map(|name| config::CONFIG.source(Some(name)))
+        .map(|source| async move {
+            log::info!("Retry the failed update of {}", source.name);
+
+            match update(Uuid::new_v4(), source).await {
+                Ok(report) => {
+                    log::info!("Updated {}: {}", source.name, report.status.as_str());
+                    !matches!(report.status, RunStatus::Failed)
+                }
+                Err(err) => {
+                    log::info!("Update {} err: {:?}", source.name, err);
+                    false
+                }
+            }
+        });
 
-    Ok(())
+    futures::future::join_all(updates)
+        .await
+        .iter()
+        .all(|ok| *ok)
+}
+
+/// Adds the job to the scheduler and to `GET /schedule`
+async fn add_job(job_scheduler: &JobScheduler, name: &'static str, schedule: &str, job: Job) {
+    let id = job_scheduler.add(job).await.unwrap();
+    jobs::register(name, schedule, id);
 }
 
 pub async fn cron_jobs() {
     let job_scheduler = JobScheduler::new().await.unwrap();
+    jobs::set_scheduler(&job_scheduler);
 
-    let update_job = match Job::new_async("0 0 3 * * *", |_uuid, _l| {
+    let update_job = match Job::new_async(UPDATE_SCHEDULE, |_uuid, _l| {
         Box::pin(async {
-            match update().await {
-                Ok(_) => log::info!("Updated"),
-                Err(err) => log::info!("Update err: {:?}", err),
-            };
+            jobs::started("update");
+            let check_in = monitor::CheckIn::start(UPDATE_SCHEDULE);
+
+            let updates = config::CONFIG.sources.iter().map(|source| async move {
+                match update(Uuid::new_v4(), source).await {
+                    Ok(report) => {
+                        log::info!("Updated {}: {}", source.name, report.status.as_str());
+                        !matches!(report.status, RunStatus::Failed)
+                    }
+                    Err(err) => {
+                        log::info!("Update {} err: {:?}", source.name, err);
+                        false
+                    }
+                }
+            });
+
+            let succeeded = futures::future::join_all(updates).await;
+            let ok = succeeded.iter().all(|ok| *ok);
+
+            if let Some(check_in) = check_in {
+                check_in.finish(ok);
+            }
+            jobs::finished("update", ok);
         })
     }) {
         Ok(v) => v,
         Err(err) => panic!("{:?}", err),
     };
 
-    job_scheduler.add(update_job).await.unwrap();
+    add_job(&job_scheduler, "update", UPDATE_SCHEDULE, update_job).await;
+
+    if config::CONFIG.run_retries > 0 {
+        let retry_job = match Job::new_async(RETRY_SCHEDULE, |_uuid, _l| {
+            Box::pin(async {
+                jobs::started("retry");
+                let ok = retry_failed_runs().await;
+                jobs::finished("retry", ok);
+            })
+        }) {
+            Ok(v) => v,
+            Err(err) => panic!("{:?}", err),
+        };
+
+        add_job(&job_scheduler, "retry", RETRY_SCHEDULE, retry_job).await;
+    }
+
+    if !config::CONFIG.retention_days.is_empty() {
+        let schedule = config::CONFIG.retention_schedule.as_str();
+        let retention_job = match Job::new_async(schedule, |_uuid, _l| {
+            Box::pin(async {
+                jobs::started("retention");
+                let ok = retention::run().await;
+                jobs::finished("retention", ok);
+            })
+        }) {
+            Ok(v) => v,
+            Err(err) => panic!("{:?}", err),
+        };
+
+        add_job(&job_scheduler, "retention", schedule, retention_job).await;
+    }
 
     log::info!("Scheduler start...");
     match job_scheduler.start().await {
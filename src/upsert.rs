@@ -0,0 +1,586 @@
+use std::pin::pin;
+
+use tokio_postgres::{
+    binary_copy::BinaryCopyInWriter,
+    types::{ToSql, Type},
+    Client, Transaction,
+};
+
+use crate::report::RowCounts;
+
+/// Parameters of one row for `UpsertSpec::copy`, in the same order as for `execute`
+pub type CopyRow = Vec<Box<dyn ToSql + Sync + Send>>;
+
+pub enum Column {
+    /// The `source` column, always bound to `$1`
+    Source,
+    /// A plain value: column name and SQL type
+    Value(&'static str, &'static str),
+    /// A reference resolved from `remote_id` within the source: column name and referenced table
+    Ref(&'static str, &'static str),
+    /// A value computed in SQL without a parameter: column name and expression
+    Sql(&'static str, &'static str),
+}
+
+impl Column {
+    fn name(&self) -> &'static str {
+        match self {
+            Column::Source => "source",
+            Column::Value(name, _) => name,
+            Column::Ref(name, _) => name,
+            Column::Sql(name, _) => name,
+        }
+    }
+}
+
+/// Rows of a preview with the same outcome: `insert`, `update`, `unchanged`,
+/// `skip` or `delete`
+pub struct PreviewRows {
+    pub action: String,
+    pub count: u64,
+    /// `{"old": ..., "new": ...}` rows as JSON, `new` is missing for `delete`
+    pub samples: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UpsertResult {
+    Inserted,
+    Updated,
+    Unchanged,
+    /// Nothing was written, e.g. a referenced row is missing
+    Skipped,
+}
+
+/// Describes how a dump row is written: rows are matched by `keys`, existing
+/// ones get their `values` updated and missing ones are inserted (unless
+/// `insert` is false) together with `insert_defaults`. Rows with an
+/// unresolved reference are skipped.
+pub struct UpsertSpec {
+    pub table: &'static str,
+    pub keys: &'static [Column],
+    pub values: &'static [Column],
+    pub insert: bool,
+    pub insert_defaults: &'static [Column],
+}
+
+impl UpsertSpec {
+    pub fn query(&self) -> String {
+        self.build(false)
+    }
+
+    /// Same as `query`, but for all rows of the `upsert_staging` table filled by `copy`
+    fn copy_query(&self) -> String {
+        self.build(true)
+    }
+
+    fn columns(&self) -> Vec<&Column> {
+        self.keys
+            .iter()
+            .chain(self.values.iter())
+            .chain(self.insert_defaults.iter())
+            .collect()
+    }
+
+    /// The `SELECT` of the row as it would be written, from the parameters or
+    /// from `upsert_staging`
+    fn new_row(&self, staging: bool) -> String {
+        let param = |n: usize| {
+            if staging {
+                format!("staging.p{n}")
+            } else {
+                format!("${n}")
+            }
+        };
+
+        let mut n = 1;
+        let row = self
+            .columns()
+            .iter()
+            .map(|column| match column {
+                Column::Source => "cast($1 as smallint) AS \"source\"".to_string(),
+                Column::Value(name, sql_type) => {
+                    n += 1;
+                    format!("cast({} as {sql_type}) AS \"{name}\"", param(n))
+                }
+                Column::Ref(name, ref_table) => {
+                    n += 1;
+                    format!(
+                        "(SELECT id FROM {ref_table} WHERE source = $1 AND remote_id = cast({} as int)) AS \"{name}\"",
+                        param(n)
+                    )
+                }
+                Column::Sql(name, expr) => format!("{expr} AS \"{name}\""),
+            })
+            .collect::<Vec<String>>()
+            .join(", ");
+        if staging {
+            format!("{row} FROM upsert_staging AS staging")
+        } else {
+            row
+        }
+    }
+
+    /// The value columns a change of the row is told by, `Sql` values
+    /// (timestamps, flags) don't count
+    fn compared(&self) -> Vec<&'static str> {
+        self.values
+            .iter()
+            .filter(|column| !matches!(column, Column::Sql(..)))
+            .map(|column| column.name())
+            .collect()
+    }
+
+    /// The conditions for inserting the row: its references are resolved
+    fn insertable(&self) -> Vec<String> {
+        self.columns()
+            .iter()
+            .filter(|column| matches!(column, Column::Ref(..)))
+            .map(|column| format!("\"{}\" IS NOT NULL", column.name()))
+            .collect()
+    }
+
+    fn build(&self, staging: bool) -> String {
+        let table = self.table;
+        let columns = self.columns();
+        let row = self.new_row(staging);
+
+        let key_match = self
+            .keys
+            .iter()
+            .map(|column| format!("{table}.\"{0}\" = new_row.\"{0}\"", column.name()))
+            .collect::<Vec<String>>()
+            .join(" AND ");
+
+        let assignments = self
+            .values
+            .iter()
+            .map(|column| format!("\"{0}\" = new_row.\"{0}\"", column.name()))
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        let compared = self.compared();
+
+        let changed = if compared.is_empty() {
+            "false".to_string()
+        } else {
+            format!(
+                "({}) IS DISTINCT FROM ({})",
+                compared
+                    .iter()
+                    .map(|name| format!("old.\"{name}\""))
+                    .collect::<Vec<String>>()
+                    .join(", "),
+                compared
+                    .iter()
+                    .map(|name| format!("new_row.\"{name}\""))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            )
+        };
+
+        let old_match = self
+            .keys
+            .iter()
+            .map(|column| format!("old.\"{0}\" = new_row.\"{0}\"", column.name()))
+            .collect::<Vec<String>>()
+            .join(" AND ");
+
+        let existing = if self.values.is_empty() {
+            format!("SELECT false AS changed FROM {table}, new_row WHERE {key_match}")
+        } else {
+            format!(
+                "UPDATE {table} SET {assignments} FROM new_row, {table} AS old \
+                WHERE {key_match} AND {old_match} RETURNING {changed} AS changed"
+            )
+        };
+
+        let counts =
+            "(SELECT count(*) FROM existing), (SELECT count(*) FROM existing WHERE changed)";
+
+        if !self.insert {
+            return format!(
+                "WITH new_row AS (SELECT {row}), existing AS ({existing}) SELECT 0::bigint, {counts};"
+            );
+        }
+
+        let names = columns
+            .iter()
+            .map(|column| format!("\"{}\"", column.name()))
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        let mut conditions = self.insertable();
+        if staging {
+            conditions.push(format!(
+                "NOT EXISTS (SELECT 1 FROM {table} WHERE {key_match})"
+            ));
+        } else {
+            conditions.push("NOT EXISTS (SELECT 1 FROM existing)".to_string());
+        }
+
+        format!(
+            "WITH new_row AS (SELECT {row}), existing AS ({existing}), \
+            inserted AS (INSERT INTO {table} ({names}) SELECT {names} FROM new_row WHERE {} RETURNING 1) \
+            SELECT (SELECT count(*) FROM inserted), {counts};",
+            conditions.join(" AND ")
+        )
+    }
+
+    /// Runs the upsert, `params` follow the `Value` and `Ref` keys and values in order.
+    pub async fn execute(
+        &self,
+        client: &Client,
+        source_id: i16,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<UpsertResult, Box<tokio_postgres::Error>> {
+        let mut all_params: Vec<&(dyn ToSql + Sync)> = vec![&source_id];
+        all_params.extend_from_slice(params);
+
+        let row = match client.query_one(&self.query(), &all_params).await {
+            Ok(v) => v,
+            Err(err) => return Err(Box::new(err)),
+        };
+
+        let (inserted, existing, changed): (i64, i64, i64) = (row.get(0), row.get(1), row.get(2));
+
+        Ok(if inserted > 0 {
+            UpsertResult::Inserted
+        } else if changed > 0 {
+            UpsertResult::Updated
+        } else if existing > 0 {
+            UpsertResult::Unchanged
+        } else {
+            UpsertResult::Skipped
+        })
+    }
+
+    /// Loads `rows` into the temporary `upsert_staging` table with a binary
+    /// `COPY`, it's dropped on commit
+    async fn stage(
+        &self,
+        transaction: &Transaction<'_>,
+        rows: &[CopyRow],
+    ) -> Result<(), Box<tokio_postgres::Error>> {
+        // Let the server infer the parameter types, the staging columns use them
+        let statement = match transaction.prepare(&self.query()).await {
+            Ok(v) => v,
+            Err(err) => return Err(Box::new(err)),
+        };
+        let types: Vec<Type> = statement.params()[1..].to_vec();
+
+        let names: Vec<String> = (2..types.len() + 2).map(|n| format!("p{n}")).collect();
+        let definitions = names
+            .iter()
+            .zip(types.iter())
+            .map(|(name, ty)| format!("{name} \"{}\".\"{}\"", ty.schema(), ty.name()))
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        match transaction
+            .batch_execute(&format!(
+                "DROP TABLE IF EXISTS upsert_staging; \
+                CREATE TEMP TABLE upsert_staging ({definitions}) ON COMMIT DROP;"
+            ))
+            .await
+        {
+            Ok(_) => (),
+            Err(err) => return Err(Box::new(err)),
+        };
+
+        let sink = match transaction
+            .copy_in(&format!(
+                "COPY upsert_staging ({}) FROM STDIN BINARY",
+                names.join(", ")
+            ))
+            .await
+        {
+            Ok(v) => v,
+            Err(err) => return Err(Box::new(err)),
+        };
+
+        let mut writer = pin!(BinaryCopyInWriter::new(sink, &types));
+
+        for row in rows.iter() {
+            let values: Vec<&(dyn ToSql + Sync)> = row
+                .iter()
+                .map(|value| value.as_ref() as &(dyn ToSql + Sync))
+                .collect();
+
+            match writer.as_mut().write(&values).await {
+                Ok(_) => (),
+                Err(err) => return Err(Box::new(err)),
+            };
+        }
+
+        match writer.finish().await {
+            Ok(_) => Ok(()),
+            Err(err) => Err(Box::new(err)),
+        }
+    }
+
+    /// Loads `rows` into a temporary staging table with a binary `COPY` and
+    /// upserts them with a single statement. Has to run in a transaction, the
+    /// staging table is dropped on commit.
+    pub async fn copy(
+        &self,
+        transaction: &Transaction<'_>,
+        source_id: i16,
+        rows: &[CopyRow],
+    ) -> Result<RowCounts, Box<tokio_postgres::Error>> {
+        match self.stage(transaction, rows).await {
+            Ok(_) => (),
+            Err(err) => return Err(err),
+        };
+
+        let row = match transaction
+            .query_one(&self.copy_query(), &[&source_id])
+            .await
+        {
+            Ok(v) => v,
+            Err(err) => return Err(Box::new(err)),
+        };
+
+        let (inserted, existing, changed): (i64, i64, i64) = (row.get(0), row.get(1), row.get(2));
+
+        Ok(RowCounts {
+            inserted: inserted as u64,
+            updated: changed as u64,
+            unchanged: (existing - changed) as u64,
+            skipped: rows.len() as u64 - inserted as u64 - existing as u64,
+            rejected: 0,
+        })
+    }
+    /// Compares the rows of `upsert_staging` with the table by the hash of the
+    /// compared values, counts them by what the upsert would do with up to `$2`
+    /// samples of each and keeps their keys in `preview_keys`
+    fn preview_query(&self) -> String {
+        let table = self.table;
+        let row = self.new_row(true);
+
+        let old_match = self
+            .keys
+            .iter()
+            .map(|column| format!("old.\"{0}\" = new_row.\"{0}\"", column.name()))
+            .collect::<Vec<String>>()
+            .join(" AND ");
+
+        let compared = self.compared();
+        let changed = if compared.is_empty() {
+            "false".to_string()
+        } else {
+            let hash = |alias: &str| {
+                format!(
+                    "md5(row({})::text)",
+                    compared
+                        .iter()
+                        .map(|name| format!("{alias}.\"{name}\""))
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                )
+            };
+            format!("{} IS DISTINCT FROM {}", hash("old"), hash("new_row"))
+        };
+
+        let mut insertable = self
+            .insertable()
+            .iter()
+            .map(|condition| format!("new_row.{condition}"))
+            .collect::<Vec<String>>();
+        insertable.push(self.insert.to_string());
+
+        let keys = self
+            .keys
+            .iter()
+            .map(|column| format!("\"{}\"", column.name()))
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        format!(
+            "WITH new_row AS (SELECT {row}), \
+            seen AS (INSERT INTO preview_keys SELECT {keys} FROM new_row), \
+            compared AS ( \
+                SELECT CASE \
+                    WHEN old.ctid IS NOT NULL AND {changed} THEN 'update' \
+                    WHEN old.ctid IS NOT NULL THEN 'unchanged' \
+                    WHEN {} THEN 'insert' \
+                    ELSE 'skip' \
+                END AS action, old AS old_row, new_row \
+                FROM new_row LEFT JOIN {table} AS old ON {old_match} \
+            ) \
+            SELECT action, count(*), coalesce(jsonb_agg(jsonb_build_object( \
+                'old', to_jsonb(old_row), 'new', to_jsonb(new_row) \
+            )) FILTER (WHERE n <= $2), '[]') \
+            FROM (SELECT *, row_number() OVER (PARTITION BY action) AS n FROM compared) AS numbered \
+            GROUP BY action;",
+            insertable.join(" AND ")
+        )
+    }
+
+    /// Rows of the source in the table whose keys aren't in `preview_keys`,
+    /// with up to `$2` samples. `None` if the rows of the source can't be told:
+    /// no `source` or reference among the keys.
+    fn deleted_query(&self) -> Option<String> {
+        let table = self.table;
+
+        let scope = self.keys.iter().find_map(|column| match column {
+            Column::Source => Some("old.source = $1".to_string()),
+            Column::Ref(name, ref_table) => Some(format!(
+                "old.\"{name}\" IN (SELECT id FROM {ref_table} WHERE source = $1)"
+            )),
+            _ => None,
+        })?;
+
+        let seen_match = self
+            .keys
+            .iter()
+            .map(|column| format!("seen.\"{0}\" = old.\"{0}\"", column.name()))
+            .collect::<Vec<String>>()
+            .join(" AND ");
+
+        Some(format!(
+            "SELECT count(*), coalesce(jsonb_agg(jsonb_build_object('old', to_jsonb(old_row))) FILTER (WHERE n <= $2), '[]') \
+            FROM ( \
+                SELECT old AS old_row, row_number() OVER () AS n FROM {table} AS old \
+                WHERE {scope} AND NOT EXISTS (SELECT 1 FROM preview_keys AS seen WHERE {seen_match}) \
+            ) AS gone;"
+        ))
+    }
+
+    /// Creates `preview_keys` for the keys of the previewed rows, a preview runs
+    /// in a transaction that's rolled back
+    pub async fn start_preview(
+        &self,
+        transaction: &Transaction<'_>,
+    ) -> Result<(), Box<tokio_postgres::Error>> {
+        let keys = self
+            .keys
+            .iter()
+            .map(|column| format!("\"{}\"", column.name()))
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        match transaction
+            .batch_execute(&format!(
+                "DROP TABLE IF EXISTS preview_keys; \
+                CREATE TEMP TABLE preview_keys ON COMMIT DROP AS SELECT {keys} FROM {} WITH NO DATA;",
+                self.table
+            ))
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(err) => Err(Box::new(err)),
+        }
+    }
+
+    /// What the upsert would do with `rows`, without writing them to the table
+    pub async fn preview(
+        &self,
+        transaction: &Transaction<'_>,
+        source_id: i16,
+        rows: &[CopyRow],
+        samples: i64,
+    ) -> Result<Vec<PreviewRows>, Box<tokio_postgres::Error>> {
+        match self.stage(transaction, rows).await {
+            Ok(_) => (),
+            Err(err) => return Err(err),
+        };
+
+        let rows = match transaction
+            .query(&self.preview_query(), &[&source_id, &samples])
+            .await
+        {
+            Ok(v) => v,
+            Err(err) => return Err(Box::new(err)),
+        };
+
+        Ok(rows
+            .iter()
+            .map(|row| PreviewRows {
+                action: row.get(0),
+                count: row.get::<_, i64>(1) as u64,
+                samples: row.get(2),
+            })
+            .collect())
+    }
+
+    /// Rows of the source the previewed ones don't have, `None` if the table
+    /// can't tell
+    pub async fn preview_deleted(
+        &self,
+        transaction: &Transaction<'_>,
+        source_id: i16,
+        samples: i64,
+    ) -> Result<Option<PreviewRows>, Box<tokio_postgres::Error>> {
+        let query = match self.deleted_query() {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+
+        match transaction.query_one(&query, &[&source_id, &samples]).await {
+            Ok(row) => Ok(Some(PreviewRows {
+                action: "delete".to_string(),
+                count: row.get::<_, i64>(0) as u64,
+                samples: row.get(1),
+            })),
+            Err(err) => Err(Box::new(err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::upsert::{Column, UpsertSpec};
+
+    const SEQUENCES: UpsertSpec = UpsertSpec {
+        table: "sequences",
+        keys: &[Column::Source, Column::Value("remote_id", "int")],
+        values: &[
+            Column::Value("name", "varchar"),
+            Column::Sql("updated_at", "now()"),
+        ],
+        insert: true,
+        insert_defaults: &[],
+    };
+
+    const BOOK_AUTHORS: UpsertSpec = UpsertSpec {
+        table: "book_authors",
+        keys: &[
+            Column::Ref("book", "books"),
+            Column::Ref("author", "authors"),
+        ],
+        values: &[],
+        insert: true,
+        insert_defaults: &[],
+    };
+
+    #[test]
+    fn test_query() {
+        let expected_result = "WITH new_row AS (SELECT cast($1 as smallint) AS \"source\", cast($2 as int) AS \"remote_id\", cast($3 as varchar) AS \"name\", now() AS \"updated_at\"), \
+            existing AS (UPDATE sequences SET \"name\" = new_row.\"name\", \"updated_at\" = new_row.\"updated_at\" FROM new_row, sequences AS old \
+            WHERE sequences.\"source\" = new_row.\"source\" AND sequences.\"remote_id\" = new_row.\"remote_id\" \
+            AND old.\"source\" = new_row.\"source\" AND old.\"remote_id\" = new_row.\"remote_id\" \
+            RETURNING (old.\"name\") IS DISTINCT FROM (new_row.\"name\") AS changed), \
+            inserted AS (INSERT INTO sequences (\"source\", \"remote_id\", \"name\", \"updated_at\") \
+            SELECT \"source\", \"remote_id\", \"name\", \"updated_at\" FROM new_row WHERE NOT EXISTS (SELECT 1 FROM existing) RETURNING 1) \
+            SELECT (SELECT count(*) FROM inserted), (SELECT count(*) FROM existing), (SELECT count(*) FROM existing WHERE changed);";
+
+        let result = SEQUENCES.query();
+
+        assert_eq!(result, expected_result);
+    }
+
+    #[test]
+    fn test_query_references() {
+        let expected_result = "WITH new_row AS (SELECT (SELECT id FROM books WHERE source = $1 AND remote_id = cast($2 as int)) AS \"book\", \
+            (SELECT id FROM authors WHERE source = $1 AND remote_id = cast($3 as int)) AS \"author\"), \
+            existing AS (SELECT false AS changed FROM book_authors, new_row \
+            WHERE book_authors.\"book\" = new_row.\"book\" AND book_authors.\"author\" = new_row.\"author\"), \
+            inserted AS (INSERT INTO book_authors (\"book\", \"author\") SELECT \"book\", \"author\" FROM new_row \
+            WHERE \"book\" IS NOT NULL AND \"author\" IS NOT NULL AND NOT EXISTS (SELECT 1 FROM existing) RETURNING 1) \
+            SELECT (SELECT count(*) FROM inserted), (SELECT count(*) FROM existing), (SELECT count(*) FROM existing WHERE changed);";
+
+        let result = BOOK_AUTHORS.query();
+
+        assert_eq!(result, expected_result);
+    }
+}
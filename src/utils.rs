@@ -1,15 +1,160 @@
 use ammonia::Builder;
+use encoding_rs::WINDOWS_1251;
 use maplit::hashset;
 use std::fs::File;
 use std::io::{self, BufRead};
 use std::path::Path;
 
-pub fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
+use crate::config::{DumpEncoding, TranslitScheme};
+
+pub struct Lines {
+    reader: Box<dyn BufRead + Send>,
+    encoding: DumpEncoding,
+}
+
+impl Iterator for Lines {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut buf = Vec::new();
+
+        match self.reader.read_until(b'\n', &mut buf) {
+            Ok(0) => None,
+            Ok(_) => {
+                if buf.ends_with(b"\n") {
+                    buf.pop();
+                    if buf.ends_with(b"\r") {
+                        buf.pop();
+                    }
+                }
+
+                Some(decode_line(buf, self.encoding))
+            }
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+pub fn read_lines<P>(filename: P, encoding: DumpEncoding) -> io::Result<Lines>
 where
     P: AsRef<Path>,
 {
+    let is_zstd = filename.as_ref().extension().is_some_and(|v| v == "zst");
     let file = File::open(filename)?;
-    Ok(io::BufReader::new(file).lines())
+
+    let reader: Box<dyn BufRead + Send> = if is_zstd {
+        Box::new(io::BufReader::new(zstd::stream::read::Decoder::new(file)?))
+    } else {
+        Box::new(io::BufReader::new(file))
+    };
+
+    Ok(Lines { reader, encoding })
+}
+
+pub fn decode_line(buf: Vec<u8>, encoding: DumpEncoding) -> io::Result<String> {
+    match encoding {
+        DumpEncoding::Utf8 => {
+            String::from_utf8(buf).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+        }
+        DumpEncoding::Windows1251 => Ok(WINDOWS_1251
+            .decode_without_bom_handling(&buf)
+            .0
+            .into_owned()),
+        DumpEncoding::Auto => match String::from_utf8(buf) {
+            Ok(v) => Ok(v),
+            Err(err) => Ok(WINDOWS_1251
+                .decode_without_bom_handling(err.as_bytes())
+                .0
+                .into_owned()),
+        },
+    }
+}
+
+/// Value tuples of an `INSERT ... VALUES (..),(..);` line, yielded one by one
+/// as text so huge statements never have to be parsed as a whole.
+pub struct InsertTuples<'a> {
+    rest: &'a str,
+}
+
+/// Only these lines carry rows, the rest of a dump (comments, `LOCK TABLES`,
+/// `SET` and so on) is skipped without going anywhere near the parser.
+pub fn is_row_line(line: &str) -> bool {
+    line.starts_with("INSERT ") || line.starts_with("REPLACE ")
+}
+
+/// Splits an `INSERT`/`REPLACE` line into its head (up to and including `VALUES `) and its tuples.
+pub fn insert_tuples(line: &str) -> Option<(&str, InsertTuples<'_>)> {
+    if !is_row_line(line) {
+        return None;
+    }
+
+    let index = line.find(" VALUES ")? + " VALUES ".len();
+
+    Some((
+        &line[..index],
+        InsertTuples {
+            rest: &line[index..],
+        },
+    ))
+}
+
+impl<'a> InsertTuples<'a> {
+    /// Continues over `rest` of an earlier cursor on the same line
+    pub fn resume(rest: &'a str) -> InsertTuples<'a> {
+        InsertTuples { rest }
+    }
+
+    /// The text after the tuples read so far
+    pub fn rest(&self) -> &'a str {
+        self.rest
+    }
+}
+
+impl<'a> Iterator for InsertTuples<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = self.rest.trim_start_matches([',', ' ', '\t']);
+        if !rest.starts_with('(') {
+            self.rest = "";
+            return None;
+        }
+
+        let mut depth = 0;
+        let mut in_string = false;
+        let mut escaped = false;
+
+        for (index, c) in rest.char_indices() {
+            if in_string {
+                match c {
+                    _ if escaped => escaped = false,
+                    '\\' => escaped = true,
+                    // A doubled quote is an escaped one and toggles back on the next char
+                    '\'' => in_string = false,
+                    _ => (),
+                }
+                continue;
+            }
+
+            match c {
+                '\'' => in_string = true,
+                '(' => depth += 1,
+                ')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        let end = index + c.len_utf8();
+                        self.rest = &rest[end..];
+                        return Some(&rest[..end]);
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        // Unterminated tuple, the line is broken
+        self.rest = "";
+        None
+    }
 }
 
 pub fn remove_wrong_chars(s: &str) -> String {
@@ -20,10 +165,176 @@ pub fn remove_wrong_chars(s: &str) -> String {
         .replace("\\'", "'")
 }
 
+pub fn strip_control_chars(s: &str) -> String {
+    s.chars()
+        .filter(|c| *c != '\u{feff}' && (!c.is_control() || *c == '\n' || *c == '\t'))
+        .collect()
+}
+
+pub fn normalize_typography(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut in_tag = false;
+    let mut prev: Option<char> = None;
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '<' => {
+                in_tag = true;
+                result.push(c);
+            }
+            '>' if in_tag => {
+                in_tag = false;
+                result.push(c);
+            }
+            _ if in_tag => result.push(c),
+            '"' => match prev {
+                None => result.push('«'),
+                Some(p) if p.is_whitespace() || "([{-—«>".contains(p) => result.push('«'),
+                Some(_) => result.push('»'),
+            },
+            '.' if chars.peek() == Some(&'.') => {
+                let mut count = 1;
+                while chars.peek() == Some(&'.') {
+                    chars.next();
+                    count += 1;
+                }
+
+                if count == 3 {
+                    result.push('…');
+                } else {
+                    result.push_str(&".".repeat(count));
+                }
+            }
+            '-' => {
+                let mut count = 1;
+                while chars.peek() == Some(&'-') {
+                    chars.next();
+                    count += 1;
+                }
+
+                let spaced = prev.is_none_or(|p| p.is_whitespace())
+                    && chars.peek().is_none_or(|n| n.is_whitespace());
+
+                if count > 1 || spaced {
+                    result.push('—');
+                } else {
+                    result.push('-');
+                }
+            }
+            _ => result.push(c),
+        }
+
+        prev = result.chars().last();
+    }
+
+    result
+}
+
+fn translit_char(c: char, scheme: TranslitScheme) -> Option<&'static str> {
+    let common = match c {
+        'а' => "a",
+        'б' => "b",
+        'в' => "v",
+        'г' => "g",
+        'д' => "d",
+        'е' | 'ё' => "e",
+        'ж' => "zh",
+        'з' => "z",
+        'и' => "i",
+        'к' => "k",
+        'л' => "l",
+        'м' => "m",
+        'н' => "n",
+        'о' => "o",
+        'п' => "p",
+        'р' => "r",
+        'с' => "s",
+        'т' => "t",
+        'у' => "u",
+        'ф' => "f",
+        'ч' => "ch",
+        'ш' => "sh",
+        _ => "",
+    };
+
+    if !common.is_empty() {
+        return Some(common);
+    }
+
+    match scheme {
+        TranslitScheme::Gost => match c {
+            'й' => Some("j"),
+            'х' => Some("x"),
+            'ц' => Some("cz"),
+            'щ' => Some("shh"),
+            'ъ' => Some("``"),
+            'ы' => Some("y`"),
+            'ь' => Some("`"),
+            'э' => Some("e`"),
+            'ю' => Some("yu"),
+            'я' => Some("ya"),
+            _ => None,
+        },
+        TranslitScheme::Passport => match c {
+            'й' => Some("i"),
+            'х' => Some("kh"),
+            'ц' => Some("ts"),
+            'щ' => Some("shch"),
+            'ъ' => Some("ie"),
+            'ы' => Some("y"),
+            'ь' => Some(""),
+            'э' => Some("e"),
+            'ю' => Some("iu"),
+            'я' => Some("ia"),
+            _ => None,
+        },
+    }
+}
+
+pub fn transliterate(s: &str, scheme: TranslitScheme) -> String {
+    let mut result = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        let lower = c.to_lowercase().next().unwrap_or(c);
+
+        match translit_char(lower, scheme) {
+            Some(v) if lower != c => {
+                let mut v_chars = v.chars();
+                if let Some(first) = v_chars.next() {
+                    result.extend(first.to_uppercase());
+                    result.push_str(v_chars.as_str());
+                }
+            }
+            Some(v) => result.push_str(v),
+            None => result.push(c),
+        }
+    }
+
+    result
+}
+
+/// Offset of the rows of a page counted from 0, a missing or negative page
+/// is the first one
+pub fn page_offset(page: Option<i64>, size: i64) -> i64 {
+    page.unwrap_or(0).max(0).saturating_mul(size)
+}
+
 pub fn parse_lang(s: &str) -> String {
     s.replace(['-', '~'], "").to_lowercase()
 }
 
+pub fn normalize_file_type(s: &str) -> String {
+    let file_type = s.trim().trim_start_matches('.').to_lowercase();
+
+    match file_type.as_str() {
+        "htm" => "html".to_string(),
+        "djv" => "djvu".to_string(),
+        "jpeg" => "jpg".to_string(),
+        _ => file_type,
+    }
+}
+
 pub fn fix_annotation_text(text: &str) -> String {
     let mut temp_text = text
         .replace("<br>", "\n")
@@ -40,7 +351,26 @@ pub fn fix_annotation_text(text: &str) -> String {
 
 #[cfg(test)]
 mod tests {
-    use crate::utils::fix_annotation_text;
+    use crate::config::{DumpEncoding, TranslitScheme};
+    use crate::utils::{
+        decode_line, fix_annotation_text, insert_tuples, normalize_file_type, normalize_typography,
+        page_offset, parse_lang, remove_wrong_chars, strip_control_chars, transliterate,
+    };
+    use proptest::prelude::*;
+
+    /// Any text, with the chars the functions care about much more often
+    const TEXT: &str = "(\\PC|[;ё~<>&/\\\\'\" \n-]|<br>|\\\\n)*";
+
+    /// Mostly broken `VALUES` lists
+    const TUPLES: &str = "(\\PC|[(),' \\\\])*";
+
+    #[test]
+    fn test_page_offset() {
+        assert_eq!(page_offset(None, 50), 0);
+        assert_eq!(page_offset(Some(-3), 50), 0);
+        assert_eq!(page_offset(Some(2), 50), 100);
+        assert_eq!(page_offset(Some(i64::MAX), 50), i64::MAX);
+    }
 
     #[test]
     fn test_fix_annotation_text_remove_extra_spaces() {
@@ -81,4 +411,184 @@ mod tests {
 
         assert_eq!(result, expected_result);
     }
+
+    #[test]
+    fn test_remove_wrong_chars_escapes() {
+        let input = "it\\'s; \\\"ёж\\\"";
+        let expected_result = "it's \"еж\"";
+
+        let result = remove_wrong_chars(input);
+
+        assert_eq!(result, expected_result);
+    }
+
+    #[test]
+    fn test_decode_line_windows_1251() {
+        let input = vec![0xcf, 0xf0, 0xe8, 0xe2, 0xe5, 0xf2];
+        let expected_result = "Привет";
+
+        let result = decode_line(input, DumpEncoding::Windows1251).unwrap();
+
+        assert_eq!(result, expected_result);
+    }
+
+    #[test]
+    fn test_decode_line_auto() {
+        let utf8_input = "Привет".as_bytes().to_vec();
+        let cp1251_input = vec![0xcf, 0xf0, 0xe8, 0xe2, 0xe5, 0xf2];
+
+        assert_eq!(
+            decode_line(utf8_input, DumpEncoding::Auto).unwrap(),
+            "Привет"
+        );
+        assert_eq!(
+            decode_line(cp1251_input, DumpEncoding::Auto).unwrap(),
+            "Привет"
+        );
+    }
+
+    #[test]
+    fn test_decode_line_utf8_invalid() {
+        let input = vec![0xcf, 0xf0];
+
+        let result = decode_line(input, DumpEncoding::Utf8);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_strip_control_chars() {
+        let input = "\u{feff}a\0b\u{7}c\td\ne\u{85}";
+        let expected_result = "abc\td\ne";
+
+        let result = strip_control_chars(input);
+
+        assert_eq!(result, expected_result);
+    }
+
+    #[test]
+    fn test_normalize_typography_quotes() {
+        let input = "Роман \"Мастер и Маргарита\"";
+        let expected_result = "Роман «Мастер и Маргарита»";
+
+        let result = normalize_typography(input);
+
+        assert_eq!(result, expected_result);
+    }
+
+    #[test]
+    fn test_normalize_typography_ellipsis_and_dashes() {
+        let input = "Ждать... - сказал он -- и ушёл. Северо-запад";
+        let expected_result = "Ждать… — сказал он — и ушёл. Северо-запад";
+
+        let result = normalize_typography(input);
+
+        assert_eq!(result, expected_result);
+    }
+
+    #[test]
+    fn test_normalize_typography_keeps_tags() {
+        let input = "<a href=\"https://example.com\">\"Ссылка\"</a>";
+        let expected_result = "<a href=\"https://example.com\">«Ссылка»</a>";
+
+        let result = normalize_typography(input);
+
+        assert_eq!(result, expected_result);
+    }
+
+    #[test]
+    fn test_transliterate_passport() {
+        let input = "Щербаков Юрий Хасанович";
+        let expected_result = "Shcherbakov Iurii Khasanovich";
+
+        let result = transliterate(input, TranslitScheme::Passport);
+
+        assert_eq!(result, expected_result);
+    }
+
+    #[test]
+    fn test_transliterate_gost() {
+        let input = "Цветаева, Марина";
+        let expected_result = "Czvetaeva, Marina";
+
+        let result = transliterate(input, TranslitScheme::Gost);
+
+        assert_eq!(result, expected_result);
+    }
+
+    #[test]
+    fn test_normalize_file_type() {
+        assert_eq!(normalize_file_type(" FB2 "), "fb2");
+        assert_eq!(normalize_file_type(".Djv"), "djvu");
+        assert_eq!(normalize_file_type("htm"), "html");
+    }
+
+    #[test]
+    fn test_insert_tuples_skips_noise() {
+        assert!(insert_tuples("-- MySQL dump 10.13").is_none());
+        assert!(insert_tuples("LOCK TABLES `t` WRITE;").is_none());
+        assert!(insert_tuples("/*!40101 SET NAMES utf8 */;").is_none());
+
+        let (head, mut tuples) = insert_tuples("REPLACE INTO `t` VALUES (1);").unwrap();
+
+        assert_eq!(head, "REPLACE INTO `t` VALUES ");
+        assert_eq!(tuples.next(), Some("(1)"));
+    }
+
+    #[test]
+    fn test_insert_tuples() {
+        let input = "INSERT INTO `t` VALUES (1,'a),(b',NULL),(2,'it\\'s','x''y');";
+
+        let (head, tuples) = insert_tuples(input).unwrap();
+
+        assert_eq!(head, "INSERT INTO `t` VALUES ");
+        assert_eq!(
+            tuples.collect::<Vec<&str>>(),
+            vec!["(1,'a),(b',NULL)", "(2,'it\\'s','x''y')"]
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn prop_remove_wrong_chars(s in TEXT) {
+            let result = remove_wrong_chars(&s);
+
+            prop_assert!(!result.contains([';', '\n', 'ё']));
+            prop_assert!(result.chars().count() <= s.chars().count());
+        }
+
+        #[test]
+        fn prop_parse_lang(s in TEXT) {
+            let result = parse_lang(&s);
+
+            prop_assert!(!result.contains(['-', '~']));
+            prop_assert_eq!(parse_lang(&result), result);
+        }
+
+        #[test]
+        fn prop_insert_tuples(s in TUPLES) {
+            let line = format!("INSERT INTO `t` VALUES {s};");
+
+            if let Some((_, tuples)) = insert_tuples(&line) {
+                for tuple in tuples {
+                    prop_assert!(tuple.starts_with('(') && tuple.ends_with(')'));
+                }
+            }
+        }
+
+        #[test]
+        fn prop_fix_annotation_text(s in TEXT) {
+            let result = fix_annotation_text(&s);
+
+            // Only links are left, other `<` are escaped
+            for (i, _) in result.match_indices('<') {
+                prop_assert!(result[i..].starts_with("<a") || result[i..].starts_with("</a"));
+            }
+
+            // Dropped tags can leave double spaces behind
+            if !s.contains('<') {
+                prop_assert!(!result.contains("  "));
+            }
+        }
+    }
 }
@@ -0,0 +1,8 @@
+CREATE TABLE `libaannotations` (
+  `AvtorId` int(11) NOT NULL,
+  `nid` int(11) NOT NULL,
+  `Title` varchar(255) NOT NULL,
+  `Body` text DEFAULT NULL,
+  PRIMARY KEY (`AvtorId`)
+) ENGINE=MyISAM DEFAULT CHARSET=utf8mb3;
+INSERT INTO `libaannotations` VALUES (1,600,'Биография','Русский писатель.<br><br>Родился в Ясной Поляне.'),(3,601,'Biography',NULL);
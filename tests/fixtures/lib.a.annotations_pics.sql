@@ -0,0 +1,7 @@
+CREATE TABLE `libapics` (
+  `AvtorId` int(11) NOT NULL,
+  `nid` int(11) NOT NULL,
+  `File` varchar(255) NOT NULL,
+  PRIMARY KEY (`AvtorId`,`File`)
+) ENGINE=MyISAM DEFAULT CHARSET=utf8mb3;
+INSERT INTO `libapics` VALUES (1,600,'/ia/1/photo.jpg');
@@ -0,0 +1,8 @@
+CREATE TABLE `libbannotations` (
+  `BookId` int(11) NOT NULL,
+  `nid` int(11) NOT NULL,
+  `Title` varchar(255) NOT NULL,
+  `Body` text DEFAULT NULL,
+  PRIMARY KEY (`BookId`)
+) ENGINE=MyISAM DEFAULT CHARSET=utf8mb3;
+INSERT INTO `libbannotations` VALUES (10,500,'Аннотация','<p>Роман-эпопея,   описывающий<br>русское общество.</p><script>alert(1)</script>'),(11,501,'Аннотация',NULL),(12,502,'Annotation','See <a href=\"https://example.org\">the site</a>\nfor more.');
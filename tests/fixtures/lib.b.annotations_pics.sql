@@ -0,0 +1,7 @@
+CREATE TABLE `libbpics` (
+  `BookId` int(11) NOT NULL,
+  `nid` int(11) NOT NULL,
+  `File` varchar(255) NOT NULL,
+  PRIMARY KEY (`BookId`,`File`)
+) ENGINE=MyISAM DEFAULT CHARSET=utf8mb3;
+INSERT INTO `libbpics` VALUES (10,500,'/ib/10/cover.jpg'),(12,502,'/ib/12/map.png');
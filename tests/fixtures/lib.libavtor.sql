@@ -0,0 +1,7 @@
+CREATE TABLE `libavtor` (
+  `BookId` int(10) unsigned NOT NULL DEFAULT 0,
+  `AvtorId` int(10) unsigned NOT NULL DEFAULT 0,
+  `Pos` tinyint(4) NOT NULL DEFAULT 0,
+  PRIMARY KEY (`BookId`,`AvtorId`)
+) ENGINE=MyISAM DEFAULT CHARSET=utf8mb3;
+INSERT INTO `libavtor` VALUES (10,1,0),(11,2,0),(12,3,1);
@@ -0,0 +1,18 @@
+-- MySQL dump excerpt
+DROP TABLE IF EXISTS `libavtorname`;
+CREATE TABLE `libavtorname` (
+  `AvtorId` int(10) unsigned NOT NULL AUTO_INCREMENT,
+  `FirstName` varchar(99) NOT NULL DEFAULT '',
+  `MiddleName` varchar(99) NOT NULL DEFAULT '',
+  `LastName` varchar(99) NOT NULL DEFAULT '',
+  `NickName` varchar(33) NOT NULL DEFAULT '',
+  `uid` int(11) NOT NULL DEFAULT 0,
+  `Email` varchar(255) NOT NULL,
+  `Homepage` varchar(255) NOT NULL,
+  `Gender` char(1) NOT NULL DEFAULT '',
+  `MasterId` int(11) NOT NULL DEFAULT 0,
+  PRIMARY KEY (`AvtorId`)
+) ENGINE=MyISAM DEFAULT CHARSET=utf8mb3;
+LOCK TABLES `libavtorname` WRITE;
+INSERT INTO `libavtorname` VALUES (1,'Лев','Николаевич','Толстой','',0,'','','M',0),(2,'Фёдор','Михайлович','Достоевский','',0,'','','M',0),(3,'John','Ronald Reuel','Tolkien;','',0,'','','',0),(4,'Anne','','Brontë','',0,'','','F',0);
+UNLOCK TABLES;
@@ -0,0 +1,30 @@
+-- MySQL dump excerpt
+DROP TABLE IF EXISTS `libbook`;
+CREATE TABLE `libbook` (
+  `BookId` int(10) unsigned NOT NULL AUTO_INCREMENT,
+  `FileSize` int(10) unsigned NOT NULL DEFAULT 0,
+  `Time` datetime NOT NULL DEFAULT current_timestamp(),
+  `Title` varchar(254) NOT NULL DEFAULT '',
+  `Title1` varchar(254) NOT NULL,
+  `Lang` char(3) NOT NULL DEFAULT 'ru',
+  `LangEx` smallint(5) unsigned NOT NULL DEFAULT 0,
+  `SrcLang` char(3) NOT NULL DEFAULT '',
+  `FileType` char(4) NOT NULL,
+  `Encoding` varchar(32) NOT NULL DEFAULT '',
+  `Year` smallint(6) NOT NULL DEFAULT 0,
+  `Deleted` char(1) NOT NULL DEFAULT '0',
+  `Ver` varchar(8) NOT NULL DEFAULT '',
+  `FileAuthor` varchar(64) NOT NULL,
+  `N` int(10) unsigned NOT NULL DEFAULT 0,
+  `keywords` varchar(255) NOT NULL,
+  `md5` binary(32) NOT NULL,
+  `Modified` timestamp NOT NULL DEFAULT current_timestamp(),
+  `pmd5` char(32) NOT NULL DEFAULT '',
+  `InfoCode` tinyint(4) NOT NULL DEFAULT 0,
+  `Pages` int(10) NOT NULL DEFAULT 0,
+  `Chars` int(10) NOT NULL DEFAULT 0,
+  PRIMARY KEY (`BookId`)
+) ENGINE=MyISAM DEFAULT CHARSET=utf8mb3;
+LOCK TABLES `libbook` WRITE;
+INSERT INTO `libbook` VALUES (10,524288,'2008-01-15 12:30:00','Война и мир','','ru',0,'','fb2','',1869,'0','','',0,'','0123456789abcdef0123456789abcdef','2020-05-01 10:00:00','',0,1300,3000000),(11,1048576,'2010-03-02 08:00:00','Преступление и наказание; роман','','RU',0,'','.FB2','',-1,'1','','',0,'','fedcba9876543210fedcba9876543210','2021-06-07 11:00:00','',0,0,0),(12,2048,'2015-11-20 23:59:59','The Hobbit','','en-GB',0,'','djv','',1937,'0','','',0,'','00000000000000000000000000000000','2022-01-01 00:00:00','',0,310,0);
+UNLOCK TABLES;
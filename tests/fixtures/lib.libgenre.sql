@@ -0,0 +1,7 @@
+CREATE TABLE `libgenre` (
+  `Id` int(10) unsigned NOT NULL AUTO_INCREMENT,
+  `BookId` int(10) unsigned NOT NULL DEFAULT 0,
+  `GenreId` int(10) unsigned NOT NULL DEFAULT 0,
+  PRIMARY KEY (`Id`)
+) ENGINE=MyISAM DEFAULT CHARSET=utf8mb3;
+INSERT INTO `libgenre` VALUES (1,10,1),(2,11,1),(3,12,2);
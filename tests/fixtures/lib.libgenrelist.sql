@@ -0,0 +1,8 @@
+CREATE TABLE `libgenrelist` (
+  `GenreId` int(10) unsigned NOT NULL AUTO_INCREMENT,
+  `GenreCode` varchar(45) NOT NULL DEFAULT '',
+  `GenreDesc` varchar(99) NOT NULL DEFAULT '',
+  `GenreMeta` varchar(45) NOT NULL DEFAULT '',
+  PRIMARY KEY (`GenreId`)
+) ENGINE=MyISAM DEFAULT CHARSET=utf8mb3;
+INSERT INTO `libgenrelist` VALUES (1,'prose_classic','Классическая проза','Проза'),(2,'sf_fantasy','Фэнтези','Фантастика');
@@ -0,0 +1,9 @@
+CREATE TABLE `libseq` (
+  `BookId` int(10) unsigned NOT NULL DEFAULT 0,
+  `SeqId` int(10) unsigned NOT NULL DEFAULT 0,
+  `SeqNumb` int(11) NOT NULL DEFAULT 0,
+  `Level` tinyint(4) NOT NULL DEFAULT 0,
+  `Type` tinyint(4) NOT NULL DEFAULT 0,
+  PRIMARY KEY (`BookId`,`SeqId`)
+) ENGINE=MyISAM DEFAULT CHARSET=utf8mb3;
+INSERT INTO `libseq` VALUES (12,100,1,0,0),(11,101,-3,0,0),(10,101,0,1,0);
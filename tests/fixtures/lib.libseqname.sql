@@ -0,0 +1,6 @@
+CREATE TABLE `libseqname` (
+  `SeqId` int(10) unsigned NOT NULL AUTO_INCREMENT,
+  `SeqName` varchar(254) NOT NULL DEFAULT '',
+  PRIMARY KEY (`SeqId`)
+) ENGINE=MyISAM DEFAULT CHARSET=utf8mb3;
+INSERT INTO `libseqname` VALUES (100,'Средиземье'),(101,'Ёлки; палки');
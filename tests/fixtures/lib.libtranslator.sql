@@ -0,0 +1,7 @@
+CREATE TABLE `libtranslator` (
+  `BookId` int(10) unsigned NOT NULL DEFAULT 0,
+  `TranslatorId` int(10) unsigned NOT NULL DEFAULT 0,
+  `Pos` tinyint(4) NOT NULL DEFAULT 0,
+  PRIMARY KEY (`BookId`,`TranslatorId`)
+) ENGINE=MyISAM DEFAULT CHARSET=utf8mb3;
+INSERT INTO `libtranslator` VALUES (12,4,0),(12,1,2);
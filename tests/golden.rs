@@ -0,0 +1,131 @@
+//! Parses the dump excerpts in `tests/fixtures` and compares the rows with the
+//! JSON in `tests/golden`. Run with `UPDATE_GOLDEN=1` to rewrite the JSON after
+//! an intended change, then review the diff.
+
+use std::path::PathBuf;
+use std::sync::Once;
+
+use library_updater::config::DumpEncoding;
+use library_updater::dump_row::parse_dump;
+use library_updater::types::{
+    Author, AuthorAnnotation, AuthorAnnotationPic, Book, BookAnnotation, BookAnnotationPic,
+    BookAuthor, BookGenre, FromVecExpression, Genre, Sequence, SequenceInfo, Translator,
+};
+use serde::Serialize;
+
+static SETUP: Once = Once::new();
+
+/// The parsers read `CONFIG`, give it what it can't do without
+fn setup() {
+    SETUP.call_once(|| {
+        for (key, value) in [
+            ("SENTRY_DSN", ""),
+            ("POSTGRES_DB_NAME", "library"),
+            ("POSTGRES_HOST", "localhost"),
+            ("POSTGRES_PORT", "5432"),
+            ("POSTGRES_USER", "library"),
+            ("POSTGRES_PASSWORD", "library"),
+            ("FL_BASE_URL", "http://localhost"),
+            ("WEBHOOKS", "[]"),
+        ] {
+            if std::env::var_os(key).is_none() {
+                std::env::set_var(key, value);
+            }
+        }
+    });
+}
+
+fn check<T: FromVecExpression<T> + Serialize>(file_name: &str) {
+    setup();
+
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let fixture = root.join("fixtures").join(file_name);
+    let golden = root.join("golden").join(format!("{file_name}.json"));
+
+    let rows: Vec<T> = match parse_dump(&fixture, DumpEncoding::Auto) {
+        Ok(v) => v,
+        Err(err) => panic!("Can't parse {file_name}: {err}"),
+    };
+
+    let actual = serde_json::to_string_pretty(&rows).unwrap() + "\n";
+
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::write(&golden, &actual).unwrap();
+        return;
+    }
+
+    let expected = match std::fs::read_to_string(&golden) {
+        Ok(v) => v,
+        Err(err) => panic!(
+            "Can't read {}: {err}, run with UPDATE_GOLDEN=1",
+            golden.display()
+        ),
+    };
+
+    assert_eq!(
+        actual,
+        expected,
+        "{file_name} parses differently from {}",
+        golden.display()
+    );
+}
+
+#[test]
+fn authors() {
+    check::<Author>("lib.libavtorname.sql");
+}
+
+#[test]
+fn books() {
+    check::<Book>("lib.libbook.sql");
+}
+
+#[test]
+fn book_authors() {
+    check::<BookAuthor>("lib.libavtor.sql");
+}
+
+#[test]
+fn translators() {
+    check::<Translator>("lib.libtranslator.sql");
+}
+
+#[test]
+fn sequences() {
+    check::<Sequence>("lib.libseqname.sql");
+}
+
+#[test]
+fn sequence_infos() {
+    check::<SequenceInfo>("lib.libseq.sql");
+}
+
+#[test]
+fn book_annotations() {
+    check::<BookAnnotation>("lib.b.annotations.sql");
+}
+
+#[test]
+fn book_annotation_pics() {
+    check::<BookAnnotationPic>("lib.b.annotations_pics.sql");
+}
+
+#[test]
+fn author_annotations() {
+    check::<AuthorAnnotation>("lib.a.annotations.sql");
+}
+
+#[test]
+fn author_annotation_pics() {
+    check::<AuthorAnnotationPic>("lib.a.annotations_pics.sql");
+}
+
+#[test]
+fn genres() {
+    check::<Genre>("lib.libgenrelist.sql");
+}
+
+#[test]
+fn book_genres() {
+    check::<BookGenre>("lib.libgenre.sql");
+}